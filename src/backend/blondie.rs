@@ -0,0 +1,66 @@
+use std::{
+    io::{BufRead, Cursor},
+    process::Command,
+};
+
+use anyhow::{anyhow, Context};
+
+use super::{dtrace, Backend, RecordSettings, Recording};
+use crate::{print_command, FlamegraphError, Workload};
+
+pub const BLONDIE_ERROR: &str = "could not find dtrace and could not profile using blondie";
+
+/// blondie, sampling with Event Tracing for Windows. Only supports profiling a command, and
+/// writes its stacks in the DTrace format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blondie;
+
+impl Backend for Blondie {
+    fn name(&self) -> &str {
+        "blondie"
+    }
+
+    fn record(
+        &self,
+        workload: Workload,
+        settings: &RecordSettings,
+    ) -> Result<Recording, FlamegraphError> {
+        let c = match workload {
+            Workload::Command(c) => c,
+            _ => return Err(anyhow!("blondie can only profile a command").into()),
+        };
+
+        let mut command_builder = Command::new(&c[0]);
+        command_builder.args(&c[1..]);
+        print_command(&command_builder);
+        if settings.dry_run {
+            log::info!("{:?} traced by blondie", command_builder);
+            return Ok(Recording::default());
+        }
+
+        let trace = blondie::trace_command(command_builder, false).map_err(|err| {
+            FlamegraphError::RecordFailed(anyhow!("{}: {:?}", BLONDIE_ERROR, err))
+        })?;
+
+        let f = std::fs::File::create(dtrace::STACKS)
+            .context("unable to create 'cargo-flamegraph.stacks'")?;
+        let mut f = std::io::BufWriter::new(f);
+        trace
+            .write_dtrace(&mut f)
+            .context("unable to write 'cargo-flamegraph.stacks'")?;
+
+        Ok(Recording::default())
+    }
+
+    fn script(&self, _: &Recording, settings: &RecordSettings) -> anyhow::Result<Box<dyn BufRead>> {
+        Ok(Box::new(Cursor::new(dtrace::read_stacks(settings.root)?)))
+    }
+
+    fn collapse(
+        &self,
+        script: &mut dyn BufRead,
+        settings: &RecordSettings,
+    ) -> anyhow::Result<Vec<u8>> {
+        dtrace::collapse(script, settings)
+    }
+}
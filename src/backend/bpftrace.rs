@@ -0,0 +1,199 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, bail, Context};
+
+use super::{is_installed, Backend, RecordSettings, Recording};
+use crate::{FlamegraphError, Workload};
+
+/// File the maps printed by bpftrace are written to.
+const OUTPUT: &str = "bpftrace.stacks";
+
+/// bpftrace, running the program given with `--script` (or `--cmd`) and collapsing the stack
+/// maps it prints on exit. Without a program, user stacks are sampled at the configured
+/// frequency. The bpftrace binary can be overridden with `$BPFTRACE`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bpftrace;
+
+fn bpftrace() -> String {
+    env::var("BPFTRACE").unwrap_or_else(|_| "bpftrace".to_string())
+}
+
+impl Backend for Bpftrace {
+    fn name(&self) -> &str {
+        "bpftrace"
+    }
+
+    fn record(
+        &self,
+        workload: Workload,
+        settings: &RecordSettings,
+    ) -> Result<Recording, FlamegraphError> {
+        if !settings.dry_run
+            && env::var_os("BPFTRACE").is_none()
+            && !is_installed(Command::new("bpftrace"))
+        {
+            return Err(FlamegraphError::BackendMissing("bpftrace"));
+        }
+        let mut command = settings.command(&bpftrace());
+
+        command.arg("-o");
+        command.arg(OUTPUT);
+
+        // Filter of the default program, limiting it to the profiled process.
+        let filter = match workload {
+            Workload::Command(c) => {
+                let escaped = shlex::try_join(c.iter().map(String::as_str))
+                    .context("unable to quote the command for bpftrace")?;
+                command.arg("-c");
+                command.arg(escaped);
+                "/pid == cpid/ ".to_string()
+            }
+            Workload::Pid(p) => match p.as_slice() {
+                [pid] => {
+                    command.arg("-p");
+                    command.arg(pid.to_string());
+                    format!("/pid == {pid}/ ")
+                }
+                _ => return Err(anyhow!("bpftrace can only attach to a single process").into()),
+            },
+            Workload::Thread(_) => {
+                return Err(anyhow!("bpftrace cannot profile single threads").into())
+            }
+            Workload::User(_) => {
+                return Err(anyhow!("bpftrace cannot profile the processes of a user").into())
+            }
+            Workload::ReadPerf(_) | Workload::Folded(_) | Workload::Import(..) => String::new(),
+        };
+
+        match (&settings.script, &settings.custom_cmd) {
+            (Some(script), _) => {
+                command.arg(script);
+            }
+            (None, Some(program)) => {
+                command.arg("-e");
+                command.arg(program);
+            }
+            (None, None) => {
+                command.arg("-e");
+                let stack = match settings.max_stack {
+                    Some(max) => format!("ustack({max})"),
+                    None => "ustack".to_string(),
+                };
+                command.arg(format!(
+                    "profile:hz:{} {filter}{{ @[{stack}] = count(); }}",
+                    settings.frequency
+                ));
+            }
+        }
+
+        let status = settings.run(self.name(), command)?;
+        Ok(Recording {
+            data: Some(PathBuf::from(OUTPUT)),
+            exit_status: Some(status),
+        })
+    }
+
+    fn script(
+        &self,
+        recording: &Recording,
+        settings: &RecordSettings,
+    ) -> anyhow::Result<Box<dyn BufRead>> {
+        if settings.script_no_inline {
+            bail!("--no-inline is not supported by the bpftrace backend");
+        }
+        let path = recording.data.as_deref().unwrap_or(Path::new(OUTPUT));
+        let file =
+            File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+        Ok(Box::new(BufReader::new(file)))
+    }
+
+    fn collapse(&self, script: &mut dyn BufRead, _: &RecordSettings) -> anyhow::Result<Vec<u8>> {
+        let mut stacks = BTreeMap::new();
+        // The scalar parts of the key and the frames of the map entry being read.
+        let mut entry: Option<(Vec<String>, Vec<String>)> = None;
+        for line in script.lines() {
+            let line = line.context("unable to read the bpftrace output")?;
+            let line = line.trim();
+            match &mut entry {
+                None => {
+                    // The start of a map entry, e.g. `@[` or `@name[comm, ` before a stack, or a
+                    // whole entry such as `@[comm]: 3`.
+                    let Some((_, rest)) =
+                        line.strip_prefix('@').and_then(|rest| rest.split_once('['))
+                    else {
+                        continue;
+                    };
+                    match rest.rsplit_once("]: ") {
+                        Some((parts, count)) => {
+                            if let Ok(count) = count.parse::<u64>() {
+                                *stacks.entry(key_parts(parts).join(";")).or_insert(0) += count;
+                            }
+                        }
+                        None => entry = Some((key_parts(rest), Vec::new())),
+                    }
+                }
+                Some((parts, frames)) => match line.strip_prefix("]: ") {
+                    Some(count) => {
+                        // Stacks are printed from the leaf up; folded stacks start at the root.
+                        let count = count.parse::<u64>().unwrap_or(0);
+                        let stack: Vec<_> =
+                            parts.iter().chain(frames.iter().rev()).cloned().collect();
+                        *stacks.entry(stack.join(";")).or_insert(0) += count;
+                        entry = None;
+                    }
+                    None if line.is_empty() || line == "," || line == "]," => (),
+                    None => frames.push(frame_name(line)),
+                },
+            }
+        }
+
+        let mut collapsed = Vec::new();
+        for (stack, count) in stacks {
+            if !stack.is_empty() && count > 0 {
+                collapsed.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+            }
+        }
+        Ok(collapsed)
+    }
+}
+
+/// The scalar parts of a map key, e.g. `comm, pid` of `@[comm, pid, ustack]`.
+fn key_parts(parts: &str) -> Vec<String> {
+    parts
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| part.trim_matches('"').replace(';', ":"))
+        .collect()
+}
+
+/// The function of a stack frame printed by bpftrace, e.g. `main+45`, or
+/// `7f5d2d2b1e27 main+45 (/usr/bin/prog)` with `ustack(perf)`.
+fn frame_name(frame: &str) -> String {
+    let frame = match frame.split_once(' ') {
+        Some((address, rest)) if u64::from_str_radix(address, 16).is_ok() => rest,
+        _ => frame,
+    };
+    let frame = match frame.rsplit_once(" (") {
+        Some((frame, _)) => frame,
+        None => frame,
+    };
+    let function = match frame.rsplit_once('+') {
+        Some((function, offset)) if offset.bytes().all(|b| b.is_ascii_hexdigit() || b == b'x') => {
+            function
+        }
+        _ => frame,
+    };
+    let function = match rustc_demangle::try_demangle(function) {
+        Ok(demangled) => format!("{demangled:#}"),
+        Err(_) => function.to_string(),
+    };
+    function.replace(';', ":")
+}
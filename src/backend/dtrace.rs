@@ -0,0 +1,216 @@
+use std::{
+    env,
+    fs::File,
+    io::{BufRead, Cursor, Read},
+    path::Path,
+    process::Command,
+};
+
+use anyhow::Context;
+use inferno::collapse::{
+    dtrace::{Folder, Options as CollapseOptions},
+    Collapse,
+};
+
+use super::{Backend, RecordSettings, Recording};
+use crate::{chown_to_user, escalate::Root, FlamegraphError, Preset, UsdtProbe, Workload};
+
+/// File the aggregated stacks are written to.
+pub(super) const STACKS: &str = "cargo-flamegraph.stacks";
+
+/// DTrace, aggregating user stacks with a `profile` probe. The dtrace binary can be overridden
+/// with `$DTRACE`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Dtrace;
+
+#[cfg(target_os = "macos")]
+pub(super) fn base_command(root: Option<Root>) -> Command {
+    // If DTrace is spawned from a parent process (or grandparent process etc.) running in Rosetta-emulated x86 mode
+    // on an ARM mac, it will fail to trace the child process with a confusing syntax error in its stdlib .d file.
+    // If the flamegraph binary, or the cargo binary, have been compiled as x86, this can cause all tracing to fail.
+    // To work around that, we unconditionally wrap dtrace on MacOS in the "arch -64/-32" wrapper so it's always
+    // running in the native architecture matching the bit width (32 oe 64) with which "flamegraph" was compiled.
+    // NOTE that dtrace-as-x86 won't trace a deliberately-cross-compiled x86 binary running under Rosetta regardless
+    // of "arch" wrapping; attempts to do that will fail with "DTrace cannot instrument translated processes".
+    // NOTE that using the ARCHPREFERENCE environment variable documented here
+    // (https://www.unix.com/man-page/osx/1/arch/) would be a much simpler solution to this issue, but it does not
+    // seem to have any effect on dtrace when set (via Command::env, shell export, or std::env in the spawning
+    // process).
+    let mut command = match root {
+        Some(root) => root.command("arch"),
+        None => Command::new("arch"),
+    };
+
+    #[cfg(target_pointer_width = "64")]
+    command.arg("-64".to_string());
+    #[cfg(target_pointer_width = "32")]
+    command.arg("-32".to_string());
+
+    command.arg(env::var("DTRACE").unwrap_or_else(|_| "dtrace".to_string()));
+    command
+}
+
+#[cfg(not(target_os = "macos"))]
+pub(super) fn base_command(root: Option<Root>) -> Command {
+    let dtrace = env::var("DTRACE").unwrap_or_else(|_| "dtrace".to_string());
+    match root {
+        Some(root) => root.command(&dtrace),
+        None => Command::new(dtrace),
+    }
+}
+
+/// Reads the stacks written by dtrace (or blondie) and removes the temporary file.
+pub(super) fn read_stacks(root: Option<Root>) -> anyhow::Result<Vec<u8>> {
+    // Ensure the file is readable by the current user if dtrace was run
+    // as root.
+    chown_to_user(&[Path::new(STACKS)], root);
+
+    let mut buf = vec![];
+    let mut f = File::open(STACKS)
+        .context("failed to open dtrace output file 'cargo-flamegraph.stacks'")?;
+
+    f.read_to_end(&mut buf)
+        .context("failed to read dtrace expected output file 'cargo-flamegraph.stacks'")?;
+
+    std::fs::remove_file(STACKS)
+        .context("unable to remove temporary file 'cargo-flamegraph.stacks'")?;
+
+    // Workaround #32 - fails parsing invalid utf8 dtrace output
+    //
+    // Intermittently, invalid utf-8 is found in cargo-flamegraph.stacks, which
+    // causes parsing to blow up with the error:
+    //
+    // > unable to collapse generated profile data: Custom { kind: InvalidData, error: StringError("stream did not contain valid UTF-8") }
+    //
+    // So here we just lossily re-encode to hopefully work around the underlying problem
+    let string = String::from_utf8_lossy(&buf);
+    let reencoded_buf = string.as_bytes().to_owned();
+
+    if reencoded_buf != buf {
+        log::warn!("Lossily converted invalid utf-8 found in cargo-flamegraph.stacks");
+    }
+
+    Ok(reencoded_buf)
+}
+
+pub(super) fn collapse(
+    script: &mut dyn BufRead,
+    settings: &RecordSettings,
+) -> anyhow::Result<Vec<u8>> {
+    let mut collapse_options = CollapseOptions::default();
+    if let Some(threads) = settings.collapse_threads {
+        collapse_options.nthreads = threads;
+    }
+
+    let mut collapsed = vec![];
+    Folder::from(collapse_options).collapse(script, &mut collapsed)?;
+    Ok(collapsed)
+}
+
+impl Backend for Dtrace {
+    fn name(&self) -> &str {
+        "dtrace"
+    }
+
+    fn preset_command(&self, preset: Preset, frequency: u32) -> anyhow::Result<String> {
+        preset.dtrace_command(frequency)
+    }
+
+    fn usdt_command(&self, probes: &[UsdtProbe]) -> anyhow::Result<String> {
+        let probes: Vec<_> = probes
+            .iter()
+            .map(|probe| format!("{}$target:::{}", probe.provider, probe.name))
+            .collect();
+        Ok(format!(
+            "{} {{ @[ustack(100)] = count(); }}",
+            probes.join(", ")
+        ))
+    }
+
+    fn count_calls_command(&self, symbol: &str, library: Option<&Path>) -> anyhow::Result<String> {
+        let module = library
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        Ok(format!(
+            "pid$target:{module}:{symbol}:entry {{ @[ustack(100)] = count(); }}"
+        ))
+    }
+
+    fn record(
+        &self,
+        workload: Workload,
+        settings: &RecordSettings,
+    ) -> Result<Recording, FlamegraphError> {
+        let mut command = base_command(settings.root);
+
+        let freq = settings.frequency;
+        let frames = settings.max_stack.unwrap_or(100);
+        let dtrace_script = settings.custom_cmd.clone().unwrap_or(format!(
+            "profile-{freq} /pid == $target/ \
+             {{ @[ustack({frames})] = count(); }}",
+        ));
+
+        command.arg("-x");
+        command.arg(format!("ustackframes={frames}"));
+
+        command.arg("-n");
+        command.arg(&dtrace_script);
+
+        command.arg("-o");
+        command.arg(STACKS);
+
+        if let Some(args) = &settings.dtrace_args {
+            command.args(args.split_whitespace());
+        }
+
+        match workload {
+            Workload::Command(c) => {
+                let mut escaped = String::new();
+                for (i, arg) in c.iter().enumerate() {
+                    if i > 0 {
+                        escaped.push(' ');
+                    }
+                    escaped.push_str(&arg.replace(' ', "\\ "));
+                }
+
+                command.arg("-c");
+                command.arg(&escaped);
+            }
+            Workload::Pid(p) => {
+                for p in p {
+                    command.arg("-p");
+                    command.arg(p.to_string());
+                }
+            }
+            Workload::Thread(_) => {
+                return Err(anyhow::anyhow!("dtrace cannot profile single threads").into())
+            }
+            Workload::User(_) => {
+                return Err(anyhow::anyhow!("dtrace cannot profile the processes of a user").into())
+            }
+            Workload::ReadPerf(_) | Workload::Folded(_) | Workload::Import(..) => (),
+        }
+
+        let status = settings.run(self.name(), command)?;
+        Ok(Recording {
+            data: None,
+            exit_status: Some(status),
+        })
+    }
+
+    fn script(&self, _: &Recording, settings: &RecordSettings) -> anyhow::Result<Box<dyn BufRead>> {
+        if settings.script_no_inline {
+            return Err(anyhow::anyhow!("--no-inline is only supported on Linux"));
+        }
+        Ok(Box::new(Cursor::new(read_stacks(settings.root)?)))
+    }
+
+    fn collapse(
+        &self,
+        script: &mut dyn BufRead,
+        settings: &RecordSettings,
+    ) -> anyhow::Result<Vec<u8>> {
+        collapse(script, settings)
+    }
+}
@@ -0,0 +1,497 @@
+//! Recorders turning a workload into stack samples.
+//!
+//! A [`Backend`] records a workload, converts the recording into its textual stack format and
+//! collapses that into folded stacks. perf, DTrace and (on Windows) blondie are built in;
+//! library users can plug in their own through [`Options::custom_backend`].
+//!
+//! [`Options::custom_backend`]: crate::Options::custom_backend
+
+use std::{
+    fmt,
+    io::BufRead,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+#[cfg(windows)]
+use std::os::windows::process::ExitStatusExt;
+
+use anyhow::anyhow;
+#[cfg(unix)]
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use self::perf_data::SampleLimit;
+use crate::{
+    escalate::Root, libtest::TestRun, print_command, FlamegraphError, Preset, StopHandle,
+    UsdtProbe, Workload,
+};
+
+#[cfg(all(windows, feature = "blondie"))]
+mod blondie;
+mod bpftrace;
+mod dtrace;
+pub(crate) mod perf;
+mod perf_data;
+
+#[cfg(all(windows, feature = "blondie"))]
+pub use self::blondie::Blondie;
+pub use self::{bpftrace::Bpftrace, dtrace::Dtrace, perf::Perf};
+
+/// A recorder producing stack samples.
+pub trait Backend: fmt::Debug {
+    /// Name of the recorder, used in messages.
+    fn name(&self) -> &str;
+
+    /// The custom command (as passed to `--cmd`) implementing `preset`.
+    fn preset_command(&self, preset: Preset, frequency: u32) -> anyhow::Result<String> {
+        let _ = (preset, frequency);
+        Err(anyhow!(
+            "the {} backend does not support presets",
+            self.name()
+        ))
+    }
+
+    /// The custom command (as passed to `--cmd`) recording the stacks of every hit of `probes`.
+    fn usdt_command(&self, probes: &[UsdtProbe]) -> anyhow::Result<String> {
+        let _ = probes;
+        Err(anyhow!(
+            "the {} backend does not support USDT probes",
+            self.name()
+        ))
+    }
+
+    /// The custom command (as passed to `--cmd`) recording the stacks of every call of `symbol`,
+    /// defined in `library` or else the profiled binary.
+    fn count_calls_command(&self, symbol: &str, library: Option<&Path>) -> anyhow::Result<String> {
+        let _ = (symbol, library);
+        Err(anyhow!(
+            "the {} backend does not support counting calls",
+            self.name()
+        ))
+    }
+
+    /// Runs `workload` under the recorder. Not called when reading an existing recording.
+    fn record(
+        &self,
+        workload: Workload,
+        settings: &RecordSettings,
+    ) -> Result<Recording, FlamegraphError>;
+
+    /// Converts a recording into the recorder's textual stack format, e.g. `perf script` output.
+    /// The output is read while it is collapsed, so it can be streamed from a running process
+    /// instead of being buffered; reading it fails if producing it fails.
+    fn script(
+        &self,
+        recording: &Recording,
+        settings: &RecordSettings,
+    ) -> anyhow::Result<Box<dyn BufRead>>;
+
+    /// Collapses the output of [`script`](Backend::script) into folded stacks.
+    fn collapse(
+        &self,
+        script: &mut dyn BufRead,
+        settings: &RecordSettings,
+    ) -> anyhow::Result<Vec<u8>>;
+
+    /// Line-level hotspot report for `symbol`, written by `--annotate`.
+    fn annotate(
+        &self,
+        recording: &Recording,
+        symbol: &str,
+        settings: &RecordSettings,
+    ) -> anyhow::Result<Vec<u8>> {
+        let _ = (recording, symbol, settings);
+        Err(anyhow!(
+            "--annotate is not supported by the {} backend",
+            self.name()
+        ))
+    }
+
+    /// Report of the cache lines contended on in a recording made with `--c2c`.
+    fn c2c_report(
+        &self,
+        recording: &Recording,
+        settings: &RecordSettings,
+    ) -> anyhow::Result<Vec<u8>> {
+        let _ = (recording, settings);
+        Err(anyhow!(
+            "--c2c is not supported by the {} backend",
+            self.name()
+        ))
+    }
+}
+
+/// The outcome of [`Backend::record`].
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    /// File the recorder wrote its data to, if any.
+    pub data: Option<PathBuf>,
+    /// Exit status of the recorder.
+    pub exit_status: Option<ExitStatus>,
+}
+
+/// Settings for recording and symbolizing, derived from the [`Options`](crate::Options).
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct RecordSettings<'a> {
+    /// Sampling frequency in Hz.
+    pub frequency: u32,
+    /// Custom recorder command given with `--cmd` or implied by a preset.
+    pub custom_cmd: Option<String>,
+    /// Arguments appended to the `perf record` command.
+    pub perf_args: Option<String>,
+    /// Size of perf's sample buffer, passed to `perf record -m`.
+    pub buffer_size: Option<String>,
+    /// Timeout of perf reading `/proc/<pid>/maps`, in milliseconds; `None` uses the default.
+    pub proc_map_timeout: Option<u32>,
+    /// Arguments passed to dtrace in addition to the probe.
+    pub dtrace_args: Option<String>,
+    /// Program run by bpftrace, given with `--script`.
+    pub script: Option<PathBuf>,
+    /// Whether the profiled binary keeps frame pointers, so perf can record call graphs with
+    /// them instead of DWARF.
+    pub frame_pointers: bool,
+    /// Whether to ignore the recorder's exit code.
+    pub ignore_status: bool,
+    /// Whether to print the recorder command instead of running it.
+    pub dry_run: bool,
+    /// Whether to keep the recorder from printing anything but errors.
+    pub quiet: bool,
+    /// Whether to skip inlined frames when symbolizing.
+    pub script_no_inline: bool,
+    /// Number of innermost frames each stack is limited to.
+    pub max_stack: Option<u32>,
+    /// Whether to read perf.data in-process instead of running `perf script`.
+    pub internal_script: bool,
+    /// How the addresses of the recording are resolved into functions.
+    pub symbolize: Symbolizer,
+    /// Whether the recording was made with `perf c2c`, so only loads of contended cache lines
+    /// are collapsed.
+    pub c2c: bool,
+    /// Whether the recording holds the scheduler events of `--sched-latency`, which are
+    /// turned into run-queue delays.
+    pub sched_latency: bool,
+    /// Whether each sample counts once instead of by its period, as `--time-units` needs.
+    pub count_samples: bool,
+    /// Whether the stacks start with the process id next to the command, e.g. `my-binary-1234`,
+    /// so they can be told apart by process.
+    pub include_pid: bool,
+    /// Functions below which stacks are cut off.
+    pub skip_after: Vec<String>,
+    /// Number of threads collapsing the stacks; `None` uses one per CPU.
+    pub collapse_threads: Option<usize>,
+    /// How long the recorder runs before it is stopped like with Ctrl-C; `None` waits for it to
+    /// exit.
+    pub duration: Option<Duration>,
+    /// Number of samples after which perf is stopped like with Ctrl-C and beyond which samples
+    /// are dropped.
+    pub max_samples: Option<u64>,
+    /// Function before whose first sample all samples are dropped.
+    pub trim_before: Option<String>,
+    pub(crate) root: Option<Root<'a>>,
+    /// The perf binary run instead of `$PERF` or `perf`, e.g. the capable copy of
+    /// `--capabilities`.
+    pub(crate) perf: Option<&'a Path>,
+    pub(crate) stop: Option<&'a StopHandle>,
+    /// Receives the tests that ran with `--per-test`, read from the output of the workload.
+    pub(crate) tests: Option<&'a Mutex<Vec<TestRun>>>,
+    /// Whether the output of the tests goes to stderr, to keep stdout to the events of
+    /// `--message-format json`.
+    pub(crate) json: bool,
+    /// Receives the recorder command instead of running it, for an asynchronous run to await.
+    #[cfg(feature = "async")]
+    pub(crate) deferred: Option<&'a Mutex<Option<Deferred>>>,
+}
+
+/// A recorder command whose run is left to the caller of [`Backend::record`].
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub(crate) struct Deferred {
+    pub name: String,
+    pub command: Command,
+    pub data: Option<PathBuf>,
+}
+
+impl RecordSettings<'_> {
+    /// Whether the recorder runs with root privileges.
+    pub fn runs_as_root(&self) -> bool {
+        self.root.is_some()
+    }
+
+    /// A command running `program`, with root privileges if requested.
+    pub fn command(&self, program: &str) -> Command {
+        match self.root {
+            Some(root) => root.command(program),
+            None => Command::new(program),
+        }
+    }
+
+    /// Runs the recorder `command` of the backend `name` to completion, interrupting it when the
+    /// run is stopped.
+    pub fn run(&self, name: &str, command: Command) -> Result<ExitStatus, FlamegraphError> {
+        self.run_recording(name, command, None)
+    }
+
+    /// Like [`run`](RecordSettings::run), but also interrupts perf once it wrote `max_samples`
+    /// samples to the perf.data file `data`.
+    pub(crate) fn run_recording(
+        &self,
+        name: &str,
+        mut command: Command,
+        data: Option<&Path>,
+    ) -> Result<ExitStatus, FlamegraphError> {
+        if self.stop.is_some_and(StopHandle::is_stopped) {
+            return Err(FlamegraphError::Cancelled);
+        }
+
+        print_command(&command);
+        if self.dry_run {
+            log::info!("{:?}", command);
+            return Ok(ExitStatus::from_raw(0));
+        }
+        // The events of the tests are read from the output of the workload while it runs, which
+        // an asynchronous run leaves to a blocking one.
+        #[cfg(feature = "async")]
+        if let Some(deferred) = self.deferred.filter(|_| self.tests.is_none()) {
+            let name = name.to_string();
+            let data = data.map(Path::to_path_buf);
+            *deferred.lock().unwrap() = Some(Deferred {
+                name,
+                command,
+                data,
+            });
+            // Replaced with the actual status once the caller has awaited the command.
+            return Ok(ExitStatus::from_raw(0));
+        }
+
+        if self.tests.is_some() {
+            command.stdout(Stdio::piped());
+        }
+        let mut recorder = command.spawn().map_err(|err| spawn_error(name, err))?;
+        let started = Instant::now();
+        let watcher = recorder
+            .stdout
+            .take()
+            .map(|output| crate::libtest::watch(output, self.json));
+        let mut limit = self.sample_limit(data);
+
+        let mut interrupted = false;
+        let exit_status = if self.stop.is_none() && self.duration.is_none() && limit.is_none() {
+            recorder.wait().map_err(|err| wait_error(name, err))?
+        } else {
+            loop {
+                if let Some(status) = recorder.try_wait().map_err(|err| wait_error(name, err))? {
+                    break status;
+                }
+                if !interrupted && self.should_stop(started, &mut limit) {
+                    if !interrupt(recorder.id()) {
+                        let _ = recorder.kill();
+                    }
+                    interrupted = true;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        };
+        if let (Some(watcher), Some(tests)) = (watcher, self.tests) {
+            *tests.lock().unwrap() = watcher.join().unwrap_or_default();
+        }
+        self.check_status(exit_status, interrupted)
+    }
+
+    /// Runs the recorder command the backend left to an asynchronous run, if any, and takes
+    /// over its exit status into `recording`.
+    #[cfg(feature = "async")]
+    pub(crate) async fn wait_deferred(
+        &self,
+        mut recording: Recording,
+    ) -> Result<Recording, FlamegraphError> {
+        let deferred = self
+            .deferred
+            .and_then(|deferred| deferred.lock().unwrap().take());
+        let Deferred {
+            name,
+            command,
+            data,
+        } = match deferred {
+            Some(deferred) => deferred,
+            None => return Ok(recording),
+        };
+
+        let mut recorder = tokio::process::Command::from(command)
+            .spawn()
+            .map_err(|err| spawn_error(&name, err))?;
+        let started = Instant::now();
+        let mut limit = self.sample_limit(data.as_deref());
+
+        let mut interrupted = false;
+        let exit_status = if self.stop.is_none() && self.duration.is_none() && limit.is_none() {
+            recorder
+                .wait()
+                .await
+                .map_err(|err| wait_error(&name, err))?
+        } else {
+            loop {
+                if let Some(status) = recorder.try_wait().map_err(|err| wait_error(&name, err))? {
+                    break status;
+                }
+                if !interrupted && self.should_stop(started, &mut limit) {
+                    if !recorder.id().is_some_and(interrupt) {
+                        let _ = recorder.start_kill();
+                    }
+                    interrupted = true;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        recording.exit_status = Some(self.check_status(exit_status, interrupted)?);
+        Ok(recording)
+    }
+
+    /// Counts the samples written to the perf.data file `data` if they are limited.
+    fn sample_limit(&self, data: Option<&Path>) -> Option<SampleLimit> {
+        self.max_samples
+            .zip(data)
+            .map(|(max, data)| SampleLimit::new(data, max))
+    }
+
+    /// Whether the recorder started at `started` should be stopped, because the run was stopped,
+    /// the `duration` is over or the recording reached its `limit` of samples.
+    fn should_stop(&self, started: Instant, limit: &mut Option<SampleLimit>) -> bool {
+        if limit.as_mut().is_some_and(SampleLimit::reached) {
+            log::info!(
+                "stopping the recording after {} samples",
+                self.max_samples.unwrap_or(0)
+            );
+            return true;
+        }
+        self.stop.is_some_and(StopHandle::is_stopped)
+            || self
+                .duration
+                .is_some_and(|duration| started.elapsed() >= duration)
+    }
+
+    /// Fails if the recorder exited with `exit_status` because of an error.
+    fn check_status(
+        &self,
+        exit_status: ExitStatus,
+        interrupted: bool,
+    ) -> Result<ExitStatus, FlamegraphError> {
+        // only stop if perf exited unsuccessfully, but
+        // was not killed by a signal (assuming that the
+        // latter case usually means the user interrupted
+        // it in some way)
+        if !self.ignore_status && !interrupted && terminated_by_error(exit_status) {
+            return Err(FlamegraphError::RecordFailed(anyhow!(
+                "the recorder exited with {}",
+                exit_status
+            )));
+        }
+        Ok(exit_status)
+    }
+}
+
+fn spawn_error(name: &str, err: std::io::Error) -> FlamegraphError {
+    FlamegraphError::RecordFailed(anyhow!(err).context(format!("could not spawn {name}")))
+}
+
+fn wait_error(name: &str, err: std::io::Error) -> FlamegraphError {
+    FlamegraphError::RecordFailed(
+        anyhow!(err).context(format!("unable to wait for {name} child command to exit")),
+    )
+}
+
+/// Asks the recorder to stop like Ctrl-C would, so it still writes the samples recorded so far.
+/// Returns false if that failed and the recorder has to be killed instead.
+#[cfg(unix)]
+fn interrupt(pid: u32) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, SIGINT) == 0 }
+}
+
+#[cfg(not(unix))]
+fn interrupt(_: u32) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn terminated_by_error(status: ExitStatus) -> bool {
+    status
+        .signal() // the default needs to be true because that's the neutral element for `&&`
+        .map_or(true, |code| code != SIGINT && code != SIGTERM)
+        && !status.success()
+}
+
+#[cfg(not(unix))]
+fn terminated_by_error(status: ExitStatus) -> bool {
+    !status.success()
+}
+
+/// Whether `command --help` can be run, i.e. the program is installed.
+fn is_installed(mut command: Command) -> bool {
+    command
+        .arg("--help")
+        .stderr(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+/// The built-in backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum BackendKind {
+    /// Linux perf
+    Perf,
+    /// DTrace
+    Dtrace,
+    /// blondie, the fallback on Windows when DTrace is not installed
+    Blondie,
+    /// bpftrace, running the program given with --script
+    Bpftrace,
+}
+
+impl BackendKind {
+    /// perf on Linux, DTrace elsewhere, falling back to blondie on Windows without DTrace.
+    pub fn platform_default() -> Self {
+        if cfg!(target_os = "linux") {
+            BackendKind::Perf
+        } else if cfg!(all(windows, feature = "blondie"))
+            && !is_installed(dtrace::base_command(None))
+        {
+            BackendKind::Blondie
+        } else {
+            BackendKind::Dtrace
+        }
+    }
+
+    pub fn backend(self) -> anyhow::Result<Arc<dyn Backend + Send + Sync>> {
+        Ok(match self {
+            BackendKind::Perf => Arc::new(Perf),
+            BackendKind::Dtrace => Arc::new(Dtrace),
+            BackendKind::Bpftrace => Arc::new(Bpftrace),
+            #[cfg(all(windows, feature = "blondie"))]
+            BackendKind::Blondie => Arc::new(Blondie),
+            #[cfg(not(all(windows, feature = "blondie")))]
+            BackendKind::Blondie => {
+                return Err(anyhow!(
+                    "the blondie backend is only available on Windows with the blondie feature"
+                ))
+            }
+        })
+    }
+}
+
+/// How the addresses of a perf recording are resolved into functions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Symbolizer {
+    /// perf script, or the ELF symbol tables with --internal-script
+    #[default]
+    Perf,
+    /// The DWARF debug info of the mapped binaries, read in-process with addr2line, including
+    /// inlined functions
+    Internal,
+}
@@ -0,0 +1,672 @@
+use std::{
+    env,
+    fmt::Write,
+    io::{self, BufRead, BufReader, Cursor, Read},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use inferno::collapse::{
+    perf::{Folder, Options as CollapseOptions},
+    Collapse,
+};
+
+use super::{is_installed, perf_data, Backend, RecordSettings, Recording, Symbolizer};
+use crate::{libtest, print_command, sched, FlamegraphError, Preset, UsdtProbe, Workload};
+
+/// Linux perf, recording with `perf record` and symbolizing with `perf script`. The perf binary
+/// can be overridden with `$PERF`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Perf;
+
+/// Default of `--proc-map-timeout`. Perf's own 500ms are too short for processes with
+/// thousands of mapped libraries.
+const PROC_MAP_TIMEOUT: u32 = 5000;
+
+fn perf(settings: &RecordSettings) -> String {
+    match settings.perf {
+        Some(perf) => perf.to_string_lossy().into_owned(),
+        None => env::var("PERF").unwrap_or_else(|_| "perf".to_string()),
+    }
+}
+
+impl Backend for Perf {
+    fn name(&self) -> &str {
+        "perf"
+    }
+
+    fn preset_command(&self, preset: Preset, frequency: u32) -> anyhow::Result<String> {
+        Ok(preset.perf_command(frequency))
+    }
+
+    fn usdt_command(&self, probes: &[UsdtProbe]) -> anyhow::Result<String> {
+        let events: String = probes
+            .iter()
+            .map(|probe| format!("-e sdt_{}:{} ", probe.provider, probe.name))
+            .collect();
+        Ok(format!("record {events}-c 1 --call-graph dwarf,16384 -g"))
+    }
+
+    fn count_calls_command(&self, _: &str, _: Option<&Path>) -> anyhow::Result<String> {
+        // The uprobe is placed by `Uprobe::add` before recording.
+        Ok(format!(
+            "record -e {UPROBE_EVENT} -c 1 --call-graph dwarf,16384 -g"
+        ))
+    }
+
+    fn record(
+        &self,
+        workload: Workload,
+        settings: &RecordSettings,
+    ) -> Result<Recording, FlamegraphError> {
+        if !settings.dry_run
+            && settings.perf.is_none()
+            && env::var_os("PERF").is_none()
+            && !is_installed(Command::new("perf"))
+        {
+            return Err(FlamegraphError::BackendMissing("perf"));
+        }
+        let mut command = settings.command(&perf(settings));
+
+        let freq = settings.frequency;
+        let call_graph = if settings.frame_pointers {
+            "fp"
+        } else {
+            "dwarf,16384"
+        };
+        let args = settings
+            .custom_cmd
+            .clone()
+            .unwrap_or(format!("record -F {freq} --call-graph {call_graph} -g"));
+
+        let mut perf_output = None;
+        let mut custom_timeout = false;
+        let mut args_have_clock = false;
+        let perf_args = settings.perf_args.as_deref().unwrap_or_default();
+        let mut args = args.split_whitespace().chain(perf_args.split_whitespace());
+        while let Some(arg) = args.next() {
+            command.arg(arg);
+
+            // A timeout given in the perf arguments takes precedence over the default.
+            custom_timeout |= arg.starts_with("--proc-map-timeout");
+            args_have_clock |= arg == "-k" || arg.starts_with("--clockid");
+
+            // Detect if user is setting `perf record`
+            // output file with `-o`. If so, save it in
+            // order to correctly compute perf's output in
+            // `Self::script`.
+            if arg == "-o" {
+                let next_arg = args
+                    .next()
+                    .ok_or_else(|| anyhow!("missing '-o' argument"))?;
+                command.arg(next_arg);
+                perf_output = Some(PathBuf::from(next_arg));
+            }
+        }
+
+        if settings.proc_map_timeout.is_some() || !custom_timeout {
+            let timeout = settings.proc_map_timeout.unwrap_or(PROC_MAP_TIMEOUT);
+            command.arg(format!("--proc-map-timeout={timeout}"));
+        }
+
+        if let Some(size) = &settings.buffer_size {
+            command.arg("-m");
+            command.arg(size);
+        }
+
+        if settings.quiet {
+            command.arg("--quiet");
+        }
+
+        // The samples are matched with the test events by time, unless a custom command already
+        // picked a clock.
+        if settings.tests.is_some() && !args_have_clock {
+            command.args(["-k", PERF_CLOCK]);
+        }
+
+        let perf_output = match perf_output {
+            Some(path) => path,
+            None => {
+                command.arg("-o");
+                command.arg("perf.data");
+                PathBuf::from("perf.data")
+            }
+        };
+
+        match workload {
+            Workload::Command(c) => {
+                if settings.tests.is_some() {
+                    // Lets test binaries of stable toolchains print JSON, set through env as
+                    // sudo resets the environment.
+                    command.args(["env", "RUSTC_BOOTSTRAP=1"]);
+                }
+                command.args(&c);
+                if settings.tests.is_some() {
+                    command.args(libtest::ARGS);
+                }
+            }
+            Workload::Pid(p) => {
+                if let Some((first, pids)) = p.split_first() {
+                    let mut arg = first.to_string();
+
+                    for pid in pids {
+                        write!(arg, ",{pid}").unwrap();
+                    }
+
+                    command.arg("-p");
+                    command.arg(arg);
+                }
+            }
+            Workload::Thread(t) => {
+                let tids: Vec<_> = t.iter().map(|(_, tid)| tid.to_string()).collect();
+                command.arg("-t");
+                command.arg(tids.join(","));
+            }
+            Workload::User(user) => {
+                command.arg("-u");
+                command.arg(user);
+            }
+            Workload::ReadPerf(_) | Workload::Folded(_) | Workload::Import(..) => (),
+        }
+
+        let status = settings.run_recording(self.name(), command, Some(&perf_output))?;
+        Ok(Recording {
+            data: Some(perf_output),
+            exit_status: Some(status),
+        })
+    }
+
+    fn script(
+        &self,
+        recording: &Recording,
+        settings: &RecordSettings,
+    ) -> anyhow::Result<Box<dyn BufRead>> {
+        let mut output = script_output(recording, settings)?;
+        if let Some(max) = settings.max_samples {
+            // The recording may hold some samples beyond the limit, taken before perf stopped.
+            output = Box::new(Cursor::new(first_samples(output, max)?));
+        }
+        if let Some(symbol) = &settings.trim_before {
+            output = Box::new(Cursor::new(trim_before(output, symbol)?));
+        }
+        Ok(output)
+    }
+
+    fn collapse(
+        &self,
+        script: &mut dyn BufRead,
+        settings: &RecordSettings,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut collapse_options = CollapseOptions::default();
+        collapse_options.skip_after = settings.skip_after.clone();
+        collapse_options.include_pid = settings.include_pid;
+        if let Some(threads) = settings.collapse_threads {
+            collapse_options.nthreads = threads;
+        }
+
+        let mut collapsed = vec![];
+        Folder::from(collapse_options).collapse(script, &mut collapsed)?;
+        Ok(collapsed)
+    }
+
+    fn annotate(
+        &self,
+        recording: &Recording,
+        symbol: &str,
+        settings: &RecordSettings,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut command = settings.command(&perf(settings));
+
+        command.args(["annotate", "--force", "--stdio", "--print-line"]);
+        command.arg(format!("--symbol={symbol}"));
+
+        if let Some(perf_output) = &recording.data {
+            command.arg("-i");
+            command.arg(perf_output);
+        }
+
+        print_command(&command);
+        let output = command.output().context("unable to call perf annotate")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "unable to run 'perf annotate' for {}: ({}) {}",
+                symbol,
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(output.stdout)
+    }
+
+    fn c2c_report(
+        &self,
+        recording: &Recording,
+        settings: &RecordSettings,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut command = settings.command(&perf(settings));
+
+        command.args(["c2c", "report", "--stdio"]);
+
+        if let Some(perf_output) = &recording.data {
+            command.arg("-i");
+            command.arg(perf_output);
+        }
+
+        print_command(&command);
+        let output = command.output().context("unable to call perf c2c report")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "unable to run 'perf c2c report': ({}) {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Converts a recording into `perf script` output, in-process if possible with
+/// `--internal-script` or `--symbolize internal`.
+fn script_output(
+    recording: &Recording,
+    settings: &RecordSettings,
+) -> anyhow::Result<Box<dyn BufRead>> {
+    // Reading perf.data in-process decodes neither where loads were served from nor the
+    // fields of tracepoints.
+    let in_process = settings.internal_script || settings.symbolize == Symbolizer::Internal;
+    if in_process && !settings.c2c && !settings.sched_latency {
+        let data = recording.data.as_deref().unwrap_or(Path::new("perf.data"));
+        match perf_data::script(data, settings) {
+            Ok(output) => return Ok(Box::new(Cursor::new(output))),
+            Err(err) => log::warn!(
+                "unable to read {} in-process, running perf script instead: {:#}",
+                data.display(),
+                err
+            ),
+        }
+    }
+
+    // We executed `perf record` as root, and will be executing `perf script` as root,
+    // so that we can resolve privileged kernel symbols from /proc/kallsyms.
+    let mut command = settings.command(&perf(settings));
+
+    command.arg("script");
+
+    // Force reading perf.data owned by another uid if it happened to be created earlier.
+    command.arg("--force");
+
+    if settings.script_no_inline {
+        command.arg("--no-inline");
+    }
+
+    if let Some(max) = settings.max_stack {
+        command.arg(format!("--max-stack={max}"));
+    }
+
+    // Without a period, every sample counts once when collapsing.
+    if settings.count_samples {
+        command.args(["-F", "-period"]);
+    }
+
+    if settings.c2c {
+        command.args(["-F", "+data_src"]);
+    }
+
+    if let Some(perf_output) = &recording.data {
+        command.arg("-i");
+        command.arg(perf_output);
+    }
+
+    print_command(&command);
+    let output = ScriptOutput::spawn(command).context("unable to call perf script")?;
+    if settings.c2c {
+        return Ok(Box::new(Cursor::new(contended_loads(output)?)));
+    }
+    if settings.sched_latency {
+        return Ok(Box::new(Cursor::new(sched::run_queue_delays(output)?)));
+    }
+    Ok(Box::new(output))
+}
+
+/// The first `max` samples of `perf script` output.
+fn first_samples(script: impl BufRead, max: u64) -> anyhow::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut samples = 0;
+    let mut headers = Headers::default();
+    for line in script.lines() {
+        let line = line.context("unable to read the perf script output")?;
+        if headers.is_header(&line) {
+            if samples == max {
+                log::info!("dropping the samples beyond the first {max}");
+                break;
+            }
+            samples += 1;
+        }
+        output.extend_from_slice(line.as_bytes());
+        output.push(b'\n');
+    }
+    Ok(output)
+}
+
+/// The function of a frame of `perf script` output, e.g. `main` in
+/// `55d4c3e0f1a2 main+0x12 (/usr/bin/my-binary)`.
+fn frame_function(frame: &str) -> &str {
+    let frame = frame.trim_start();
+    let function = frame.split_once(' ').map_or("", |(_, function)| function);
+    let function = function
+        .rsplit_once(" (")
+        .map_or(function, |(function, _)| function);
+    function
+        .rsplit_once("+0x")
+        .map_or(function, |(function, _)| function)
+}
+
+/// Drops the samples of `perf script` output taken before the first one with `symbol` on its
+/// stack, e.g. while the loader and the runtime started the program before its `main`.
+/// Functions in a module match by their last path segment, e.g. `my_crate::main`.
+fn trim_before(script: impl BufRead, symbol: &str) -> anyhow::Result<Vec<u8>> {
+    let matches = |function: &str| {
+        function == symbol
+            || function
+                .strip_suffix(symbol)
+                .is_some_and(|path| path.ends_with("::"))
+    };
+    let mut output = Vec::new();
+    // The lines of the current sample until `symbol` was found.
+    let mut sample = Vec::new();
+    let mut found = false;
+    let mut dropped = 0;
+    let mut headers = Headers::default();
+    for line in script.lines() {
+        let line = line.context("unable to read the perf script output")?;
+        if !found {
+            if headers.is_header(&line) {
+                sample.clear();
+                dropped += 1;
+            } else if !line.trim().is_empty() && matches(frame_function(&line)) {
+                found = true;
+                dropped -= 1;
+                output.append(&mut sample);
+            }
+        }
+        let lines = if found { &mut output } else { &mut sample };
+        lines.extend_from_slice(line.as_bytes());
+        lines.push(b'\n');
+    }
+    if !found {
+        anyhow::bail!("no sample has {symbol} on its stack");
+    }
+    log::info!("dropped {dropped} samples taken before {symbol}");
+    Ok(output)
+}
+
+/// Keeps the samples of `perf script -F +data_src` output that loaded a cache line modified by
+/// another CPU (a HITM), with the data source removed so the output collapses like any other.
+fn contended_loads(script: impl BufRead) -> anyhow::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut keep = true;
+    let mut headers = Headers::default();
+    for line in script.lines() {
+        let line = line.context("unable to read the perf script output")?;
+        let mut line = line.as_str();
+        if headers.is_header(line) {
+            // The data source follows the event as a raw value and its decoded form, e.g.
+            // `68100142 |OP LOAD|LVL L1 hit|SNP HitM|...`.
+            if let Some(start) = line.find(" |OP ") {
+                keep = line[start..].contains("HitM");
+                let header = line[..start].trim_end();
+                line = header.rsplit_once(' ').map_or(header, |(header, _)| header);
+            }
+        }
+        if keep {
+            output.extend_from_slice(line.as_bytes());
+            output.push(b'\n');
+        }
+    }
+    Ok(output)
+}
+
+/// Adds `binary` to perf's build id cache, which makes perf aware of its USDT probes so they
+/// can be recorded as `sdt_PROVIDER:PROBE` events.
+pub(crate) fn cache_probes(binary: &Path, settings: &RecordSettings) -> anyhow::Result<()> {
+    add_build_id(binary, settings)
+        .with_context(|| format!("unable to add the probes of {} to perf", binary.display()))
+}
+
+/// Adds `binary` and the shared libraries it loads to perf's build id cache for
+/// `--cache-build-ids`. Libraries that cannot be added, e.g. without a build id, are skipped.
+pub(crate) fn cache_build_ids(binary: &Path, settings: &RecordSettings) -> anyhow::Result<()> {
+    add_build_id(binary, settings).with_context(|| {
+        format!(
+            "unable to add {} to perf's build id cache",
+            binary.display()
+        )
+    })?;
+    for library in shared_libraries(binary) {
+        if let Err(err) = add_build_id(&library, settings) {
+            log::warn!(
+                "unable to add {} to perf's build id cache: {:#}",
+                library.display(),
+                err
+            );
+        }
+    }
+    Ok(())
+}
+
+fn add_build_id(file: &Path, settings: &RecordSettings) -> anyhow::Result<()> {
+    let mut command = settings.command(&perf(settings));
+    command.args(["buildid-cache", "--add"]).arg(file);
+
+    print_command(&command);
+    let output = command
+        .output()
+        .context("unable to call perf buildid-cache")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "({}) {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// The shared libraries `binary` loads, as resolved by `ldd`; none if they cannot be listed.
+fn shared_libraries(binary: &Path) -> Vec<PathBuf> {
+    let output = match Command::new("ldd").arg(binary).output() {
+        Ok(output) if output.status.success() => output,
+        _ => return Vec::new(),
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            // `libc.so.6 => /lib/x86_64-linux-gnu/libc.so.6 (0x...)`, or the dynamic loader as
+            // `/lib64/ld-linux-x86-64.so.2 (0x...)`.
+            let path = line.split_once("=>").map_or(line, |(_, path)| path);
+            let path = path.rsplit_once(" (").map_or(path, |(path, _)| path).trim();
+            path.starts_with('/').then(|| PathBuf::from(path))
+        })
+        .collect()
+}
+
+/// Tells the sample headers of `perf script` output apart from the frames of their stacks. Both
+/// may be indented, but a header starts every block of lines.
+#[derive(Debug, Default)]
+pub(crate) struct Headers {
+    in_sample: bool,
+}
+
+impl Headers {
+    /// Whether `line`, the next line of the output, is a sample header.
+    pub fn is_header(&mut self, line: &str) -> bool {
+        let blank = line.trim().is_empty();
+        let header = !self.in_sample && !blank;
+        self.in_sample = !blank;
+        header
+    }
+}
+
+/// Clock perf timestamps the samples with when they are matched with events seen by flamegraph,
+/// such as test events or energy readings.
+pub(crate) const PERF_CLOCK: &str = "CLOCK_REALTIME";
+
+/// Current time of `PERF_CLOCK`, in nanoseconds since the Unix epoch.
+pub(crate) fn clock_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64)
+}
+
+/// Position after the timestamp of a `perf script` sample header and the timestamp in
+/// nanoseconds, e.g. `1760000000.123456` in `prog 4242/4243 1760000000.123456: 250000 cycles:`.
+pub(crate) fn sample_time(header: &str) -> Option<(usize, u64)> {
+    let mut offset = 0;
+    for word in header.split(' ') {
+        let end = offset + word.len();
+        offset = end + 1;
+        let Some((secs, fraction)) = word.strip_suffix(':').and_then(|t| t.split_once('.')) else {
+            continue;
+        };
+        let (Ok(secs), Ok(nanos)) = (
+            secs.parse::<u64>(),
+            format!("{fraction:0<9}").parse::<u64>(),
+        ) else {
+            continue;
+        };
+        return Some((end, secs * 1_000_000_000 + nanos));
+    }
+    None
+}
+
+/// Event of the uprobe placed for `--count-calls`.
+const UPROBE_EVENT: &str = "flamegraph:calls";
+
+/// A uprobe on the entry of a function, removed again when dropped.
+pub(crate) struct Uprobe {
+    delete: Command,
+}
+
+impl Uprobe {
+    /// Places a uprobe on `symbol` of `binary`, recorded as the event `flamegraph:calls`.
+    pub fn add(binary: &Path, symbol: &str, settings: &RecordSettings) -> anyhow::Result<Self> {
+        let mut delete = settings.command(&perf(settings));
+        delete.args(["probe", "--quiet", "--del", UPROBE_EVENT]);
+        // A probe left behind by an earlier run would keep the event name from being reused.
+        let _ = delete.output();
+
+        let mut command = settings.command(&perf(settings));
+        command.args(["probe", "--quiet", "-x"]).arg(binary);
+        command.arg("--add").arg(format!("{UPROBE_EVENT}={symbol}"));
+
+        print_command(&command);
+        let output = command.output().context("unable to call perf probe")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "unable to place a uprobe on {} in {}: ({}) {}",
+                symbol,
+                binary.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(Uprobe { delete })
+    }
+}
+
+impl Drop for Uprobe {
+    fn drop(&mut self) {
+        match self.delete.output() {
+            Ok(output) if output.status.success() => (),
+            _ => log::warn!("unable to remove the uprobe {}", UPROBE_EVENT),
+        }
+    }
+}
+
+/// The output of a running `perf script`, read while it is produced. Ends with an error if perf
+/// script fails.
+struct ScriptOutput {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    stderr: Option<JoinHandle<Vec<u8>>>,
+}
+
+impl ScriptOutput {
+    fn spawn(mut command: Command) -> io::Result<Self> {
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        // Read stderr concurrently so perf script cannot block on a full pipe.
+        let mut stderr = child.stderr.take().unwrap();
+        let stderr = thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = stderr.read_to_end(&mut output);
+            output
+        });
+        Ok(ScriptOutput {
+            child,
+            stdout,
+            stderr: Some(stderr),
+        })
+    }
+
+    /// Waits for perf script once its output is exhausted.
+    fn finish(&mut self) -> io::Result<()> {
+        let stderr = match self.stderr.take() {
+            Some(stderr) => stderr.join().unwrap_or_default(),
+            None => return Ok(()),
+        };
+        if !stderr.is_empty() {
+            log::trace!(
+                "perf script: {}",
+                String::from_utf8_lossy(&stderr).trim_end()
+            );
+        }
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "unable to run 'perf script': ({}) {}",
+                status,
+                String::from_utf8_lossy(&stderr)
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Read for ScriptOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stdout.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            self.finish()?;
+        }
+        Ok(len)
+    }
+}
+
+impl BufRead for ScriptOutput {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.stdout.fill_buf()?.is_empty() {
+            self.finish()?;
+        }
+        self.stdout.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.stdout.consume(amt);
+    }
+}
+
+impl Drop for ScriptOutput {
+    fn drop(&mut self) {
+        if self.stderr.is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
@@ -0,0 +1,810 @@
+//! Reads perf.data in-process, writing the same text `perf script` would.
+//!
+//! Only call graphs recorded by the kernel are supported, i.e. frame pointer or LBR call graphs
+//! (`--call-graph fp` or `--call-graph lbr`). DWARF call graphs need the user stacks to be
+//! unwound, which is left to `perf script`. Symbols are taken from the ELF symbol tables of the
+//! mapped files and from `/proc/kallsyms`, without inlined functions unless the functions are
+//! looked up in the DWARF debug info of the files with `--symbolize internal`.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, bail, Context};
+use gimli::{EndianRcSlice, RunTimeEndian};
+use object::{Object, ObjectSection, ObjectSegment, ObjectSymbol, SymbolKind};
+
+use super::{RecordSettings, Symbolizer};
+
+const MAGIC: &[u8] = b"PERFILE2";
+
+const SAMPLE_IP: u64 = 1 << 0;
+const SAMPLE_TID: u64 = 1 << 1;
+const SAMPLE_TIME: u64 = 1 << 2;
+const SAMPLE_ADDR: u64 = 1 << 3;
+const SAMPLE_READ: u64 = 1 << 4;
+const SAMPLE_CALLCHAIN: u64 = 1 << 5;
+const SAMPLE_ID: u64 = 1 << 6;
+const SAMPLE_CPU: u64 = 1 << 7;
+const SAMPLE_PERIOD: u64 = 1 << 8;
+const SAMPLE_STREAM_ID: u64 = 1 << 9;
+const SAMPLE_STACK_USER: u64 = 1 << 13;
+const SAMPLE_IDENTIFIER: u64 = 1 << 16;
+
+/// `perf_event_attr.freq`: `sample_period` holds a frequency.
+const ATTR_FREQ: u64 = 1 << 10;
+
+const FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+const FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+const FORMAT_ID: u64 = 1 << 2;
+const FORMAT_GROUP: u64 = 1 << 3;
+const FORMAT_LOST: u64 = 1 << 4;
+
+const RECORD_MMAP: u32 = 1;
+const RECORD_LOST: u32 = 2;
+const RECORD_COMM: u32 = 3;
+const RECORD_FORK: u32 = 7;
+const RECORD_SAMPLE: u32 = 9;
+const RECORD_MMAP2: u32 = 10;
+const RECORD_AUXTRACE: u32 = 71;
+
+/// Callchain entries from here on mark the context of the following addresses.
+const CONTEXT_MAX: u64 = -4095i64 as u64;
+const CONTEXT_KERNEL: u64 = -128i64 as u64;
+
+/// Converts the perf.data file at `path` into `perf script` output, printing the period of
+/// each sample unless samples are counted and at most `--script-max-stack` frames of its stack.
+pub(super) fn script(path: &Path, settings: &RecordSettings) -> anyhow::Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("unable to read {}", path.display()))?;
+    let mut header = Reader::new(&data);
+    if header.bytes(MAGIC.len())? != MAGIC {
+        bail!("{} is not a perf.data file", path.display());
+    }
+    let _header_size = header.u64()?;
+    let attr_size = header.u64()? as usize;
+    let attrs = header.section(&data)?;
+    let records = header.section(&data)?;
+
+    let events = read_events(&data, attrs, attr_size)?;
+    let first = events
+        .first()
+        .ok_or_else(|| anyhow!("{} contains no events", path.display()))?;
+    if events
+        .iter()
+        .any(|event| event.sample_type != first.sample_type)
+    {
+        bail!("events with different sample formats are not supported");
+    }
+    if first.sample_type & SAMPLE_CALLCHAIN == 0 {
+        bail!("the recording has no call graphs");
+    }
+    if first.sample_type & SAMPLE_STACK_USER != 0 {
+        bail!("DWARF call graphs need to be unwound by perf script");
+    }
+
+    let mut state = State {
+        periods: !settings.count_samples,
+        max_stack: settings.max_stack.map_or(usize::MAX, |max| max as usize),
+        debug_info: settings.symbolize == Symbolizer::Internal && !settings.script_no_inline,
+        ..State::default()
+    };
+    let mut output = String::new();
+    let mut lost = 0;
+    let mut records = Reader::new(records);
+    while !records.is_empty() {
+        let kind = records.u32()?;
+        let _misc = records.u16()?;
+        let size = records.u16()? as usize;
+        if size < 8 {
+            bail!("malformed record in {}", path.display());
+        }
+        let mut body = Reader::new(records.bytes(size - 8)?);
+        match kind {
+            RECORD_LOST => {
+                let _id = body.u64()?;
+                lost += body.u64()?;
+            }
+            RECORD_COMM => {
+                let _pid = body.u32()?;
+                let tid = body.u32()?;
+                state.comms.insert(tid, body.str()?);
+            }
+            RECORD_FORK => {
+                let pid = body.u32()?;
+                let ppid = body.u32()?;
+                let tid = body.u32()?;
+                let ptid = body.u32()?;
+                if let Some(comm) = state.comms.get(&ptid).cloned() {
+                    state.comms.insert(tid, comm);
+                }
+                if pid != ppid {
+                    let maps = state.maps.get(&ppid).cloned().unwrap_or_default();
+                    state.maps.insert(pid, maps);
+                }
+            }
+            RECORD_MMAP | RECORD_MMAP2 => {
+                let pid = body.u32()?;
+                let _tid = body.u32()?;
+                let start = body.u64()?;
+                let len = body.u64()?;
+                let pgoff = body.u64()?;
+                if kind == RECORD_MMAP2 {
+                    // Device and inode, or the build id, followed by the protection and flags.
+                    body.bytes(32)?;
+                }
+                let path = body.str()?;
+                state.maps.entry(pid).or_default().push(Mapping {
+                    start,
+                    end: start.saturating_add(len),
+                    pgoff,
+                    path,
+                });
+            }
+            RECORD_SAMPLE => {
+                let sample = Sample::read(&mut body, first)?;
+                state.write_sample(&sample, &events, &mut output);
+            }
+            RECORD_AUXTRACE => {
+                // The trace data follows the record.
+                let payload = body.u64()? as usize;
+                records.bytes(payload)?;
+            }
+            _ => (),
+        }
+    }
+
+    if lost > 0 {
+        log::warn!("{lost} events were lost while recording; a larger --buffer-size may help");
+    }
+    Ok(output.into_bytes())
+}
+
+struct Event {
+    name: String,
+    ids: Vec<u64>,
+    sample_type: u64,
+    read_format: u64,
+    /// Period of samples that do not record their own, as with a fixed `perf record -c`.
+    period: u64,
+}
+
+fn read_events(data: &[u8], attrs: &[u8], attr_size: usize) -> anyhow::Result<Vec<Event>> {
+    if attr_size < 56 {
+        bail!("unsupported event attribute size {attr_size}");
+    }
+    attrs
+        .chunks_exact(attr_size)
+        .map(|attr| {
+            let mut reader = Reader::new(attr);
+            let kind = reader.u32()?;
+            let _size = reader.u32()?;
+            let config = reader.u64()?;
+            let period_or_freq = reader.u64()?;
+            let sample_type = reader.u64()?;
+            let read_format = reader.u64()?;
+            let flags = reader.u64()?;
+            let period = if flags & ATTR_FREQ != 0 {
+                1
+            } else {
+                period_or_freq
+            };
+
+            // The ids of the event follow its attributes.
+            let mut ids = Reader::new(Reader::new(&attr[attr_size - 16..]).section(data)?);
+            let mut event_ids = Vec::new();
+            while !ids.is_empty() {
+                event_ids.push(ids.u64()?);
+            }
+
+            Ok(Event {
+                name: event_name(kind, config),
+                ids: event_ids,
+                sample_type,
+                read_format,
+                period,
+            })
+        })
+        .collect()
+}
+
+fn event_name(kind: u32, config: u64) -> String {
+    let name = match (kind, config) {
+        (0, 0) => "cycles",
+        (0, 1) => "instructions",
+        (0, 2) => "cache-references",
+        (0, 3) => "cache-misses",
+        (0, 4) => "branch-instructions",
+        (0, 5) => "branch-misses",
+        (1, 0) => "cpu-clock",
+        (1, 1) => "task-clock",
+        (1, 2) => "page-faults",
+        (1, 3) => "context-switches",
+        (1, 4) => "cpu-migrations",
+        _ => return format!("event-{kind}-{config:#x}"),
+    };
+    name.to_string()
+}
+
+#[derive(Default)]
+struct Sample {
+    id: Option<u64>,
+    pid: u32,
+    tid: u32,
+    time: u64,
+    period: Option<u64>,
+    callchain: Vec<u64>,
+}
+
+impl Sample {
+    fn read(reader: &mut Reader, event: &Event) -> anyhow::Result<Self> {
+        let sample_type = event.sample_type;
+        let mut sample = Sample::default();
+        if sample_type & SAMPLE_IDENTIFIER != 0 {
+            sample.id = Some(reader.u64()?);
+        }
+        if sample_type & SAMPLE_IP != 0 {
+            reader.u64()?;
+        }
+        if sample_type & SAMPLE_TID != 0 {
+            sample.pid = reader.u32()?;
+            sample.tid = reader.u32()?;
+        }
+        if sample_type & SAMPLE_TIME != 0 {
+            sample.time = reader.u64()?;
+        }
+        if sample_type & SAMPLE_ADDR != 0 {
+            reader.u64()?;
+        }
+        if sample_type & SAMPLE_ID != 0 {
+            sample.id = Some(reader.u64()?);
+        }
+        if sample_type & SAMPLE_STREAM_ID != 0 {
+            reader.u64()?;
+        }
+        if sample_type & SAMPLE_CPU != 0 {
+            reader.u64()?;
+        }
+        if sample_type & SAMPLE_PERIOD != 0 {
+            sample.period = Some(reader.u64()?);
+        }
+        if sample_type & SAMPLE_READ != 0 {
+            let format = event.read_format;
+            let values = if format & FORMAT_GROUP != 0 {
+                reader.u64()? as usize
+            } else {
+                1
+            };
+            let times = (format & FORMAT_TOTAL_TIME_ENABLED != 0) as usize
+                + (format & FORMAT_TOTAL_TIME_RUNNING != 0) as usize;
+            let per_value =
+                1 + (format & FORMAT_ID != 0) as usize + (format & FORMAT_LOST != 0) as usize;
+            reader.bytes(8 * (times + values * per_value))?;
+        }
+        let entries = reader.u64()? as usize;
+        sample.callchain = (0..entries)
+            .map(|_| reader.u64())
+            .collect::<anyhow::Result<_>>()?;
+        Ok(sample)
+    }
+}
+
+#[derive(Clone)]
+struct Mapping {
+    start: u64,
+    end: u64,
+    pgoff: u64,
+    path: String,
+}
+
+#[derive(Default)]
+struct State {
+    comms: HashMap<u32, String>,
+    /// Mappings of each process, in the order they were created.
+    maps: HashMap<u32, Vec<Mapping>>,
+    images: HashMap<String, Option<Image>>,
+    kernel: Option<Vec<(u64, String)>>,
+    periods: bool,
+    max_stack: usize,
+    /// Whether functions are looked up in the debug info, for `--symbolize internal`.
+    debug_info: bool,
+}
+
+impl State {
+    fn write_sample(&mut self, sample: &Sample, events: &[Event], output: &mut String) {
+        let event = sample
+            .id
+            .and_then(|id| events.iter().find(|event| event.ids.contains(&id)))
+            .unwrap_or(&events[0]);
+        let comm = match self.comms.get(&sample.tid) {
+            Some(comm) => comm.clone(),
+            None => format!(":{}", sample.tid),
+        };
+        write!(
+            output,
+            "{} {}/{} {}.{:06}: ",
+            comm,
+            sample.pid,
+            sample.tid,
+            sample.time / 1_000_000_000,
+            sample.time % 1_000_000_000 / 1_000,
+        )
+        .unwrap();
+        // Collapsing weighs each sample by its period, so event-based profiles count events.
+        if self.periods {
+            write!(output, "{} ", sample.period.unwrap_or(event.period)).unwrap();
+        }
+        writeln!(output, "{}: ", event.name).unwrap();
+
+        let mut kernel = false;
+        let mut leaf = true;
+        let mut frames = 0;
+        for &ip in &sample.callchain {
+            if ip >= CONTEXT_MAX {
+                kernel = ip == CONTEXT_KERNEL;
+                continue;
+            }
+            if frames == self.max_stack {
+                break;
+            }
+            frames += 1;
+            // Return addresses point after the call, which may be the start of another function.
+            let lookup = if leaf { ip } else { ip.saturating_sub(1) };
+            leaf = false;
+            let (symbol, functions, dso) = if kernel {
+                (self.kernel_symbol(lookup), Vec::new(), "[kernel.kallsyms]")
+            } else {
+                self.user_symbol(sample.pid, lookup)
+            };
+            // Like perf script, inlined functions get a line of their own at the same address,
+            // before the function they were inlined into.
+            let inlined = functions.len().saturating_sub(1);
+            for name in &functions[..inlined] {
+                writeln!(output, "\t{ip:16x} {name} ({dso})").unwrap();
+            }
+            match (symbol, functions.last()) {
+                (Some((name, offset)), _) => {
+                    writeln!(output, "\t{ip:16x} {name}+{offset:#x} ({dso})").unwrap()
+                }
+                (None, Some(name)) => writeln!(output, "\t{ip:16x} {name} ({dso})").unwrap(),
+                (None, None) => writeln!(output, "\t{ip:16x} [unknown] ({dso})").unwrap(),
+            }
+        }
+        output.push('\n');
+    }
+
+    /// The symbol at `ip` in the process `pid`, the functions found in the debug info at it
+    /// from the innermost inlined one, and the file mapped there.
+    fn user_symbol(&mut self, pid: u32, ip: u64) -> (Option<(String, u64)>, Vec<String>, &str) {
+        let mapping = match self.maps.get(&pid).and_then(|maps| {
+            maps.iter()
+                .rev()
+                .find(|map| map.start <= ip && ip < map.end)
+        }) {
+            Some(mapping) => mapping,
+            None => return (None, Vec::new(), "[unknown]"),
+        };
+        let debug_info = self.debug_info;
+        let image = self
+            .images
+            .entry(mapping.path.clone())
+            .or_insert_with(|| Image::load(&mapping.path, debug_info));
+        let offset = ip - mapping.start + mapping.pgoff;
+        let (symbol, functions) = match image {
+            Some(image) => (image.symbol(offset), image.functions(offset)),
+            None => (None, Vec::new()),
+        };
+        (symbol, functions, &mapping.path)
+    }
+
+    fn kernel_symbol(&mut self, ip: u64) -> Option<(String, u64)> {
+        let symbols = self.kernel.get_or_insert_with(read_kallsyms);
+        let index = symbols.partition_point(|(address, _)| *address <= ip);
+        let (address, name) = symbols.get(index.checked_sub(1)?)?;
+        Some((name.clone(), ip - address))
+    }
+}
+
+/// Function symbols of an ELF file.
+struct Image {
+    /// File offset, size in the file and virtual address of each segment.
+    segments: Vec<(u64, u64, u64)>,
+    /// Address, size and demangled name, sorted by address.
+    symbols: Vec<(u64, u64, String)>,
+    /// The DWARF debug info, with `--symbolize internal` if the file has any.
+    debug_info: Option<addr2line::Context<EndianRcSlice<RunTimeEndian>>>,
+    /// The functions found in the debug info at each address looked up so far, as most samples
+    /// hit the same few addresses.
+    functions: HashMap<u64, Vec<String>>,
+}
+
+impl Image {
+    fn load(path: &str, debug_info: bool) -> Option<Image> {
+        let data = fs::read(path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+        let segments = file
+            .segments()
+            .map(|segment| {
+                let (offset, size) = segment.file_range();
+                (offset, size, segment.address())
+            })
+            .collect();
+        let mut symbols: Vec<_> = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+            .filter_map(|symbol| {
+                let name = symbol.name().ok()?;
+                let name = format!("{:#}", rustc_demangle::demangle(name));
+                Some((symbol.address(), symbol.size(), name))
+            })
+            .collect();
+        symbols.sort_unstable();
+        symbols.dedup_by_key(|(address, _, _)| *address);
+        let debug_info = if debug_info {
+            load_debug_info(&file)
+        } else {
+            None
+        };
+        Some(Image {
+            segments,
+            symbols,
+            debug_info,
+            functions: HashMap::new(),
+        })
+    }
+
+    /// The virtual address the file offset `offset` is loaded at.
+    fn address(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|(start, size, _)| *start <= offset && offset < start + size)
+            .map(|(start, _, address)| offset - start + address)
+    }
+
+    /// The symbol containing the file offset `offset`, and the offset into it.
+    fn symbol(&self, offset: u64) -> Option<(String, u64)> {
+        let address = self.address(offset)?;
+        let index = self
+            .symbols
+            .partition_point(|(start, _, _)| *start <= address);
+        let (start, size, name) = self.symbols.get(index.checked_sub(1)?)?;
+        if *size != 0 && address >= start + size {
+            return None;
+        }
+        Some((name.clone(), address - start))
+    }
+
+    /// The functions the debug info has at the file offset `offset`, from the innermost inlined
+    /// one; empty without debug info.
+    fn functions(&mut self, offset: u64) -> Vec<String> {
+        let (Some(context), Some(address)) = (&self.debug_info, self.address(offset)) else {
+            return Vec::new();
+        };
+        self.functions
+            .entry(address)
+            .or_insert_with(|| {
+                let mut functions = Vec::new();
+                let Ok(mut frames) = context.find_frames(address).skip_all_loads() else {
+                    return functions;
+                };
+                while let Ok(Some(frame)) = frames.next() {
+                    let Some(name) = frame.function.as_ref().and_then(|f| f.raw_name().ok()) else {
+                        continue;
+                    };
+                    functions.push(format!("{:#}", rustc_demangle::demangle(&name)));
+                }
+                functions
+            })
+            .clone()
+    }
+}
+
+/// Parses the DWARF sections of `file`; `None` if it has no debug info.
+fn load_debug_info(
+    file: &object::File<'_>,
+) -> Option<addr2line::Context<EndianRcSlice<RunTimeEndian>>> {
+    file.section_by_name(".debug_info")?;
+    let endian = if file.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+        let data = file
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or_default();
+        Ok(EndianRcSlice::new(Rc::from(&*data), endian))
+    })
+    .ok()?;
+    addr2line::Context::from_dwarf(dwarf).ok()
+}
+
+/// Kernel symbols sorted by address; empty if their addresses are hidden.
+fn read_kallsyms() -> Vec<(u64, String)> {
+    let kallsyms = fs::read_to_string("/proc/kallsyms").unwrap_or_default();
+    let mut symbols: Vec<_> = kallsyms
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let _kind = fields.next()?;
+            Some((address, fields.next()?.to_string()))
+        })
+        .filter(|(address, _)| *address != 0)
+        .collect();
+    symbols.sort_unstable();
+    symbols
+}
+
+/// Counts the samples perf writes to a perf.data file while it records, for `--max-samples`.
+#[derive(Debug)]
+pub(crate) struct SampleLimit {
+    path: PathBuf,
+    max: u64,
+    samples: u64,
+    /// Where the next record starts, once the header was read.
+    offset: Option<u64>,
+    /// When the recording started; a file modified before is left over from an earlier one.
+    started: SystemTime,
+}
+
+impl SampleLimit {
+    pub fn new(path: &Path, max: u64) -> Self {
+        SampleLimit {
+            path: path.to_path_buf(),
+            max,
+            samples: 0,
+            offset: None,
+            started: SystemTime::now(),
+        }
+    }
+
+    /// Whether perf has written `max` samples by now. Only the records written since the last
+    /// call are read; a record perf has not completely written yet is read the next time.
+    pub fn reached(&mut self) -> bool {
+        if let Err(err) = self.count() {
+            log::debug!("unable to count the samples in {:?}: {}", self.path, err);
+        }
+        self.samples >= self.max
+    }
+
+    fn count(&mut self) -> anyhow::Result<()> {
+        let mut file = fs::File::open(&self.path)?;
+        if file.metadata()?.modified()? < self.started {
+            return Ok(());
+        }
+        let offset = match self.offset {
+            Some(offset) => offset,
+            None => {
+                let mut header = [0; 56];
+                file.read_exact(&mut header)?;
+                let mut header = Reader::new(&header);
+                if header.bytes(MAGIC.len())? != MAGIC {
+                    bail!("not a perf.data file");
+                }
+                // The header size, the size of an attribute and the attribute section come
+                // before the data section.
+                header.bytes(32)?;
+                header.u64()?
+            }
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut records = Reader::new(&data);
+        let mut read = 0;
+        while records.data.len() >= 8 {
+            let kind = records.u32()?;
+            let _misc = records.u16()?;
+            let size = records.u16()? as usize;
+            if size < 8 {
+                bail!("malformed record");
+            }
+            if records.bytes(size - 8).is_err() {
+                break;
+            }
+            read += size as u64;
+            if kind == RECORD_SAMPLE {
+                self.samples += 1;
+            }
+        }
+        self.offset = Some(offset + read);
+        Ok(())
+    }
+}
+
+/// Reads native-endian values from a byte slice.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if len > self.data.len() {
+            bail!("unexpected end of perf data");
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_ne_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_ne_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_ne_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// The NUL-terminated string ending a record.
+    fn str(&mut self) -> anyhow::Result<String> {
+        let end = self
+            .data
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.data.len());
+        let string = String::from_utf8_lossy(&self.data[..end]).into_owned();
+        self.data = &[];
+        Ok(string)
+    }
+
+    /// Reads an offset and size, and returns that part of `data`.
+    fn section(&mut self, data: &'a [u8]) -> anyhow::Result<&'a [u8]> {
+        let offset = self.u64()? as usize;
+        let size = self.u64()? as usize;
+        offset
+            .checked_add(size)
+            .and_then(|end| data.get(offset..end))
+            .ok_or_else(|| anyhow!("perf data section out of bounds"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Size of the attributes perf 6.x writes, including the section of their ids.
+    const ATTR_SIZE: usize = 136 + 16;
+
+    const CONTEXT_USER: u64 = -512i64 as u64;
+
+    fn u64s(values: &[u64]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect()
+    }
+
+    /// An attribute as in the attribute section of perf.data, its ids at `ids` of the file.
+    fn attr(
+        kind: u32,
+        config: u64,
+        sample_type: u64,
+        period: u64,
+        flags: u64,
+        ids: (u64, u64),
+    ) -> Vec<u8> {
+        let mut attr = Vec::new();
+        attr.extend_from_slice(&kind.to_ne_bytes());
+        attr.extend_from_slice(&136u32.to_ne_bytes());
+        attr.extend(u64s(&[config, period, sample_type, 0, flags]));
+        attr.resize(136, 0);
+        attr.extend(u64s(&[ids.0, ids.1]));
+        attr
+    }
+
+    fn event(sample_type: u64, period: u64) -> Event {
+        Event {
+            name: "cycles".to_string(),
+            ids: vec![7],
+            sample_type,
+            read_format: 0,
+            period,
+        }
+    }
+
+    #[test]
+    fn read_events_with_fixed_period_and_frequency() {
+        // Two events sharing a data section that holds their ids after the attributes.
+        let sample_type = SAMPLE_IDENTIFIER | SAMPLE_TID | SAMPLE_TIME | SAMPLE_CALLCHAIN;
+        let ids_offset = 2 * ATTR_SIZE as u64;
+        let mut data = attr(0, 0, sample_type, 4000, ATTR_FREQ, (ids_offset, 16));
+        data.extend(attr(1, 0, sample_type, 250_000, 0, (ids_offset + 16, 8)));
+        data.extend(u64s(&[7, 8, 9]));
+
+        let events = read_events(&data, &data[..2 * ATTR_SIZE], ATTR_SIZE).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].name, "cycles");
+        assert_eq!(events[0].ids, [7, 8]);
+        // A frequency says nothing about the period of a sample.
+        assert_eq!(events[0].period, 1);
+        assert_eq!(events[1].name, "cpu-clock");
+        assert_eq!(events[1].ids, [9]);
+        assert_eq!(events[1].period, 250_000);
+        assert_eq!(events[1].sample_type, sample_type);
+    }
+
+    #[test]
+    fn read_events_rejects_short_attributes() {
+        assert!(read_events(&[], &[0; 48], 48).is_err());
+    }
+
+    #[test]
+    fn sample_without_period_uses_the_event_period() {
+        let event = event(
+            SAMPLE_IDENTIFIER | SAMPLE_IP | SAMPLE_TID | SAMPLE_TIME | SAMPLE_CALLCHAIN,
+            250_000,
+        );
+        let mut body = u64s(&[7, 0x1000]);
+        body.extend_from_slice(&42u32.to_ne_bytes());
+        body.extend_from_slice(&43u32.to_ne_bytes());
+        body.extend(u64s(&[1_760_000_000_123_456_789, 2, CONTEXT_USER, 0x1000]));
+
+        let sample = Sample::read(&mut Reader::new(&body), &event).unwrap();
+        assert_eq!(sample.id, Some(7));
+        assert_eq!((sample.pid, sample.tid), (42, 43));
+        assert_eq!(sample.period, None);
+        assert_eq!(sample.callchain, [CONTEXT_USER, 0x1000]);
+
+        let mut state = State {
+            periods: true,
+            max_stack: usize::MAX,
+            ..State::default()
+        };
+        state.comms.insert(43, "worker".to_string());
+        let mut output = String::new();
+        state.write_sample(&sample, &[event], &mut output);
+        assert_eq!(
+            output,
+            "worker 42/43 1760000000.123456: 250000 cycles: \n\
+             \t            1000 [unknown] ([unknown])\n\n"
+        );
+    }
+
+    #[test]
+    fn sample_with_period() {
+        let event = event(
+            SAMPLE_TID | SAMPLE_TIME | SAMPLE_CPU | SAMPLE_PERIOD | SAMPLE_CALLCHAIN,
+            1,
+        );
+        let mut body = Vec::new();
+        body.extend_from_slice(&42u32.to_ne_bytes());
+        body.extend_from_slice(&42u32.to_ne_bytes());
+        body.extend(u64s(&[1_000_000_000, 3, 12_345, 1, 0x2000]));
+
+        let sample = Sample::read(&mut Reader::new(&body), &event).unwrap();
+        assert_eq!(sample.id, None);
+        assert_eq!(sample.time, 1_000_000_000);
+        assert_eq!(sample.period, Some(12_345));
+        assert_eq!(sample.callchain, [0x2000]);
+
+        let mut state = State {
+            periods: true,
+            max_stack: usize::MAX,
+            ..State::default()
+        };
+        let mut output = String::new();
+        state.write_sample(&sample, &[event], &mut output);
+        assert!(output.starts_with(":42 42/42 1.000000: 12345 cycles: \n"));
+    }
+}
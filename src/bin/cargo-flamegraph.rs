@@ -1,10 +1,13 @@
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
 use anyhow::{anyhow, Context};
 use cargo_metadata::{Artifact, ArtifactDebuginfo, Message, MetadataCommand, Package, TargetKind};
-use clap::{Args, Parser};
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser};
 
-use flamegraph::Workload;
+use flamegraph::{JsonEvents, MessageFormat, Phase, ProfilerObserver, Workload};
 
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
 #[clap(rename_all = "snake_case")]
@@ -13,6 +16,14 @@ enum UnitTestTargetKind {
     Lib,
 }
 
+/// Shells `--completions` can generate a script for.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
 #[derive(Args, Debug)]
 struct Opt {
     /// Build with the dev profile
@@ -40,15 +51,25 @@ struct Opt {
     example: Option<String>,
 
     /// Test binary to run (currently profiles the test harness and all tests in the binary)
-    #[clap(long, group = "exec-args")]
+    #[clap(long, group = "exec-args", group = "libtest")]
     test: Option<String>,
 
     /// Crate target to unit test, <unit-test> may be omitted if crate only has one target
     /// (currently profiles the test harness and all tests in the binary; test selection
     /// can be passed as trailing arguments after `--` as separator)
-    #[clap(long, group = "exec-args")]
+    #[clap(long, group = "exec-args", group = "libtest")]
     unit_test: Option<Option<String>>,
 
+    /// Run only the test named exactly <TEST> of --unit-test or --test, on a single thread and
+    /// without capturing its output
+    #[clap(long, value_name = "TEST", requires = "libtest")]
+    exact: Option<String>,
+
+    /// Keep the frames of the test harness below the tests of --unit-test and --test, which are
+    /// removed by default so every test is the root of its stacks
+    #[clap(long, requires = "libtest")]
+    keep_harness_frames: bool,
+
     /// Kind of target (lib or bin) when running with <unit-test> which is may be
     /// required when we have two targets with the same name.
     #[clap(long)]
@@ -64,10 +85,90 @@ struct Opt {
     #[clap(long, group = "exec-args")]
     bench: Option<String>,
 
+    /// Profile only the Criterion benchmarks of <bench> whose ID matches <ID>, running them in
+    /// Criterion's profiling mode without warmup or analysis
+    #[clap(long, value_name = "ID", requires = "bench")]
+    criterion_filter: Option<String>,
+
+    /// Seconds to run each Criterion benchmark for in profiling mode [default: 5]
+    #[clap(long, value_name = "SECS", requires = "bench")]
+    profile_time: Option<u64>,
+
+    /// Build the tests with cargo-nextest and profile the single test matching <FILTER> in a
+    /// process of its own, as nextest runs it, instead of the whole test harness
+    #[clap(long, value_name = "FILTER", group = "exec-args")]
+    nextest: Option<String>,
+
+    /// Profile the doctests of the item at <PATH>, e.g. `module::function`. Needs a nightly
+    /// toolchain, as in `cargo +nightly flamegraph --doctest ...`
+    #[clap(long, value_name = "PATH", group = "exec-args")]
+    doctest: Option<String>,
+
+    /// Profile every binary and benchmark of the package in turn, writing a flamegraph per
+    /// target and an index of them (benchmarks are run with `--bench`)
+    #[clap(long, group = "exec-args")]
+    all_targets: bool,
+
+    /// List the targets that can be profiled and exit
+    #[clap(long, conflicts_with_all = ["exec-args", "workspace"])]
+    list: bool,
+
+    /// Print a script completing the options of `cargo flamegraph` and the names of the
+    /// targets and packages of the current workspace in the given shell
+    #[clap(long, value_name = "SHELL", exclusive = true)]
+    completions: Option<CompletionShell>,
+
+    /// Print the completions of the last trailing argument, given the ones before it, as the
+    /// scripts of --completions do on every tab
+    #[clap(long, hide = true)]
+    complete: bool,
+
+    /// Profile every benchmark of the package in turn, building them all at once, and write a
+    /// flamegraph per benchmark and an index of them
+    #[clap(long, group = "exec-args")]
+    benches: bool,
+
+    /// Profile the target once per value of NAME, replacing `{NAME}` in the trailing arguments
+    /// and setting the NAME environment variable, and write a flamegraph per value, differential
+    /// flamegraphs against the first one and an index of them; may be repeated to profile every
+    /// combination of values
+    #[clap(
+        long,
+        value_name = "NAME={VALUE,...}",
+        conflicts_with_all = ["all_targets", "benches", "workspace", "list"]
+    )]
+    matrix: Vec<flamegraph::matrix::Parameter>,
+
+    /// Build and profile the target at two git revisions, each in a temporary worktree, and
+    /// write a differential flamegraph and a table of the functions whose share of samples
+    /// changed; fails if any grew by more than --regression-threshold. Also spelled
+    /// `cargo flamegraph compare-rev <BEFORE> <AFTER>`
+    #[clap(
+        long,
+        num_args = 2,
+        value_names = ["BEFORE", "AFTER"],
+        conflicts_with_all = ["all_targets", "benches", "workspace", "list", "matrix", "dry_run"]
+    )]
+    compare_rev: Vec<String>,
+
+    /// Like --all-targets, for all packages of the workspace
+    #[clap(
+        long,
+        conflicts_with_all = ["bin", "example", "test", "bench", "unit_test", "unit_bench", "nextest", "doctest"]
+    )]
+    workspace: bool,
+
     /// Path to Cargo.toml
     #[clap(long)]
     manifest_path: Option<PathBuf>,
 
+    /// Directory for all build artifacts, like `cargo build --target-dir`
+    #[clap(long, value_name = "DIRECTORY")]
+    target_dir: Option<PathBuf>,
+
+    #[clap(flatten)]
+    lock: LockOptions,
+
     /// Build features to enable
     #[clap(short, long)]
     features: Option<String>,
@@ -76,10 +177,29 @@ struct Opt {
     #[clap(long)]
     no_default_features: bool,
 
+    /// Pass <ARG> on to the cargo build, e.g. `--cargo-arg=-Zbuild-std=std`; may be repeated
+    #[clap(long, value_name = "ARG", allow_hyphen_values = true)]
+    cargo_arg: Vec<String>,
+
     /// No-op. For compatibility with `cargo run --release`.
     #[clap(short, long)]
     release: bool,
 
+    /// Keep the debuginfo setting of the build profile instead of enabling debug info, which
+    /// profiles other than dev may lack, so symbols and inlined frames are shown
+    #[clap(long)]
+    no_debuginfo: bool,
+
+    /// Build with `-C force-frame-pointers=yes`, so perf can record complete call graphs with
+    /// frame pointers. Applies to the dependencies as well, as unwinding stops at the first
+    /// function without a frame pointer
+    #[clap(long)]
+    force_frame_pointers: bool,
+
+    /// Record with root privileges without asking when DTrace requires them (macOS)
+    #[clap(long)]
+    auto_root: bool,
+
     #[clap(flatten)]
     graph: flamegraph::Options,
 
@@ -88,11 +208,51 @@ struct Opt {
     trailing_arguments: Vec<String>,
 }
 
+/// Cargo options keeping the lockfile and the network untouched, passed to every cargo
+/// invocation.
+#[derive(Args, Debug, Clone, Copy)]
+struct LockOptions {
+    /// Require Cargo.lock to be up to date
+    #[clap(long)]
+    locked: bool,
+
+    /// Run cargo without accessing the network
+    #[clap(long)]
+    offline: bool,
+
+    /// Equivalent to specifying both --locked and --offline
+    #[clap(long)]
+    frozen: bool,
+}
+
+impl LockOptions {
+    fn args(self) -> Vec<String> {
+        [
+            (self.locked, "--locked"),
+            (self.offline, "--offline"),
+            (self.frozen, "--frozen"),
+        ]
+        .into_iter()
+        .filter(|&(set, _)| set)
+        .map(|(_, flag)| flag.to_string())
+        .collect()
+    }
+
+    fn metadata_command(self, manifest_path: Option<&Path>) -> MetadataCommand {
+        let mut metadata_command = MetadataCommand::new();
+        if let Some(manifest_path) = manifest_path {
+            metadata_command.manifest_path(manifest_path);
+        }
+        metadata_command.other_options(self.args());
+        metadata_command
+    }
+}
+
 #[derive(Parser, Debug)]
 #[clap(bin_name = "cargo")]
 enum Cli {
     /// A cargo subcommand for generating flamegraphs, using inferno
-    #[clap(version)]
+    #[clap(version, args_override_self = true)]
     Flamegraph(Opt),
 }
 
@@ -102,7 +262,7 @@ fn build(opt: &Opt, kind: Vec<TargetKind>) -> anyhow::Result<Vec<Artifact>> {
 
     // This will build benchmarks with the `bench` profile. This is needed
     // because the `--profile` argument for `cargo build` is unstable.
-    if !opt.dev && (opt.bench.is_some() || opt.unit_bench.is_some()) {
+    if !opt.dev && (opt.bench.is_some() || opt.unit_bench.is_some() || opt.benches) {
         cmd.args(["bench", "--no-run"]);
     } else if opt.unit_test.is_some() {
         cmd.args(["test", "--no-run"]);
@@ -112,7 +272,7 @@ fn build(opt: &Opt, kind: Vec<TargetKind>) -> anyhow::Result<Vec<Artifact>> {
 
     if let Some(profile) = &opt.profile {
         cmd.arg("--profile").arg(profile);
-    } else if !opt.dev && opt.bench.is_none() && opt.unit_bench.is_none() {
+    } else if !opt.dev && opt.bench.is_none() && opt.unit_bench.is_none() && !opt.benches {
         // do not use `--release` when we are building for `bench`
         cmd.arg("--release");
     }
@@ -147,6 +307,10 @@ fn build(opt: &Opt, kind: Vec<TargetKind>) -> anyhow::Result<Vec<Artifact>> {
         cmd.arg(bench);
     }
 
+    if opt.benches {
+        cmd.arg("--benches");
+    }
+
     if let Some(Some(ref unit_test)) = opt.unit_test {
         match kind.iter().any(|k| k == &TargetKind::Lib) {
             true => cmd.arg("--lib"),
@@ -161,11 +325,77 @@ fn build(opt: &Opt, kind: Vec<TargetKind>) -> anyhow::Result<Vec<Artifact>> {
         };
     }
 
+    cargo_options(opt, &mut cmd);
+    cmd.args(cargo_verbosity(opt));
+
+    cmd.arg("--message-format=json-render-diagnostics");
+
+    if opt.graph.dry_run {
+        println!("{:?}", cmd);
+        return Ok(Vec::new());
+    }
+
+    if opt.graph.verbose > 0 {
+        println!("build command: {:?}", cmd);
+    }
+
+    let Output { status, stdout, .. } = building(opt, || {
+        cmd.stderr(Stdio::inherit())
+            .output()
+            .context("failed to execute cargo build command")
+    })?;
+
+    if !status.success() {
+        return Err(anyhow!("cargo build failed"));
+    }
+
+    Message::parse_stream(&*stdout)
+        .filter_map(|m| match m {
+            Ok(Message::CompilerArtifact(artifact)) => Some(Ok(artifact)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e).context("failed to parse cargo build output")),
+        })
+        .collect()
+}
+
+/// Runs `build`, reporting it as the building phase with `--message-format json`.
+fn building<T>(opt: &Opt, build: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    if opt.graph.message_format != MessageFormat::Json {
+        return build();
+    }
+    JsonEvents.phase_started(Phase::Building);
+    let started = Instant::now();
+    let result = build()?;
+    JsonEvents.phase_finished(Phase::Building, started.elapsed());
+    Ok(result)
+}
+
+/// The flag passing `--quiet` or `-vv` on to cargo.
+fn cargo_verbosity(opt: &Opt) -> Option<&'static str> {
+    if opt.graph.quiet {
+        Some("--quiet")
+    } else if opt.graph.verbose >= 2 {
+        Some("--verbose")
+    } else {
+        None
+    }
+}
+
+/// Adds the options of `opt` that apply to any cargo build, whichever targets it builds.
+fn cargo_options(opt: &Opt, cmd: &mut std::process::Command) {
     if let Some(ref manifest_path) = opt.manifest_path {
         cmd.arg("--manifest-path");
         cmd.arg(manifest_path);
     }
 
+    // The artifacts are located through the build messages, wherever they end up.
+    if let Some(ref target_dir) = opt.target_dir {
+        cmd.arg("--target-dir");
+        cmd.arg(target_dir);
+    }
+
+    cmd.args(opt.lock.args());
+
     if let Some(ref features) = opt.features {
         cmd.arg("--features");
         cmd.arg(features);
@@ -175,50 +405,342 @@ fn build(opt: &Opt, kind: Vec<TargetKind>) -> anyhow::Result<Vec<Artifact>> {
         cmd.arg("--no-default-features");
     }
 
-    cmd.arg("--message-format=json-render-diagnostics");
+    if !opt.dev && !opt.no_debuginfo {
+        cmd.args([
+            "--config",
+            &format!("profile.{}.debug=true", build_profile(opt)),
+        ]);
+    }
+
+    if opt.force_frame_pointers {
+        force_frame_pointers(cmd);
+    }
+
+    cmd.args(&opt.cargo_arg);
+}
+
+/// Adds `-C force-frame-pointers=yes` to the rustflags of the build. Flags from the environment
+/// replace the configured `build.rustflags`, so they are extended where they are set.
+fn force_frame_pointers(cmd: &mut std::process::Command) {
+    const FLAG: &str = "-Cforce-frame-pointers=yes";
+    if let Ok(flags) = std::env::var("CARGO_ENCODED_RUSTFLAGS") {
+        let flags = if flags.is_empty() {
+            FLAG.to_string()
+        } else {
+            format!("{flags}\x1f{FLAG}")
+        };
+        cmd.env("CARGO_ENCODED_RUSTFLAGS", flags);
+    } else if let Ok(flags) = std::env::var("RUSTFLAGS") {
+        cmd.env("RUSTFLAGS", format!("{flags} {FLAG}"));
+    } else {
+        cmd.args(["--config", &format!("build.rustflags=[\"{FLAG}\"]")]);
+    }
+}
+
+/// Name of the cargo profile `build` compiles the target with.
+fn build_profile(opt: &Opt) -> &str {
+    if let Some(profile) = &opt.profile {
+        profile
+    } else if opt.dev {
+        "dev"
+    } else if opt.bench.is_some() || opt.unit_bench.is_some() || opt.benches {
+        "bench"
+    } else {
+        "release"
+    }
+}
+
+/// Builds the tests with `cargo nextest list` and returns the command running the one test
+/// matching `filter` on its own, like nextest does, together with the test's name. A test named
+/// exactly `filter` is preferred over others containing it.
+fn nextest_workload(opt: &Opt, filter: &str) -> anyhow::Result<(Vec<String>, String)> {
+    use std::process::{Command, Output, Stdio};
+    let mut cmd = Command::new("cargo");
+    cmd.args(["nextest", "list", "--message-format", "json"]);
+    cmd.args(["--cargo-profile", build_profile(opt)]);
+
+    if let Some(ref package) = opt.package {
+        cmd.arg("--package");
+        cmd.arg(package);
+    }
 
-    if opt.graph.verbose {
+    if let Some(ref target) = opt.target {
+        cmd.arg("--target");
+        cmd.arg(target);
+    }
+
+    cargo_options(opt, &mut cmd);
+    // nextest has flags of its own for the verbosity of cargo.
+    cmd.args(cargo_verbosity(opt).map(|flag| flag.replacen("--", "--cargo-", 1)));
+    cmd.arg(filter);
+
+    if opt.graph.dry_run {
+        println!("{:?}", cmd);
+        let mut command: Vec<_> = ["<test binary>", "--exact", filter, "--nocapture"]
+            .map(str::to_string)
+            .to_vec();
+        command.extend(opt.trailing_arguments.iter().cloned());
+        return Ok((command, filter.to_string()));
+    }
+
+    if opt.graph.verbose > 0 {
         println!("build command: {:?}", cmd);
     }
 
     let Output { status, stdout, .. } = cmd
         .stderr(Stdio::inherit())
         .output()
-        .context("failed to execute cargo build command")?;
+        .context("failed to execute cargo nextest, is it installed?")?;
 
     if !status.success() {
-        return Err(anyhow!("cargo build failed"));
+        return Err(anyhow!("cargo nextest list failed"));
     }
 
-    Message::parse_stream(&*stdout)
-        .filter_map(|m| match m {
-            Ok(Message::CompilerArtifact(artifact)) => Some(Ok(artifact)),
-            Ok(_) => None,
-            Err(e) => Some(Err(e).context("failed to parse cargo build output")),
-        })
-        .collect()
+    let list: serde_json::Value =
+        serde_json::from_slice(&stdout).context("failed to parse cargo nextest list output")?;
+    let mut tests = Vec::new();
+    let suites = list["rust-suites"]
+        .as_object()
+        .into_iter()
+        .flat_map(|s| s.values());
+    for suite in suites {
+        let binary = match suite["binary-path"].as_str() {
+            Some(binary) => binary,
+            None => continue,
+        };
+        for (name, test) in suite["testcases"].as_object().into_iter().flatten() {
+            if test["filter-match"]["status"] == "matches" {
+                tests.push((binary, name.as_str()));
+            }
+        }
+    }
+
+    let (binary, name) = match tests.iter().find(|(_, name)| *name == filter) {
+        Some(&test) => test,
+        None => match tests[..] {
+            [test] => test,
+            [] => return Err(anyhow!("no test matches {:?}", filter)),
+            _ => {
+                let names: Vec<_> = tests.iter().map(|(_, name)| *name).collect();
+                return Err(anyhow!(
+                    "{} tests match {:?}, pick one of them: {}",
+                    tests.len(),
+                    filter,
+                    names.join(", ")
+                ));
+            }
+        },
+    };
+
+    let mut command = vec![
+        binary.to_string(),
+        "--exact".to_string(),
+        name.to_string(),
+        "--nocapture".to_string(),
+    ];
+    command.extend(opt.trailing_arguments.iter().cloned());
+    Ok((command, name.to_string()))
 }
 
-fn workload(opt: &Opt, artifacts: &[Artifact]) -> anyhow::Result<Vec<String>> {
-    let mut trailing_arguments = opt.trailing_arguments.clone();
+/// Builds the doctests of `item` with rustdoc's `--persist-doctests` and returns the command
+/// running them. `cargo test --doc` runs them once while building, as rustdoc compiles doctests
+/// only right before running them.
+fn doctest_workload(opt: &Opt, item: &str) -> anyhow::Result<Vec<String>> {
+    use std::process::Command;
+    let persist_dir = target_directory(opt)?.join("flamegraph-doctests");
 
-    if artifacts.iter().all(|a| a.executable.is_none()) {
+    let mut cmd = Command::new("cargo");
+    cmd.args(["test", "--doc", "--profile", build_profile(opt)]);
+
+    if let Some(ref package) = opt.package {
+        cmd.arg("--package");
+        cmd.arg(package);
+    }
+
+    if let Some(ref target) = opt.target {
+        cmd.arg("--target");
+        cmd.arg(target);
+    }
+
+    cargo_options(opt, &mut cmd);
+    cmd.args(cargo_verbosity(opt));
+    cmd.arg(item);
+
+    // Flags from the environment replace the configured `build.rustdocflags`, like with
+    // `force_frame_pointers`, so they are extended where they are set.
+    let (var, separator) = match std::env::var_os("CARGO_ENCODED_RUSTDOCFLAGS") {
+        Some(_) => ("CARGO_ENCODED_RUSTDOCFLAGS", "\x1f"),
+        None => ("RUSTDOCFLAGS", " "),
+    };
+    let mut rustdocflags = std::env::var_os(var).unwrap_or_default();
+    let flags = [
+        "-Zunstable-options".as_ref(),
+        "--persist-doctests".as_ref(),
+        persist_dir.as_os_str(),
+    ];
+    for flag in flags {
+        if !rustdocflags.is_empty() {
+            rustdocflags.push(separator);
+        }
+        rustdocflags.push(flag);
+    }
+    cmd.env(var, rustdocflags);
+
+    if opt.graph.dry_run {
+        println!("{:?}", cmd);
+        let binary = persist_dir.join("<doctest>").join("rust_out");
+        return Ok(vec![binary.to_string_lossy().into_owned()]);
+    }
+
+    if opt.graph.verbose > 0 {
+        println!("build command: {:?}", cmd);
+    }
+
+    match std::fs::remove_dir_all(&persist_dir) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(err).with_context(|| format!("unable to clear {:?}", persist_dir));
+        }
+        _ => (),
+    }
+
+    if opt.graph.message_format == MessageFormat::Json {
+        // Keep the test harness output from mixing with the events.
+        cmd.stdout(std::io::stderr());
+    }
+    let status = building(opt, || {
+        cmd.status().context("failed to execute cargo test command")
+    })?;
+    if !status.success() {
         return Err(anyhow!(
-            "build artifacts do not contain any executable to profile"
+            "cargo test --doc failed; note that persisting doctests needs a nightly toolchain"
         ));
     }
 
+    // Doctests of the 2024 edition are merged into a single binary, which runs the selected
+    // ones. Earlier editions have a binary per doctest.
+    let mut binaries: Vec<_> = std::fs::read_dir(&persist_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path().join("rust_out"))
+        .filter(|binary| binary.is_file())
+        .collect();
+    binaries.sort();
+
+    let binary = match &binaries[..] {
+        [binary] => binary,
+        [] => return Err(anyhow!("no doctests of {:?} were found", item)),
+        _ => {
+            let names: Vec<_> = binaries
+                .iter()
+                .filter_map(|binary| binary.parent()?.file_name())
+                .map(|name| name.to_string_lossy())
+                .collect();
+            return Err(anyhow!(
+                "{} doctests match {:?}, choose a more specific path: {}",
+                binaries.len(),
+                item,
+                names.join(", ")
+            ));
+        }
+    };
+
+    let mut command = vec![binary.to_string_lossy().into_owned()];
+    command.extend(opt.trailing_arguments.iter().cloned());
+    Ok(command)
+}
+
+/// Selects the test given with `--exact` in the libtest harness, and keeps the harness from
+/// running it on a thread of its own or capturing its output.
+fn exact_test_arguments(opt: &Opt, trailing_arguments: &mut Vec<String>) {
+    if let Some(test) = &opt.exact {
+        let arguments = [test, "--exact", "--nocapture", "--test-threads=1"];
+        trailing_arguments.splice(0..0, arguments.iter().map(|arg| arg.to_string()));
+    }
+}
+
+/// Puts the Criterion harness into profiling mode, which runs the benchmarks matching the
+/// filter for a fixed time and skips warmup, analysis and reports.
+fn criterion_arguments(opt: &Opt, trailing_arguments: &mut Vec<String>) {
+    let mut arguments = vec![
+        "--profile-time".to_string(),
+        opt.profile_time.unwrap_or(5).to_string(),
+    ];
+    // Criterion only runs benchmarks when given `--bench`, as `cargo bench` does.
+    if !trailing_arguments.iter().any(|arg| arg == "--bench") {
+        arguments.insert(0, "--bench".to_string());
+    }
+    arguments.extend(opt.criterion_filter.clone());
+    trailing_arguments.splice(0..0, arguments);
+}
+
+/// The directory the build writes to, `--target-dir` or the one of the workspace.
+fn target_directory(opt: &Opt) -> anyhow::Result<PathBuf> {
+    match &opt.target_dir {
+        Some(target_dir) => Ok(target_dir.clone()),
+        None => Ok(opt
+            .lock
+            .metadata_command(opt.manifest_path.as_deref())
+            .no_deps()
+            .exec()
+            .context("failed to access crate metadata")?
+            .target_directory
+            .into_std_path_buf()),
+    }
+}
+
+/// Where the build places the binary of `target`, for `--dry-run`, which builds nothing. The
+/// names of test and benchmark binaries contain a hash that is only known after building.
+fn planned_binary(opt: &Opt, kind: &[TargetKind], target: &str) -> anyhow::Result<String> {
+    let mut dir = target_directory(opt)?;
+    if let Some(triple) = &opt.target {
+        dir.push(triple);
+    }
+    dir.push(match build_profile(opt) {
+        "dev" | "test" => "debug",
+        "release" | "bench" => "release",
+        profile => profile,
+    });
+    let binary = if kind.contains(&TargetKind::Example) {
+        dir.join("examples").join(target)
+    } else if kind == [TargetKind::Bin] {
+        dir.join(target)
+    } else {
+        dir.join("deps")
+            .join(format!("{}-<hash>", target.replace('-', "_")))
+    };
+    Ok(format!(
+        "{}{}",
+        binary.display(),
+        std::env::consts::EXE_SUFFIX
+    ))
+}
+
+fn workload(opt: &Opt, artifacts: &[Artifact]) -> anyhow::Result<Vec<String>> {
+    let mut trailing_arguments = opt.trailing_arguments.clone();
+
     let (kind, target): (&[TargetKind], _) = match opt {
         Opt { bin: Some(t), .. } => (&[TargetKind::Bin], t),
         Opt {
             example: Some(t), ..
         } => (&[TargetKind::Example], t),
-        Opt { test: Some(t), .. } => (&[TargetKind::Test], t),
-        Opt { bench: Some(t), .. } => (&[TargetKind::Bench], t),
+        Opt { test: Some(t), .. } => {
+            exact_test_arguments(opt, &mut trailing_arguments);
+            (&[TargetKind::Test], t)
+        }
+        Opt { bench: Some(t), .. } => {
+            if opt.criterion_filter.is_some() || opt.profile_time.is_some() {
+                criterion_arguments(opt, &mut trailing_arguments);
+            }
+            (&[TargetKind::Bench], t)
+        }
         Opt {
             unit_test: Some(Some(t)),
             ..
-        } => (&[TargetKind::Lib, TargetKind::Bin], t),
+        } => {
+            exact_test_arguments(opt, &mut trailing_arguments);
+            (&[TargetKind::Lib, TargetKind::Bin], t)
+        }
         Opt {
             unit_bench: Some(Some(t)),
             ..
@@ -229,16 +751,28 @@ fn workload(opt: &Opt, artifacts: &[Artifact]) -> anyhow::Result<Vec<String>> {
         _ => return Err(anyhow!("no target for profiling")),
     };
 
+    if opt.graph.dry_run {
+        let mut command = vec![planned_binary(opt, kind, target)?];
+        command.extend(trailing_arguments);
+        return Ok(command);
+    }
+
+    if artifacts.iter().all(|a| a.executable.is_none()) {
+        return Err(anyhow!(
+            "build artifacts do not contain any executable to profile"
+        ));
+    }
+
     // `target.kind` is a `Vec`, but it always seems to contain exactly one element.
-    let (debug_level, binary_path) = artifacts
+    let (artifact, binary_path) = artifacts
         .iter()
         .find_map(|a| {
             a.executable
                 .as_deref()
                 .filter(|_| {
-                    a.target.name == *target && a.target.kind.iter().any(|k| kind.contains(&k))
+                    a.target.name == *target && a.target.kind.iter().any(|k| kind.contains(k))
                 })
-                .map(|e| (&a.profile.debuginfo, e))
+                .map(|e| (a, e))
         })
         .ok_or_else(|| {
             let targets: Vec<_> = artifacts
@@ -252,6 +786,7 @@ fn workload(opt: &Opt, artifacts: &[Artifact]) -> anyhow::Result<Vec<String>> {
             )
         })?;
 
+    let debug_level = &artifact.profile.debuginfo;
     if !opt.dev && debug_level == &ArtifactDebuginfo::None {
         let profile = match opt
             .example
@@ -272,12 +807,54 @@ fn workload(opt: &Opt, artifacts: &[Artifact]) -> anyhow::Result<Vec<String>> {
         eprintln!("CARGO_PROFILE_{}_DEBUG=true\n", profile.to_uppercase());
     }
 
+    #[cfg(target_os = "macos")]
+    if debug_level != &ArtifactDebuginfo::None {
+        ensure_dsym(artifact, binary_path.as_std_path());
+    }
+
     let mut command = Vec::with_capacity(1 + trailing_arguments.len());
     command.push(binary_path.to_string());
     command.extend(trailing_arguments);
     Ok(command)
 }
 
+/// Generates the dSYM bundle of `binary` if cargo did not, i.e. when the debug info is left in
+/// the object files (`split-debuginfo = "unpacked"`), which DTrace does not read.
+#[cfg(target_os = "macos")]
+fn ensure_dsym(artifact: &Artifact, binary: &Path) {
+    use std::process::Command;
+
+    if artifact
+        .filenames
+        .iter()
+        .any(|file| file.extension() == Some("dSYM"))
+    {
+        return;
+    }
+    let dsym = PathBuf::from(format!("{}.dSYM", binary.display()));
+    let modified = |path: &Path| {
+        path.metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    if matches!((modified(&dsym), modified(binary)), (Some(dsym), Some(binary)) if dsym >= binary) {
+        return;
+    }
+
+    log::info!("generating {:?} with dsymutil", dsym);
+    let generated = Command::new("dsymutil")
+        .arg(binary)
+        .arg("-o")
+        .arg(&dsym)
+        .status();
+    if !matches!(generated, Ok(status) if status.success()) {
+        log::warn!(
+            "unable to run dsymutil; frames of {} may only show addresses",
+            binary.display()
+        );
+    }
+}
+
 #[derive(Clone, Debug)]
 struct BinaryTarget {
     package: String,
@@ -327,17 +904,83 @@ pub fn find_crate_root(manifest_path: Option<&Path>) -> anyhow::Result<PathBuf>
     }
 }
 
+/// Adds the `required-features` of the selected target to `--features`, as cargo refuses to
+/// build a target selected by name without them.
+fn enable_required_features(opt: &mut Opt) -> anyhow::Result<()> {
+    let (kind, name) = match opt {
+        Opt { bin: Some(t), .. } => (TargetKind::Bin, t.clone()),
+        Opt {
+            example: Some(t), ..
+        } => (TargetKind::Example, t.clone()),
+        Opt { test: Some(t), .. } => (TargetKind::Test, t.clone()),
+        Opt { bench: Some(t), .. } => (TargetKind::Bench, t.clone()),
+        _ => return Ok(()),
+    };
+
+    let mut metadata_command = opt.lock.metadata_command(opt.manifest_path.as_deref());
+    metadata_command.no_deps();
+    let crate_root = find_crate_root(opt.manifest_path.as_deref())?;
+    let packages = metadata_command
+        .exec()
+        .context("failed to access crate metadata")?
+        .packages;
+    let target = packages
+        .iter()
+        .filter(|p| match &opt.package {
+            Some(pkg) => pkg == &p.name,
+            None => p.manifest_path.starts_with(&crate_root),
+        })
+        .flat_map(|p| p.targets.iter().map(move |t| (p, t)))
+        .find(|(_, t)| t.name == name && t.kind.contains(&kind));
+    let (package, target) = match target {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+
+    let mut features: Vec<_> = opt
+        .features
+        .as_deref()
+        .unwrap_or_default()
+        .split([',', ' '])
+        .filter(|feature| !feature.is_empty())
+        .map(str::to_string)
+        .collect();
+    let missing: Vec<_> = target
+        .required_features
+        .iter()
+        .filter(|feature| {
+            !features.contains(feature)
+                && !features.contains(&format!("{}/{}", package.name, feature))
+        })
+        .cloned()
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    log::info!(
+        "enabling the features {} required by {}",
+        missing.join(", "),
+        target.name
+    );
+    features.extend(missing);
+    opt.features = Some(features.join(","));
+    // Features without a package only apply to the selected package.
+    if opt.package.is_none() {
+        opt.package = Some(package.name.to_string());
+    }
+    Ok(())
+}
+
 fn find_unique_target(
     kind: &[TargetKind],
     pkg: Option<&str>,
     manifest_path: Option<&Path>,
+    lock: LockOptions,
     target_name: Option<&str>,
 ) -> anyhow::Result<BinaryTarget> {
-    let mut metadata_command = MetadataCommand::new();
+    let mut metadata_command = lock.metadata_command(manifest_path);
     metadata_command.no_deps();
-    if let Some(ref manifest_path) = manifest_path {
-        metadata_command.manifest_path(manifest_path);
-    }
 
     let crate_root = find_crate_root(manifest_path)?;
 
@@ -379,7 +1022,7 @@ fn find_unique_target(
             }
             targets.into_iter().filter_map(move |t| {
                 // Keep only targets that are of the right kind.
-                if !t.kind.iter().any(|s| kind.contains(&s)) {
+                if !t.kind.iter().any(|s| kind.contains(s)) {
                     return None;
                 }
 
@@ -426,10 +1069,204 @@ fn find_unique_target(
     }
 }
 
+/// Names of all crates in the dependency graph, used to attribute frames to crates.
+fn dependency_crates(
+    manifest_path: Option<&Path>,
+    lock: LockOptions,
+) -> anyhow::Result<Vec<String>> {
+    let metadata = lock
+        .metadata_command(manifest_path)
+        .exec()
+        .context("failed to access crate metadata")?;
+
+    Ok(metadata
+        .packages
+        .into_iter()
+        .flat_map(|p| p.targets)
+        .map(|t| t.name.replace('-', "_"))
+        .collect())
+}
+
+/// Target selection options, which `[package.metadata.flamegraph]` may only use to
+/// choose a default target.
+const TARGET_OPTIONS: &[&str] = &[
+    "bin",
+    "example",
+    "test",
+    "bench",
+    "unit-test",
+    "unit-bench",
+    "nextest",
+    "doctest",
+];
+
+/// The `[package.metadata.flamegraph]` table of the selected package and where it comes from,
+/// without the target selection keys if a target is given on the command line.
+fn package_metadata(opt: &Opt) -> anyhow::Result<Option<(toml::Table, String)>> {
+    let mut metadata_command = opt.lock.metadata_command(opt.manifest_path.as_deref());
+    metadata_command.no_deps();
+
+    let crate_root = find_crate_root(opt.manifest_path.as_deref())?;
+    let package = metadata_command
+        .exec()
+        .context("failed to access crate metadata")?
+        .packages
+        .into_iter()
+        .find(|p| match &opt.package {
+            Some(pkg) => pkg == &p.name,
+            None => p.manifest_path.parent().map(|dir| dir.as_std_path()) == Some(&crate_root),
+        });
+
+    let (name, metadata) = match package {
+        Some(Package { name, metadata, .. }) => (name, metadata),
+        None => return Ok(None),
+    };
+    let table = match metadata.get("flamegraph") {
+        Some(table) => toml::Table::try_from(table).with_context(|| {
+            anyhow!(
+                "[package.metadata.flamegraph] of package {} must be a table",
+                name
+            )
+        })?,
+        None => return Ok(None),
+    };
+
+    let has_target = opt.bin.is_some()
+        || opt.example.is_some()
+        || opt.test.is_some()
+        || opt.bench.is_some()
+        || opt.unit_test.is_some()
+        || opt.unit_bench.is_some()
+        || opt.nextest.is_some()
+        || opt.doctest.is_some();
+    let table = table
+        .into_iter()
+        .filter(|(key, _)| {
+            !(has_target && TARGET_OPTIONS.contains(&key.replace('_', "-").as_str()))
+        })
+        .collect();
+
+    Ok(Some((
+        table,
+        format!("[package.metadata.flamegraph] of package {name}"),
+    )))
+}
+
+/// Asks a yes/no question on the terminal, answering no if there is none.
+#[cfg(target_os = "macos")]
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    use std::io::{IsTerminal, Write};
+
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    eprint!("{question} [Y/n] ");
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "" | "y" | "Y" | "yes"))
+}
+
 fn main() -> anyhow::Result<()> {
-    let Cli::Flamegraph(mut opt) = Cli::parse();
+    flamegraph::logger::init();
+    let command = flamegraph::config::with_env(Cli::command());
+    let subcommand = command
+        .find_subcommand("flamegraph")
+        .expect("flamegraph subcommand is defined");
+
+    // `cargo flamegraph compare-rev A B` is short for `cargo flamegraph --compare-rev A B`.
+    let mut args: Vec<_> = std::env::args_os().collect();
+    if args.get(2).is_some_and(|arg| arg == "compare-rev") {
+        args[2] = "--compare-rev".into();
+    }
+
+    // Package metadata overrides the configuration files, but not the command line. A first
+    // pass over the command line determines the package whose metadata to use.
+    let metadata = match command
+        .clone()
+        .try_get_matches_from(&args)
+        .and_then(|matches| Cli::from_arg_matches(&matches))
+    {
+        Ok(Cli::Flamegraph(opt)) => package_metadata(&opt)?,
+        Err(_) => None,
+    };
+    let args = flamegraph::config::apply(subcommand, args, 2, metadata)?;
+    let matches = command.clone().get_matches_from(args);
+    let Cli::Flamegraph(mut opt) = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    log::set_max_level(opt.graph.log_level());
+    flamegraph::logger::set_message_format(opt.graph.message_format);
+    #[cfg(target_os = "linux")]
+    if opt.graph.remove_capabilities {
+        return flamegraph::capabilities::remove();
+    }
+
+    if opt.list {
+        return list_targets(&opt);
+    }
+
+    if let Some(shell) = opt.completions {
+        print!("{}", completion_script(shell));
+        return Ok(());
+    }
+
+    if opt.complete {
+        for candidate in completions(&opt.trailing_arguments) {
+            println!("{candidate}");
+        }
+        return Ok(());
+    }
+
     opt.graph.check()?;
+    #[cfg(windows)]
+    if let Some(code) = flamegraph::uac::relaunch_elevated(&opt.graph)? {
+        std::process::exit(code);
+    }
+    opt.graph.check_permissions()?;
+
+    if opt.all_targets || opt.workspace || opt.benches {
+        #[cfg(target_os = "macos")]
+        ask_for_root(&mut opt)?;
+        return profile_all_targets(&opt, &matches);
+    }
+
+    if !opt.compare_rev.is_empty() {
+        #[cfg(target_os = "macos")]
+        ask_for_root(&mut opt)?;
+        return compare_revisions(&opt, &matches);
+    }
+
+    if let Some(filter) = opt.nextest.clone() {
+        #[cfg(target_os = "macos")]
+        ask_for_root(&mut opt)?;
+        let (workload, test) = nextest_workload(&opt, &filter)?;
+        profile_command(opt, workload, test)?;
+        return Ok(());
+    }
+
+    if let Some(item) = opt.doctest.clone() {
+        #[cfg(target_os = "macos")]
+        ask_for_root(&mut opt)?;
+        let workload = doctest_workload(&opt, &item)?;
+        profile_command(opt, workload, item)?;
+        return Ok(());
+    }
 
+    let kind = select_target(&mut opt)?;
+
+    #[cfg(target_os = "macos")]
+    ask_for_root(&mut opt)?;
+
+    if !opt.matrix.is_empty() {
+        return profile_matrix(&opt, &matches, kind);
+    }
+
+    profile(opt, kind)?;
+    Ok(())
+}
+
+/// Finds the target to profile when none is given and returns its kind, defaulting to the only
+/// binary of the package.
+fn select_target(opt: &mut Opt) -> anyhow::Result<Vec<TargetKind>> {
     let kind = if opt.bin.is_none()
         && opt.bench.is_none()
         && opt.example.is_none()
@@ -441,12 +1278,13 @@ fn main() -> anyhow::Result<()> {
             &[TargetKind::Bin],
             opt.package.as_deref(),
             opt.manifest_path.as_deref(),
+            opt.lock,
             None,
         )?;
         opt.bin = Some(target.target);
         opt.package = Some(target.package);
         target.kind
-    } else if let Some(unit_test) = opt.unit_test {
+    } else if let Some(unit_test) = opt.unit_test.clone() {
         let kinds = match opt.unit_test_kind {
             Some(UnitTestTargetKind::Bin) => &[TargetKind::Bin][..], // get slice to help type inference
             Some(UnitTestTargetKind::Lib) => &[TargetKind::Lib],
@@ -457,16 +1295,18 @@ fn main() -> anyhow::Result<()> {
             kinds,
             opt.package.as_deref(),
             opt.manifest_path.as_deref(),
+            opt.lock,
             unit_test.as_deref(),
         )?;
         opt.unit_test = Some(Some(target.target));
         opt.package = Some(target.package);
         target.kind
-    } else if let Some(unit_bench) = opt.unit_bench {
+    } else if let Some(unit_bench) = opt.unit_bench.clone() {
         let target = find_unique_target(
             &[TargetKind::Bin, TargetKind::Lib],
             opt.package.as_deref(),
             opt.manifest_path.as_deref(),
+            opt.lock,
             unit_bench.as_deref(),
         )?;
         opt.unit_bench = Some(Some(target.target));
@@ -475,15 +1315,561 @@ fn main() -> anyhow::Result<()> {
     } else {
         Vec::new()
     };
+    Ok(kind)
+}
 
-    #[cfg(target_os = "macos")]
-    if let None = opt.graph.root {
+/// Chooses to record as root, which DTrace needs, asking first unless `--auto-root` is given.
+#[cfg(target_os = "macos")]
+fn ask_for_root(opt: &mut Opt) -> anyhow::Result<()> {
+    if !opt.graph.runs_as_root() {
+        if !opt.auto_root
+            && !confirm("DTrace requires elevated permissions on MacOS. Record using sudo?")?
+        {
+            return Err(anyhow!(
+                "DTrace requires elevated permissions on MacOS; re-invoke using 'cargo flamegraph --root ...' or '--auto-root'",
+            ));
+        }
+        opt.graph.root = Some(None);
+    }
+    Ok(())
+}
+
+/// Profiles the already built `workload` running the test or doctest `test`.
+fn profile_command(
+    mut opt: Opt,
+    workload: Vec<String>,
+    test: String,
+) -> anyhow::Result<flamegraph::Profile> {
+    if opt.graph.crate_report() {
+        opt.graph.known_crates = dependency_crates(opt.manifest_path.as_deref(), opt.lock)?;
+    }
+
+    opt.graph.build_profile = Some(build_profile(&opt).to_string());
+    opt.graph.target_name = Some(test);
+    Ok(flamegraph::generate_flamegraph_for_workload(
+        Workload::Command(workload),
+        opt.graph,
+    )?)
+}
+
+/// Builds and profiles the target selected in `opt`, which is of the given `kind`.
+fn profile(mut opt: Opt, kind: Vec<TargetKind>) -> anyhow::Result<flamegraph::Profile> {
+    enable_required_features(&mut opt)?;
+    let artifacts = build(&opt, kind)?;
+    profile_built(opt, &artifacts)
+}
+
+/// Profiles the target selected in `opt` among the already built `artifacts`.
+fn profile_built(mut opt: Opt, artifacts: &[Artifact]) -> anyhow::Result<flamegraph::Profile> {
+    if opt.graph.crate_report() {
+        opt.graph.known_crates = dependency_crates(opt.manifest_path.as_deref(), opt.lock)?;
+    }
+
+    opt.graph.build_profile = Some(build_profile(&opt).to_string());
+    opt.graph.target_name = opt
+        .bin
+        .clone()
+        .or_else(|| opt.example.clone())
+        .or_else(|| opt.test.clone())
+        .or_else(|| opt.bench.clone())
+        .or_else(|| opt.unit_test.clone().flatten())
+        .or_else(|| opt.unit_bench.clone().flatten());
+    opt.graph.strip_harness =
+        (opt.unit_test.is_some() || opt.test.is_some()) && !opt.keep_harness_frames;
+
+    let workload = workload(&opt, artifacts)?;
+    Ok(flamegraph::generate_flamegraph_for_workload(
+        Workload::Command(workload),
+        opt.graph,
+    )?)
+}
+
+/// A git worktree with a revision checked out, removed again when dropped.
+struct Worktree {
+    repository: PathBuf,
+    path: PathBuf,
+}
+
+impl Worktree {
+    fn add(repository: &Path, revision: &str, name: &str) -> anyhow::Result<Self> {
+        let path = std::env::temp_dir().join(format!("flamegraph-{}-{}", name, std::process::id()));
+        git(
+            repository,
+            &[
+                "worktree".as_ref(),
+                "add".as_ref(),
+                "--detach".as_ref(),
+                path.as_os_str(),
+                revision.as_ref(),
+            ],
+        )
+        .with_context(|| format!("unable to check out revision {revision}"))?;
+        Ok(Worktree {
+            repository: repository.to_path_buf(),
+            path,
+        })
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        let args = [
+            "worktree".as_ref(),
+            "remove".as_ref(),
+            "--force".as_ref(),
+            self.path.as_os_str(),
+        ];
+        if let Err(err) = git(&self.repository, &args) {
+            log::warn!("unable to remove the worktree {:?}: {:#}", self.path, err);
+        }
+    }
+}
+
+/// Runs git in `dir` and returns its trimmed output.
+fn git(dir: &Path, args: &[&std::ffi::OsStr]) -> anyhow::Result<String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("failed to execute git")?;
+    if !output.status.success() {
         return Err(anyhow!(
-            "DTrace requires elevated permissions on MacOS; re-invoke using 'cargo flamegraph --root ...'",
+            "git failed: ({}) {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
         ));
     }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Profiles the selected target at both revisions of `--compare-rev`, then writes a
+/// differential flamegraph and prints the functions whose share of samples changed.
+fn compare_revisions(opt: &Opt, matches: &ArgMatches) -> anyhow::Result<()> {
+    let crate_root = find_crate_root(opt.manifest_path.as_deref())?.canonicalize()?;
+    let repository = PathBuf::from(git(
+        &crate_root,
+        &["rev-parse".as_ref(), "--show-toplevel".as_ref()],
+    )?)
+    .canonicalize()?;
+    let relative_root = crate_root
+        .strip_prefix(&repository)
+        .context("the crate is not part of a git repository")?;
+    let manifest_name = opt
+        .manifest_path
+        .as_deref()
+        .and_then(Path::file_name)
+        .unwrap_or_else(|| "Cargo.toml".as_ref());
+
+    // Each side keeps its own target directory, so both stay incremental across comparisons.
+    let target_dir = target_directory(opt)?.join("flamegraph-compare");
+
+    let stem = opt
+        .graph
+        .output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "flamegraph".to_string());
+    let mut profiles = Vec::new();
+    for (revision, side) in opt.compare_rev.iter().zip(["before", "after"]) {
+        let worktree = Worktree::add(&repository, revision, side)?;
+
+        // Every run consumes its options, so they are parsed again for each revision.
+        let Cli::Flamegraph(mut run_opt) = Cli::from_arg_matches(matches)?;
+        run_opt.graph.root = opt.graph.root.clone();
+        run_opt.graph.disable_open();
+        run_opt.manifest_path = Some(worktree.path.join(relative_root).join(manifest_name));
+        run_opt.target_dir = Some(target_dir.join(side));
+        let file_name: String = revision
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        run_opt.graph.output = opt
+            .graph
+            .output
+            .with_file_name(format!("{stem}-{file_name}.svg"));
+
+        log::info!("profiling revision {}", revision);
+        let kind = select_target(&mut run_opt)?;
+        let profile = profile(run_opt, kind)
+            .with_context(|| format!("unable to profile revision {revision}"))?;
+        profiles.push(profile.collapsed);
+    }
 
-    let artifacts = build(&opt, kind)?;
-    let workload = workload(&opt, &artifacts)?;
-    flamegraph::generate_flamegraph_for_workload(Workload::Command(workload), opt.graph)
+    let (before, after) = (&opt.compare_rev[0], &opt.compare_rev[1]);
+    let path = opt.graph.output.with_file_name(format!("{stem}-diff.svg"));
+    log::info!("writing differential flamegraph to {:?}", path);
+    flamegraph::diff::write(
+        &profiles[0],
+        &profiles[1],
+        &format!("{after} compared to {before}"),
+        &opt.graph.flamegraph_options,
+        &path,
+    )?;
+    let regressions = flamegraph::diff::print_regressions(
+        &profiles[0],
+        &profiles[1],
+        opt.graph.regression_threshold,
+    )?;
+    opt.graph.open_output(&path)?;
+
+    if regressions > 0 {
+        return Err(anyhow!(
+            "{} function(s) regressed by more than {}% from {} to {}",
+            regressions,
+            opt.graph.regression_threshold,
+            before,
+            after
+        ));
+    }
+    Ok(())
+}
+
+/// Builds the selected target once and profiles it for every combination of `--matrix` values.
+fn profile_matrix(opt: &Opt, matches: &ArgMatches, kind: Vec<TargetKind>) -> anyhow::Result<()> {
+    let artifacts = build(opt, kind)?;
+    flamegraph::matrix::profile(&opt.matrix, &opt.graph, |combination, output| {
+        // Every run consumes its options, so they are parsed again for each combination.
+        let Cli::Flamegraph(mut run_opt) = Cli::from_arg_matches(matches)?;
+        run_opt.graph.root = opt.graph.root.clone();
+        run_opt.graph.disable_open();
+        run_opt.graph.output = output;
+        run_opt.package = opt.package.clone();
+        run_opt.bin = opt.bin.clone();
+        run_opt.unit_test = opt.unit_test.clone();
+        run_opt.unit_bench = opt.unit_bench.clone();
+        run_opt.trailing_arguments = combination.apply(&opt.trailing_arguments);
+        profile_built(run_opt, &artifacts)
+    })
+}
+
+/// Profiles every target selected with `--all-targets`, `--workspace` or `--benches` in turn.
+/// A failing target does not stop the others.
+fn profile_all_targets(opt: &Opt, matches: &ArgMatches) -> anyhow::Result<()> {
+    let targets = if opt.benches {
+        package_targets(opt, false, &[TargetKind::Bench])?
+    } else {
+        all_targets(opt)?
+    };
+    if targets.is_empty() {
+        return Err(anyhow!("no binaries or benchmarks to profile"));
+    }
+
+    // All benchmarks are built at once, as they are with `cargo bench`.
+    let prebuilt = if opt.benches {
+        let Cli::Flamegraph(mut build_opt) = Cli::from_arg_matches(matches)?;
+        build_opt.package = Some(targets[0].package.clone());
+        Some(build(&build_opt, Vec::new())?)
+    } else {
+        None
+    };
+
+    let stem = opt
+        .graph
+        .output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "flamegraph".to_string());
+    let mut outputs = Vec::new();
+    let mut failed = 0;
+    for target in &targets {
+        // Every run consumes its options, so they are parsed again for each target.
+        let Cli::Flamegraph(mut target_opt) = Cli::from_arg_matches(matches)?;
+        target_opt.graph.root = opt.graph.root.clone();
+        target_opt.graph.disable_open();
+        target_opt.all_targets = false;
+        target_opt.workspace = false;
+        target_opt.benches = false;
+        target_opt.package = Some(target.package.clone());
+        if target.kind.contains(&TargetKind::Bench) {
+            target_opt.bench = Some(target.target.clone());
+            if !target_opt
+                .trailing_arguments
+                .iter()
+                .any(|arg| arg == "--bench")
+            {
+                target_opt.trailing_arguments.push("--bench".to_string());
+            }
+        } else {
+            target_opt.bin = Some(target.target.clone());
+        }
+        target_opt.graph.output = opt
+            .graph
+            .output
+            .with_file_name(format!("{stem}-{}.svg", target.target));
+
+        log::info!("profiling {}", target);
+        let profiled = match &prebuilt {
+            Some(artifacts) => profile_built(target_opt, artifacts),
+            None => profile(target_opt, target.kind.clone()),
+        };
+        match profiled {
+            Ok(profile) => outputs.extend(
+                profile
+                    .outputs
+                    .first()
+                    .map(|path| flamegraph::gallery::Artifact::new(path, target.to_string())),
+            ),
+            Err(err) => {
+                log::error!("unable to profile {}: {:#}", target, err);
+                failed += 1;
+            }
+        }
+    }
+
+    if !outputs.is_empty() {
+        let index = opt
+            .graph
+            .output
+            .with_file_name(format!("{stem}-index.html"));
+        log::info!("writing index of all flamegraphs to {:?}", index);
+        flamegraph::gallery::write_index(&index, "Flamegraphs", "", &outputs)?;
+        opt.graph.open_output(&index)?;
+    }
+
+    if failed > 0 {
+        return Err(anyhow!(
+            "profiling failed for {} of {} targets",
+            failed,
+            targets.len()
+        ));
+    }
+    Ok(())
+}
+
+/// The binaries and benchmarks of the selected package, or of all workspace members with
+/// `--workspace`.
+fn all_targets(opt: &Opt) -> anyhow::Result<Vec<BinaryTarget>> {
+    package_targets(opt, opt.workspace, &[TargetKind::Bin, TargetKind::Bench])
+}
+
+/// Prints the targets of the workspace, or of the package selected with `--package`, with the
+/// option selecting each.
+fn list_targets(opt: &Opt) -> anyhow::Result<()> {
+    let kinds = [
+        TargetKind::Bin,
+        TargetKind::Example,
+        TargetKind::Test,
+        TargetKind::Bench,
+        TargetKind::Lib,
+    ];
+    let mut rows: Vec<_> = package_targets(opt, true, &kinds)?
+        .into_iter()
+        .map(|target| {
+            let kind = kinds
+                .iter()
+                .position(|kind| target.kind.contains(kind))
+                .unwrap_or_default();
+            let option = match kinds[kind] {
+                TargetKind::Bin => "--bin",
+                TargetKind::Example => "--example",
+                TargetKind::Test => "--test",
+                TargetKind::Bench => "--bench",
+                _ => "--unit-test",
+            };
+            (target.package, kind, format!("{option} {}", target.target))
+        })
+        .collect();
+    rows.sort();
+
+    let width = rows
+        .iter()
+        .map(|(package, ..)| package.len())
+        .max()
+        .unwrap_or_default();
+    for (package, _, option) in rows {
+        println!("{package:width$}  {option}");
+    }
+    Ok(())
+}
+
+/// Completion scripts for `cargo flamegraph`. They take over the completion of `cargo`, run
+/// `cargo flamegraph --complete -- WORDS...` when completing its flamegraph subcommand and
+/// leave other subcommands to the completion of cargo that was set up before.
+fn completion_script(shell: CompletionShell) -> &'static str {
+    match shell {
+        CompletionShell::Bash => {
+            r#"# Loads the completion of cargo first, which this one falls back to.
+if ! complete -p cargo &>/dev/null && declare -F _completion_loader &>/dev/null; then
+    _completion_loader cargo
+fi
+_cargo_flamegraph_fallback=$(complete -p cargo 2>/dev/null | sed -n 's/.* -F \([^ ]*\) .*/\1/p')
+
+_cargo_flamegraph() {
+    if [[ ${COMP_WORDS[1]} != flamegraph ]]; then
+        if [[ -n $_cargo_flamegraph_fallback ]]; then
+            "$_cargo_flamegraph_fallback" "$@"
+        fi
+        return
+    fi
+    local IFS=$'\n'
+    COMPREPLY=($(cargo flamegraph --complete -- "${COMP_WORDS[@]:2:COMP_CWORD-1}" 2>/dev/null))
+}
+complete -o default -F _cargo_flamegraph cargo
+"#
+        }
+        CompletionShell::Zsh => {
+            r#"_cargo_flamegraph() {
+    if [[ $words[2] != flamegraph ]]; then
+        (( $+functions[_cargo] )) && _cargo "$@"
+        return
+    fi
+    local -a candidates
+    candidates=(${(f)"$(cargo flamegraph --complete -- "${(@)words[3,CURRENT]}" 2>/dev/null)"})
+    if (( $#candidates )); then
+        compadd -a candidates
+    else
+        _files
+    fi
+}
+compdef _cargo_flamegraph cargo
+"#
+        }
+        CompletionShell::Fish => {
+            r#"complete -c cargo -n '__fish_seen_subcommand_from flamegraph' -a '(cargo flamegraph --complete -- (commandline -opc)[3..-1] (commandline -ct) 2>/dev/null)'
+"#
+        }
+    }
+}
+
+/// The candidates for the last of `words`, the arguments of `cargo flamegraph` up to the cursor:
+/// the names of targets or packages after an option selecting one, the possible values of other
+/// options and else the options themselves. Nothing is offered for the trailing arguments or
+/// when the candidates are unknown, e.g. for paths, which the shell then completes.
+fn completions(words: &[String]) -> Vec<String> {
+    let Some((current, before)) = words.split_last() else {
+        return Vec::new();
+    };
+    if before.iter().any(|word| word == "--") {
+        return Vec::new();
+    }
+    let mut command = Cli::command();
+    command.build();
+    let subcommand = command
+        .find_subcommand("flamegraph")
+        .expect("flamegraph subcommand is defined");
+    let option = before.last().and_then(|word| {
+        subcommand.get_arguments().find(|arg| {
+            arg.get_action().takes_values()
+                && match (word.strip_prefix("--"), word.strip_prefix('-')) {
+                    (Some(long), _) => arg.get_long() == Some(long),
+                    (None, Some(short)) => short
+                        .parse()
+                        .is_ok_and(|short| arg.get_short() == Some(short)),
+                    (None, None) => false,
+                }
+        })
+    });
+
+    let candidates = match option {
+        Some(arg) => match arg.get_id().as_str() {
+            "package" => {
+                let mut packages: Vec<_> = completion_targets(&before[..before.len() - 1], None)
+                    .into_iter()
+                    .map(|target| target.package)
+                    .collect();
+                packages.dedup();
+                packages
+            }
+            id @ ("bin" | "example" | "test" | "bench") => {
+                let kind = match id {
+                    "bin" => TargetKind::Bin,
+                    "example" => TargetKind::Example,
+                    "test" => TargetKind::Test,
+                    _ => TargetKind::Bench,
+                };
+                completion_targets(&before[..before.len() - 1], Some(kind))
+                    .into_iter()
+                    .map(|target| target.target)
+                    .collect()
+            }
+            _ => arg
+                .get_possible_values()
+                .iter()
+                .filter(|value| !value.is_hide_set())
+                .map(|value| value.get_name().to_string())
+                .collect(),
+        },
+        None if current.starts_with('-') => subcommand
+            .get_arguments()
+            .filter(|arg| !arg.is_hide_set())
+            .filter_map(|arg| arg.get_long())
+            .map(|long| format!("--{long}"))
+            .collect(),
+        None => Vec::new(),
+    };
+    candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(current.as_str()))
+        .collect()
+}
+
+/// The targets of `kind`, or of any kind, in the workspace, or in the package selected by the
+/// `--package` and `--manifest-path` among `words`. Empty if the metadata cannot be read.
+fn completion_targets(words: &[String], kind: Option<TargetKind>) -> Vec<BinaryTarget> {
+    let args = ["cargo", "flamegraph"]
+        .into_iter()
+        .map(String::from)
+        .chain(words.iter().cloned());
+    let opt =
+        match Cli::try_parse_from(args).or_else(|_| Cli::try_parse_from(["cargo", "flamegraph"])) {
+            Ok(Cli::Flamegraph(opt)) => opt,
+            Err(_) => return Vec::new(),
+        };
+    let kinds = match kind {
+        Some(kind) => vec![kind],
+        None => vec![
+            TargetKind::Lib,
+            TargetKind::Bin,
+            TargetKind::Example,
+            TargetKind::Test,
+            TargetKind::Bench,
+        ],
+    };
+    let mut targets = package_targets(&opt, true, &kinds).unwrap_or_default();
+    targets.sort_by(|a, b| (&a.package, &a.target).cmp(&(&b.package, &b.target)));
+    targets
+}
+
+/// The targets of the given `kinds` in the package selected with `--package`, or else in all
+/// workspace members if `workspace` is set or the package in the current directory.
+fn package_targets(
+    opt: &Opt,
+    workspace: bool,
+    kinds: &[TargetKind],
+) -> anyhow::Result<Vec<BinaryTarget>> {
+    let mut metadata_command = opt.lock.metadata_command(opt.manifest_path.as_deref());
+    metadata_command.no_deps();
+    let crate_root = find_crate_root(opt.manifest_path.as_deref())?;
+
+    Ok(metadata_command
+        .exec()
+        .context("failed to access crate metadata")?
+        .packages
+        .into_iter()
+        .filter(|p| match &opt.package {
+            Some(pkg) => pkg == &p.name,
+            None if workspace => true,
+            None => p.manifest_path.parent().map(|dir| dir.as_std_path()) == Some(&crate_root),
+        })
+        .flat_map(|p| {
+            let package = p.name;
+            p.targets.into_iter().filter_map(move |t| {
+                t.kind
+                    .iter()
+                    .any(|k| kinds.contains(k))
+                    .then(|| BinaryTarget {
+                        package: package.clone(),
+                        target: t.name,
+                        kind: t.kind,
+                    })
+            })
+        })
+        .collect())
 }
@@ -1,18 +1,22 @@
 use std::path::PathBuf;
 
 use anyhow::anyhow;
-use clap::{CommandFactory, Parser};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use clap_complete::Shell;
 
-use flamegraph::Workload;
+use flamegraph::{InputFormat, Workload};
 
 #[derive(Debug, Parser)]
-#[clap(version)]
+#[clap(version, args_override_self = true)]
 struct Opt {
     /// Profile a running process by pid (comma separated list)
     #[clap(short, long, value_delimiter(','))]
     pid: Vec<u32>,
 
+    /// Profile single threads of running processes by tid (comma separated list)
+    #[clap(long, value_delimiter(','), conflicts_with = "pid")]
+    tid: Vec<u32>,
+
     /// Generate shell completions for the given shell.
     #[clap(long, value_name = "SHELL", exclusive(true))]
     completions: Option<Shell>,
@@ -20,15 +24,85 @@ struct Opt {
     #[clap(flatten)]
     graph: flamegraph::Options,
 
-    #[clap(long = "perfdata", conflicts_with = "pid")]
+    #[clap(long = "perfdata", conflicts_with_all = ["pid", "tid"])]
     perf_file: Option<PathBuf>,
 
+    /// Profile the processes of a systemd service, e.g. `nginx.service`, with root privileges if
+    /// they run as another user; stop with Ctrl-C or after --duration (Linux only)
+    #[clap(long, value_name = "UNIT", conflicts_with_all = ["pid", "tid", "perf_file"])]
+    systemd_unit: Option<String>,
+
+    /// Profile all processes of a user, by name or uid, e.g. the service account of workers,
+    /// with root privileges if it is another user; stop with Ctrl-C or after --duration (perf
+    /// only)
+    #[clap(
+        long,
+        value_name = "USER",
+        conflicts_with_all = ["pid", "tid", "perf_file", "systemd_unit"]
+    )]
+    uid: Option<String>,
+
+    /// Profile the command once per value of NAME, replacing `{NAME}` in its arguments and
+    /// setting the NAME environment variable, and write a flamegraph per value, differential
+    /// flamegraphs against the first one and an index of them; may be repeated to profile every
+    /// combination of values
+    #[clap(
+        long,
+        value_name = "NAME={VALUE,...}",
+        conflicts_with_all = ["pid", "tid", "perf_file", "uid"]
+    )]
+    matrix: Vec<flamegraph::matrix::Parameter>,
+
     #[clap(last = true)]
     trailing_arguments: Vec<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Render a flamegraph from folded stacks or a perf data file without profiling again
+    Render(Render),
+}
+
+#[derive(Debug, Parser)]
+struct Render {
+    /// Folded stacks, e.g. written with `--export folded`, or a perf data file
+    input: PathBuf,
+
+    /// Convert the input from the profile of another tool
+    #[clap(long, value_name = "FORMAT")]
+    from: Option<InputFormat>,
+
+    #[clap(flatten)]
+    graph: flamegraph::Options,
+}
+
+/// Whether `path` is a perf data file rather than folded stacks.
+fn is_perf_data(path: &std::path::Path) -> bool {
+    let mut magic = [0; 8];
+    std::fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+        .is_ok_and(|()| &magic == b"PERFILE2")
 }
 
 fn main() -> anyhow::Result<()> {
-    let opt = Opt::parse();
+    flamegraph::logger::init();
+    let command = flamegraph::config::with_env(Opt::command());
+    let mut args: Vec<_> = std::env::args_os().collect();
+    if args.get(1).is_some_and(|arg| arg == "render") {
+        let render = command
+            .find_subcommand("render")
+            .expect("render subcommand is defined");
+        args = flamegraph::config::apply(render, args, 2, None)?;
+    } else if !args.iter().any(|arg| arg == "--completions") {
+        args = flamegraph::config::apply(&command, args, 1, None)?;
+    }
+    let matches = command.get_matches_from(args);
+    let mut opt = Opt::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    log::set_max_level(opt.graph.log_level());
+    flamegraph::logger::set_message_format(opt.graph.message_format);
 
     if let Some(shell) = opt.completions {
         clap_complete::generate(
@@ -40,11 +114,82 @@ fn main() -> anyhow::Result<()> {
         return Ok(());
     }
 
+    if let Some(Command::Render(render)) = opt.command {
+        log::set_max_level(render.graph.log_level());
+        flamegraph::logger::set_message_format(render.graph.message_format);
+        render.graph.check()?;
+        let workload = if let Some(format) = render.from {
+            Workload::Import(format, render.input)
+        } else if is_perf_data(&render.input) {
+            Workload::ReadPerf(render.input)
+        } else {
+            Workload::Folded(render.input)
+        };
+        flamegraph::generate_flamegraph_for_workload(workload, render.graph)?;
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    if opt.graph.remove_capabilities {
+        return flamegraph::capabilities::remove();
+    }
+
     opt.graph.check()?;
+    #[cfg(windows)]
+    if let Some(code) = flamegraph::uac::relaunch_elevated(&opt.graph)? {
+        std::process::exit(code);
+    }
 
     let workload = if let Some(perf_file) = opt.perf_file {
         Workload::ReadPerf(perf_file)
+    } else if let Some(unit) = &opt.systemd_unit {
+        if !opt.trailing_arguments.is_empty() {
+            return Err(anyhow!("cannot pass in command with --systemd-unit"));
+        }
+        let pids = unit_pids(unit, &mut opt.graph)?;
+        opt.graph.check_permissions()?;
+        Workload::Pid(pids)
+    } else if let Some(user) = opt.uid {
+        if !opt.trailing_arguments.is_empty() {
+            return Err(anyhow!("cannot pass in command with --uid"));
+        }
+        let own = uid_of("")?;
+        if !opt.graph.runs_as_root() && own != "0" && uid_of(&user)? != own {
+            log::info!("recording with root privileges, as {user} is another user");
+            opt.graph.root = Some(None);
+        }
+        opt.graph.check_permissions()?;
+        Workload::User(user)
+    } else if !opt.tid.is_empty() {
+        if !opt.trailing_arguments.is_empty() {
+            return Err(anyhow!("cannot pass in command with --tid"));
+        }
+        opt.graph.check_permissions()?;
+        let threads = opt
+            .tid
+            .iter()
+            .map(|&tid| Ok((thread_group(tid)?, tid)))
+            .collect::<anyhow::Result<_>>()?;
+        Workload::Thread(threads)
     } else {
+        opt.graph.check_permissions()?;
+        if !opt.matrix.is_empty() {
+            if opt.trailing_arguments.is_empty() {
+                return Err(anyhow!("no workload given to generate a flamegraph for"));
+            }
+            return flamegraph::matrix::profile(&opt.matrix, &opt.graph, |combination, output| {
+                // Every run consumes its options, so they are parsed again for each one.
+                let mut graph = Opt::from_arg_matches(&matches)?.graph;
+                graph.root = opt.graph.root.clone();
+                graph.disable_open();
+                graph.output = output;
+                let command = combination.apply(&opt.trailing_arguments);
+                Ok(flamegraph::generate_flamegraph_for_workload(
+                    Workload::Command(command),
+                    graph,
+                )?)
+            });
+        }
         match (opt.pid.is_empty(), opt.trailing_arguments.is_empty()) {
             (false, true) => Workload::Pid(opt.pid),
             (true, false) => Workload::Command(opt.trailing_arguments.clone()),
@@ -52,5 +197,55 @@ fn main() -> anyhow::Result<()> {
             (true, true) => return Err(anyhow!("no workload given to generate a flamegraph for")),
         }
     };
-    flamegraph::generate_flamegraph_for_workload(workload, opt.graph)
+    flamegraph::generate_flamegraph_for_workload(workload, opt.graph)?;
+    Ok(())
+}
+
+/// Finds the process a thread belongs to.
+#[cfg(target_os = "linux")]
+fn thread_group(tid: u32) -> anyhow::Result<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{tid}/status"))
+        .map_err(|err| anyhow!("unable to find thread {tid}: {err}"))?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Tgid:"))
+        .and_then(|pid| pid.trim().parse().ok())
+        .ok_or_else(|| anyhow!("unable to find the process of thread {tid}"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_group(_: u32) -> anyhow::Result<u32> {
+    Err(anyhow!("--tid is only supported on Linux"))
+}
+
+/// The uid of a user given by name or uid, or of the current user if `user` is empty.
+fn uid_of(user: &str) -> anyhow::Result<String> {
+    let mut command = std::process::Command::new("id");
+    command.arg("-u");
+    if !user.is_empty() {
+        command.arg(user);
+    }
+    let output = command
+        .output()
+        .map_err(|err| anyhow!("unable to run id: {err}"))?;
+    if !output.status.success() {
+        return Err(anyhow!("no such user: {user}"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Finds the processes of a systemd unit, recording them with root privileges if needed.
+#[cfg(target_os = "linux")]
+fn unit_pids(unit: &str, graph: &mut flamegraph::Options) -> anyhow::Result<Vec<u32>> {
+    let pids = flamegraph::systemd::pids(unit)?;
+    if !graph.runs_as_root() && flamegraph::systemd::needs_root(&pids) {
+        log::info!("recording with root privileges, as {unit} runs as another user");
+        graph.root = Some(None);
+    }
+    Ok(pids)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unit_pids(_: &str, _: &mut flamegraph::Options) -> anyhow::Result<Vec<u32>> {
+    Err(anyhow!("--systemd-unit is only supported on Linux"))
 }
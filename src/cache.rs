@@ -0,0 +1,94 @@
+//! Folded stacks of earlier runs, so rendering a recording again with different flamegraph
+//! options skips symbolizing and collapsing it.
+//!
+//! Entries are keyed by a hash of the recorded data and the settings affecting the stacks, and
+//! live in `$XDG_CACHE_HOME/flamegraph` (`~/.cache/flamegraph`, or `%LOCALAPPDATA%\flamegraph`
+//! on Windows). Only the most recent entries are kept.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    env, fs,
+    hash::{Hash, Hasher},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::RecordSettings;
+
+/// Entries kept in the cache directory.
+const MAX_ENTRIES: usize = 20;
+
+fn dir() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+    };
+    base.map(|base| base.join("flamegraph"))
+}
+
+/// Identifies the stacks collapsed from `data` by `backend` with `settings`.
+pub(crate) fn key(data: &Path, backend: &str, settings: &RecordSettings) -> io::Result<String> {
+    let mut hasher = DefaultHasher::new();
+    backend.hash(&mut hasher);
+    settings.script_no_inline.hash(&mut hasher);
+    settings.max_stack.hash(&mut hasher);
+    settings.internal_script.hash(&mut hasher);
+    settings.symbolize.hash(&mut hasher);
+    settings.count_samples.hash(&mut hasher);
+    settings.c2c.hash(&mut hasher);
+    settings.sched_latency.hash(&mut hasher);
+    settings.include_pid.hash(&mut hasher);
+    settings.skip_after.hash(&mut hasher);
+    settings.max_samples.hash(&mut hasher);
+    settings.trim_before.hash(&mut hasher);
+
+    let mut file = fs::File::open(data)?;
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.write(&buf[..len]);
+    }
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+pub(crate) fn load(key: &str) -> Option<Vec<u8>> {
+    let path = dir()?.join(key).with_extension("folded");
+    let collapsed = fs::read(&path).ok()?;
+    log::debug!("using cached stacks from {:?}", path);
+    Some(collapsed)
+}
+
+/// Stores `collapsed` under `key` and evicts the oldest entries. Failures are only logged, as
+/// the cache is an optimization.
+pub(crate) fn store(key: &str, collapsed: &[u8]) {
+    let dir = match dir() {
+        Some(dir) => dir,
+        None => return,
+    };
+    let path = dir.join(key).with_extension("folded");
+    if let Err(err) = fs::create_dir_all(&dir).and_then(|()| fs::write(&path, collapsed)) {
+        log::debug!("unable to cache the stacks in {:?}: {}", path, err);
+        return;
+    }
+
+    let mut entries: Vec<_> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "folded"))
+            .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+            .collect(),
+        Err(_) => return,
+    };
+    if entries.len() > MAX_ENTRIES {
+        entries.sort();
+        for (_, path) in &entries[..entries.len() - MAX_ENTRIES] {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
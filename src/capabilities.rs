@@ -0,0 +1,139 @@
+//! A copy of perf with file capabilities, allowing to profile without root privileges.
+//!
+//! The copy is created once (which needs root privileges to run `setcap`) and reused by later
+//! runs until it is removed with `--remove-capabilities`. It is only executable by its owner.
+
+use std::{
+    env,
+    fs::{self, Permissions},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::escalate::Root;
+
+/// `CAP_PERFMON` (or `CAP_SYS_ADMIN` before Linux 5.8) to record, `CAP_SYS_PTRACE` to attach to
+/// running processes and `CAP_SYSLOG` to resolve kernel symbols.
+fn capabilities() -> &'static str {
+    if kernel_at_least(5, 8) {
+        "cap_perfmon,cap_sys_ptrace,cap_syslog+ep"
+    } else {
+        "cap_sys_admin,cap_sys_ptrace,cap_syslog+ep"
+    }
+}
+
+fn kernel_at_least(major: u32, minor: u32) -> bool {
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    let mut version = release
+        .trim()
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    (version.next().unwrap_or(0), version.next().unwrap_or(0)) >= (major, minor)
+}
+
+/// Location of the capable copy of perf.
+pub fn path() -> Option<PathBuf> {
+    let data_dir = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))?;
+    Some(data_dir.join("flamegraph").join("perf"))
+}
+
+/// Finds `program` in `$PATH` or, since the libcap tools usually live there, the sbin
+/// directories.
+fn find_program(program: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .chain(["/usr/sbin", "/sbin"].map(PathBuf::from))
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+fn has_capabilities(perf: &Path) -> anyhow::Result<bool> {
+    let getcap = find_program("getcap")
+        .ok_or_else(|| anyhow!("getcap not found, please install libcap (e.g. libcap2-bin)"))?;
+    let output = Command::new(getcap)
+        .arg(perf)
+        .output()
+        .context("unable to run getcap")?;
+    let capabilities = String::from_utf8_lossy(&output.stdout);
+    Ok(capabilities.contains("cap_perfmon") || capabilities.contains("cap_sys_admin"))
+}
+
+/// Returns a perf binary that can profile without root privileges: `perf` itself if it
+/// already has the required capabilities, otherwise the capable copy, which is created using
+/// `root` if it does not exist yet.
+pub(crate) fn perf(root: Root) -> anyhow::Result<PathBuf> {
+    let perf = match env::var_os("PERF") {
+        Some(perf) => PathBuf::from(perf),
+        None => find_program("perf")
+            .ok_or_else(|| anyhow!("perf is not installed or not present in $PATH"))?,
+    };
+    if has_capabilities(&perf)? {
+        return Ok(perf);
+    }
+
+    let copy = path().ok_or_else(|| anyhow!("unable to determine the data directory"))?;
+    if copy.is_file() && has_capabilities(&copy)? {
+        return Ok(copy);
+    }
+
+    let contents =
+        fs::read(&perf).with_context(|| format!("unable to read '{}'", perf.display()))?;
+    anyhow::ensure!(
+        !contents.starts_with(b"#!"),
+        "'{}' is a wrapper script, set PERF to the perf binary it runs (e.g. /usr/bin/perf_6.1)",
+        perf.display()
+    );
+
+    if let Some(dir) = copy.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("unable to create '{}'", dir.display()))?;
+    }
+    // Replace instead of overwriting, in case a previous copy is still being executed.
+    let _ = fs::remove_file(&copy);
+    fs::write(&copy, contents)
+        .with_context(|| format!("unable to copy perf to '{}'", copy.display()))?;
+    fs::set_permissions(&copy, Permissions::from_mode(0o700))
+        .with_context(|| format!("unable to restrict access to '{}'", copy.display()))?;
+
+    let setcap = find_program("setcap")
+        .ok_or_else(|| anyhow!("setcap not found, please install libcap (e.g. libcap2-bin)"))?;
+    log::info!(
+        "granting {} to a copy of perf at '{}' (remove it with --remove-capabilities)",
+        capabilities(),
+        copy.display()
+    );
+    let mut command = root.command(&setcap.to_string_lossy());
+    command.arg(capabilities()).arg(&copy);
+    crate::print_command(&command);
+    let status = command.status();
+    if !matches!(status, Ok(status) if status.success()) {
+        let _ = fs::remove_file(&copy);
+        return Err(anyhow!(
+            "unable to grant capabilities to '{}'",
+            copy.display()
+        ));
+    }
+    anyhow::ensure!(
+        has_capabilities(&copy)?,
+        "'{}' does not have the capabilities after running setcap",
+        copy.display()
+    );
+    Ok(copy)
+}
+
+/// Deletes the capable copy of perf, if any.
+pub fn remove() -> anyhow::Result<()> {
+    let copy = path().ok_or_else(|| anyhow!("unable to determine the data directory"))?;
+    if !copy.exists() {
+        log::info!("no capable copy of perf at '{}'", copy.display());
+        return Ok(());
+    }
+    fs::remove_file(&copy).with_context(|| format!("unable to remove '{}'", copy.display()))?;
+    log::info!("removed the capable copy of perf at '{}'", copy.display());
+    Ok(())
+}
@@ -0,0 +1,443 @@
+//! Support for `flamegraph.toml` configuration files.
+//!
+//! Every key of a configuration file corresponds to the long name of a command
+//! line option, e.g.
+//!
+//! ```toml
+//! freq = 4999
+//! palette = "rust"
+//! output = "flamegraph-{bin}-{date}.svg"
+//! root = true
+//! skip-after = ["main"]
+//! ```
+//!
+//! The user configuration (`~/.config/flamegraph/config.toml`) is applied
+//! first, then the `flamegraph.toml` found in the current directory or any
+//! parent directory, then `FLAMEGRAPH_*` environment variables and finally the
+//! options given on the command line. Each of them replaces what the ones
+//! before it set for an option, including `false` and lists.
+
+use std::{
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use clap::Command;
+use toml::{Table, Value};
+
+pub const PROJECT_CONFIG: &str = "flamegraph.toml";
+
+/// Name of the environment variable mirroring the option `--<long>`, e.g.
+/// `FLAMEGRAPH_SKIP_AFTER` for `--skip-after`.
+pub fn env_var(long: &str) -> String {
+    format!("FLAMEGRAPH_{}", long.replace('-', "_").to_uppercase())
+}
+
+/// Lets every option of `command` (and its subcommands) be set through its
+/// `FLAMEGRAPH_*` environment variable.
+pub fn with_env(command: Command) -> Command {
+    let command = command.mut_args(|arg| match arg.get_long() {
+        Some("help" | "version" | "completions") | None => arg,
+        Some(long) => {
+            let var = env_var(long);
+            arg.env(var)
+        }
+    });
+
+    let subcommands: Vec<String> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect();
+    subcommands.into_iter().fold(command, |command, name| {
+        command.mut_subcommand(name, with_env)
+    })
+}
+
+/// Location of the per-user configuration file.
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                env::var_os("APPDATA").map(PathBuf::from)
+            } else {
+                env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+            }
+        })?;
+    Some(config_dir.join("flamegraph").join("config.toml"))
+}
+
+/// Location of the nearest `flamegraph.toml` in the current directory or its parents.
+pub fn project_config_path() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG))
+        .find(|path| path.is_file())
+}
+
+fn read_table(path: &Path) -> anyhow::Result<Table> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read config file '{}'", path.display()))?;
+    contents
+        .parse()
+        .with_context(|| format!("invalid config file '{}'", path.display()))
+}
+
+/// Converts the entries of a configuration table into command line arguments.
+pub fn table_to_args(
+    command: &Command,
+    table: &Table,
+    origin: &str,
+) -> anyhow::Result<Vec<OsString>> {
+    let mut args = Vec::new();
+
+    for (key, value) in table {
+        let long = key.replace('_', "-");
+        let arg = match command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+        {
+            Some(arg) => arg,
+            None => return Err(anyhow!("unknown option '{key}' in {origin}")),
+        };
+
+        // Environment variables take precedence over configuration files.
+        if env::var_os(env_var(&long)).is_some() {
+            continue;
+        }
+
+        let flag = format!("--{long}");
+        match value {
+            Value::Boolean(true) => args.push(flag.into()),
+            Value::Boolean(false) => {}
+            // Counted flags such as `verbose = 2` are repeated.
+            Value::Integer(count) if matches!(arg.get_action(), clap::ArgAction::Count) => {
+                args.extend((0..*count).map(|_| OsString::from(&flag)));
+            }
+            Value::Array(values) => {
+                for value in values {
+                    args.push(format!("{flag}={}", scalar(value, key, origin)?).into());
+                }
+            }
+            value => args.push(format!("{flag}={}", scalar(value, key, origin)?).into()),
+        }
+    }
+
+    Ok(args)
+}
+
+/// Whether the option `--long` of `command` is given in `args`, before any `--` separator.
+fn is_given(command: &Command, args: &[OsString], long: &str) -> bool {
+    let short = command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long))
+        .and_then(|arg| arg.get_short());
+    let flag = format!("--{long}");
+    let prefix = format!("--{long}=");
+    args.iter()
+        .map(|arg| arg.to_string_lossy())
+        .take_while(|arg| arg != "--")
+        .any(|arg| {
+            arg == flag
+                || arg.starts_with(&prefix)
+                || short.is_some_and(|short| in_short_group(command, &arg, short))
+        })
+}
+
+/// Whether the short flag `short` of `command` is in `arg`, a group of short flags such as
+/// `-vv` or `-vo`. The first flag of the group that takes a value ends it, since the rest is its
+/// value, e.g. `-oout.svg`.
+fn in_short_group(command: &Command, arg: &str, short: char) -> bool {
+    let Some(group) = arg
+        .strip_prefix('-')
+        .filter(|group| !group.starts_with('-'))
+    else {
+        return false;
+    };
+    for letter in group.chars() {
+        if letter == short {
+            return true;
+        }
+        let takes_value = command
+            .get_arguments()
+            .find(|arg| arg.get_short() == Some(letter))
+            .is_some_and(|arg| arg.get_action().takes_values());
+        if takes_value {
+            return false;
+        }
+    }
+    false
+}
+
+fn scalar(value: &Value, key: &str, origin: &str) -> anyhow::Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        _ => Err(anyhow!("unsupported value for '{key}' in {origin}")),
+    }
+}
+
+/// Removes `long <value>` or `long=<value>` from the options in `args` (i.e.
+/// before a `--` separator) and returns its value.
+fn take_option(args: &mut Vec<OsString>, long: &str) -> anyhow::Result<Option<String>> {
+    let prefix = format!("{long}=");
+    for i in 0..args.len() {
+        let arg = args[i].to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            let value = value.to_string();
+            args.remove(i);
+            return Ok(Some(value));
+        }
+        if arg == long {
+            let value = args
+                .get(i + 1)
+                .map(|value| value.to_string_lossy().into_owned())
+                .ok_or_else(|| anyhow!("missing value for '{long}'"))?;
+            args.drain(i..=i + 1);
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+/// Splits off the `--` separator and the trailing arguments following it.
+fn split_trailing(args: &mut Vec<OsString>) -> Option<Vec<OsString>> {
+    let separator = args.iter().position(|arg| arg == "--")?;
+    Some(args.split_off(separator))
+}
+
+/// Location of a saved invocation.
+pub fn invocation_path(name: &str) -> anyhow::Result<PathBuf> {
+    anyhow::ensure!(
+        !name.is_empty() && !name.contains(['/', '\\']) && name != "." && name != "..",
+        "invalid invocation name '{name}'"
+    );
+    let dir = user_config_path()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .ok_or_else(|| anyhow!("unable to determine the configuration directory"))?;
+    Ok(dir.join("invocations").join(format!("{name}.toml")))
+}
+
+fn save_invocation(name: &str, args: &[OsString]) -> anyhow::Result<()> {
+    let path = invocation_path(name)?;
+    let args = args
+        .iter()
+        .map(|arg| {
+            arg.to_str()
+                .map(|arg| Value::String(arg.to_string()))
+                .ok_or_else(|| anyhow!("cannot save non UTF-8 argument {:?}", arg))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut table = Table::new();
+    table.insert("args".to_string(), Value::Array(args));
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("unable to create '{}'", dir.display()))?;
+    }
+    std::fs::write(&path, table.to_string())
+        .with_context(|| format!("unable to save invocation to '{}'", path.display()))?;
+    log::info!("saved invocation '{}' to '{}'", name, path.display());
+    Ok(())
+}
+
+fn load_invocation(name: &str) -> anyhow::Result<Vec<OsString>> {
+    let path = invocation_path(name)?;
+    if !path.is_file() {
+        return Err(anyhow!(
+            "no saved invocation named '{}' (expected '{}')",
+            name,
+            path.display()
+        ));
+    }
+    let table = read_table(&path)?;
+    match table.get("args") {
+        Some(Value::Array(args)) => args
+            .iter()
+            .map(|arg| match arg {
+                Value::String(arg) => Ok(arg.into()),
+                _ => Err(anyhow!("invalid argument in '{}'", path.display())),
+            })
+            .collect(),
+        _ => Err(anyhow!("'{}' does not contain any args", path.display())),
+    }
+}
+
+/// Converts the configuration `layers`, each a table and where it comes from, into command line
+/// arguments. Every option is taken from the last layer that sets it, and left out if it is
+/// given in `cli_args`.
+fn layer_args(
+    command: &Command,
+    layers: &[(Table, String)],
+    cli_args: &[OsString],
+) -> anyhow::Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for (i, (table, origin)) in layers.iter().enumerate() {
+        let is_replaced = |long: &str| {
+            layers[i + 1..]
+                .iter()
+                .any(|(later, _)| later.keys().any(|key| key.replace('_', "-") == long))
+                || is_given(command, cli_args, long)
+        };
+        let table: Table = table
+            .iter()
+            .filter(|(key, _)| !is_replaced(&key.replace('_', "-")))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        args.extend(table_to_args(command, &table, origin)?);
+    }
+    Ok(args)
+}
+
+/// Inserts the options from the user and project configuration files and the
+/// given `defaults`, a table and where it comes from, into `args`, right after
+/// the first `skip` arguments (the binary name and, for cargo subcommands, the
+/// subcommand name). Every option is taken from the last of them that sets it,
+/// and left out if it is given on the command line.
+///
+/// `--invocation <NAME>` is replaced by the arguments of a saved invocation,
+/// in which case configuration files and `defaults` are ignored so the run is
+/// reproduced exactly. With `--save-invocation <NAME>`, the resulting
+/// arguments are saved under that name.
+pub fn apply(
+    command: &Command,
+    args: impl IntoIterator<Item = OsString>,
+    skip: usize,
+    defaults: Option<(Table, String)>,
+) -> anyhow::Result<Vec<OsString>> {
+    let mut args: Vec<_> = args.into_iter().collect();
+    let at = skip.min(args.len());
+    let mut cli_args = args.split_off(at);
+
+    let save = take_option(&mut cli_args, "--save-invocation")?;
+    let replay = take_option(&mut cli_args, "--invocation")?;
+
+    let mut effective_args = match replay {
+        Some(name) => load_invocation(&name)?,
+        None => {
+            let mut layers = Vec::new();
+            for path in [user_config_path(), project_config_path()]
+                .into_iter()
+                .flatten()
+                .filter(|path| path.is_file())
+            {
+                layers.push((read_table(&path)?, format!("'{}'", path.display())));
+            }
+            layers.extend(defaults);
+            layer_args(command, &layers, &cli_args)?
+        }
+    };
+
+    // Trailing arguments given on the command line replace the saved ones.
+    let saved_trailing = split_trailing(&mut effective_args);
+    let cli_trailing = split_trailing(&mut cli_args);
+    effective_args.extend(cli_args);
+    effective_args.extend(cli_trailing.or(saved_trailing).unwrap_or_default());
+
+    if let Some(name) = save {
+        save_invocation(&name, &effective_args)?;
+    }
+
+    args.extend(effective_args);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, ArgAction};
+
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("flamegraph")
+            .arg(
+                Arg::new("verbose")
+                    .short('v')
+                    .long("verbose")
+                    .action(ArgAction::Count),
+            )
+            .arg(Arg::new("output").short('o').long("output"))
+            .arg(Arg::new("open").long("open").action(ArgAction::SetTrue))
+            .arg(Arg::new("freq").short('F').long("freq"))
+            .arg(
+                Arg::new("skip-after")
+                    .long("skip-after")
+                    .action(ArgAction::Append),
+            )
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn layer(toml: &str, origin: &str) -> (Table, String) {
+        (toml.parse().unwrap(), origin.to_string())
+    }
+
+    #[test]
+    fn later_layers_replace_earlier_ones() {
+        let layers = [
+            layer(
+                "freq = 99\nopen = true\nskip-after = [\"a\", \"b\"]",
+                "user",
+            ),
+            layer("freq = 4999\nopen = false\nskip_after = [\"c\"]", "project"),
+        ];
+        assert_eq!(
+            layer_args(&command(), &layers, &[]).unwrap(),
+            args(&["--freq=4999", "--skip-after=c"])
+        );
+    }
+
+    #[test]
+    fn options_given_on_the_command_line_are_left_out() {
+        let layers = [layer("freq = 99\noutput = \"user.svg\"", "user")];
+        assert_eq!(
+            layer_args(&command(), &layers, &args(&["-F", "1000"])).unwrap(),
+            args(&["--output=user.svg"])
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let layers = [layer("frequency = 99", "user")];
+        assert!(layer_args(&command(), &layers, &[]).is_err());
+    }
+
+    #[test]
+    fn long_options_are_detected() {
+        let command = command();
+        assert!(is_given(&command, &args(&["--output", "a.svg"]), "output"));
+        assert!(is_given(&command, &args(&["--output=a.svg"]), "output"));
+        assert!(!is_given(&command, &args(&["--open"]), "output"));
+    }
+
+    #[test]
+    fn short_flags_are_detected_in_groups() {
+        let command = command();
+        assert!(is_given(&command, &args(&["-o", "a.svg"]), "output"));
+        assert!(is_given(&command, &args(&["-oa.svg"]), "output"));
+        assert!(is_given(&command, &args(&["-vo", "a.svg"]), "output"));
+        assert!(is_given(&command, &args(&["-vvo", "a.svg"]), "verbose"));
+        // The rest of the group is the value of `-F`.
+        assert!(!is_given(&command, &args(&["-Fo"]), "output"));
+    }
+
+    #[test]
+    fn trailing_arguments_are_ignored() {
+        let command = command();
+        assert!(!is_given(&command, &args(&["--", "-o", "a.svg"]), "output"));
+        assert!(!is_given(
+            &command,
+            &args(&["--", "--output=a.svg"]),
+            "output"
+        ));
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use inferno::flamegraph::from_reader;
+
+use crate::{folded, FlamegraphOptions};
+
+/// Crates shipped with the toolchain, which never show up in cargo metadata.
+const TOOLCHAIN_CRATES: &[&str] = &["std", "core", "alloc", "proc_macro", "test"];
+
+/// Name used for frames that cannot be attributed to a Rust crate.
+const OTHER: &str = "[other]";
+
+/// Determines the crate owning a (demangled) frame from its symbol path.
+///
+/// For trait implementations such as `<foo::Bar as core::fmt::Debug>::fmt` the
+/// crate of the implementing type is used. When `known` is not empty, only
+/// those crates and the toolchain crates are recognized.
+fn owning_crate<'a>(frame: &'a str, known: &HashSet<String>) -> &'a str {
+    let path = frame.trim_start_matches(['<', '&', '*']);
+    let path = path
+        .strip_prefix("dyn ")
+        .or_else(|| path.strip_prefix("mut "))
+        .unwrap_or(path);
+
+    let segment = match path.split_once("::") {
+        Some((segment, _)) => segment,
+        None => return OTHER,
+    };
+
+    let is_identifier = !segment.is_empty()
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    let is_known =
+        known.is_empty() || known.contains(segment) || TOOLCHAIN_CRATES.contains(&segment);
+
+    if is_identifier && is_known {
+        segment
+    } else {
+        OTHER
+    }
+}
+
+/// Maps every frame to its crate, merging consecutive frames of the same crate.
+fn crate_stacks(collapsed: &[u8], known: &HashSet<String>) -> Vec<(Vec<String>, u64)> {
+    folded::lines(collapsed)
+        .map(|(stack, count)| {
+            let mut crates: Vec<String> = Vec::new();
+            for frame in stack.split(';') {
+                let name = owning_crate(frame, known);
+                if crates.last().map(String::as_str) != Some(name) {
+                    crates.push(name.to_string());
+                }
+            }
+            (crates, count)
+        })
+        .collect()
+}
+
+/// Prints the self and inclusive share of samples of each crate and optionally
+/// writes a flamegraph whose frames are crates instead of functions.
+pub(crate) fn report(
+    collapsed: &[u8],
+    known: &[String],
+    flamegraph: Option<&Path>,
+    options: &FlamegraphOptions,
+) -> anyhow::Result<()> {
+    let known: HashSet<_> = known.iter().map(|name| name.replace('-', "_")).collect();
+    let stacks = crate_stacks(collapsed, &known);
+    let total: u64 = stacks.iter().map(|(_, count)| count).sum();
+    anyhow::ensure!(total > 0, "no samples to aggregate by crate");
+
+    let mut self_counts: HashMap<&str, u64> = HashMap::new();
+    let mut inclusive_counts: HashMap<&str, u64> = HashMap::new();
+    for (crates, count) in &stacks {
+        if let Some(leaf) = crates.last() {
+            *self_counts.entry(leaf).or_insert(0) += count;
+        }
+        let unique: HashSet<_> = crates.iter().map(String::as_str).collect();
+        for name in unique {
+            *inclusive_counts.entry(name).or_insert(0) += count;
+        }
+    }
+
+    let mut rows: Vec<_> = inclusive_counts.into_iter().collect();
+    rows.sort_by(|a, b| {
+        let self_a = self_counts.get(a.0).copied().unwrap_or(0);
+        let self_b = self_counts.get(b.0).copied().unwrap_or(0);
+        self_b.cmp(&self_a).then(b.1.cmp(&a.1))
+    });
+
+    let percent = |count: u64| count as f64 * 100.0 / total as f64;
+    let mut table = vec![format!("{:>10} {:>10}  crate", "self", "total")];
+    table.extend(rows.into_iter().map(|(name, inclusive)| {
+        let own = self_counts.get(name).copied().unwrap_or(0);
+        format!(
+            "{:>9.2}% {:>9.2}%  {}",
+            percent(own),
+            percent(inclusive),
+            name
+        )
+    }));
+    log::info!("{}", table.join("\n"));
+
+    if let Some(path) = flamegraph {
+        let mut folded = Vec::new();
+        for (crates, count) in &stacks {
+            writeln!(folded, "{} {}", crates.join(";"), count).unwrap();
+        }
+
+        let mut inferno_opts = options.clone().into_inferno();
+        inferno_opts.title = "Crate Graph".to_string();
+        inferno_opts.name_type = "Crate:".to_string();
+
+        log::info!("writing crate flamegraph to {:?}", path);
+        let writer = BufWriter::new(
+            File::create(path).with_context(|| format!("unable to create {}", path.display()))?,
+        );
+        from_reader(&mut inferno_opts, BufReader::new(&*folded), writer)
+            .context("unable to generate the crate flamegraph")?;
+    }
+
+    Ok(())
+}
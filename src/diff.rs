@@ -0,0 +1,50 @@
+//! Differential flamegraphs, showing how the stacks of one run changed compared to another.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use anyhow::Context;
+use inferno::{differential, flamegraph::from_reader};
+
+use crate::{folded, regression, FlamegraphOptions};
+
+/// Writes a flamegraph of the `after` stacks to `path`, with frames colored red where they
+/// grew and blue where they shrank compared to `before`. The counts of `before` are scaled to
+/// the total of `after` first, so runs of different lengths can be compared.
+pub fn write(
+    before: &[u8],
+    after: &[u8],
+    title: &str,
+    options: &FlamegraphOptions,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let mut stacks = Vec::new();
+    let diff_options = differential::Options {
+        normalize: true,
+        ..Default::default()
+    };
+    differential::from_readers(diff_options, before, after, &mut stacks)
+        .context("unable to compare the stacks")?;
+
+    let mut inferno_opts = options.clone().into_inferno();
+    inferno_opts.title = title.to_string();
+    let writer = BufWriter::new(
+        File::create(path).with_context(|| format!("unable to create {}", path.display()))?,
+    );
+    from_reader(&mut inferno_opts, BufReader::new(&*stacks), writer)
+        .with_context(|| format!("unable to generate {}", path.display()))
+}
+
+/// Prints a table of how the share of samples of each function changed from `before` to
+/// `after`, as `--check-against` does, and returns the number of functions whose share grew by
+/// more than `threshold` percent.
+pub fn print_regressions(before: &[u8], after: &[u8], threshold: f64) -> anyhow::Result<usize> {
+    anyhow::ensure!(
+        folded::total_samples(before) > 0 && folded::total_samples(after) > 0,
+        "no samples to compare"
+    );
+    Ok(regression::compare(before, after, threshold))
+}
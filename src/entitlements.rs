@@ -0,0 +1,206 @@
+//! What keeps DTrace from attaching to a process on macOS, and fixing it for development builds.
+//!
+//! System Integrity Protection forbids tracing the binaries of the system, and binaries signed
+//! with the hardened runtime can only be attached to if they also have the `get-task-allow`
+//! entitlement, which Xcode adds to debug builds. Developer mode (`DevToolsSecurity`) lets
+//! debugging tools attach to processes without asking for a password every time.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Context};
+
+use crate::escalate::Root;
+
+const GET_TASK_ALLOW: &str = "com.apple.security.get-task-allow";
+
+/// Directories of the system, which System Integrity Protection protects.
+const PROTECTED: &[&str] = &["/System/", "/bin/", "/sbin/", "/usr/"];
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    // codesign prints what it displays on stderr, except the entitlements.
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Some(text)
+}
+
+fn sip_enabled() -> bool {
+    output("csrutil", &["status"]).is_some_and(|status| status.contains("enabled"))
+}
+
+/// Whether developer mode is on, if `DevToolsSecurity` can tell.
+fn developer_mode() -> Option<bool> {
+    let status = output("DevToolsSecurity", &["-status"])?;
+    if status.contains("enabled") {
+        Some(true)
+    } else if status.contains("disabled") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+fn is_protected(binary: &Path) -> bool {
+    let path = binary.to_string_lossy();
+    PROTECTED.iter().any(|dir| path.starts_with(dir)) && !path.starts_with("/usr/local/")
+}
+
+/// Whether `binary` is signed with the hardened runtime but lacks `get-task-allow`, which keeps
+/// DTrace from attaching to it.
+fn is_hardened(binary: &Path) -> bool {
+    let binary = binary.to_string_lossy();
+    let runtime = output("codesign", &["--display", "--verbose", &binary])
+        .is_some_and(|info| info.contains("(runtime)"));
+    if !runtime {
+        return false;
+    }
+    let entitlements = output(
+        "codesign",
+        &["--display", "--entitlements", "-", "--xml", &binary],
+    )
+    .unwrap_or_default();
+    let entitlements: String = entitlements.split_whitespace().collect();
+    !entitlements.contains(&format!("<key>{GET_TASK_ALLOW}</key><true/>"))
+}
+
+/// What keeps DTrace from attaching to a binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Problem {
+    /// It is a system binary and System Integrity Protection is on.
+    Protected,
+    /// It is signed with the hardened runtime but lacks `get-task-allow`.
+    Hardened,
+}
+
+impl Problem {
+    fn of(binary: &Path) -> Option<Self> {
+        if is_protected(binary) {
+            sip_enabled().then_some(Problem::Protected)
+        } else {
+            is_hardened(binary).then_some(Problem::Hardened)
+        }
+    }
+
+    fn explain(self, binary: &Path) -> String {
+        match self {
+            Problem::Protected => format!(
+                "System Integrity Protection keeps DTrace from tracing {}; profile a copy of it \
+                 outside of the system directories instead",
+                binary.display()
+            ),
+            Problem::Hardened => format!(
+                "{} uses the hardened runtime without {GET_TASK_ALLOW}, so DTrace cannot attach \
+                 to it; for a development build, pass --fix-entitlements to sign it with the \
+                 entitlement",
+                binary.display()
+            ),
+        }
+    }
+}
+
+/// The running executable of the process `pid`.
+pub(crate) fn executable(pid: u32) -> Option<PathBuf> {
+    let path = output("ps", &["-o", "comm=", "-p", &pid.to_string()])?;
+    let path = path.trim();
+    path.starts_with('/').then(|| PathBuf::from(path))
+}
+
+/// Signs `binary` ad hoc, keeping the hardened runtime, with `get-task-allow`.
+fn allow_attaching(binary: &Path) -> anyhow::Result<()> {
+    let plist = env::temp_dir().join(format!(
+        "flamegraph-entitlements-{}.plist",
+        std::process::id()
+    ));
+    fs::write(
+        &plist,
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \
+             \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+             <plist version=\"1.0\"><dict><key>{GET_TASK_ALLOW}</key><true/></dict></plist>\n"
+        ),
+    )
+    .context("unable to write the entitlements")?;
+    let mut command = Command::new("codesign");
+    command
+        .args([
+            "--force",
+            "--sign",
+            "-",
+            "--options",
+            "runtime",
+            "--entitlements",
+        ])
+        .arg(&plist)
+        .arg(binary);
+    crate::print_command(&command);
+    let status = command.status();
+    let _ = fs::remove_file(&plist);
+    if !status.context("unable to run codesign")?.success() {
+        bail!(
+            "codesign failed to add {GET_TASK_ALLOW} to {}",
+            binary.display()
+        );
+    }
+    Ok(())
+}
+
+/// Checks that DTrace can attach to `binary` before recording it, signing it with
+/// `get-task-allow` and enabling developer mode if `fix` is set (`--fix-entitlements`) and
+/// warning with how to do so otherwise.
+pub(crate) fn check(binary: &Path, fix: bool, root: Option<Root>) -> anyhow::Result<()> {
+    match Problem::of(binary) {
+        Some(Problem::Hardened) if fix => {
+            log::info!(
+                "signing {} with {GET_TASK_ALLOW} so DTrace can attach to it",
+                binary.display()
+            );
+            allow_attaching(binary)?;
+        }
+        Some(problem) => log::warn!("{}", problem.explain(binary)),
+        None => (),
+    }
+
+    if fix && developer_mode() == Some(false) {
+        log::info!("enabling developer mode");
+        let mut command = match root {
+            Some(root) => root.command("DevToolsSecurity"),
+            None => Command::new("DevToolsSecurity"),
+        };
+        command.arg("-enable");
+        crate::print_command(&command);
+        if !command
+            .status()
+            .context("unable to run DevToolsSecurity")?
+            .success()
+        {
+            bail!("unable to enable developer mode; run `sudo DevToolsSecurity -enable`");
+        }
+    }
+    Ok(())
+}
+
+/// The likely reason DTrace failed to record `binary`, with how to fix it.
+pub(crate) fn diagnose(binary: Option<&Path>) -> Option<String> {
+    if let Some(binary) = binary {
+        if let Some(problem) = Problem::of(binary) {
+            return Some(problem.explain(binary));
+        }
+    }
+    if developer_mode() == Some(false) {
+        return Some(
+            "developer mode is off, which keeps tools from attaching to processes; pass \
+             --fix-entitlements or run `sudo DevToolsSecurity -enable`"
+                .to_string(),
+        );
+    }
+    sip_enabled().then(|| {
+        "System Integrity Protection restricts DTrace; `csrutil enable --without dtrace` from \
+         the recovery system lifts that"
+            .to_string()
+    })
+}
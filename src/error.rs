@@ -0,0 +1,56 @@
+use std::{error::Error, fmt};
+
+/// Errors returned by [`generate_flamegraph_for_workload`](crate::generate_flamegraph_for_workload).
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum FlamegraphError {
+    /// The recorder (perf, dtrace) is not installed or not present in `$PATH`.
+    BackendMissing(&'static str),
+    /// The recorder could not be run or exited unsuccessfully.
+    RecordFailed(anyhow::Error),
+    /// The recorded data could not be collapsed into stacks.
+    CollapseFailed(anyhow::Error),
+    /// The flamegraph could not be rendered.
+    RenderFailed(anyhow::Error),
+    /// The run was stopped with a [`StopHandle`](crate::StopHandle) before recording started.
+    Cancelled,
+    /// Any other failure, e.g. invalid options or outputs that cannot be written.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for FlamegraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlamegraphError::BackendMissing(backend) => {
+                write!(f, "{backend} is not installed or not present in $PATH")
+            }
+            FlamegraphError::RecordFailed(_) => f.write_str("failed to sample program"),
+            FlamegraphError::CollapseFailed(_) => {
+                f.write_str("unable to collapse generated profile data")
+            }
+            FlamegraphError::RenderFailed(_) => {
+                f.write_str("unable to generate a flamegraph from the collapsed stack data")
+            }
+            FlamegraphError::Cancelled => f.write_str("the run was cancelled"),
+            FlamegraphError::Other(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
+impl Error for FlamegraphError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FlamegraphError::BackendMissing(_) | FlamegraphError::Cancelled => None,
+            FlamegraphError::RecordFailed(err)
+            | FlamegraphError::CollapseFailed(err)
+            | FlamegraphError::RenderFailed(err) => Some(err.as_ref()),
+            FlamegraphError::Other(err) => err.source(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for FlamegraphError {
+    fn from(err: anyhow::Error) -> Self {
+        FlamegraphError::Other(err)
+    }
+}
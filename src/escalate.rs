@@ -0,0 +1,89 @@
+use std::{env, ffi::OsString, process::Command};
+
+use anyhow::{anyhow, bail};
+
+/// Tools for running the recorder with root privileges.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Escalate {
+    /// sudo
+    #[default]
+    Sudo,
+    /// doas, as found on the BSDs and some Linux distributions
+    Doas,
+    /// run0, shipped with systemd 256 and later
+    Run0,
+    /// pkexec, which asks for the password with a graphical polkit prompt
+    Pkexec,
+}
+
+impl Escalate {
+    fn program(self) -> &'static str {
+        match self {
+            Escalate::Sudo => "sudo",
+            Escalate::Doas => "doas",
+            Escalate::Run0 => "run0",
+            Escalate::Pkexec => "pkexec",
+        }
+    }
+}
+
+/// Where the command run with root privileges goes in an `--escalation-cmd` template.
+const PLACEHOLDER: &str = "{}";
+
+/// Splits an `--escalation-cmd` template into words, checking that the command run with root
+/// privileges can go at its end.
+pub(crate) fn parse_template(template: &str) -> anyhow::Result<Vec<String>> {
+    let words = shlex::split(template)
+        .ok_or_else(|| anyhow!("unable to parse the escalation command {template:?}"))?;
+    match words.iter().position(|word| word == PLACEHOLDER) {
+        _ if words.is_empty() => bail!("the escalation command is empty"),
+        Some(0) => bail!("the escalation command needs a program before {PLACEHOLDER}"),
+        Some(i) if i + 1 != words.len() => bail!(
+            "{PLACEHOLDER} has to be the last word of the escalation command, as the arguments \
+             of the command run with root privileges follow it"
+        ),
+        _ => Ok(words),
+    }
+}
+
+/// How to gain root privileges: the escalation tool and the extra flags passed to it, or the
+/// words of an `--escalation-cmd` template used instead.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Root<'a> {
+    pub tool: Escalate,
+    pub flags: Option<&'a str>,
+    pub custom: Option<&'a [String]>,
+}
+
+impl Root<'_> {
+    /// A command running `program` with root privileges.
+    pub fn command(self, program: &str) -> Command {
+        if let Some((custom, words)) = self.custom.and_then(<[String]>::split_first) {
+            let mut c = Command::new(custom);
+            for word in words {
+                c.arg(if word == PLACEHOLDER { program } else { word });
+            }
+            if words.last().map_or(true, |word| word != PLACEHOLDER) {
+                c.arg(program);
+            }
+            return c;
+        }
+        let mut c = Command::new(self.tool.program());
+        if let Some(flags) = self.flags {
+            c.arg(flags);
+        }
+        // pkexec starts the program in root's home directory, which would break relative paths
+        // such as perf's output file.
+        if self.tool == Escalate::Pkexec {
+            if let Ok(dir) = env::current_dir() {
+                let mut chdir = OsString::from("--chdir=");
+                chdir.push(dir);
+                c.arg("env");
+                c.arg(chdir);
+            }
+        }
+        c.arg(program);
+        c
+    }
+}
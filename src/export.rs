@@ -0,0 +1,343 @@
+//! Output formats for the collapsed stacks.
+//!
+//! Every output is written by an [`Exporter`]. The flamegraph itself is rendered by [`Svg`];
+//! further formats can be requested with `--export` or, when using the library, by adding any
+//! implementation to [`Options::exporters`].
+//!
+//! [`Options::exporters`]: crate::Options::exporters
+
+use std::{collections::HashMap, fmt, io::Write, sync::Arc};
+
+use anyhow::Context;
+use inferno::flamegraph::{from_reader, FuncFrameAttrsMap};
+
+use crate::{folded, optimize, sandwich::html_escape, FlamegraphOptions};
+
+/// Writes collapsed stacks in an output format.
+pub trait Exporter: fmt::Debug {
+    /// Name of the format, used in messages.
+    fn name(&self) -> &str;
+
+    /// File extension of the output, without the leading dot.
+    fn extension(&self) -> &str;
+
+    /// Writes `collapsed`, lines of the form `frame;frame;frame count`, to `writer`.
+    fn export(
+        &self,
+        collapsed: &[u8],
+        options: &FlamegraphOptions,
+        writer: &mut dyn Write,
+    ) -> anyhow::Result<()>;
+}
+
+/// Interactive SVG flamegraph rendered by inferno.
+#[derive(Debug, Clone, Default)]
+pub struct Svg {
+    /// Frame attributes in the format of inferno's `--nameattr` files.
+    pub(crate) frame_attrs: Option<String>,
+    /// Merge frames narrower than a pixel and shorten the attributes, see `--optimize-svg`.
+    pub(crate) optimize: bool,
+}
+
+impl Exporter for Svg {
+    fn name(&self) -> &str {
+        "SVG"
+    }
+
+    fn extension(&self) -> &str {
+        "svg"
+    }
+
+    fn export(
+        &self,
+        collapsed: &[u8],
+        options: &FlamegraphOptions,
+        writer: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        let mut inferno_opts = options.clone().into_inferno();
+        if let Some(frame_attrs) = &self.frame_attrs {
+            inferno_opts.func_frameattrs = FuncFrameAttrsMap::from_reader(frame_attrs.as_bytes())?;
+        }
+        if !self.optimize {
+            from_reader(&mut inferno_opts, collapsed, writer)?;
+            return Ok(());
+        }
+
+        let total = folded::total_samples(collapsed);
+        let width = inferno_opts.image_width.unwrap_or(1200).max(1) as u64;
+        let merged = optimize::merge_small_frames(collapsed, total / width);
+        let mut svg = Vec::new();
+        from_reader(&mut inferno_opts, &*merged, &mut svg)?;
+        let svg = String::from_utf8(svg).context("inferno wrote an invalid SVG")?;
+        writer.write_all(optimize::compact_svg(&svg).as_bytes())?;
+        Ok(())
+    }
+}
+
+/// The collapsed stacks as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Folded;
+
+impl Exporter for Folded {
+    fn name(&self) -> &str {
+        "folded stacks"
+    }
+
+    fn extension(&self) -> &str {
+        "folded"
+    }
+
+    fn export(
+        &self,
+        collapsed: &[u8],
+        _: &FlamegraphOptions,
+        writer: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        writer.write_all(collapsed)?;
+        Ok(())
+    }
+}
+
+/// A standalone HTML page showing the SVG flamegraph.
+#[derive(Debug, Clone, Default)]
+pub struct Html {
+    pub(crate) svg: Svg,
+}
+
+impl Exporter for Html {
+    fn name(&self) -> &str {
+        "HTML"
+    }
+
+    fn extension(&self) -> &str {
+        "html"
+    }
+
+    fn export(
+        &self,
+        collapsed: &[u8],
+        options: &FlamegraphOptions,
+        writer: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        let mut svg = Vec::new();
+        self.svg.export(collapsed, options, &mut svg)?;
+        let svg = String::from_utf8_lossy(&svg);
+        // Drop the XML prolog, which is not allowed inside an HTML document.
+        let svg = svg.find("<svg").map_or(&*svg, |start| &svg[start..]);
+
+        write!(
+            writer,
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+</head>
+<body>
+{svg}
+</body>
+</html>
+"#,
+            title = html_escape(options.title.as_deref().unwrap_or("Flame Graph")),
+        )?;
+        Ok(())
+    }
+}
+
+/// A [speedscope](https://www.speedscope.app) sampled profile.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Speedscope;
+
+impl Exporter for Speedscope {
+    fn name(&self) -> &str {
+        "speedscope"
+    }
+
+    fn extension(&self) -> &str {
+        "speedscope.json"
+    }
+
+    fn export(
+        &self,
+        collapsed: &[u8],
+        options: &FlamegraphOptions,
+        writer: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        let mut frames = FrameTable::default();
+        let mut samples = Vec::new();
+        let mut weights = Vec::new();
+        for (stack, count) in folded::lines(collapsed) {
+            samples.push(
+                stack
+                    .split(';')
+                    .map(|frame| frames.id(frame))
+                    .collect::<Vec<_>>(),
+            );
+            weights.push(count);
+        }
+
+        let profile = serde_json::json!({
+            "$schema": "https://www.speedscope.app/file-format-schema.json",
+            "shared": {
+                "frames": frames.names.iter().map(|name| serde_json::json!({ "name": name })).collect::<Vec<_>>(),
+            },
+            "profiles": [{
+                "type": "sampled",
+                "name": options.title.as_deref().unwrap_or("Flame Graph"),
+                "unit": "none",
+                "startValue": 0,
+                "endValue": weights.iter().sum::<u64>(),
+                "samples": samples,
+                "weights": weights,
+            }],
+            "exporter": concat!("flamegraph@", env!("CARGO_PKG_VERSION")),
+        });
+        serde_json::to_writer(writer, &profile).context("unable to write speedscope profile")?;
+        Ok(())
+    }
+}
+
+/// An uncompressed [pprof](https://github.com/google/pprof) profile, as read by `go tool pprof`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pprof;
+
+impl Exporter for Pprof {
+    fn name(&self) -> &str {
+        "pprof"
+    }
+
+    fn extension(&self) -> &str {
+        "pb"
+    }
+
+    fn export(
+        &self,
+        collapsed: &[u8],
+        options: &FlamegraphOptions,
+        writer: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        // The string table starts with the empty string, and every frame gets a function and a
+        // location with the same id, the index of its name in the frame table plus one.
+        let mut frames = FrameTable::default();
+        let mut profile = Vec::new();
+
+        let count_name = options.count_name.as_deref().unwrap_or("samples");
+        let mut sample_type = Vec::new();
+        protobuf::int(&mut sample_type, 1, 1);
+        protobuf::int(&mut sample_type, 2, 2);
+        protobuf::bytes(&mut profile, 1, &sample_type);
+
+        for (stack, count) in folded::lines(collapsed) {
+            // Locations of a sample start at the leaf.
+            let ids: Vec<_> = stack
+                .rsplit(';')
+                .map(|frame| frames.id(frame) as u64 + 1)
+                .collect();
+            let mut sample = Vec::new();
+            protobuf::packed(&mut sample, 1, &ids);
+            protobuf::packed(&mut sample, 2, &[count]);
+            protobuf::bytes(&mut profile, 2, &sample);
+        }
+
+        // The names of the frames follow the empty string, the count name and its unit.
+        let first_name = 2;
+        for id in 1..=frames.names.len() as u64 {
+            let mut line = Vec::new();
+            protobuf::int(&mut line, 1, id);
+            let mut location = Vec::new();
+            protobuf::int(&mut location, 1, id);
+            protobuf::bytes(&mut location, 4, &line);
+            protobuf::bytes(&mut profile, 4, &location);
+
+            let mut function = Vec::new();
+            protobuf::int(&mut function, 1, id);
+            protobuf::int(&mut function, 2, id + first_name);
+            protobuf::int(&mut function, 3, id + first_name);
+            protobuf::bytes(&mut profile, 5, &function);
+        }
+
+        for string in ["", count_name, "count"]
+            .into_iter()
+            .chain(frames.names.iter().map(String::as_str))
+        {
+            protobuf::bytes(&mut profile, 6, string.as_bytes());
+        }
+
+        writer.write_all(&profile)?;
+        Ok(())
+    }
+}
+
+/// Assigns consecutive ids to frame names.
+#[derive(Default)]
+struct FrameTable {
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+}
+
+impl FrameTable {
+    fn id(&mut self, frame: &str) -> usize {
+        if let Some(&id) = self.ids.get(frame) {
+            return id;
+        }
+        let id = self.names.len();
+        self.names.push(frame.to_string());
+        self.ids.insert(frame.to_string(), id);
+        id
+    }
+}
+
+/// Just enough of the protobuf wire format to write pprof profiles.
+mod protobuf {
+    fn varint(buf: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        buf.push(value as u8);
+    }
+
+    pub(super) fn int(buf: &mut Vec<u8>, field: u64, value: u64) {
+        varint(buf, field << 3);
+        varint(buf, value);
+    }
+
+    pub(super) fn bytes(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+        varint(buf, field << 3 | 2);
+        varint(buf, value.len() as u64);
+        buf.extend_from_slice(value);
+    }
+
+    pub(super) fn packed(buf: &mut Vec<u8>, field: u64, values: &[u64]) {
+        let mut packed = Vec::new();
+        for &value in values {
+            varint(&mut packed, value);
+        }
+        bytes(buf, field, &packed);
+    }
+}
+
+/// The formats that can be requested with `--export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ExportFormat {
+    /// The collapsed stacks (.folded)
+    Folded,
+    /// A speedscope profile (.speedscope.json)
+    Speedscope,
+    /// A pprof profile (.pb)
+    Pprof,
+    /// A standalone HTML page with the flamegraph (.html)
+    Html,
+}
+
+impl ExportFormat {
+    pub fn exporter(self) -> Arc<dyn Exporter + Send + Sync> {
+        match self {
+            ExportFormat::Folded => Arc::new(Folded),
+            ExportFormat::Speedscope => Arc::new(Speedscope),
+            ExportFormat::Pprof => Arc::new(Pprof),
+            ExportFormat::Html => Arc::new(Html::default()),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Folding the internals of the standard library, for `--fold-std`.
+
+use crate::folded;
+
+/// Crates of the standard library, as the first path segment of their functions.
+const STD_CRATES: &[&str] = &["core", "alloc", "std"];
+
+/// Whether `frame` is a function of the standard library, including trait implementations like
+/// `<alloc::vec::Vec<T> as core::iter::traits::collect::Extend<T>>::extend`.
+fn is_std(frame: &str) -> bool {
+    let path = frame.strip_prefix('<').unwrap_or(frame);
+    STD_CRATES.iter().any(|krate| {
+        path.strip_prefix(krate)
+            .is_some_and(|rest| rest.starts_with("::"))
+    })
+}
+
+/// Folds every run of standard library frames into its first one, the function the code
+/// outside of the standard library called, which keeps their samples. Frames called back from
+/// the standard library, e.g. the closure passed to an iterator, are kept.
+pub(crate) fn fold_std(collapsed: &[u8]) -> Vec<u8> {
+    folded::merge(folded::lines(collapsed).map(|(stack, count)| {
+        let mut folded = String::with_capacity(stack.len());
+        let mut in_std = false;
+        for frame in stack.split(';') {
+            let std = is_std(frame);
+            if !(std && in_std) {
+                if !folded.is_empty() {
+                    folded.push(';');
+                }
+                folded.push_str(frame);
+            }
+            in_std = std;
+        }
+        (folded, count)
+    }))
+}
@@ -0,0 +1,118 @@
+//! Helpers for working with collapsed ("folded") stack data, i.e. lines of the
+//! form `frame;frame;frame count`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Splits a folded line into its stack and sample count.
+///
+/// Returns `None` for empty lines and lines without a trailing count.
+pub(crate) fn parse_line(line: &str) -> Option<(&str, u64)> {
+    let line = line.trim_end();
+    let (stack, count) = line.rsplit_once(' ')?;
+    let count = count.parse().ok()?;
+    Some((stack, count))
+}
+
+/// Iterates over all well-formed `(stack, count)` pairs in the folded data.
+pub(crate) fn lines(data: &[u8]) -> impl Iterator<Item = (&str, u64)> {
+    data.split(|b| *b == b'\n')
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .filter_map(parse_line)
+}
+
+/// Total number of samples in the folded data.
+pub(crate) fn total_samples(data: &[u8]) -> u64 {
+    lines(data).map(|(_, count)| count).sum()
+}
+
+/// Number of samples each function appears in (inclusive of its callees).
+///
+/// Recursive functions are only counted once per stack.
+pub(crate) fn inclusive_counts(data: &[u8]) -> HashMap<String, u64> {
+    let mut counts = HashMap::new();
+    let mut seen = HashSet::new();
+
+    for (stack, count) in lines(data) {
+        seen.clear();
+        for frame in stack.split(';') {
+            if seen.insert(frame) {
+                *counts.entry(frame.to_string()).or_insert(0) += count;
+            }
+        }
+    }
+
+    counts
+}
+
+/// The stacks of one process, split off with [`split_pids`].
+pub(crate) struct Process {
+    pub command: String,
+    pub pid: u32,
+    pub collapsed: Vec<u8>,
+}
+
+/// Splits folded data whose stacks start with `command-pid`, as collapsed for `--split-pids`,
+/// into the stacks of each process, named after the command with the most samples. Also
+/// returns all stacks merged, with the pids removed again.
+pub(crate) fn split_pids(data: &[u8]) -> (Vec<u8>, Vec<Process>) {
+    let mut merged = Vec::with_capacity(data.len());
+    // The stacks and the samples of each command, by pid.
+    let mut processes: BTreeMap<u32, (Vec<u8>, HashMap<&str, u64>)> = BTreeMap::new();
+    for (stack, count) in lines(data) {
+        let (root, rest) = stack.split_once(';').unwrap_or((stack, ""));
+        let Some((command, pid)) = root
+            .rsplit_once('-')
+            .and_then(|(command, pid)| Some((command, pid.parse::<u32>().ok()?)))
+        else {
+            merged.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+            continue;
+        };
+        let separator = if rest.is_empty() { "" } else { ";" };
+        merged.extend_from_slice(format!("{command}{separator}{rest} {count}\n").as_bytes());
+        let (collapsed, commands) = processes.entry(pid).or_default();
+        collapsed.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+        *commands.entry(command).or_insert(0) += count;
+    }
+    let processes = processes
+        .into_iter()
+        .map(|(pid, (collapsed, commands))| Process {
+            command: commands
+                .into_iter()
+                .max_by_key(|&(command, count)| (count, std::cmp::Reverse(command)))
+                .map(|(command, _)| command.to_string())
+                .unwrap_or_default(),
+            pid,
+            collapsed,
+        })
+        .collect();
+    (merged, processes)
+}
+
+/// Writes folded data of `stacks`, merging the stacks that occur several times, e.g. after
+/// frames were removed from them, in the order they first occur.
+pub(crate) fn merge(stacks: impl IntoIterator<Item = (String, u64)>) -> Vec<u8> {
+    let mut merged: Vec<(String, u64)> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for (stack, count) in stacks {
+        match index.get(&stack) {
+            Some(&i) => merged[i].1 += count,
+            None => {
+                index.insert(stack.clone(), merged.len());
+                merged.push((stack, count));
+            }
+        }
+    }
+    let mut output = Vec::new();
+    for (stack, count) in merged {
+        output.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+    }
+    output
+}
+
+/// Keeps the `max` innermost frames of every stack.
+pub(crate) fn innermost_frames(data: &[u8], max: usize) -> Vec<u8> {
+    merge(lines(data).map(|(stack, count)| {
+        let frames: Vec<_> = stack.split(';').collect();
+        (frames[frames.len().saturating_sub(max)..].join(";"), count)
+    }))
+}
@@ -0,0 +1,145 @@
+//! Detection of binaries that keep frame pointers, which perf unwinds faster and without the
+//! depth limit of DWARF call graphs.
+
+use std::path::Path;
+
+use object::{Architecture, Object, ObjectSection, ObjectSymbol, SymbolKind};
+
+use crate::folded;
+
+/// Functions whose prologue is inspected.
+const SAMPLED_FUNCTIONS: usize = 2000;
+/// Share of the inspected functions that need to set up a frame pointer. Some functions never
+/// do, e.g. the ones written in assembly.
+const THRESHOLD: f64 = 0.5;
+/// Crates of the standard library, which is prebuilt with its own settings and does not tell
+/// how the rest of the binary was compiled.
+const SYSROOT_CRATES: &[&str] = &[
+    "std",
+    "core",
+    "alloc",
+    "compiler_builtins",
+    "panic_unwind",
+    "panic_abort",
+    "std_detect",
+    "hashbrown",
+    "gimli",
+    "addr2line",
+    "object",
+    "miniz_oxide",
+    "rustc_demangle",
+    "memchr",
+    "adler",
+    "adler2",
+    "__rustc",
+];
+/// Types whose methods are defined in `core`.
+const PRIMITIVES: &[&str] = &[
+    "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64",
+    "i128", "isize", "f32", "f64",
+];
+/// Frames below which a stack is complete, after the process name.
+const STACK_ROOTS: &[&str] = &[
+    "_start",
+    "__libc_start_main",
+    "__libc_start_call_main",
+    "start_thread",
+    "clone",
+    "clone3",
+];
+
+/// Whether most functions of `binary` set up a frame pointer, or `None` if that cannot be told
+/// for the binary or its architecture.
+pub(crate) fn keeps_frame_pointers(binary: &Path) -> Option<bool> {
+    let data = std::fs::read(binary).ok()?;
+    let file = object::File::parse(&*data).ok()?;
+    let sets_up_frame: fn(&[u8]) -> bool = match file.architecture() {
+        Architecture::X86_64 => |code| {
+            // `push %rbp; mov %rsp,%rbp`, possibly after `endbr64`.
+            let code = code.strip_prefix(&[0xf3, 0x0f, 0x1e, 0xfa]).unwrap_or(code);
+            code.starts_with(&[0x55, 0x48, 0x89, 0xe5])
+        },
+        // `mov x29, sp` within the first instructions, after saving x29 and x30.
+        Architecture::Aarch64 => |code| {
+            code.chunks_exact(4)
+                .take(8)
+                .any(|insn| insn == [0xfd, 0x03, 0x00, 0x91])
+        },
+        _ => return None,
+    };
+
+    let (mut functions, mut with_frame) = (0, 0);
+    for symbol in file.symbols() {
+        if symbol.kind() != SymbolKind::Text || symbol.size() < 16 {
+            continue;
+        }
+        if !symbol.name().is_ok_and(is_user_function) {
+            continue;
+        }
+        let section = match symbol.section_index() {
+            Some(index) => file.section_by_index(index).ok()?,
+            None => continue,
+        };
+        let code = match section.data_range(symbol.address(), symbol.size()) {
+            Ok(Some(code)) => code,
+            _ => continue,
+        };
+        functions += 1;
+        if sets_up_frame(code) {
+            with_frame += 1;
+        }
+        if functions == SAMPLED_FUNCTIONS {
+            break;
+        }
+    }
+
+    // Stripped binaries have no symbols to tell the functions apart.
+    if functions == 0 {
+        return None;
+    }
+    log::debug!(
+        "{} of {} functions of {} set up a frame pointer",
+        with_frame,
+        functions,
+        binary.display()
+    );
+    Some(with_frame as f64 >= functions as f64 * THRESHOLD)
+}
+
+/// Whether the symbol `name` belongs to a crate that is not part of the standard library, and
+/// thus shows how the profiled code was compiled. Functions that are not Rust are mostly from
+/// the C runtime.
+fn is_user_function(name: &str) -> bool {
+    let name = format!("{:#}", rustc_demangle::demangle(name));
+    if !name.contains("::") {
+        return false;
+    }
+    // The crate of `<crate::Type as Trait>::method` is the one of the type.
+    let path = name.trim_start_matches(|c| "<&*[( ".contains(c));
+    let path = ["mut ", "const ", "dyn "]
+        .iter()
+        .fold(path, |path, prefix| path.trim_start_matches(prefix));
+    let krate = path
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default();
+    !krate.is_empty() && !SYSROOT_CRATES.contains(&krate) && !PRIMITIVES.contains(&krate)
+}
+
+/// Share of the samples in `collapsed` whose stack does not reach the start of the process or
+/// thread, which happens when DWARF unwinding runs out of copied stack.
+pub(crate) fn truncated_share(collapsed: &[u8]) -> f64 {
+    let (mut total, mut truncated) = (0, 0);
+    for (stack, count) in folded::lines(collapsed) {
+        // The first frame is the process name.
+        let root = stack.split(';').nth(1).unwrap_or_default();
+        total += count;
+        if !STACK_ROOTS.contains(&root) {
+            truncated += count;
+        }
+    }
+    if total == 0 {
+        return 0.0;
+    }
+    truncated as f64 / total as f64
+}
@@ -0,0 +1,145 @@
+//! An HTML page linking the files written by one or more runs.
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::sandwich::html_escape;
+
+/// A file written during a run.
+pub struct Artifact {
+    pub path: PathBuf,
+    pub description: String,
+}
+
+impl Artifact {
+    pub fn new(path: impl Into<PathBuf>, description: impl Into<String>) -> Self {
+        Artifact {
+            path: path.into(),
+            description: description.into(),
+        }
+    }
+
+    pub fn is_svg(&self) -> bool {
+        self.path
+            .extension()
+            .is_some_and(|extension| extension == "svg")
+    }
+
+    /// The format of the file, named after its extension, e.g. `svg` or `folded`.
+    pub fn file_type(&self) -> String {
+        self.path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default()
+    }
+}
+
+/// Path of `path` relative to the directory containing `index`, falling back to
+/// the absolute path for files outside of it.
+fn link(index: &Path, path: &Path) -> String {
+    let dir = index
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let (dir, path) = match (dir.canonicalize(), path.canonicalize()) {
+        (Ok(dir), Ok(path)) => (dir, path),
+        _ => (PathBuf::new(), path.to_path_buf()),
+    };
+    match path.strip_prefix(&dir) {
+        Ok(relative) => relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+        Err(_) => path.display().to_string(),
+    }
+}
+
+/// Writes an HTML page linking all `artifacts`, showing SVGs as thumbnails.
+pub fn write_index(
+    index: &Path,
+    title: &str,
+    notes: &str,
+    artifacts: &[Artifact],
+) -> anyhow::Result<()> {
+    let mut entries = String::new();
+    for artifact in artifacts {
+        let href = html_escape(&link(index, &artifact.path));
+        let description = html_escape(&artifact.description);
+        let file_name = html_escape(
+            &artifact
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        );
+
+        let preview = if artifact.is_svg() {
+            format!(r#"<img src="{href}" alt="{description}">"#)
+        } else {
+            String::new()
+        };
+        writeln!(
+            entries,
+            r#"<a class="entry" href="{href}">{preview}<div><b>{description}</b><br>{file_name}</div></a>"#
+        )
+        .unwrap();
+    }
+
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+.entries {{ display: flex; flex-wrap: wrap; gap: 1em; }}
+.entry {{ width: 320px; padding: 0.5em; border: 1px solid #ccc; color: inherit; text-decoration: none; }}
+.entry img {{ width: 100%; height: 180px; object-fit: cover; object-position: bottom; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<pre>{notes}</pre>
+<div class="entries">
+{entries}</div>
+</body>
+</html>
+"#,
+        title = html_escape(title),
+        notes = html_escape(notes),
+    );
+
+    std::fs::write(index, page).with_context(|| format!("unable to write {}", index.display()))
+}
+
+/// Writes a JSON manifest of all `artifacts` to `manifest`, for scripts processing the outputs
+/// of a run: the `metadata` of the run and, for every file, its `type`, its `path` relative to
+/// the manifest and its `description`.
+pub fn write_manifest(
+    manifest: &Path,
+    metadata: serde_json::Value,
+    artifacts: &[Artifact],
+) -> anyhow::Result<()> {
+    let artifacts: Vec<_> = artifacts
+        .iter()
+        .map(|artifact| {
+            serde_json::json!({
+                "type": artifact.file_type(),
+                "path": link(manifest, &artifact.path),
+                "description": artifact.description,
+            })
+        })
+        .collect();
+    let contents = serde_json::to_string_pretty(&serde_json::json!({
+        "metadata": metadata,
+        "artifacts": artifacts,
+    }))?;
+    std::fs::write(manifest, contents + "\n")
+        .with_context(|| format!("unable to write {}", manifest.display()))
+}
@@ -0,0 +1,57 @@
+//! Dropping the stacks of threads that wait for work, for `--drop-idle`.
+
+use crate::folded;
+
+/// Functions that threads of services, runtimes and thread pools spend their time blocked in
+/// while there is nothing to do.
+const IDLE_FUNCTIONS: &[&str] = &[
+    // Linux
+    "epoll_wait",
+    "epoll_pwait",
+    "futex_wait",
+    "pthread_cond_wait",
+    "pthread_cond_timedwait",
+    "clock_nanosleep",
+    "__select",
+    "do_idle",
+    "cpu_idle",
+    // macOS
+    "kevent",
+    "__psynch_cvwait",
+    "__workq_kernreturn",
+    "mach_msg_trap",
+    // Windows
+    "NtWaitForSingleObject",
+    "NtWaitForMultipleObjects",
+    "NtRemoveIoCompletion",
+    // Rust
+    "std::thread::park",
+    "parking_lot_core::thread_parker",
+    "crossbeam_utils::backoff::Backoff::snooze",
+];
+
+/// Removes the stacks containing a frame that mentions one of the built-in idle functions or
+/// of `extra`.
+pub(crate) fn drop_idle(collapsed: &[u8], extra: &[String]) -> Vec<u8> {
+    let functions: Vec<&str> = IDLE_FUNCTIONS
+        .iter()
+        .copied()
+        .chain(extra.iter().map(String::as_str))
+        .collect();
+    let mut kept = Vec::with_capacity(collapsed.len());
+    let mut dropped = 0;
+    let mut total = 0;
+    for (stack, count) in folded::lines(collapsed) {
+        total += count;
+        let idle = stack
+            .split(';')
+            .any(|frame| functions.iter().any(|function| frame.contains(function)));
+        if idle {
+            dropped += count;
+        } else {
+            kept.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+        }
+    }
+    log::info!("dropped {} of {} samples as idle", dropped, total);
+    kept
+}
@@ -0,0 +1,32 @@
+//! bytehound recordings, e.g. `memory-profiling_my-binary_1700000000_1234.dat`.
+//!
+//! The recordings are a binary format of bytehound's own, so they are exported as heaptrack
+//! data with `bytehound export-heaptrack` first. The bytehound binary can be overridden with
+//! `$BYTEHOUND`.
+
+use std::{env, fs::File, io::BufReader, path::Path, process::Command};
+
+use anyhow::{bail, Context};
+
+use super::{heaptrack, HeapWeight, TempFile};
+
+pub(super) fn fold(path: &Path, weight: HeapWeight) -> anyhow::Result<Vec<u8>> {
+    let bytehound = env::var("BYTEHOUND").unwrap_or_else(|_| "bytehound".to_string());
+    let exported = TempFile::new("bytehound.heaptrack");
+    let mut command = Command::new(&bytehound);
+    command
+        .arg("export-heaptrack")
+        .arg("--output")
+        .arg(&exported.0)
+        .arg(path);
+    crate::print_command(&command);
+    let status = command
+        .status()
+        .with_context(|| format!("unable to run {bytehound}; is bytehound installed?"))?;
+    if !status.success() {
+        bail!("{bytehound} failed to export {} ({status})", path.display());
+    }
+    let file = File::open(&exported.0)
+        .with_context(|| format!("unable to open {}", exported.0.display()))?;
+    heaptrack::fold(Box::new(BufReader::new(file)), weight)
+}
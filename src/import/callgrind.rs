@@ -0,0 +1,176 @@
+//! callgrind and cachegrind output, e.g. `callgrind.out.1234` as written by valgrind or
+//! iai-callgrind.
+//!
+//! The output describes a call graph rather than stacks: the self cost of every function and
+//! the inclusive cost of every call between two functions. Stacks are rebuilt by walking the
+//! graph from the functions that are not called by others, splitting the inclusive cost of a
+//! function among its callees in proportion to what each call cost. Only the first event, e.g.
+//! `Ir`, is used.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::BufRead,
+};
+
+use anyhow::Context;
+
+use super::{frame_name, write_folded};
+
+#[derive(Default)]
+struct Graph {
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+    self_cost: Vec<u64>,
+    /// The inclusive cost of the calls of each function, by callee.
+    calls: Vec<BTreeMap<usize, u64>>,
+}
+
+impl Graph {
+    fn function(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = self.names.len();
+        self.names.push(frame_name(name));
+        self.ids.insert(name.to_string(), id);
+        self.self_cost.push(0);
+        self.calls.push(BTreeMap::new());
+        id
+    }
+
+    fn inclusive(&self, function: usize) -> u64 {
+        self.self_cost[function] + self.calls[function].values().sum::<u64>()
+    }
+}
+
+/// Resolves the name compression of `fn=(12) name` and `fn=(12)`.
+fn name<'a>(value: &'a str, compressed: &'a mut HashMap<String, String>) -> &'a str {
+    let Some(rest) = value.strip_prefix('(') else {
+        return value;
+    };
+    let Some((id, name)) = rest.split_once(')') else {
+        return value;
+    };
+    let name = name.trim_start();
+    if name.is_empty() {
+        compressed.get(id).map_or(value, String::as_str)
+    } else {
+        compressed.insert(id.to_string(), name.to_string());
+        name
+    }
+}
+
+/// The cost of the first event on a cost line, after `positions` position columns.
+fn cost(line: &str, positions: usize) -> u64 {
+    line.split_whitespace()
+        .nth(positions)
+        .and_then(|cost| cost.parse().ok())
+        .unwrap_or(0)
+}
+
+struct Walk<'a> {
+    graph: &'a Graph,
+    /// Stacks costing less than this are left out, which keeps deep and wide graphs in check.
+    threshold: f64,
+    path: Vec<usize>,
+    stacks: BTreeMap<String, f64>,
+}
+
+impl Walk<'_> {
+    /// Adds `amount` of the inclusive cost of `function`, called at the end of the current path.
+    fn visit(&mut self, function: usize, amount: f64) {
+        let inclusive = self.graph.inclusive(function);
+        if amount < self.threshold || inclusive == 0 {
+            return;
+        }
+        let share = amount / inclusive as f64;
+        self.path.push(function);
+        let mut own = self.graph.self_cost[function] as f64 * share;
+        for (&callee, &cost) in &self.graph.calls[function] {
+            if self.path.contains(&callee) {
+                // Recursive calls are kept in the function calling itself.
+                own += cost as f64 * share;
+            } else {
+                self.visit(callee, cost as f64 * share);
+            }
+        }
+        if own > 0.0 {
+            let stack: Vec<_> = self
+                .path
+                .iter()
+                .map(|&function| self.graph.names[function].as_str())
+                .collect();
+            *self.stacks.entry(stack.join(";")).or_insert(0.0) += own;
+        }
+        self.path.pop();
+    }
+}
+
+pub(super) fn fold(input: Box<dyn BufRead>) -> anyhow::Result<Vec<u8>> {
+    let mut graph = Graph::default();
+    let mut compressed = HashMap::new();
+    let mut positions = 1;
+    let mut current: Option<usize> = None;
+    let mut callee: Option<usize> = None;
+    // Whether the next cost line is the inclusive cost of a call to `callee`.
+    let mut in_call = false;
+    for line in input.lines() {
+        let line = line.context("unable to read the callgrind output")?;
+        let line = line.trim();
+        if line.starts_with(|c: char| c.is_ascii_digit() || "+-*".contains(c)) {
+            let cost = cost(line, positions);
+            if let Some(caller) = current {
+                match callee.filter(|_| in_call) {
+                    Some(callee) => *graph.calls[caller].entry(callee).or_insert(0) += cost,
+                    None => graph.self_cost[caller] += cost,
+                }
+            }
+            in_call = false;
+        } else if let Some((key, value)) = line.split_once('=') {
+            match key {
+                "fn" => {
+                    current = Some(graph.function(name(value, &mut compressed)));
+                    callee = None;
+                }
+                "cfn" => callee = Some(graph.function(name(value, &mut compressed))),
+                "calls" => in_call = true,
+                _ => (),
+            }
+        } else if let Some(columns) = line.strip_prefix("positions:") {
+            positions = columns.split_whitespace().count();
+        }
+    }
+
+    // Functions are called from outside of the graph by what they cost beyond their calls in it.
+    let mut called = vec![0; graph.names.len()];
+    for (caller, calls) in graph.calls.iter().enumerate() {
+        for (&callee, &cost) in calls {
+            if callee != caller {
+                called[callee] += cost;
+            }
+        }
+    }
+    let roots: Vec<_> = (0..graph.names.len())
+        .filter_map(|function| {
+            let outside = graph.inclusive(function).saturating_sub(called[function]);
+            (outside > 0).then_some((function, outside))
+        })
+        .collect();
+    let total: u64 = roots.iter().map(|&(_, cost)| cost).sum();
+
+    let mut walk = Walk {
+        graph: &graph,
+        threshold: (total as f64 / 1_000_000.0).max(0.5),
+        path: Vec::new(),
+        stacks: BTreeMap::new(),
+    };
+    for (function, cost) in roots {
+        walk.visit(function, cost as f64);
+    }
+    let stacks = walk
+        .stacks
+        .into_iter()
+        .map(|(stack, cost)| (stack, cost.round() as u64))
+        .collect();
+    Ok(write_folded(stacks))
+}
@@ -0,0 +1,262 @@
+//! Chrome Trace Event JSON, e.g. saved from the performance panel of Chrome's DevTools, from
+//! Electron or from `chrome://tracing`, and Perfetto traces, which are converted into JSON with
+//! `traceconv` first.
+//!
+//! Traces with sampled stacks, either as the `cpuProfile` of `ProfileChunk` events or as
+//! `samples` referring to `stackFrames`, are folded from those. Otherwise the durations of
+//! nested events, `X` events or pairs of `B` and `E` events, are folded per thread, weighted by
+//! the microseconds spent in each event outside of the events nested in it.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    io::{BufRead, Read},
+    path::Path,
+    process::Command,
+};
+
+use anyhow::{bail, Context};
+use serde_json::Value;
+
+use super::{frame_name, open, write_folded, TempFile};
+
+/// An event with a duration on a thread.
+struct Span {
+    start: f64,
+    end: f64,
+    name: String,
+}
+
+/// Sampled stacks of `ProfileChunk` events, weighted by the microseconds between samples.
+fn profile_chunks(events: &[Value], stacks: &mut BTreeMap<String, f64>) {
+    // Nodes of all chunks, by id: the function and the parent node.
+    let mut nodes: HashMap<u64, (String, Option<u64>)> = HashMap::new();
+    let mut samples = Vec::new();
+    for event in events
+        .iter()
+        .filter(|event| event["name"] == "ProfileChunk")
+    {
+        let data = &event["args"]["data"];
+        let profile = &data["cpuProfile"];
+        for node in profile["nodes"].as_array().into_iter().flatten() {
+            let Some(id) = node["id"].as_u64() else {
+                continue;
+            };
+            let name = match node["callFrame"]["functionName"].as_str() {
+                Some("") | None => "(anonymous)",
+                Some(name) => name,
+            };
+            nodes.insert(id, (frame_name(name), node["parent"].as_u64()));
+        }
+        let deltas = data["timeDeltas"].as_array();
+        for (i, sample) in profile["samples"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            let delta = deltas
+                .and_then(|deltas| deltas.get(i + 1))
+                .and_then(Value::as_f64)
+                .unwrap_or(0.0);
+            if let Some(id) = sample.as_u64() {
+                samples.push((id, delta));
+            }
+        }
+    }
+    for (mut id, weight) in samples {
+        let mut stack = Vec::new();
+        while let Some((name, parent)) = nodes.get(&id) {
+            // The root node and the idle and program nodes are not functions.
+            if !matches!(name.as_str(), "(root)" | "(idle)" | "(program)") {
+                stack.push(name.as_str());
+            }
+            match parent {
+                Some(parent) if stack.len() < nodes.len() => id = *parent,
+                _ => break,
+            }
+        }
+        stack.reverse();
+        if !stack.is_empty() && weight > 0.0 {
+            *stacks.entry(stack.join(";")).or_insert(0.0) += weight;
+        }
+    }
+}
+
+/// Sampled stacks of the `samples` of a trace referring to its `stackFrames`.
+fn stack_frames(trace: &Value, stacks: &mut BTreeMap<String, f64>) {
+    let frames = trace["stackFrames"].as_object();
+    let lookup = |id: &str| frames.and_then(|frames| frames.get(id));
+    for sample in trace["samples"].as_array().into_iter().flatten() {
+        let mut id = match &sample["sf"] {
+            Value::String(id) => id.clone(),
+            Value::Number(id) => id.to_string(),
+            _ => continue,
+        };
+        let mut stack = Vec::new();
+        while let Some(frame) = lookup(&id) {
+            stack.push(frame_name(frame["name"].as_str().unwrap_or("[unknown]")));
+            match &frame["parent"] {
+                Value::String(parent) => id = parent.clone(),
+                Value::Number(parent) => id = parent.to_string(),
+                _ => break,
+            }
+            if stack.len() > frames.map_or(0, |frames| frames.len()) {
+                break;
+            }
+        }
+        stack.reverse();
+        let weight = sample["weight"].as_f64().unwrap_or(1.0);
+        if !stack.is_empty() {
+            *stacks.entry(stack.join(";")).or_insert(0.0) += weight;
+        }
+    }
+}
+
+/// The self time of nested events per thread.
+fn durations(events: &[Value], stacks: &mut BTreeMap<String, f64>) {
+    let thread = |event: &Value| (event["pid"].to_string(), event["tid"].to_string());
+    let mut names = HashMap::new();
+    let mut spans: BTreeMap<(String, String), Vec<Span>> = BTreeMap::new();
+    let mut begun: HashMap<(String, String), Vec<(f64, String)>> = HashMap::new();
+    for event in events {
+        let name = event["name"].as_str().unwrap_or("[unknown]");
+        let ts = event["ts"].as_f64().unwrap_or(0.0);
+        match event["ph"].as_str() {
+            Some("X") => spans.entry(thread(event)).or_default().push(Span {
+                start: ts,
+                end: ts + event["dur"].as_f64().unwrap_or(0.0),
+                name: frame_name(name),
+            }),
+            Some("B") => begun
+                .entry(thread(event))
+                .or_default()
+                .push((ts, frame_name(name))),
+            Some("E") => {
+                let thread = thread(event);
+                if let Some((start, name)) = begun.get_mut(&thread).and_then(Vec::pop) {
+                    spans.entry(thread).or_default().push(Span {
+                        start,
+                        end: ts,
+                        name,
+                    });
+                }
+            }
+            Some("M") if name == "thread_name" => {
+                if let Some(thread_name) = event["args"]["name"].as_str() {
+                    names.insert(thread(event), frame_name(thread_name));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    for (thread, mut spans) in spans {
+        let root = names
+            .get(&thread)
+            .cloned()
+            .unwrap_or_else(|| format!("thread {}", thread.1));
+        // Outer events first, so the events nested in them follow.
+        spans.sort_by(|a, b| a.start.total_cmp(&b.start).then(b.end.total_cmp(&a.end)));
+        // The events containing the current one, with the time spent in their nested events.
+        let mut nesting: Vec<(&Span, f64)> = Vec::new();
+        let mut finish = |nesting: &mut Vec<(&Span, f64)>| {
+            let Some((span, nested)) = nesting.pop() else {
+                return;
+            };
+            let duration = span.end - span.start;
+            let mut stack = vec![root.as_str()];
+            stack.extend(nesting.iter().map(|(span, _)| span.name.as_str()));
+            stack.push(&span.name);
+            let own = duration - nested;
+            if own > 0.0 {
+                *stacks.entry(stack.join(";")).or_insert(0.0) += own;
+            }
+            if let Some((_, parent_nested)) = nesting.last_mut() {
+                *parent_nested += duration;
+            }
+        };
+        for span in &spans {
+            while nesting
+                .last()
+                .is_some_and(|(outer, _)| outer.end <= span.start)
+            {
+                finish(&mut nesting);
+            }
+            nesting.push((span, 0.0));
+        }
+        while !nesting.is_empty() {
+            finish(&mut nesting);
+        }
+    }
+}
+
+/// Parses the JSON of a trace, which may be an array of events without its closing bracket,
+/// as the format allows.
+fn parse(mut input: Box<dyn BufRead>) -> anyhow::Result<Value> {
+    let mut json = String::new();
+    input
+        .read_to_string(&mut json)
+        .context("unable to read the trace")?;
+    let trimmed = json.trim_end().trim_end_matches(',');
+    if trimmed.trim_start().starts_with('[') && !trimmed.ends_with(']') {
+        json = format!("{trimmed}]");
+    }
+    serde_json::from_str(&json).context("invalid JSON")
+}
+
+pub(super) fn fold(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut input = open(path)?;
+    let perfetto = !input
+        .fill_buf()
+        .context("unable to read the trace")?
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .is_some_and(|b| *b == b'{' || *b == b'[');
+    let trace = if perfetto {
+        let converted = TempFile::new("perfetto.json");
+        convert_perfetto(path, &converted.0)?;
+        parse(open(&converted.0)?)?
+    } else {
+        parse(input)?
+    };
+
+    let events = match &trace {
+        Value::Array(events) => events.as_slice(),
+        trace => trace["traceEvents"]
+            .as_array()
+            .map_or(&[][..], Vec::as_slice),
+    };
+    let mut stacks = BTreeMap::new();
+    profile_chunks(events, &mut stacks);
+    if stacks.is_empty() {
+        stack_frames(&trace, &mut stacks);
+    }
+    if stacks.is_empty() {
+        durations(events, &mut stacks);
+    }
+    let stacks = stacks
+        .into_iter()
+        .map(|(stack, weight)| (stack, weight.round() as u64))
+        .collect();
+    Ok(write_folded(stacks))
+}
+
+/// Converts the Perfetto trace at `path` into Trace Event JSON with `traceconv`, which can be
+/// overridden with `$TRACECONV`.
+fn convert_perfetto(path: &Path, output: &Path) -> anyhow::Result<()> {
+    let traceconv = env::var("TRACECONV").unwrap_or_else(|_| "traceconv".to_string());
+    let mut command = Command::new(&traceconv);
+    command.arg("json").arg(path).arg(output);
+    crate::print_command(&command);
+    let status = command
+        .status()
+        .with_context(|| format!("unable to run {traceconv} to convert the Perfetto trace"))?;
+    if !status.success() {
+        bail!(
+            "{traceconv} failed to convert {} ({status})",
+            path.display()
+        );
+    }
+    Ok(())
+}
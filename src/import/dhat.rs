@@ -0,0 +1,61 @@
+//! DHAT profiles, as written by the dhat crate (`dhat-heap.json`) or by valgrind's DHAT
+//! (`dhat.out.<pid>`).
+//!
+//! Every program point in `pps` has its totals and the indices of its frames in `ftbl`, from
+//! the allocation site outwards. A frame is described as `0x1234: function (file:line:column)`.
+
+use std::{collections::BTreeMap, io::BufRead};
+
+use anyhow::Context;
+use serde_json::Value;
+
+use super::{frame_name, write_folded, HeapWeight};
+
+/// The function of a frame in `ftbl`.
+fn function(frame: &str) -> &str {
+    let frame = match frame.split_once(": ") {
+        Some((address, rest)) if address.starts_with("0x") => rest,
+        _ => frame,
+    };
+    match frame.rsplit_once(" (") {
+        Some((function, location)) if location.ends_with(')') => function,
+        _ => frame,
+    }
+}
+
+pub(super) fn fold(input: Box<dyn BufRead>, weight: HeapWeight) -> anyhow::Result<Vec<u8>> {
+    let profile: Value = serde_json::from_reader(input).context("invalid JSON")?;
+    let frames: Vec<String> = profile["ftbl"]
+        .as_array()
+        .context("no frame table (ftbl)")?
+        .iter()
+        .map(|frame| frame_name(function(frame.as_str().unwrap_or("[unknown]"))))
+        .collect();
+    let points = profile["pps"]
+        .as_array()
+        .context("no program points (pps)")?;
+
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+    for point in points {
+        // Ad-hoc and copy profiles count blocks and bytes in total only.
+        let count = match weight {
+            HeapWeight::Allocations => point["tbk"].as_u64(),
+            HeapWeight::Bytes => point["gb"].as_u64().or_else(|| point["tb"].as_u64()),
+        };
+        let Some(count) = count.filter(|&count| count > 0) else {
+            continue;
+        };
+        let stack: Vec<&str> = point["fs"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .rev()
+            .filter_map(|index| frames.get(index.as_u64()? as usize))
+            .map(String::as_str)
+            .filter(|frame| *frame != "[root]")
+            .collect();
+        *stacks.entry(stack.join(";")).or_insert(0) += count;
+    }
+    Ok(write_folded(stacks))
+}
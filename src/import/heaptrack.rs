@@ -0,0 +1,171 @@
+//! heaptrack data, as written by `heaptrack` after interpreting a run, e.g.
+//! `heaptrack.my-binary.1234.zst`.
+//!
+//! Every line is a record: `s` interns a string, `i` describes an instruction pointer with
+//! its module and the functions at it, `t` adds a trace node of an instruction pointer and
+//! its parent, `a` the size and trace of an allocation, `+` an allocation and `-` the
+//! matching deallocation. All numbers are
+//! hexadecimal and strings, instruction pointers and traces are referred to by their 1-based
+//! index.
+
+use std::{collections::BTreeMap, io::BufRead};
+
+use anyhow::Context;
+
+use super::{frame_name, write_folded, HeapWeight};
+
+#[derive(Default)]
+struct Data {
+    /// The version of the file format; version 3 prefixes strings with their length.
+    version: u32,
+    strings: Vec<String>,
+    /// The functions at each instruction pointer, from the innermost inlined one.
+    ips: Vec<Vec<String>>,
+    /// The instruction pointer and parent trace of each trace node.
+    traces: Vec<(usize, usize)>,
+    /// The size and trace of each allocation info, referred to by `+` and `-` from version 2
+    /// on.
+    allocations: Vec<(u64, usize)>,
+}
+
+fn hex(field: &str) -> Option<usize> {
+    usize::from_str_radix(field, 16).ok()
+}
+
+impl Data {
+    fn string(&self, index: usize) -> Option<&str> {
+        index
+            .checked_sub(1)
+            .and_then(|index| self.strings.get(index))
+            .map(String::as_str)
+    }
+
+    /// The functions of `trace`, from the leaf to the root.
+    fn stack(&self, mut trace: usize) -> Vec<&str> {
+        let mut stack = Vec::new();
+        // Bounded by the number of traces in case the parents form a cycle.
+        for _ in 0..self.traces.len() {
+            let Some(index) = trace.checked_sub(1) else {
+                break;
+            };
+            let Some(&(ip, parent)) = self.traces.get(index) else {
+                break;
+            };
+            if let Some(functions) = ip.checked_sub(1).and_then(|i| self.ips.get(i)) {
+                stack.extend(functions.iter().map(String::as_str));
+            }
+            trace = parent;
+        }
+        stack
+    }
+}
+
+/// Folds the allocations in heaptrack data into their stacks.
+pub(super) fn fold(input: Box<dyn BufRead>, weight: HeapWeight) -> anyhow::Result<Vec<u8>> {
+    let mut data = Data::default();
+    // Allocations (positive) and deallocations (negative) of bytes, by trace.
+    let mut events: Vec<(usize, i64)> = Vec::new();
+    for line in input.lines() {
+        let line = line.context("unable to read the heaptrack data")?;
+        let Some((kind, rest)) = line.split_once(' ') else {
+            continue;
+        };
+        let fields: Vec<_> = rest.split(' ').collect();
+        match kind {
+            "v" => {
+                data.version = fields.get(1).and_then(|v| hex(v)).unwrap_or(1) as u32;
+            }
+            "s" => {
+                let string = match rest.split_once(' ') {
+                    Some((_, string)) if data.version >= 3 => string,
+                    _ => rest,
+                };
+                data.strings.push(string.to_string());
+            }
+            "i" => {
+                // `i IP MODULE [FUNCTION FILE LINE]...`, with the function of the instruction
+                // pointer first and the functions inlined into it after it.
+                let mut functions: Vec<_> = fields
+                    .get(2..)
+                    .unwrap_or_default()
+                    .chunks(3)
+                    .filter_map(|frame| data.string(hex(frame[0])?))
+                    .map(frame_name)
+                    .collect();
+                if functions.is_empty() {
+                    functions.push("[unknown]".to_string());
+                }
+                functions.rotate_left(1);
+                data.ips.push(functions);
+            }
+            "t" => {
+                if let [ip, parent] = fields[..] {
+                    data.traces
+                        .push((hex(ip).unwrap_or(0), hex(parent).unwrap_or(0)));
+                }
+            }
+            "a" => {
+                if let [size, trace] = fields[..] {
+                    let size = hex(size).unwrap_or(0) as u64;
+                    data.allocations.push((size, hex(trace).unwrap_or(0)));
+                }
+            }
+            "+" | "-" => {
+                let allocation = match fields[..] {
+                    [info] => hex(info).and_then(|info| data.allocations.get(info).copied()),
+                    // Version 1: `+ SIZE TRACE POINTER`, with deallocations only giving the
+                    // pointer, so they are not matched.
+                    [size, trace, _pointer] if kind == "+" => {
+                        Some((hex(size).unwrap_or(0) as u64, hex(trace).unwrap_or(0)))
+                    }
+                    _ => None,
+                };
+                if let Some((size, trace)) = allocation {
+                    let size = size as i64;
+                    events.push((trace, if kind == "+" { size } else { -size }));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut counts: BTreeMap<usize, u64> = BTreeMap::new();
+    match weight {
+        HeapWeight::Allocations => {
+            for &(trace, size) in &events {
+                if size >= 0 {
+                    *counts.entry(trace).or_insert(0) += 1;
+                }
+            }
+        }
+        HeapWeight::Bytes => {
+            // Replay the events up to the one after which the most bytes were live.
+            let (mut live, mut peak, mut end) = (0, 0, 0);
+            for (i, &(_, size)) in events.iter().enumerate() {
+                live += size;
+                if live > peak {
+                    (peak, end) = (live, i + 1);
+                }
+            }
+            let mut live: BTreeMap<usize, i64> = BTreeMap::new();
+            for &(trace, size) in &events[..end] {
+                *live.entry(trace).or_insert(0) += size;
+            }
+            counts.extend(
+                live.into_iter()
+                    .map(|(trace, bytes)| (trace, bytes.max(0) as u64)),
+            );
+        }
+    }
+
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+    for (trace, count) in counts {
+        let mut stack = data.stack(trace);
+        if stack.is_empty() {
+            continue;
+        }
+        stack.reverse();
+        *stacks.entry(stack.join(";")).or_insert(0) += count;
+    }
+    Ok(write_folded(stacks))
+}
@@ -0,0 +1,188 @@
+//! Converting the profiles of other tools into folded stacks, which are then rendered like a
+//! recording of flamegraph's own, for `flamegraph render --from`.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, Cursor, Read},
+    path::Path,
+    process::{self, Command},
+};
+
+use anyhow::{bail, Context};
+use inferno::collapse::{sample, vtune, Collapse};
+
+mod bytehound;
+mod callgrind;
+mod chrome;
+mod dhat;
+mod heaptrack;
+mod pprof;
+mod speedscope;
+
+/// The profile formats that can be rendered with `flamegraph render --from`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum InputFormat {
+    /// heaptrack data (heaptrack.*.zst)
+    Heaptrack,
+    /// A DHAT profile (dhat-heap.json), e.g. written by the dhat crate or valgrind
+    Dhat,
+    /// A bytehound recording (memory-profiling_*.dat), converted with `bytehound`
+    Bytehound,
+    /// callgrind or cachegrind output (callgrind.out.*), weighted by the first event, e.g.
+    /// instructions
+    Callgrind,
+    /// A pprof profile (*.pb.gz), e.g. written by Go or pprof-rs, weighted by its default
+    /// sample type
+    Pprof,
+    /// A legacy gperftools CPU profile, converted with `pprof`
+    Gperftools,
+    /// A VTune top-down report exported as CSV with the `CPU Time:Self` and `Module` columns,
+    /// weighted by CPU time
+    Vtune,
+    /// The text output of macOS's `sample` command
+    Sample,
+    /// A speedscope profile (*.speedscope.json), e.g. written by py-spy
+    Speedscope,
+    /// Chrome Trace Event JSON, e.g. of DevTools or Electron, or a Perfetto trace, converted
+    /// with `traceconv`
+    Chrome,
+}
+
+/// What the stacks of heap profiles are weighted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum HeapWeight {
+    /// The number of allocations
+    Allocations,
+    /// The bytes that were live when the heap was at its largest
+    Bytes,
+}
+
+impl InputFormat {
+    /// Name of the format, as given to `--from`.
+    pub fn name(self) -> &'static str {
+        match self {
+            InputFormat::Heaptrack => "heaptrack",
+            InputFormat::Dhat => "dhat",
+            InputFormat::Bytehound => "bytehound",
+            InputFormat::Callgrind => "callgrind",
+            InputFormat::Pprof => "pprof",
+            InputFormat::Gperftools => "gperftools",
+            InputFormat::Vtune => "vtune",
+            InputFormat::Sample => "sample",
+            InputFormat::Speedscope => "speedscope",
+            InputFormat::Chrome => "chrome",
+        }
+    }
+
+    /// What the samples of the converted stacks count, unless it depends on the profile.
+    pub(crate) fn count_name(self, weight: HeapWeight) -> Option<&'static str> {
+        match (self, weight) {
+            (
+                InputFormat::Pprof
+                | InputFormat::Gperftools
+                | InputFormat::Speedscope
+                | InputFormat::Chrome,
+                _,
+            ) => None,
+            (InputFormat::Callgrind, _) => Some("instructions"),
+            (InputFormat::Vtune, _) => Some("ms"),
+            (InputFormat::Sample, _) => Some("samples"),
+            (_, HeapWeight::Allocations) => Some("allocations"),
+            (_, HeapWeight::Bytes) => Some("bytes"),
+        }
+    }
+
+    /// Reads the profile at `path` and folds its stacks, weighted by `weight` for heap
+    /// profiles.
+    pub(crate) fn fold(self, path: &Path, weight: HeapWeight) -> anyhow::Result<Vec<u8>> {
+        let collapsed = match self {
+            InputFormat::Heaptrack => heaptrack::fold(open(path)?, weight),
+            InputFormat::Dhat => dhat::fold(open(path)?, weight),
+            InputFormat::Bytehound => bytehound::fold(path, weight),
+            InputFormat::Callgrind => callgrind::fold(open(path)?),
+            InputFormat::Pprof => pprof::fold(open(path)?),
+            InputFormat::Gperftools => pprof::fold_gperftools(path),
+            InputFormat::Vtune => collapse(vtune::Folder::default(), open(path)?),
+            InputFormat::Sample => collapse(sample::Folder::default(), open(path)?),
+            InputFormat::Speedscope => speedscope::fold(open(path)?),
+            InputFormat::Chrome => chrome::fold(path),
+        };
+        collapsed.with_context(|| format!("unable to read {} as {}", path.display(), self.name()))
+    }
+}
+
+/// Opens `path`, decompressing it with `zstd` or `gzip` if it is compressed, as the profiles
+/// of some tools are by default.
+fn open(path: &Path) -> anyhow::Result<Box<dyn BufRead>> {
+    let mut file =
+        File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+    let mut magic = [0; 4];
+    let read = file.read(&mut magic).unwrap_or(0);
+    let tool = match &magic[..read] {
+        [0x28, 0xb5, 0x2f, 0xfd] => "zstd",
+        [0x1f, 0x8b, ..] => "gzip",
+        _ => {
+            let file =
+                File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+            return Ok(Box::new(BufReader::new(file)));
+        }
+    };
+    let output = Command::new(tool)
+        .arg("-dc")
+        .arg(path)
+        .output()
+        .with_context(|| format!("unable to run {tool} to decompress {}", path.display()))?;
+    if !output.status.success() {
+        bail!(
+            "{tool} failed to decompress {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(Box::new(Cursor::new(output.stdout)))
+}
+
+/// A file in the temporary directory named after this process, removed when dropped.
+struct TempFile(std::path::PathBuf);
+
+impl TempFile {
+    fn new(name: &str) -> Self {
+        TempFile(std::env::temp_dir().join(format!("flamegraph-{}-{name}", process::id())))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Folds `input` with one of inferno's collapsers.
+fn collapse(mut folder: impl Collapse, input: Box<dyn BufRead>) -> anyhow::Result<Vec<u8>> {
+    let mut collapsed = Vec::new();
+    folder.collapse(input, &mut collapsed)?;
+    Ok(collapsed)
+}
+
+/// Writes `stacks` as folded stacks, leaving out the empty ones.
+fn write_folded(stacks: BTreeMap<String, u64>) -> Vec<u8> {
+    let mut collapsed = Vec::new();
+    for (stack, count) in stacks {
+        if !stack.is_empty() && count > 0 {
+            collapsed.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+        }
+    }
+    collapsed
+}
+
+/// A function name as it appears in a folded stack, demangled and without the frame separator.
+fn frame_name(function: &str) -> String {
+    let function = match rustc_demangle::try_demangle(function) {
+        Ok(demangled) => format!("{demangled:#}"),
+        Err(_) => function.to_string(),
+    };
+    function.replace(';', ":")
+}
@@ -0,0 +1,347 @@
+//! pprof profiles, e.g. written by Go, pprof-rs or `--export pprof`, and legacy gperftools
+//! CPU profiles, which are converted into pprof profiles with the pprof tool first.
+
+use std::{collections::BTreeMap, env, io::BufRead, path::Path, process::Command};
+
+use anyhow::{bail, Context};
+
+use super::{frame_name, open, write_folded, TempFile};
+
+/// Just enough of the protobuf wire format to read pprof profiles.
+mod protobuf {
+    pub(super) enum Value<'a> {
+        Int(u64),
+        Bytes(&'a [u8]),
+    }
+
+    fn varint(buf: &mut &[u8]) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = buf.split_first()?;
+            *buf = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn fixed(buf: &mut &[u8], len: usize) -> Option<u64> {
+        if buf.len() < len {
+            return None;
+        }
+        let (bytes, rest) = buf.split_at(len);
+        *buf = rest;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &b| value << 8 | u64::from(b)),
+        )
+    }
+
+    /// The fields of a message, as `(number, value)` pairs.
+    pub(super) struct Fields<'a>(pub &'a [u8]);
+
+    impl<'a> Iterator for Fields<'a> {
+        type Item = (u64, Value<'a>);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let key = varint(&mut self.0)?;
+            let value = match key & 7 {
+                0 => Value::Int(varint(&mut self.0)?),
+                1 => Value::Int(fixed(&mut self.0, 8)?),
+                2 => {
+                    let len = varint(&mut self.0)? as usize;
+                    if self.0.len() < len {
+                        return None;
+                    }
+                    let (bytes, rest) = self.0.split_at(len);
+                    self.0 = rest;
+                    Value::Bytes(bytes)
+                }
+                5 => Value::Int(fixed(&mut self.0, 4)?),
+                _ => return None,
+            };
+            Some((key >> 3, value))
+        }
+    }
+
+    /// The integers of a repeated field, which are usually packed.
+    pub(super) fn ints(value: Value<'_>, ints: &mut Vec<u64>) {
+        match value {
+            Value::Int(int) => ints.push(int),
+            Value::Bytes(mut packed) => {
+                while let Some(int) = varint(&mut packed) {
+                    ints.push(int);
+                }
+            }
+        }
+    }
+
+    pub(super) fn int(value: Value<'_>) -> u64 {
+        match value {
+            Value::Int(int) => int,
+            Value::Bytes(_) => 0,
+        }
+    }
+}
+
+use protobuf::{int, ints, Fields, Value};
+
+struct Sample {
+    locations: Vec<u64>,
+    values: Vec<u64>,
+}
+
+#[derive(Default)]
+struct Location {
+    address: u64,
+    /// The functions at the location, from the innermost inlined one.
+    functions: Vec<u64>,
+}
+
+pub(super) fn fold(mut input: Box<dyn BufRead>) -> anyhow::Result<Vec<u8>> {
+    let mut profile = Vec::new();
+    input
+        .read_to_end(&mut profile)
+        .context("unable to read the profile")?;
+
+    let mut sample_types = Vec::new();
+    let mut samples = Vec::new();
+    let mut locations = BTreeMap::new();
+    let mut functions = BTreeMap::new();
+    let mut strings = Vec::new();
+    let mut default_sample_type = 0;
+    for (field, value) in Fields(&profile) {
+        let message = match value {
+            Value::Bytes(message) => message,
+            Value::Int(value) => {
+                if field == 14 {
+                    default_sample_type = value;
+                }
+                continue;
+            }
+        };
+        match field {
+            1 => {
+                let kind = Fields(message).find(|(field, _)| *field == 1);
+                sample_types.push(kind.map_or(0, |(_, kind)| int(kind)));
+            }
+            2 => {
+                let mut sample = Sample {
+                    locations: Vec::new(),
+                    values: Vec::new(),
+                };
+                for (field, value) in Fields(message) {
+                    match field {
+                        1 => ints(value, &mut sample.locations),
+                        2 => ints(value, &mut sample.values),
+                        _ => (),
+                    }
+                }
+                samples.push(sample);
+            }
+            4 => {
+                let (mut id, mut location) = (0, Location::default());
+                for (field, value) in Fields(message) {
+                    match (field, value) {
+                        (1, value) => id = int(value),
+                        (3, value) => location.address = int(value),
+                        (4, Value::Bytes(line)) => {
+                            let function = Fields(line).find(|(field, _)| *field == 1);
+                            location
+                                .functions
+                                .push(function.map_or(0, |(_, id)| int(id)));
+                        }
+                        _ => (),
+                    }
+                }
+                locations.insert(id, location);
+            }
+            5 => {
+                let (mut id, mut name) = (0, 0);
+                for (field, value) in Fields(message) {
+                    match field {
+                        1 => id = int(value),
+                        2 => name = int(value),
+                        _ => (),
+                    }
+                }
+                functions.insert(id, name);
+            }
+            6 => strings.push(String::from_utf8_lossy(message).into_owned()),
+            _ => (),
+        }
+    }
+    if strings.is_empty() && samples.is_empty() {
+        bail!("not a pprof profile");
+    }
+
+    // Like pprof, show the default sample type, or else the last one.
+    let string = |index: u64| strings.get(index as usize).map_or("", String::as_str);
+    let value = sample_types
+        .iter()
+        .position(|&kind| default_sample_type != 0 && kind == default_sample_type)
+        .unwrap_or(sample_types.len().saturating_sub(1));
+    if let Some(&kind) = sample_types.get(value) {
+        log::info!("weighing the stacks by {}", string(kind));
+    }
+
+    let names: BTreeMap<u64, String> = functions
+        .into_iter()
+        .map(|(id, name)| (id, frame_name(string(name))))
+        .collect();
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+    for sample in samples {
+        // Values are signed; differences of profiles can be negative.
+        let count = sample.values.get(value).copied().unwrap_or(0) as i64;
+        if count <= 0 {
+            continue;
+        }
+        let mut stack = Vec::new();
+        for id in &sample.locations {
+            let Some(location) = locations.get(id) else {
+                continue;
+            };
+            let known: Vec<_> = location
+                .functions
+                .iter()
+                .filter_map(|function| names.get(function))
+                .cloned()
+                .collect();
+            if known.is_empty() {
+                stack.push(format!("{:#x}", location.address));
+            } else {
+                stack.extend(known);
+            }
+        }
+        // Locations start at the leaf.
+        stack.reverse();
+        *stacks.entry(stack.join(";")).or_insert(0) += count as u64;
+    }
+    Ok(write_folded(stacks))
+}
+
+/// Converts the legacy gperftools CPU profile at `path` into a pprof profile with the pprof
+/// tool, which symbolizes it with the binaries it was recorded from, and folds it. The pprof
+/// binary can be overridden with `$PPROF`.
+pub(super) fn fold_gperftools(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let pprof = env::var("PPROF").unwrap_or_else(|_| "pprof".to_string());
+    let converted = TempFile::new("gperftools.pb.gz");
+    let mut command = Command::new(&pprof);
+    command
+        .arg("-proto")
+        .arg("-output")
+        .arg(&converted.0)
+        .arg(path);
+    crate::print_command(&command);
+    let status = command
+        .status()
+        .with_context(|| format!("unable to run {pprof}; is pprof installed?"))?;
+    if !status.success() {
+        bail!("{pprof} failed to convert {} ({status})", path.display());
+    }
+    fold(open(&converted.0)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn varint(mut value: u64, buf: &mut Vec<u8>) {
+        while value >= 0x80 {
+            buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        buf.push(value as u8);
+    }
+
+    fn int(number: u64, value: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        varint(number << 3, &mut buf);
+        varint(value, &mut buf);
+        buf
+    }
+
+    fn bytes(number: u64, bytes: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        varint(number << 3 | 2, &mut buf);
+        varint(bytes.len() as u64, &mut buf);
+        buf.extend_from_slice(bytes);
+        buf
+    }
+
+    fn packed(number: u64, values: &[u64]) -> Vec<u8> {
+        let mut packed = Vec::new();
+        for &value in values {
+            varint(value, &mut packed);
+        }
+        bytes(number, &packed)
+    }
+
+    /// A profile with samples and cpu time, where `main` calls `work`, which is inlined into it,
+    /// and an address without symbols.
+    fn profile() -> Vec<u8> {
+        let mut profile = Vec::new();
+        for (kind, unit) in [(1, 2), (3, 4)] {
+            profile.extend(bytes(1, &[int(1, kind), int(2, unit)].concat()));
+        }
+        profile.extend(bytes(2, &[packed(1, &[1]), packed(2, &[1, 5000])].concat()));
+        profile.extend(bytes(
+            2,
+            &[packed(1, &[2, 1]), packed(2, &[2, 250])].concat(),
+        ));
+        // Unpacked values, and a negative value as in a difference of profiles.
+        profile.extend(bytes(
+            2,
+            &[int(1, 2), int(2, 1), int(2, -3i64 as u64)].concat(),
+        ));
+        profile.extend(bytes(
+            4,
+            &[
+                int(1, 1),
+                int(3, 0x1000),
+                bytes(4, &int(1, 2)),
+                bytes(4, &int(1, 1)),
+            ]
+            .concat(),
+        ));
+        profile.extend(bytes(4, &[int(1, 2), int(3, 0x2a)].concat()));
+        for (id, name) in [(1, 5), (2, 6)] {
+            profile.extend(bytes(5, &[int(1, id), int(2, name)].concat()));
+        }
+        for string in ["", "samples", "count", "cpu", "nanoseconds", "main", "work"] {
+            profile.extend(bytes(6, string.as_bytes()));
+        }
+        profile
+    }
+
+    #[test]
+    fn folds_the_last_sample_type() {
+        let folded = fold(Box::new(Cursor::new(profile()))).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "main;work 5000\nmain;work;0x2a 250\n"
+        );
+    }
+
+    #[test]
+    fn folds_the_default_sample_type() {
+        let mut profile = profile();
+        profile.extend(int(14, 1));
+        let folded = fold(Box::new(Cursor::new(profile))).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "0x2a 1\nmain;work 1\nmain;work;0x2a 2\n"
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(fold(Box::new(Cursor::new(b"\x00\x00"))).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! speedscope profiles, e.g. written by py-spy, rbspy, Hermes or `--export speedscope`.
+//!
+//! A file has a table of frames shared by its profiles. Sampled profiles list their stacks
+//! from the root with a weight each; evented profiles open and close frames at points in
+//! time, which are weighted by the time spent in each stack. When there are several profiles,
+//! e.g. one per thread, their stacks start with the name of their profile.
+
+use std::{collections::BTreeMap, io::BufRead};
+
+use anyhow::{bail, Context};
+use serde_json::Value;
+
+use super::{frame_name, write_folded};
+
+/// Factor turning values in `unit` into microseconds, keeping the fractions of milliseconds
+/// that are common in evented profiles. Other units are kept.
+fn to_micros(unit: &str) -> f64 {
+    match unit {
+        "nanoseconds" => 0.001,
+        "milliseconds" => 1000.0,
+        "seconds" => 1_000_000.0,
+        _ => 1.0,
+    }
+}
+
+pub(super) fn fold(input: Box<dyn BufRead>) -> anyhow::Result<Vec<u8>> {
+    let file: Value = serde_json::from_reader(input).context("invalid JSON")?;
+    let frames: Vec<String> = file["shared"]["frames"]
+        .as_array()
+        .context("no frames (shared.frames)")?
+        .iter()
+        .map(|frame| frame_name(frame["name"].as_str().unwrap_or("[unknown]")))
+        .collect();
+    let profiles = file["profiles"].as_array().context("no profiles")?;
+    let frame = |index: &Value| {
+        index
+            .as_u64()
+            .and_then(|index| frames.get(index as usize))
+            .map(String::as_str)
+    };
+
+    let mut stacks: BTreeMap<String, f64> = BTreeMap::new();
+    for profile in profiles {
+        let unit = profile["unit"].as_str().unwrap_or("none");
+        let factor = to_micros(unit);
+        if factor != 1.0 {
+            log::info!(
+                "weighing the stacks of {} by microseconds",
+                profile["name"].as_str().unwrap_or("the profile")
+            );
+        }
+        let mut add = |stack: &[&str], weight: f64| {
+            if stack.is_empty() || weight <= 0.0 {
+                return;
+            }
+            let mut folded = String::new();
+            if profiles.len() > 1 {
+                folded.push_str(&frame_name(profile["name"].as_str().unwrap_or("[unnamed]")));
+                folded.push(';');
+            }
+            folded.push_str(&stack.join(";"));
+            *stacks.entry(folded).or_insert(0.0) += weight * factor;
+        };
+        match profile["type"].as_str() {
+            Some("sampled") => {
+                let samples = profile["samples"].as_array().context("no samples")?;
+                let weights = profile["weights"].as_array();
+                for (i, sample) in samples.iter().enumerate() {
+                    let stack: Vec<_> = sample
+                        .as_array()
+                        .map(Vec::as_slice)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(frame)
+                        .collect();
+                    let weight = weights
+                        .and_then(|weights| weights.get(i))
+                        .and_then(Value::as_f64)
+                        .unwrap_or(1.0);
+                    add(&stack, weight);
+                }
+            }
+            Some("evented") => {
+                let events = profile["events"].as_array().context("no events")?;
+                let mut stack = Vec::new();
+                let mut last = profile["startValue"].as_f64().unwrap_or(0.0);
+                for event in events {
+                    let at = event["at"].as_f64().unwrap_or(last);
+                    add(&stack, at - last);
+                    last = at;
+                    match event["type"].as_str() {
+                        Some("O") => stack.push(frame(&event["frame"]).unwrap_or("[unknown]")),
+                        Some("C") => {
+                            stack.pop();
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Some(kind) => bail!("unknown type of profile: {kind}"),
+            None => bail!("profile without a type"),
+        }
+    }
+    let stacks = stacks
+        .into_iter()
+        .map(|(stack, weight)| (stack, weight.round() as u64))
+        .collect();
+    Ok(write_folded(stacks))
+}
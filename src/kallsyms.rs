@@ -0,0 +1,82 @@
+//! Detection of kernel symbols that `perf script` cannot resolve, which otherwise show up as
+//! towers of `[unknown]` frames.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use crate::{escalate::Root, paranoid, sysctl};
+
+const KPTR_RESTRICT: &str = "kernel.kptr_restrict";
+/// How `perf script` shows a kernel frame it could not symbolize.
+pub(crate) const UNRESOLVED: &[u8] = b"[unknown] ([kernel.kallsyms])";
+
+/// Whether `/proc/kallsyms` shows real addresses to the current user; hidden addresses read as
+/// zero.
+fn addresses_visible() -> bool {
+    File::open("/proc/kallsyms")
+        .ok()
+        .and_then(|kallsyms| BufReader::new(kallsyms).lines().next())
+        .and_then(Result::ok)
+        .and_then(|line| {
+            line.split_whitespace()
+                .next()
+                .map(|address| address.chars().any(|c| c != '0'))
+        })
+        .unwrap_or(false)
+}
+
+/// `kptr_restrict` 0 shows kernel addresses to everyone if `perf_event_paranoid` is at most 1,
+/// otherwise (like `kptr_restrict` 1) only to users with `CAP_SYSLOG`. 2 hides them from
+/// everyone.
+fn visible_to(as_root: bool) -> bool {
+    match sysctl::read(KPTR_RESTRICT) {
+        Some(level) if as_root || paranoid::is_root() => level < 2,
+        _ => addresses_visible(),
+    }
+}
+
+/// When `perf script` runs as root but `kptr_restrict` hides kernel addresses even from root,
+/// relaxes it to 1 (visible to root only) until the returned guard is dropped, for
+/// `--fix-kptr-restrict`.
+pub(crate) fn ensure_resolvable(root: Root) -> anyhow::Result<Option<sysctl::Override>> {
+    match sysctl::read(KPTR_RESTRICT) {
+        Some(level) if level >= 2 => sysctl::Override::new(KPTR_RESTRICT, level, 1, root).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Warns about `unresolved` kernel frames in the `perf script` output, along with the likely
+/// cause.
+pub(crate) fn warn_unresolved(unresolved: usize, as_root: bool) {
+    if unresolved == 0 {
+        return;
+    }
+
+    let summary = format!("{unresolved} kernel frames could not be resolved to symbols");
+    if visible_to(as_root) {
+        log::warn!("{summary}");
+        return;
+    }
+    let cause = match sysctl::read(KPTR_RESTRICT) {
+        Some(level) if level >= 2 => format!(
+            "  {KPTR_RESTRICT} is {level}, which hides kernel addresses from everyone. \
+             Lower it with `sudo sysctl {KPTR_RESTRICT}=1` and run with --root, or run with \
+             --root --fix-kptr-restrict to lower it while symbolizing."
+        ),
+        Some(0) => format!(
+            "  {} is {}, which hides kernel addresses from unprivileged users. Run with --root or \
+             --capabilities, or lower it with `sudo sysctl {}=1`.",
+            paranoid::PARANOID,
+            sysctl::read(paranoid::PARANOID).unwrap_or_default(),
+            paranoid::PARANOID,
+        ),
+        Some(level) => format!(
+            "  {KPTR_RESTRICT} is {level}, which hides kernel addresses from unprivileged users. \
+             Run with --root or --capabilities, or lower it with `sudo sysctl {KPTR_RESTRICT}=0`."
+        ),
+        None => "  /proc/kallsyms does not show kernel addresses. Run with --root.".to_string(),
+    };
+    log::warn!("{summary}\n{cause}");
+}
@@ -1,620 +1,1085 @@
+#[cfg(feature = "cli")]
+use std::str::FromStr;
 use std::{
     env,
-    fs::File,
-    io::{BufReader, BufWriter, Read, Write},
-    path::PathBuf,
-    process::{exit, Command, ExitStatus, Stdio},
-    str::FromStr,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+    sync::Arc,
+    time::Duration,
 };
 
-#[cfg(unix)]
-use std::os::unix::process::ExitStatusExt;
-
-#[cfg(target_os = "linux")]
-use inferno::collapse::perf::{Folder, Options as CollapseOptions};
-
-#[cfg(not(target_os = "linux"))]
-use inferno::collapse::dtrace::{Folder, Options as CollapseOptions};
-
-#[cfg(unix)]
-use signal_hook::consts::{SIGINT, SIGTERM};
-
-use anyhow::{anyhow, Context};
+use anyhow::anyhow;
+#[cfg(feature = "cli")]
 use clap::{
     builder::{PossibleValuesParser, TypedValueParser},
     Args,
 };
-use inferno::{collapse::Collapse, flamegraph::color::Palette, flamegraph::from_reader};
+use inferno::flamegraph::color::Palette;
+
+pub mod backend;
+mod cache;
+#[cfg(target_os = "linux")]
+pub mod capabilities;
+#[cfg(feature = "cli")]
+pub mod config;
+mod crates;
+pub mod diff;
+#[cfg(target_os = "macos")]
+mod entitlements;
+mod error;
+mod escalate;
+pub mod export;
+mod fold_std;
+mod folded;
+#[cfg(target_os = "linux")]
+mod frame_pointers;
+pub mod gallery;
+mod idle;
+mod import;
+#[cfg(target_os = "linux")]
+mod kallsyms;
+mod libtest;
+pub mod logger;
+#[cfg(feature = "cli")]
+pub mod matrix;
+mod metadata;
+#[cfg(feature = "notify")]
+mod notify;
+mod observer;
+#[cfg(feature = "cli")]
+mod open;
+mod optimize;
+#[cfg(target_os = "linux")]
+mod paranoid;
+#[cfg(target_os = "linux")]
+mod power;
+mod preset;
+#[cfg(all(unix, feature = "profiler"))]
+pub mod profiler;
+mod regression;
+mod run;
+mod sandwich;
+mod sched;
+mod source_links;
+mod stop;
+mod stripped;
+#[cfg(target_os = "linux")]
+mod sysctl;
+#[cfg(target_os = "linux")]
+pub mod systemd;
+#[cfg(windows)]
+pub mod uac;
+mod usdt;
+mod variance;
+
+pub use backend::{Backend, BackendKind, RecordSettings, Recording, Symbolizer};
+pub use error::FlamegraphError;
+pub use escalate::Escalate;
+pub use export::{ExportFormat, Exporter};
+pub use import::{HeapWeight, InputFormat};
+pub use observer::{JsonEvents, MessageFormat, Phase, ProfilerObserver, Progress};
+pub use preset::Preset;
+#[cfg(all(unix, feature = "profiler"))]
+pub use profiler::{profile, ProfilerGuard, ProfilerOptions};
+pub use stop::StopHandle;
+pub use usdt::UsdtProbe;
+
+use escalate::Root;
 
 pub enum Workload {
     Command(Vec<String>),
     Pid(Vec<u32>),
+    /// Single threads of running processes, as `(pid, tid)` pairs. Only supported by perf.
+    Thread(Vec<(u32, u32)>),
+    /// All processes of a user, by name or uid. Only supported by perf.
+    User(String),
     ReadPerf(PathBuf),
+    /// Stacks that were already folded, e.g. written with `--export folded`, which are only
+    /// rendered.
+    Folded(PathBuf),
+    /// The profile of another tool, which is converted into folded stacks and rendered.
+    Import(InputFormat, PathBuf),
 }
 
-#[cfg(target_os = "linux")]
-mod arch {
-    use std::fmt::Write;
-    use std::time::Duration;
-
-    use indicatif::{ProgressBar, ProgressStyle};
-
-    use super::*;
-
-    pub const SPAWN_ERROR: &str = "could not spawn perf";
-    pub const WAIT_ERROR: &str = "unable to wait for perf child command to exit";
-
-    pub(crate) fn initial_command(
-        workload: Workload,
-        sudo: Option<Option<&str>>,
-        freq: u32,
-        custom_cmd: Option<String>,
-        verbose: bool,
-        ignore_status: bool,
-    ) -> Option<PathBuf> {
-        let perf = if let Ok(path) = env::var("PERF") {
-            path
-        } else {
-            if Command::new("perf")
-                .arg("--help")
-                .stderr(Stdio::null())
-                .stdout(Stdio::null())
-                .status()
-                .is_err()
-            {
-                eprintln!("perf is not installed or not present in $PATH");
-                exit(1);
-            }
-
-            String::from("perf")
-        };
-        let mut command = sudo_command(&perf, sudo);
-
-        let args = custom_cmd.unwrap_or(format!("record -F {freq} --call-graph dwarf,16384 -g"));
-
-        let mut perf_output = None;
-        let mut args = args.split_whitespace();
-        while let Some(arg) = args.next() {
-            command.arg(arg);
-
-            // Detect if user is setting `perf record`
-            // output file with `-o`. If so, save it in
-            // order to correctly compute perf's output in
-            // `Self::output`.
-            if arg == "-o" {
-                let next_arg = args.next().expect("missing '-o' argument");
-                command.arg(next_arg);
-                perf_output = Some(PathBuf::from(next_arg));
-            }
-        }
-
-        let perf_output = match perf_output {
-            Some(path) => path,
-            None => {
-                command.arg("-o");
-                command.arg("perf.data");
-                PathBuf::from("perf.data")
-            }
-        };
-
-        match workload {
-            Workload::Command(c) => {
-                command.args(&c);
-            }
-            Workload::Pid(p) => {
-                if let Some((first, pids)) = p.split_first() {
-                    let mut arg = first.to_string();
-
-                    for pid in pids {
-                        write!(arg, ",{pid}").unwrap();
-                    }
-
-                    command.arg("-p");
-                    command.arg(arg);
-                }
-            }
-            Workload::ReadPerf(_) => (),
-        }
+/// Gives files created with root privileges back to the invoking user, so they do not get in
+/// the way of later runs without root privileges. This applies to files written by a recorder
+/// running as `root` and, if flamegraph itself was started with sudo, to all files.
+#[cfg(unix)]
+pub(crate) fn chown_to_user(paths: &[&Path], root: Option<Root>) {
+    let sudo_user = env::var("SUDO_UID")
+        .and_then(|uid| Ok(format!("{}:{}", uid, env::var("SUDO_GID")?)))
+        .or_else(|_| env::var("SUDO_USER"));
+    let (user, mut command) = match (sudo_user, root) {
+        (Ok(user), _) => (user, Command::new("chown")),
+        (Err(_), Some(root)) => match env::var("USER") {
+            Ok(user) => (user, root.command("chown")),
+            Err(_) => return,
+        },
+        (Err(_), None) => return,
+    };
 
-        run(command, verbose, ignore_status);
-        Some(perf_output)
+    let paths: Vec<_> = paths.iter().filter(|path| path.exists()).collect();
+    if paths.is_empty() {
+        return;
     }
+    command.arg(&user).args(&paths);
+    if !matches!(command.status(), Ok(status) if status.success()) {
+        log::warn!("unable to change the owner of {:?} to {}", paths, user);
+    }
+}
 
-    pub fn output(
-        perf_output: Option<PathBuf>,
-        script_no_inline: bool,
-        sudo: Option<Option<&str>>,
-    ) -> anyhow::Result<Vec<u8>> {
-        // We executed `perf record` with sudo, and will be executing `perf script` with sudo,
-        // so that we can resolve privileged kernel symbols from /proc/kallsyms.
-        let perf = env::var("PERF").unwrap_or_else(|_| "perf".to_string());
-        let mut command = sudo_command(&perf, sudo);
-
-        command.arg("script");
-
-        // Force reading perf.data owned by another uid if it happened to be created earlier.
-        command.arg("--force");
+#[cfg(not(unix))]
+pub(crate) fn chown_to_user(_: &[&Path], _: Option<Root>) {}
+
+/// Path of an additional output file named after the flamegraph, e.g.
+/// `flamegraph-callers.svg` for `flamegraph.svg`.
+pub(crate) fn sibling_path(output: &Path, suffix: &str) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "flamegraph".to_string());
+    output.with_file_name(format!("{stem}-{suffix}"))
+}
 
-        if script_no_inline {
-            command.arg("--no-inline");
-        }
+pub(crate) fn print_command(cmd: &Command) {
+    log::debug!("command {:?}", cmd);
+}
 
-        if let Some(perf_output) = perf_output {
-            command.arg("-i");
-            command.arg(perf_output);
-        }
+/// What a run of [`generate_flamegraph_for_workload`] produced.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Profile {
+    /// The collapsed stacks the flamegraph was rendered from, one `frame;frame;... count` line
+    /// per stack.
+    pub collapsed: Vec<u8>,
+    /// Total number of samples in `collapsed`.
+    pub total_samples: u64,
+    /// All written files, starting with the flamegraph.
+    pub outputs: Vec<PathBuf>,
+    /// Exit status of the recorder, which perf takes over from the profiled command. `None` when
+    /// reading an existing perf data file.
+    pub exit_status: Option<ExitStatus>,
+}
 
-        // perf script can take a long time to run. Notify the user that it is running
-        // by using a spinner. Note that if this function exits before calling
-        // spinner.finish(), then the spinner will be completely removed from the terminal.
-        let spinner = ProgressBar::new_spinner().with_prefix("Running perf script");
-        spinner.set_style(
-            ProgressStyle::with_template("{prefix} [{elapsed}]: {spinner:.green}").unwrap(),
-        );
-        spinner.enable_steady_tick(Duration::from_millis(500));
-
-        let result = command.output().context("unable to call perf script");
-        spinner.finish();
-        let output = result?;
-        if !output.status.success() {
-            anyhow::bail!(format!(
-                "unable to run 'perf script': ({}) {}",
-                output.status,
-                std::str::from_utf8(&output.stderr)?
-            ));
-        }
-        Ok(output.stdout)
+pub fn generate_flamegraph_for_workload(
+    workload: Workload,
+    opts: Options,
+) -> Result<Profile, FlamegraphError> {
+    #[cfg(feature = "notify")]
+    if opts.notify && !opts.dry_run {
+        let result = generate_flamegraph_with_default_observer(workload, opts);
+        notify::finished(&result);
+        return result;
     }
+    generate_flamegraph_with_default_observer(workload, opts)
 }
 
-#[cfg(not(target_os = "linux"))]
-mod arch {
-    use super::*;
-
-    pub const SPAWN_ERROR: &str = "could not spawn dtrace";
-    pub const WAIT_ERROR: &str = "unable to wait for dtrace child command to exit";
-    #[cfg(target_os = "windows")]
-    pub const BLONDIE_ERROR: &str = "could not find dtrace and could not profile using blondie";
-
-    #[cfg(target_os = "macos")]
-    fn base_dtrace_command(sudo: Option<Option<&str>>) -> Command {
-        // If DTrace is spawned from a parent process (or grandparent process etc.) running in Rosetta-emulated x86 mode
-        // on an ARM mac, it will fail to trace the child process with a confusing syntax error in its stdlib .d file.
-        // If the flamegraph binary, or the cargo binary, have been compiled as x86, this can cause all tracing to fail.
-        // To work around that, we unconditionally wrap dtrace on MacOS in the "arch -64/-32" wrapper so it's always
-        // running in the native architecture matching the bit width (32 oe 64) with which "flamegraph" was compiled.
-        // NOTE that dtrace-as-x86 won't trace a deliberately-cross-compiled x86 binary running under Rosetta regardless
-        // of "arch" wrapping; attempts to do that will fail with "DTrace cannot instrument translated processes".
-        // NOTE that using the ARCHPREFERENCE environment variable documented here
-        // (https://www.unix.com/man-page/osx/1/arch/) would be a much simpler solution to this issue, but it does not
-        // seem to have any effect on dtrace when set (via Command::env, shell export, or std::env in the spawning
-        // process).
-        let mut command = sudo_command("arch", sudo);
-
-        #[cfg(target_pointer_width = "64")]
-        command.arg("-64".to_string());
-        #[cfg(target_pointer_width = "32")]
-        command.arg("-32".to_string());
-
-        command.arg(env::var("DTRACE").unwrap_or_else(|_| "dtrace".to_string()));
-        command
+/// Generates the flamegraph, reporting progress as chosen with `--message-format` and `--quiet`.
+fn generate_flamegraph_with_default_observer(
+    workload: Workload,
+    opts: Options,
+) -> Result<Profile, FlamegraphError> {
+    if opts.message_format == MessageFormat::Json {
+        return generate_flamegraph_with_observer(workload, opts, &JsonEvents);
     }
-
-    #[cfg(not(target_os = "macos"))]
-    fn base_dtrace_command(sudo: Option<Option<&str>>) -> Command {
-        let dtrace = env::var("DTRACE").unwrap_or_else(|_| "dtrace".to_string());
-        sudo_command(&dtrace, sudo)
+    #[cfg(feature = "cli")]
+    if !opts.quiet && !opts.dry_run {
+        return generate_flamegraph_with_observer(workload, opts, &observer::Spinner::default());
     }
+    generate_flamegraph_with_observer(workload, opts, &observer::Silent)
+}
 
-    pub(crate) fn initial_command(
-        workload: Workload,
-        sudo: Option<Option<&str>>,
-        freq: u32,
-        custom_cmd: Option<String>,
-        verbose: bool,
-        ignore_status: bool,
-    ) -> Option<PathBuf> {
-        let mut command = base_dtrace_command(sudo);
-
-        let dtrace_script = custom_cmd.unwrap_or(format!(
-            "profile-{freq} /pid == $target/ \
-             {{ @[ustack(100)] = count(); }}",
-        ));
-
-        command.arg("-x");
-        command.arg("ustackframes=100");
-
-        command.arg("-n");
-        command.arg(&dtrace_script);
-
-        command.arg("-o");
-        command.arg("cargo-flamegraph.stacks");
-
-        match workload {
-            Workload::Command(c) => {
-                let mut escaped = String::new();
-                for (i, arg) in c.iter().enumerate() {
-                    if i > 0 {
-                        escaped.push(' ');
-                    }
-                    escaped.push_str(&arg.replace(' ', "\\ "));
-                }
-
-                command.arg("-c");
-                command.arg(&escaped);
-
-                #[cfg(target_os = "windows")]
-                {
-                    let mut help_test = crate::arch::base_dtrace_command(None);
-
-                    let dtrace_found = help_test
-                        .arg("--help")
-                        .stderr(Stdio::null())
-                        .stdout(Stdio::null())
-                        .status()
-                        .is_ok();
-                    if !dtrace_found {
-                        let mut command_builder = Command::new(&c[0]);
-                        command_builder.args(&c[1..]);
-                        print_command(&command_builder, verbose);
-
-                        let trace = match blondie::trace_command(command_builder, false) {
-                            Err(err) => {
-                                eprintln!("{}: {:?}", BLONDIE_ERROR, err);
-                                exit(1);
-                            }
-                            Ok(trace) => trace,
-                        };
-
-                        let f = std::fs::File::create("./cargo-flamegraph.stacks").unwrap();
-                        let mut f = std::io::BufWriter::new(f);
-                        trace.write_dtrace(&mut f).unwrap();
-
-                        return None;
-                    }
-                }
-            }
-            Workload::Pid(p) => {
-                for p in p {
-                    command.arg("-p");
-                    command.arg(p.to_string());
-                }
-            }
-            Workload::ReadPerf(_) => (),
+/// Like [`generate_flamegraph_for_workload`], but reports progress to `observer` instead of
+/// showing a spinner on the terminal.
+pub fn generate_flamegraph_with_observer(
+    workload: Workload,
+    opts: Options,
+    observer: &dyn ProfilerObserver,
+) -> Result<Profile, FlamegraphError> {
+    let run = run::Run::new(&workload, opts)?;
+    let settings = run.settings()?;
+
+    if let Workload::Command(command) = &workload {
+        if run.repeats() {
+            return run.finish_runs(command, &settings, observer);
         }
-
-        run(command, verbose, ignore_status);
-        None
     }
 
-    pub fn output(
-        _: Option<PathBuf>,
-        script_no_inline: bool,
-        sudo: Option<Option<&str>>,
-    ) -> anyhow::Result<Vec<u8>> {
-        if script_no_inline {
-            return Err(anyhow::anyhow!("--no-inline is only supported on Linux"));
-        }
-
-        // Ensure the file is readable by the current user if dtrace was run
-        // with sudo.
-        if sudo.is_some() {
-            #[cfg(unix)]
-            if let Ok(user) = env::var("USER") {
-                Command::new("sudo")
-                    .args(["chown", user.as_str(), "cargo-flamegraph.stacks"])
-                    .spawn()
-                    .expect(arch::SPAWN_ERROR)
-                    .wait()
-                    .expect(arch::WAIT_ERROR);
-            }
-        }
-
-        let mut buf = vec![];
-        let mut f = File::open("cargo-flamegraph.stacks")
-            .context("failed to open dtrace output file 'cargo-flamegraph.stacks'")?;
-
-        f.read_to_end(&mut buf)
-            .context("failed to read dtrace expected output file 'cargo-flamegraph.stacks'")?;
-
-        std::fs::remove_file("cargo-flamegraph.stacks")
-            .context("unable to remove temporary file 'cargo-flamegraph.stacks'")?;
-
-        // Workaround #32 - fails parsing invalid utf8 dtrace output
-        //
-        // Intermittently, invalid utf-8 is found in cargo-flamegraph.stacks, which
-        // causes parsing to blow up with the error:
-        //
-        // > unable to collapse generated profile data: Custom { kind: InvalidData, error: StringError("stream did not contain valid UTF-8") }
-        //
-        // So here we just lossily re-encode to hopefully work around the underlying problem
-        let string = String::from_utf8_lossy(&buf);
-        let reencoded_buf = string.as_bytes().to_owned();
-
-        if reencoded_buf != buf {
-            println!("Lossily converted invalid utf-8 found in cargo-flamegraph.stacks");
+    let recording = if let Workload::ReadPerf(data)
+    | Workload::Folded(data)
+    | Workload::Import(_, data) = workload
+    {
+        Recording {
+            data: Some(data),
+            exit_status: None,
         }
-
-        Ok(reencoded_buf)
-    }
-}
-
-fn sudo_command(command: &str, sudo: Option<Option<&str>>) -> Command {
-    let sudo = match sudo {
-        Some(sudo) => sudo,
-        None => return Command::new(command),
+    } else {
+        let recorder = run.start_recording(&settings, observer)?;
+        let recording = run.backend().record(workload, &settings);
+        run.finish_recording(recorder, recording, observer)?
     };
 
-    let mut c = Command::new("sudo");
-    if let Some(sudo_args) = sudo {
-        c.arg(sudo_args);
-    }
-    c.arg(command);
-    c
+    run.finish(recording, &settings, observer)
 }
 
-fn run(mut command: Command, verbose: bool, ignore_status: bool) {
-    print_command(&command, verbose);
-    let mut recorder = command.spawn().expect(arch::SPAWN_ERROR);
-    let exit_status = recorder.wait().expect(arch::WAIT_ERROR);
-
-    // only stop if perf exited unsuccessfully, but
-    // was not killed by a signal (assuming that the
-    // latter case usually means the user interrupted
-    // it in some way)
-    if !ignore_status && terminated_by_error(exit_status) {
-        eprintln!("failed to sample program");
-        exit(1);
+/// Like [`generate_flamegraph_for_workload`], but awaits the recorder with tokio instead of
+/// blocking the calling thread while the workload runs, so the runtime needs the process and
+/// time drivers enabled. Preparing the run, symbolizing and rendering happen in blocking tasks.
+/// No progress is shown, and [`Options::runs`] above 1 is rejected.
+#[cfg(feature = "async")]
+pub async fn generate_flamegraph_async(
+    workload: Workload,
+    opts: Options,
+) -> Result<Profile, FlamegraphError> {
+    if opts.runs > 1 {
+        return Err(anyhow!("--runs is not supported by asynchronous runs").into());
     }
-}
+    let (run, workload) = blocking(move || Ok((run::Run::new(&workload, opts)?, workload))).await?;
 
-#[cfg(unix)]
-fn terminated_by_error(status: ExitStatus) -> bool {
-    status
-        .signal() // the default needs to be true because that's the neutral element for `&&`
-        .map_or(true, |code| code != SIGINT && code != SIGTERM)
-        && !status.success()
-}
+    let (run, recording) = if let Workload::ReadPerf(data)
+    | Workload::Folded(data)
+    | Workload::Import(_, data) = workload
+    {
+        let recording = Recording {
+            data: Some(data),
+            exit_status: None,
+        };
+        (run, recording)
+    } else {
+        // The backend leaves the recorder command in `deferred` to be awaited here.
+        let (run, recorder, recording, deferred) = blocking(move || {
+            let deferred = std::sync::Mutex::new(None);
+            let mut settings = run.settings()?;
+            settings.deferred = Some(&deferred);
+            let recorder = run.start_recording(&settings, &observer::Silent)?;
+            let recording = run.backend().record(workload, &settings);
+            drop(settings);
+            Ok((run, recorder, recording, deferred))
+        })
+        .await?;
+
+        let recording = match recording {
+            Ok(recording) => {
+                let mut settings = run.settings()?;
+                settings.deferred = Some(&deferred);
+                settings.wait_deferred(recording).await
+            }
+            Err(err) => Err(err),
+        };
+        blocking(move || {
+            let recording = run.finish_recording(recorder, recording, &observer::Silent)?;
+            Ok((run, recording))
+        })
+        .await?
+    };
 
-#[cfg(not(unix))]
-fn terminated_by_error(status: ExitStatus) -> bool {
-    !status.success()
+    blocking(move || {
+        let settings = run.settings()?;
+        run.finish(recording, &settings, &observer::Silent)
+    })
+    .await
 }
 
-fn print_command(cmd: &Command, verbose: bool) {
-    if verbose {
-        println!("command {:?}", cmd);
-    }
+/// Runs `f` on tokio's thread pool for blocking work.
+#[cfg(feature = "async")]
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, FlamegraphError> + Send + 'static,
+) -> Result<T, FlamegraphError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| FlamegraphError::Other(err.into()))?
 }
 
-pub fn generate_flamegraph_for_workload(workload: Workload, opts: Options) -> anyhow::Result<()> {
-    // Handle SIGINT with an empty handler. This has the
-    // implicit effect of allowing the signal to reach the
-    // process under observation while we continue to
-    // generate our flamegraph.  (ctrl+c will send the
-    // SIGINT signal to all processes in the foreground
-    // process group).
-    #[cfg(unix)]
-    let handler = unsafe {
-        signal_hook::low_level::register(SIGINT, || {}).expect("cannot register signal handler")
-    };
-
-    let sudo = opts.root.as_ref().map(|inner| inner.as_deref());
-
-    let perf_output = if let Workload::ReadPerf(perf_file) = workload {
-        Some(perf_file)
-    } else {
-        arch::initial_command(
-            workload,
-            sudo,
-            opts.frequency(),
-            opts.custom_cmd,
-            opts.verbose,
-            opts.ignore_status,
-        )
-    };
+#[derive(Debug)]
+#[cfg_attr(feature = "cli", derive(Args))]
+pub struct Options {
+    /// Print extra output to help debug problems; repeat (-vv) to also show the full output of
+    /// cargo and the recorder
+    #[cfg_attr(feature = "cli", clap(short, long, action = clap::ArgAction::Count))]
+    pub verbose: u8,
+
+    /// Only print errors and the paths of the written files, without progress or recorder
+    /// messages
+    #[cfg_attr(feature = "cli", clap(short, long, conflicts_with = "verbose"))]
+    pub quiet: bool,
+
+    /// Print progress, log messages and the written files as JSON objects, one per line on
+    /// stdout
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, value_enum, value_name = "FMT", default_value = "human")
+    )]
+    pub message_format: MessageFormat,
 
-    #[cfg(unix)]
-    signal_hook::low_level::unregister(handler);
+    /// Output file, may contain the same placeholders as --title
+    #[cfg_attr(feature = "cli", clap(short, long, default_value = "flamegraph.svg"))]
+    pub output: PathBuf,
 
-    let output = arch::output(perf_output, opts.script_no_inline, sudo)?;
+    /// Open the output .svg file with default program
+    #[cfg(feature = "cli")]
+    #[clap(long)]
+    pub open: bool,
 
-    let perf_reader = BufReader::new(&*output);
+    /// Open the output with PROGRAM instead, e.g. `firefox` or `speedscope`. The file is passed
+    /// as the last argument, unless an argument contains `{path}` or `{url}`
+    #[cfg(feature = "cli")]
+    #[clap(long, value_name = "PROGRAM")]
+    pub open_with: Option<String>,
 
-    let mut collapsed = vec![];
+    /// Show a desktop notification when the flamegraph is written or profiling fails
+    #[cfg(feature = "notify")]
+    #[clap(long)]
+    pub notify: bool,
 
-    let collapsed_writer = BufWriter::new(&mut collapsed);
+    /// Run with root privileges (using `sudo`, unless another tool is chosen with --escalate or --escalation-cmd). Accepts an optional argument containing command line options which will be passed to sudo. On Windows, relaunches flamegraph as administrator through a UAC prompt instead
+    #[cfg_attr(feature = "cli", clap(long, value_name = "SUDO FLAGS"))]
+    pub root: Option<Option<String>>,
 
-    #[allow(unused_mut)]
-    let mut collapse_options = CollapseOptions::default();
+    /// Tool used to gain root privileges (implies --root unless --capabilities is given)
+    #[cfg_attr(feature = "cli", clap(long, value_enum, value_name = "TOOL"))]
+    pub escalate: Option<Escalate>,
 
-    #[cfg(target_os = "linux")]
-    {
-        collapse_options.skip_after = opts.flamegraph_options.skip_after.clone();
-    }
+    /// Command used to gain root privileges instead of a known tool, e.g. `corp-sudo --reason
+    /// profiling -- {}`, where `{}` stands for the command run as root and its arguments; they
+    /// are appended if it is left out (implies --root unless --capabilities is given)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, value_name = "TEMPLATE", conflicts_with = "escalate")
+    )]
+    pub escalation_cmd: Option<String>,
 
-    Folder::from(collapse_options)
-        .collapse(perf_reader, collapsed_writer)
-        .context("unable to collapse generated profile data")?;
+    /// Sampling frequency in Hz [default: 997]
+    #[cfg_attr(feature = "cli", clap(short = 'F', long = "freq"))]
+    pub frequency: Option<u32>,
 
-    if let Some(command) = opts.post_process {
-        let command_vec = shlex::split(&command)
-            .ok_or_else(|| anyhow!("unable to parse post-process command"))?;
+    /// Report the time spent in each frame in milliseconds, derived from the sampling frequency,
+    /// instead of the sample count
+    #[cfg_attr(feature = "cli", clap(long, conflicts_with = "preset"))]
+    pub time_units: bool,
 
-        let mut child = Command::new(
-            command_vec
-                .first()
-                .ok_or_else(|| anyhow!("unable to parse post-process command"))?,
+    /// Custom command for invoking perf/dtrace
+    #[cfg_attr(feature = "cli", clap(short, long = "cmd"))]
+    pub custom_cmd: Option<String>,
+
+    /// Extra arguments appended to the `perf record` command, e.g. `--perf-args "-e cycles:u
+    /// --no-buildid"`, keeping the default frequency and call graph unless overridden
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, value_name = "ARGS", allow_hyphen_values = true)
+    )]
+    pub perf_args: Option<String>,
+
+    /// Size of perf's per-CPU sample buffer, in pages or with a B/K/M/G suffix (`perf record
+    /// -m`). Raise it if perf reports lost events
+    #[cfg_attr(feature = "cli", clap(long, value_name = "PAGES|SIZE"))]
+    pub buffer_size: Option<String>,
+
+    /// Milliseconds perf may spend reading the memory maps of already running processes; their
+    /// symbols are lost when it times out [default: 5000]
+    #[cfg_attr(feature = "cli", clap(long, value_name = "MS"))]
+    pub proc_map_timeout: Option<u32>,
+
+    /// Add the profiled binary and the shared libraries it loads to perf's build id cache
+    /// before recording, so the recording still symbolizes after they are rebuilt, and on other
+    /// machines along with the cache packed by `perf archive` (perf only)
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub cache_build_ids: bool,
+
+    /// Stop recording after <SECONDS>, e.g. when attaching to a running process, and render the
+    /// samples recorded so far
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, value_name = "SECONDS", value_parser = parse_duration)
+    )]
+    pub duration: Option<Duration>,
+
+    /// Stop recording once perf wrote <N> samples and drop the samples taken beyond them, which
+    /// keeps profiles of very hot workloads at a predictable size (perf only)
+    #[cfg_attr(feature = "cli", clap(long, value_name = "N"))]
+    pub max_samples: Option<u64>,
+
+    /// Drop the samples taken before the first one with <SYMBOL> on its stack, `main` if none is
+    /// given, which leaves out the loader and runtime starting a short-lived program (perf only)
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            value_name = "SYMBOL",
+            num_args = 0..=1,
+            default_missing_value = "main"
         )
-        .args(command_vec.get(1..).unwrap_or(&[]))
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .with_context(|| format!("unable to execute {:?}", command_vec))?;
-
-        let mut stdin = child
-            .stdin
-            .take()
-            .ok_or_else(|| anyhow::anyhow!("unable to capture post-process stdin"))?;
-
-        let mut stdout = child
-            .stdout
-            .take()
-            .ok_or_else(|| anyhow::anyhow!("unable to capture post-process stdout"))?;
-
-        let thread_handle = std::thread::spawn(move || -> anyhow::Result<_> {
-            let mut collapsed_processed = Vec::new();
-            stdout.read_to_end(&mut collapsed_processed).context(
-                "unable to read the processed stacks from the stdout of the post-process process",
-            )?;
-            Ok(collapsed_processed)
-        });
-
-        stdin
-            .write_all(&collapsed)
-            .context("unable to write the raw stacks to the stdin of the post-process process")?;
-        drop(stdin);
-
-        anyhow::ensure!(
-            child.wait()?.success(),
-            "post-process exited with a non zero exit code"
-        );
-
-        collapsed = thread_handle.join().unwrap()?;
-    }
-
-    let collapsed_reader = BufReader::new(&*collapsed);
+    )]
+    pub trim_before: Option<String>,
 
-    let flamegraph_filename = opts.output;
-    println!("writing flamegraph to {:?}", flamegraph_filename);
-    let flamegraph_file = File::create(&flamegraph_filename)
-        .context("unable to create flamegraph.svg output file")?;
+    /// Extra arguments passed to dtrace, e.g. `--dtrace-args "-x bufsize=64m"`, keeping the
+    /// default probe
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, value_name = "ARGS", allow_hyphen_values = true)
+    )]
+    pub dtrace_args: Option<String>,
+
+    /// bpftrace program printing maps keyed by stacks, e.g. `@[ustack] = count();`, for
+    /// --backend bpftrace [default: sampling user stacks at the configured frequency]
+    #[cfg_attr(feature = "cli", clap(long, value_name = "FILE.bt"))]
+    pub script: Option<PathBuf>,
+
+    /// Recorder to sample with [default: perf on Linux, dtrace elsewhere, blondie on Windows
+    /// without dtrace]
+    #[cfg_attr(feature = "cli", clap(long, value_enum))]
+    pub backend: Option<BackendKind>,
+
+    /// Record with the events and settings for a common kind of analysis
+    #[cfg_attr(feature = "cli", clap(long, value_enum))]
+    pub preset: Option<Preset>,
+
+    /// Record with `perf c2c` to find false sharing: the flamegraph shows the stacks of loads
+    /// from cache lines modified by another CPU, and the contended cache lines are written next
+    /// to it (Linux only)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, conflicts_with_all = ["preset", "custom_cmd", "time_units"])
+    )]
+    pub c2c: bool,
+
+    /// Record how long tasks waited in the run queue to get a CPU, from being woken up or
+    /// preempted until being switched in, attributed to the stacks they waited in. Records the
+    /// whole system, with a tower per command (Linux only)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, conflicts_with_all = ["preset", "custom_cmd", "c2c", "time_units"])
+    )]
+    pub sched_latency: bool,
+
+    /// Record the stacks of every hit of the user-level statically defined tracepoint
+    /// PROVIDER:PROBE of the profiled binary instead of sampling; may be repeated
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            value_name = "PROVIDER:PROBE",
+            conflicts_with_all = ["preset", "custom_cmd", "c2c", "time_units"]
+        )
+    )]
+    pub usdt: Vec<UsdtProbe>,
+
+    /// Record the stacks of every call of <SYMBOL> instead of sampling, showing who calls it how
+    /// often, with a uprobe (perf) or the pid provider (DTrace)
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            value_name = "SYMBOL",
+            conflicts_with_all = ["preset", "custom_cmd", "c2c", "time_units", "usdt"]
+        )
+    )]
+    pub count_calls: Option<String>,
 
-    let flamegraph_writer = BufWriter::new(flamegraph_file);
+    /// Binary or library defining the --count-calls symbol, e.g. libc for `malloc` [default: the
+    /// profiled binary]
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, value_name = "PATH", requires = "count_calls")
+    )]
+    pub count_calls_in: Option<PathBuf>,
 
-    let mut inferno_opts = opts.flamegraph_options.into_inferno();
-    from_reader(&mut inferno_opts, collapsed_reader, flamegraph_writer)
-        .context("unable to generate a flamegraph from the collapsed stack data")?;
+    /// Weigh the samples by the energy the CPU packages consumed while they were taken, read
+    /// from the RAPL counters of the powercap interface, instead of by CPU time (Linux only)
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, conflicts_with_all = ["preset", "custom_cmd", "c2c", "time_units"])
+    )]
+    pub power: bool,
 
-    if opts.open {
-        opener::open(&flamegraph_filename).context(format!(
-            "failed to open '{}'",
-            flamegraph_filename.display()
-        ))?;
-    }
+    #[cfg_attr(feature = "cli", clap(flatten))]
+    pub flamegraph_options: FlamegraphOptions,
 
-    Ok(())
-}
+    /// Record with a copy of perf that was granted the capabilities needed for profiling,
+    /// creating it as root on first use, so later runs need no root privileges
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(feature = "cli", clap(long))]
+    capabilities: bool,
 
-#[derive(Debug, Args)]
-pub struct Options {
-    /// Print extra output to help debug problems
-    #[clap(short, long)]
-    pub verbose: bool,
+    /// Delete the copy of perf created by --capabilities and exit
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub remove_capabilities: bool,
 
-    /// Output file
-    #[clap(short, long, default_value = "flamegraph.svg")]
-    output: PathBuf,
+    /// Temporarily lower kernel.perf_event_paranoid (as root) while recording if it keeps
+    /// perf from profiling
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(feature = "cli", clap(long))]
+    fix_paranoid: bool,
 
-    /// Open the output .svg file with default program
-    #[clap(long)]
-    open: bool,
+    /// Temporarily lower kernel.kptr_restrict from 2 to 1 (as root) while symbolizing if it
+    /// hides kernel addresses even from root
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(feature = "cli", clap(long, requires = "root"))]
+    fix_kptr_restrict: bool,
 
-    /// Run with root privileges (using `sudo`). Accepts an optional argument containing command line options which will be passed to sudo
-    #[clap(long, value_name = "SUDO FLAGS")]
-    pub root: Option<Option<String>>,
+    /// Sign the profiled binary ad hoc with the get-task-allow entitlement if its hardened
+    /// runtime keeps DTrace from attaching to it, and turn on developer mode if it is off; meant
+    /// for development builds
+    #[cfg(target_os = "macos")]
+    #[cfg_attr(feature = "cli", clap(long))]
+    fix_entitlements: bool,
 
-    /// Sampling frequency in Hz [default: 997]
-    #[clap(short = 'F', long = "freq")]
-    frequency: Option<u32>,
+    /// Ignores perf's exit code
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub ignore_status: bool,
 
-    /// Custom command for invoking perf/dtrace
-    #[clap(short, long = "cmd")]
-    custom_cmd: Option<String>,
+    /// Print the commands that would run and the files that would be written, without running
+    /// or writing anything
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub dry_run: bool,
 
-    #[clap(flatten)]
-    flamegraph_options: FlamegraphOptions,
+    /// Disable inlining for perf script because of performance issues
+    #[cfg_attr(feature = "cli", clap(long = "no-inline"))]
+    pub script_no_inline: bool,
+
+    /// Keep only the <N> innermost frames of every stack, which perf script, the DTrace and
+    /// bpftrace probes and rendered folded stacks are limited to; very deep stacks otherwise slow
+    /// down symbolizing and inflate the SVG
+    #[cfg_attr(feature = "cli", clap(long, value_name = "N"))]
+    pub script_max_stack: Option<u32>,
+
+    /// Read perf.data in-process instead of running perf script, which is much faster on large
+    /// recordings. Needs frame pointer or LBR call graphs, which are recorded by default when the
+    /// profiled binary keeps frame pointers, and shows no inlined functions; falls back to perf
+    /// script otherwise
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub internal_script: bool,
+
+    /// Resolve the addresses of perf recordings with perf script, or in-process from the debug
+    /// info of the binaries, caching each address; often an order of magnitude faster for large
+    /// Rust binaries and keeps inlined functions. Needs the same call graphs as --internal-script
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, value_enum, value_name = "MODE", default_value = "perf")
+    )]
+    pub symbolize: Symbolizer,
 
-    /// Ignores perf's exit code
-    #[clap(long)]
-    ignore_status: bool,
+    /// Do not reuse or store the folded stacks of the recording in the cache directory, which
+    /// saves symbolizing the same perf.data again when only flamegraph options change
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub no_cache: bool,
 
-    /// Disable inlining for perf script because of performance issues
-    #[clap(long = "no-inline")]
-    script_no_inline: bool,
+    /// Number of threads collapsing the stacks [default: one per CPU]
+    #[cfg_attr(feature = "cli", clap(long, value_name = "N"))]
+    pub collapse_threads: Option<usize>,
 
     /// Run a command to process the folded stacks, taking the input from stdin and outputting to
     /// stdout.
-    #[clap(long)]
+    #[cfg_attr(feature = "cli", clap(long))]
     post_process: Option<String>,
+
+    /// What the stacks of heap profiles converted with `render --from` are weighted by
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, value_enum, value_name = "WEIGHT", default_value = "allocations")
+    )]
+    pub heap_weight: HeapWeight,
+
+    /// Drop the stacks of threads waiting for work, e.g. in epoll_wait, on a condition variable
+    /// or parked, before processing and rendering them
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub drop_idle: bool,
+
+    /// Also drop stacks with a frame containing <PATTERN> with --drop-idle; may be repeated
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, value_name = "PATTERN", requires = "drop_idle")
+    )]
+    pub idle_function: Vec<String>,
+
+    /// Fold the frames of `core`, `alloc` and `std` called from outside of them into the first
+    /// one, keeping their samples, which declutters graphs full of iterator and formatting
+    /// plumbing
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub fold_std: bool,
+
+    /// Compare the folded stacks against a baseline and fail if any function regressed
+    #[cfg_attr(feature = "cli", clap(long, value_name = "FOLDED FILE"))]
+    check_against: Option<PathBuf>,
+
+    /// Allowed relative growth of a function's share of samples when using --check-against
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            value_name = "PERCENT",
+            default_value = "10%",
+            value_parser = regression::parse_threshold
+        )
+    )]
+    pub regression_threshold: f64,
+
+    /// Record the command N times and render the stacks of all runs together, outlining the
+    /// functions whose share of the samples varies between the runs
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            value_name = "N",
+            default_value = "1",
+            value_parser = clap::value_parser!(u16).range(1..).map(usize::from)
+        )
+    )]
+    pub runs: usize,
+
+    /// Standard deviation of a function's share of samples across --runs, relative to its mean,
+    /// above which the function is outlined as unstable
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            value_name = "PERCENT",
+            default_value = "10%",
+            value_parser = regression::parse_threshold
+        )
+    )]
+    pub unstable_threshold: f64,
+
+    /// Also write a flamegraph per process next to the SVG, named after the process, e.g. when
+    /// attaching to several processes with --pid (perf only)
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub split_pids: bool,
+
+    /// Also write a flamegraph per test case next to the SVG when profiling a libtest test
+    /// binary, e.g. with `cargo flamegraph --unit-test`, which is run with `--format json` to
+    /// tell when each test ran (perf only)
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub per_test: bool,
+
+    /// Also write a two-panel view with the merged callers and callees of frames matching <SYMBOL>
+    #[cfg_attr(feature = "cli", clap(long, value_name = "SYMBOL"))]
+    sandwich: Option<String>,
+
+    /// Link frames of functions defined in the current git repository to their source, given as
+    /// a URL with the placeholders {path}, {line} and {rev} (the checked out commit), or as
+    /// `host/org/repo@{rev}` for GitHub, GitLab, Bitbucket, Codeberg, Gitea and SourceHut
+    #[cfg_attr(feature = "cli", clap(long, value_name = "URL TEMPLATE"))]
+    source_link: Option<String>,
+
+    /// Write a line-level hotspot report for <SYMBOL> next to the SVG, using `perf annotate`;
+    /// may be repeated
+    #[cfg_attr(feature = "cli", clap(long, value_name = "SYMBOL"))]
+    annotate: Vec<String>,
+
+    /// Also write the stacks in <FORMAT> next to the SVG, named after it; may be repeated
+    #[cfg_attr(feature = "cli", clap(long, value_enum, value_name = "FORMAT"))]
+    pub export: Vec<ExportFormat>,
+
+    /// Also write a JSON manifest next to the SVG listing every written file with its type and
+    /// description, along with the metadata of the run
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub manifest: bool,
+
+    /// Write a smaller SVG that browsers open quickly even for large profiles: frames narrower
+    /// than a pixel are merged into an "[other]" frame per caller and coordinates are rounded
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub optimize_svg: bool,
+
+    /// Print a summary of the samples spent in each crate
+    #[cfg_attr(feature = "cli", clap(long))]
+    crate_report: bool,
+
+    /// Also write a flamegraph aggregated by crate to <FILE> (implies --crate-report)
+    #[cfg_attr(feature = "cli", clap(long, value_name = "FILE"))]
+    crate_flamegraph: Option<PathBuf>,
+
+    /// Save all options of this run (including the profiled command) under <NAME>
+    #[cfg(feature = "cli")]
+    #[clap(long, value_name = "NAME")]
+    save_invocation: Option<String>,
+
+    /// Replay the options saved with --save-invocation <NAME>; further options override them
+    #[cfg(feature = "cli")]
+    #[clap(long, value_name = "NAME")]
+    invocation: Option<String>,
+
+    /// Do not embed the command line, git commit, hostname, date, sampling frequency and build
+    /// profile into the SVG
+    #[cfg_attr(feature = "cli", clap(long))]
+    no_metadata: bool,
+
+    /// Write the same SVG for the same stacks on every run, e.g. for snapshot tests: implies
+    /// --deterministic and --no-metadata
+    #[cfg_attr(feature = "cli", clap(long))]
+    pub reproducible: bool,
+
+    /// Build profile of the profiled binary, recorded in the SVG metadata
+    #[cfg_attr(feature = "cli", clap(skip))]
+    pub build_profile: Option<String>,
+
+    /// Name of the profiled target, used for the `{bin}` title placeholder. Defaults to the file
+    /// name of the profiled command.
+    #[cfg_attr(feature = "cli", clap(skip))]
+    pub target_name: Option<String>,
+
+    /// Whether to remove the frames of the libtest harness below the tests, so every test is
+    /// the root of its stacks
+    #[cfg_attr(feature = "cli", clap(skip))]
+    pub strip_harness: bool,
+
+    /// Ends the recording early when stopped
+    #[cfg_attr(feature = "cli", clap(skip))]
+    pub stop: Option<StopHandle>,
+
+    /// Additional output formats, written like the ones requested with `--export`
+    #[cfg_attr(feature = "cli", clap(skip))]
+    pub exporters: Vec<Arc<dyn Exporter + Send + Sync>>,
+
+    /// Recorder used instead of the built-in one chosen with `--backend`
+    #[cfg_attr(feature = "cli", clap(skip))]
+    pub custom_backend: Option<Arc<dyn Backend + Send + Sync>>,
+
+    /// Names of the crates frames can be attributed to in the crate report. When empty, the
+    /// first path segment of every Rust symbol is used.
+    #[cfg_attr(feature = "cli", clap(skip))]
+    pub known_crates: Vec<String>,
+}
+
+/// Parses the seconds of `--duration`, e.g. `30` or `2.5s`.
+#[cfg(feature = "cli")]
+fn parse_duration(seconds: &str) -> Result<Duration, String> {
+    let duration = seconds
+        .strip_suffix('s')
+        .unwrap_or(seconds)
+        .parse::<f64>()
+        .map_err(|_| format!("expected a number of seconds, got '{seconds}'"))
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).map_err(|err| err.to_string()))?;
+    if duration.is_zero() {
+        return Err("the duration must be positive".to_string());
+    }
+    Ok(duration)
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            verbose: 0,
+            quiet: false,
+            message_format: MessageFormat::Human,
+            output: PathBuf::from("flamegraph.svg"),
+            #[cfg(feature = "cli")]
+            open: false,
+            #[cfg(feature = "cli")]
+            open_with: None,
+            #[cfg(feature = "notify")]
+            notify: false,
+            root: None,
+            escalate: None,
+            escalation_cmd: None,
+            frequency: None,
+            time_units: false,
+            custom_cmd: None,
+            perf_args: None,
+            buffer_size: None,
+            proc_map_timeout: None,
+            cache_build_ids: false,
+            duration: None,
+            max_samples: None,
+            trim_before: None,
+            dtrace_args: None,
+            script: None,
+            backend: None,
+            preset: None,
+            c2c: false,
+            sched_latency: false,
+            usdt: Vec::new(),
+            count_calls: None,
+            count_calls_in: None,
+            #[cfg(target_os = "linux")]
+            power: false,
+            flamegraph_options: FlamegraphOptions::default(),
+            #[cfg(target_os = "linux")]
+            capabilities: false,
+            #[cfg(target_os = "linux")]
+            remove_capabilities: false,
+            #[cfg(target_os = "linux")]
+            fix_paranoid: false,
+            #[cfg(target_os = "linux")]
+            fix_kptr_restrict: false,
+            #[cfg(target_os = "macos")]
+            fix_entitlements: false,
+            ignore_status: false,
+            dry_run: false,
+            script_no_inline: false,
+            script_max_stack: None,
+            internal_script: false,
+            symbolize: Symbolizer::Perf,
+            no_cache: false,
+            collapse_threads: None,
+            post_process: None,
+            heap_weight: HeapWeight::Allocations,
+            drop_idle: false,
+            idle_function: Vec::new(),
+            fold_std: false,
+            check_against: None,
+            regression_threshold: regression::parse_threshold("10%").unwrap(),
+            runs: 1,
+            unstable_threshold: regression::parse_threshold("10%").unwrap(),
+            split_pids: false,
+            per_test: false,
+            sandwich: None,
+            source_link: None,
+            annotate: Vec::new(),
+            export: Vec::new(),
+            manifest: false,
+            optimize_svg: false,
+            crate_report: false,
+            crate_flamegraph: None,
+            #[cfg(feature = "cli")]
+            save_invocation: None,
+            #[cfg(feature = "cli")]
+            invocation: None,
+            no_metadata: false,
+            reproducible: false,
+            build_profile: None,
+            target_name: None,
+            strip_harness: false,
+            stop: None,
+            exporters: Vec::new(),
+            custom_backend: None,
+            known_crates: Vec::new(),
+        }
+    }
 }
 
 impl Options {
     pub fn check(&self) -> anyhow::Result<()> {
         // Manually checking conflict because structopts `conflicts_with` leads
         // to a panic in completion generation for zsh at the moment (see #158)
-        match self.frequency.is_some() && self.custom_cmd.is_some() {
-            true => Err(anyhow!(
+        if self.frequency.is_some() && self.custom_cmd.is_some() {
+            return Err(anyhow!(
                 "Cannot pass both a custom command and a frequency."
-            )),
-            false => Ok(()),
+            ));
+        }
+        if self.preset.is_some() && self.custom_cmd.is_some() {
+            return Err(anyhow!("Cannot pass both a custom command and a preset."));
+        }
+        Ok(())
+    }
+
+    /// Fails with instructions on how to fix it if `kernel.perf_event_paranoid` keeps perf from
+    /// recording, so the problem is reported before building and running the workload.
+    pub fn check_permissions(&self) -> anyhow::Result<()> {
+        #[cfg(target_os = "linux")]
+        if !self.fix_paranoid && !self.capabilities && self.uses_perf() {
+            let record_command = self.record_command()?;
+            let required = paranoid::required_level(&format!(
+                "{} {}",
+                record_command.as_deref().unwrap_or_default(),
+                self.perf_args.as_deref().unwrap_or_default()
+            ));
+            if let Some(level) = paranoid::too_high(required, self.runs_as_root()) {
+                return Err(paranoid::error(level, required));
+            }
+        }
+        // ETW and DTrace only record with administrator rights.
+        #[cfg(windows)]
+        if !self.runs_as_root() && !uac::is_elevated() {
+            return Err(uac::error());
+        }
+        Ok(())
+    }
+
+    /// Whether the recorder runs with root privileges.
+    pub fn runs_as_root(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        if self.capabilities {
+            return false;
+        }
+        // Without a tool, `--root` relaunches the whole process elevated on Windows.
+        #[cfg(windows)]
+        if self.escalate.is_none() && self.escalation_cmd.is_none() {
+            return false;
+        }
+        self.root.is_some() || self.escalate.is_some() || self.escalation_cmd.is_some()
+    }
+
+    /// The custom perf/dtrace command, either given with `--cmd` or implied by the preset.
+    fn record_command(&self) -> anyhow::Result<Option<String>> {
+        match (&self.custom_cmd, self.preset) {
+            (Some(custom_cmd), _) => Ok(Some(custom_cmd.clone())),
+            (None, Some(preset)) => self
+                .backend()?
+                .preset_command(preset, self.frequency())
+                .map(Some),
+            (None, None) if self.count_calls.is_some() => self
+                .backend()?
+                .count_calls_command(
+                    self.count_calls.as_deref().unwrap_or_default(),
+                    self.count_calls_in.as_deref(),
+                )
+                .map(Some),
+            (None, None) if !self.usdt.is_empty() => {
+                self.backend()?.usdt_command(&self.usdt).map(Some)
+            }
+            (None, None) if self.sched_latency => Ok(Some(format!(
+                "record {} -c 1 --call-graph dwarf,16384 -g",
+                sched::EVENTS
+            ))),
+            (None, None) if self.c2c => Ok(Some(format!(
+                "c2c record -F {} --call-graph dwarf,16384 -g",
+                self.frequency()
+            ))),
+            #[cfg(target_os = "linux")]
+            (None, None) if self.power => Ok(Some(format!(
+                "record -F {} --call-graph dwarf,16384 -g -k {}",
+                self.frequency(),
+                backend::perf::PERF_CLOCK
+            ))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The recorder of this run: the custom backend if one is set, otherwise the one chosen
+    /// with `--backend` or the platform default.
+    pub fn backend(&self) -> anyhow::Result<Arc<dyn Backend + Send + Sync>> {
+        match &self.custom_backend {
+            Some(backend) => Ok(Arc::clone(backend)),
+            None => self
+                .backend
+                .unwrap_or_else(BackendKind::platform_default)
+                .backend(),
+        }
+    }
+
+    /// Whether the recorder is perf, which the permission checks and fixes apply to.
+    #[cfg(target_os = "linux")]
+    fn uses_perf(&self) -> bool {
+        self.custom_backend.is_none()
+            && self.backend.unwrap_or_else(BackendKind::platform_default) == BackendKind::Perf
+    }
+
+    /// Level of the messages to show according to `--verbose` and `--quiet`.
+    pub fn log_level(&self) -> log::LevelFilter {
+        if self.verbose >= 2 {
+            log::LevelFilter::Trace
+        } else if self.verbose == 1 {
+            log::LevelFilter::Debug
+        } else if self.quiet {
+            log::LevelFilter::Error
+        } else {
+            log::LevelFilter::Info
         }
     }
 
     pub fn frequency(&self) -> u32 {
         self.frequency.unwrap_or(997)
     }
+
+    pub fn crate_report(&self) -> bool {
+        self.crate_report || self.crate_flamegraph.is_some()
+    }
+
+    /// Opens `path` if asked to with `--open` or `--open-with`.
+    #[cfg(feature = "cli")]
+    pub fn open_output(&self, path: &Path) -> anyhow::Result<()> {
+        if self.open || self.open_with.is_some() {
+            open::open(path, self.open_with.as_deref())?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the output from being opened, for runs whose outputs are opened together at the
+    /// end.
+    #[cfg(feature = "cli")]
+    pub fn disable_open(&mut self) {
+        self.open = false;
+        self.open_with = None;
+    }
 }
 
-#[derive(Debug, Args)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "cli", derive(Args))]
 pub struct FlamegraphOptions {
-    /// Set title text in SVG
-    #[clap(long, value_name = "STRING")]
+    /// Set title text in SVG. May contain the placeholders {bin}, {profile}, {git}, {date},
+    /// {hostname}, {freq} and {env:VAR}
+    #[cfg_attr(feature = "cli", clap(long, value_name = "STRING"))]
     pub title: Option<String>,
 
-    /// Set second level title text in SVG
-    #[clap(long, value_name = "STRING")]
+    /// Set second level title text in SVG. Supports the same placeholders as --title
+    #[cfg_attr(feature = "cli", clap(long, value_name = "STRING"))]
     pub subtitle: Option<String>,
 
     /// Colors are selected such that the color of a function does not change between runs
-    #[clap(long)]
+    #[cfg_attr(feature = "cli", clap(long))]
     pub deterministic: bool,
 
     /// Plot the flame graph up-side-down
-    #[clap(short, long)]
+    #[cfg_attr(feature = "cli", clap(short, long))]
     pub inverted: bool,
 
     /// Generate stack-reversed flame graph
-    #[clap(long)]
+    #[cfg_attr(feature = "cli", clap(long))]
     pub reverse: bool,
 
     /// Set embedded notes in SVG
-    #[clap(long, value_name = "STRING")]
+    #[cfg_attr(feature = "cli", clap(long, value_name = "STRING"))]
     pub notes: Option<String>,
 
     /// Omit functions smaller than <FLOAT> pixels
-    #[clap(long, default_value = "0.01", value_name = "FLOAT")]
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, default_value = "0.01", value_name = "FLOAT")
+    )]
     pub min_width: f64,
 
     /// Image width in pixels
-    #[clap(long)]
+    #[cfg_attr(feature = "cli", clap(long))]
     pub image_width: Option<usize>,
 
+    /// Name of the sampled quantity shown in frame details [default: samples]
+    #[cfg_attr(feature = "cli", clap(long, value_name = "STRING"))]
+    pub count_name: Option<String>,
+
     /// Color palette
-    #[clap(
-        long,
-        value_parser = PossibleValuesParser::new(Palette::VARIANTS).map(|s| Palette::from_str(&s).unwrap())
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            value_parser = PossibleValuesParser::new(Palette::VARIANTS).map(|s| Palette::from_str(&s).unwrap())
+        )
     )]
     pub palette: Option<Palette>,
 
     /// Cut off stack frames below <FUNCTION>; may be repeated
     #[cfg(target_os = "linux")]
-    #[clap(long, value_name = "FUNCTION")]
+    #[cfg_attr(feature = "cli", clap(long, value_name = "FUNCTION"))]
     pub skip_after: Vec<String>,
 
     /// Produce a flame chart (sort by time, do not merge stacks)
-    #[clap(long = "flamechart", conflicts_with = "reverse")]
+    #[cfg_attr(feature = "cli", clap(long = "flamechart", conflicts_with = "reverse"))]
     pub flame_chart: bool,
+
+    /// Factor the counts shown in frame details are multiplied with, set by `--time-units`
+    #[cfg_attr(feature = "cli", clap(skip))]
+    pub factor: Option<f64>,
+}
+
+impl Default for FlamegraphOptions {
+    fn default() -> Self {
+        FlamegraphOptions {
+            title: None,
+            subtitle: None,
+            deterministic: false,
+            inverted: false,
+            reverse: false,
+            notes: None,
+            min_width: 0.01,
+            image_width: None,
+            count_name: None,
+            palette: None,
+            #[cfg(target_os = "linux")]
+            skip_after: Vec::new(),
+            flame_chart: false,
+            factor: None,
+        }
+    }
 }
 
 impl FlamegraphOptions {
@@ -635,7 +1100,13 @@ impl FlamegraphOptions {
         if let Some(palette) = self.palette {
             options.colors = palette;
         }
+        if let Some(count_name) = self.count_name {
+            options.count_name = count_name;
+        }
         options.flame_chart = self.flame_chart;
+        if let Some(factor) = self.factor {
+            options.factor = factor;
+        }
 
         options
     }
@@ -0,0 +1,229 @@
+//! Per-test flamegraphs for `--per-test`.
+//!
+//! A libtest test binary is run with `--format json`, printing an event when a test starts and
+//! when it ends. The events are timestamped as they are read, and perf timestamps the samples
+//! with the same clock. libtest runs every test on a thread named after it, so a sample belongs
+//! to the test that ran at its time on a thread of that name; the command of the sample is
+//! replaced with the full name of the test, which makes it the root frame of the stack. With
+//! `--test-threads=1` the tests run one after the other on the main thread instead, so a sample
+//! belongs to the test that ran at its time on any thread.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::Context;
+use serde_json::Value;
+
+use crate::backend::perf::{clock_time, sample_time, Headers};
+
+/// Arguments making a test binary print its events as JSON. These are unstable options of
+/// libtest, which stable toolchains accept with `RUSTC_BOOTSTRAP=1`.
+pub(crate) const ARGS: &[&str] = &["-Z", "unstable-options", "--format", "json"];
+
+/// Length the kernel truncates thread names to.
+const COMM_LEN: usize = 15;
+
+/// A test that ran from `start` to `end`, in nanoseconds since the Unix epoch.
+#[derive(Debug, Clone)]
+pub(crate) struct TestRun {
+    pub name: String,
+    start: u64,
+    end: u64,
+}
+
+/// Reads the events of a test binary from its `output` until it exits, logging a line per test
+/// like libtest prints, and returns the tests that ran. What the tests print, e.g. lines that
+/// are not events with `--nocapture`, is passed through to stdout, or to stderr with `json` to
+/// keep stdout to the events of `--message-format json`.
+pub(crate) fn watch(output: impl Read + Send + 'static, json: bool) -> JoinHandle<Vec<TestRun>> {
+    let pass_through = move |text: &str| {
+        if json {
+            eprint!("{text}");
+        } else {
+            print!("{text}");
+        }
+    };
+    thread::spawn(move || {
+        let mut started = HashMap::new();
+        let mut runs = Vec::new();
+        for line in BufReader::new(output).lines() {
+            let Ok(line) = line else { break };
+            let time = clock_time();
+            let event: Value = match serde_json::from_str(&line) {
+                Ok(event @ Value::Object(_)) => event,
+                _ => {
+                    pass_through(&format!("{line}\n"));
+                    continue;
+                }
+            };
+            let name = event["name"].as_str().unwrap_or_default();
+            match (event["type"].as_str(), event["event"].as_str()) {
+                (Some("suite"), Some("started")) => {
+                    log::info!("\nrunning {} tests", event["test_count"]);
+                }
+                (Some("suite"), Some(result)) => log::info!(
+                    "\ntest result: {result}. {} passed; {} failed; {} ignored",
+                    event["passed"],
+                    event["failed"],
+                    event["ignored"]
+                ),
+                (Some("test"), Some("started")) => {
+                    started.insert(name.to_string(), time);
+                }
+                (Some("test"), Some(result)) => {
+                    log::info!("test {name} ... {result}");
+                    if let Some(stdout) = event["stdout"].as_str() {
+                        pass_through(stdout);
+                    }
+                    if let Some(start) = started.remove(name) {
+                        runs.push(TestRun {
+                            name: name.to_string(),
+                            start,
+                            end: time,
+                        });
+                    }
+                }
+                _ => (),
+            }
+        }
+        runs
+    })
+}
+
+/// The command of a `perf script` sample header and the position after it, e.g. `my test`
+/// in `my test 4242/4243 1760000000.123456: 250000 cycles:`.
+fn command(header: &str) -> Option<(&str, usize)> {
+    let start = header.len() - header.trim_start().len();
+    let mut offset = start;
+    for word in header[start..].split(' ') {
+        let is_pid = !word.is_empty()
+            && word
+                .split('/')
+                .all(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()));
+        if is_pid && offset > start {
+            return Some((header[start..offset].trim_end(), offset));
+        }
+        offset += word.len() + 1;
+    }
+    None
+}
+
+/// Whether no two of the `runs` overlap in time, as when libtest runs the tests on its main
+/// thread.
+fn sequential(runs: &[TestRun]) -> bool {
+    let mut runs: Vec<_> = runs.iter().collect();
+    runs.sort_by_key(|run| run.start);
+    runs.windows(2).all(|pair| pair[0].end <= pair[1].start)
+}
+
+/// Rewrites the commands of the samples in `script` that were taken on the thread of a test
+/// while it ran to the name of the test. If the tests ran one after the other, the samples of
+/// all threads taken while a test ran are attributed to it.
+pub(crate) fn attribute(script: impl BufRead, runs: &[TestRun]) -> anyhow::Result<Vec<u8>> {
+    let sequential = sequential(runs);
+    let mut output = Vec::new();
+    let mut headers = Headers::default();
+    for line in script.lines() {
+        let line = line.context("unable to read the perf script output")?;
+        let test = headers
+            .is_header(&line)
+            .then(|| {
+                let (command, end) = command(&line)?;
+                let (_, time) = sample_time(&line)?;
+                let run = runs.iter().find(|run| {
+                    run.start <= time
+                        && time <= run.end
+                        && (sequential
+                            || run
+                                .name
+                                .as_bytes()
+                                .get(..COMM_LEN)
+                                .unwrap_or(run.name.as_bytes())
+                                == command.as_bytes())
+                })?;
+                Some((run, end))
+            })
+            .flatten();
+        match test {
+            Some((run, end)) => {
+                output.extend_from_slice(format!("{} {}\n", run.name, &line[end..]).as_bytes())
+            }
+            None => {
+                output.extend_from_slice(line.as_bytes());
+                output.push(b'\n');
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// Splits off the stacks of each test in `runs`, which start with the name of the test, from
+/// folded data.
+pub(crate) fn split(data: &[u8], runs: &[TestRun]) -> Vec<(String, Vec<u8>)> {
+    let mut tests: Vec<(String, Vec<u8>)> = Vec::new();
+    for run in runs {
+        if !tests.iter().any(|(name, _)| *name == run.name) {
+            tests.push((run.name.clone(), Vec::new()));
+        }
+    }
+    for (stack, count) in crate::folded::lines(data) {
+        let root = stack.split(';').next().unwrap_or(stack);
+        if let Some((_, collapsed)) = tests.iter_mut().find(|(name, _)| name == root) {
+            collapsed.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+        }
+    }
+    tests.retain(|(_, collapsed)| !collapsed.is_empty());
+    tests
+}
+
+/// Frame of libtest calling every test, as in panic backtraces.
+const HARNESS_FRAME: &str = "test::__rust_begin_short_backtrace";
+
+/// Removes the frames of the harness running a test from the stacks of tests, i.e. everything
+/// up to libtest calling the test function, so the test is the root of its stacks after the
+/// first frame naming the thread. That frame is the test itself for tests run on threads of
+/// their own or with `--per-test`, but the test binary for tests run on its main thread, as with
+/// `--test-threads=1` and `--exact`. Stacks outside of tests are kept.
+pub(crate) fn strip_harness(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    for (stack, count) in crate::folded::lines(data) {
+        let frames: Vec<_> = stack.split(';').collect();
+        let Some(harness) = frames
+            .iter()
+            .rposition(|frame| frame.starts_with(HARNESS_FRAME))
+        else {
+            output.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+            continue;
+        };
+        // The test is called through `FnOnce::call_once` and the closure of its test descriptor.
+        let mut body = harness + 1;
+        while frames
+            .get(body)
+            .is_some_and(|frame| frame.starts_with("core::ops::function::"))
+        {
+            body += 1;
+        }
+        if frames
+            .get(body)
+            .is_some_and(|frame| frame.ends_with("::{{closure}}"))
+        {
+            body += 1;
+        }
+        let body = if body < frames.len() {
+            &frames[body..]
+        } else {
+            &frames[harness..=harness]
+        };
+        let root = frames[0];
+        let stack = if harness == 0 {
+            body.join(";")
+        } else {
+            format!("{root};{}", body.join(";"))
+        };
+        output.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+    }
+    output
+}
@@ -0,0 +1,62 @@
+//! Terminal output for the log messages of the library, used by the binaries.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{JsonEvents, MessageFormat};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Messages of other crates, e.g. inferno, are only of interest when debugging. The
+        // binaries are named `flamegraph` and `cargo_flamegraph`.
+        let target = metadata.target();
+        metadata.level() <= log::max_level()
+            && (target.starts_with(env!("CARGO_CRATE_NAME"))
+                || target.starts_with("cargo_flamegraph")
+                || log::max_level() >= LevelFilter::Debug)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if JSON.load(Ordering::Relaxed) {
+            let level = match record.level() {
+                Level::Warn => "warning".to_string(),
+                level => level.as_str().to_lowercase(),
+            };
+            JsonEvents::emit(
+                "message",
+                serde_json::json!({ "level": level, "message": record.args().to_string() }),
+            );
+            return;
+        }
+        match record.level() {
+            Level::Error => eprintln!("error: {}", record.args()),
+            Level::Warn => eprintln!("warning: {}", record.args()),
+            Level::Info | Level::Debug | Level::Trace => println!("{}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Prints log messages to the terminal: information to stdout, warnings and errors to stderr.
+/// Shows information and above until the level is changed with [`log::set_max_level`], e.g. to
+/// [`Options::log_level`](crate::Options::log_level).
+pub fn init() {
+    if log::set_logger(&Logger).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+/// Prints log messages as `message` events of [`JsonEvents`] on stdout instead of as text, for
+/// `--message-format json`.
+pub fn set_message_format(format: MessageFormat) {
+    JSON.store(format == MessageFormat::Json, Ordering::Relaxed);
+}
@@ -0,0 +1,209 @@
+//! Profiling a workload once per combination of parameter values, as given with
+//! `--matrix NAME={a,b,c}`.
+//!
+//! Every run writes a flamegraph named after its combination, a differential flamegraph against
+//! the first combination, and an index page linking them all.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+
+use crate::{
+    diff,
+    gallery::{self, Artifact},
+    Options, Profile,
+};
+
+/// A parameter and the values it takes, parsed from `NAME={a,b,c}` or `NAME=a,b,c`.
+#[derive(Debug, Clone)]
+pub struct Parameter {
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+impl FromStr for Parameter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, values) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME={{VALUE,...}}, got '{s}'"))?;
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!(
+                "parameter name '{name}' may only contain letters, digits and underscores"
+            ));
+        }
+        let values = values
+            .strip_prefix('{')
+            .and_then(|values| values.strip_suffix('}'))
+            .unwrap_or(values);
+        let values: Vec<_> = values.split(',').map(|value| value.to_string()).collect();
+        if values.iter().any(String::is_empty) {
+            return Err(format!("empty value for parameter '{name}'"));
+        }
+        Ok(Parameter {
+            name: name.to_string(),
+            values,
+        })
+    }
+}
+
+/// One value for each parameter.
+#[derive(Debug, Clone)]
+pub struct Combination {
+    values: Vec<(String, String)>,
+}
+
+impl Combination {
+    /// Replaces `{NAME}` in `args` with the value of each parameter.
+    pub fn apply(&self, args: &[String]) -> Vec<String> {
+        args.iter()
+            .map(|arg| {
+                self.values.iter().fold(arg.clone(), |arg, (name, value)| {
+                    arg.replace(&format!("{{{name}}}"), value)
+                })
+            })
+            .collect()
+    }
+
+    /// Part of the file names of this combination's outputs, e.g. `THREADS-8`.
+    fn file_suffix(&self) -> String {
+        self.values
+            .iter()
+            .map(|(name, value)| {
+                let value: String = value
+                    .chars()
+                    .map(|c| match c {
+                        'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                        _ => '_',
+                    })
+                    .collect();
+                format!("{name}-{value}")
+            })
+            .collect::<Vec<_>>()
+            .join("_")
+    }
+}
+
+impl fmt::Display for Combination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.values.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// All combinations of the values of `parameters`, varying the last parameter fastest.
+pub fn combinations(parameters: &[Parameter]) -> Vec<Combination> {
+    parameters.iter().fold(
+        vec![Combination { values: Vec::new() }],
+        |combinations, parameter| {
+            combinations
+                .iter()
+                .flat_map(|combination| {
+                    parameter.values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination
+                            .values
+                            .push((parameter.name.clone(), value.clone()));
+                        combination
+                    })
+                })
+                .collect()
+        },
+    )
+}
+
+/// Profiles every combination of `parameters` with `run`, which receives the combination and
+/// the flamegraph to write, derived from the output of `options`. The parameters are also set
+/// as environment variables of the profiled command. A failing run does not stop the others.
+pub fn profile(
+    parameters: &[Parameter],
+    options: &Options,
+    mut run: impl FnMut(&Combination, PathBuf) -> anyhow::Result<Profile>,
+) -> anyhow::Result<()> {
+    let output: &Path = &options.output;
+    let stem = output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "flamegraph".to_string());
+    let combinations = combinations(parameters);
+
+    let mut artifacts = Vec::new();
+    let mut baseline: Option<(Combination, Vec<u8>)> = None;
+    let mut failed = 0;
+    for combination in &combinations {
+        for (name, value) in &combination.values {
+            std::env::set_var(name, value);
+        }
+        let suffix = combination.file_suffix();
+        log::info!("profiling with {}", combination);
+        let profile = match run(
+            combination,
+            output.with_file_name(format!("{stem}-{suffix}.svg")),
+        ) {
+            Ok(profile) => profile,
+            Err(err) => {
+                log::error!("unable to profile with {}: {:#}", combination, err);
+                failed += 1;
+                continue;
+            }
+        };
+        artifacts.extend(
+            profile
+                .outputs
+                .first()
+                .map(|path| Artifact::new(path, combination.to_string())),
+        );
+
+        match &baseline {
+            Some((base, base_collapsed)) => {
+                let path = output.with_file_name(format!("{stem}-{suffix}-diff.svg"));
+                let title = format!("{combination} compared to {base}");
+                let diffed = diff::write(
+                    base_collapsed,
+                    &profile.collapsed,
+                    &title,
+                    &options.flamegraph_options,
+                    &path,
+                );
+                match diffed {
+                    Ok(()) => artifacts.push(Artifact::new(path, title)),
+                    Err(err) => log::error!("{:#}", err),
+                }
+            }
+            None => baseline = Some((combination.clone(), profile.collapsed)),
+        }
+    }
+
+    if !artifacts.is_empty() {
+        let index = output.with_file_name(format!("{stem}-index.html"));
+        let notes = match &baseline {
+            Some((base, _)) => format!(
+                "Frames of the differential flamegraphs are red where they grew and blue where \
+                 they shrank compared to {base}."
+            ),
+            None => String::new(),
+        };
+        log::info!("writing index of all flamegraphs to {:?}", index);
+        gallery::write_index(&index, "Flamegraphs", &notes, &artifacts)?;
+        options.open_output(&index)?;
+    }
+
+    if failed > 0 {
+        return Err(anyhow!(
+            "profiling failed for {} of {} combinations",
+            failed,
+            combinations.len()
+        ));
+    }
+    Ok(())
+}
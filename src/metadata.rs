@@ -0,0 +1,222 @@
+use std::{
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::Workload;
+
+/// Information about a profiling run that is embedded into the generated SVG,
+/// so that it is still known months later what a flamegraph was recorded from.
+pub(crate) struct RunMetadata {
+    pub bin: Option<String>,
+    pub command: Option<String>,
+    pub git_commit: Option<String>,
+    pub hostname: Option<String>,
+    pub date: String,
+    pub frequency: u32,
+    pub profile: Option<String>,
+}
+
+impl RunMetadata {
+    pub fn collect(
+        workload: &Workload,
+        frequency: u32,
+        profile: Option<String>,
+        bin: Option<String>,
+    ) -> Self {
+        let bin = bin.or_else(|| match workload {
+            Workload::Command(c) => c.first().and_then(|program| {
+                Path::new(program)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+            }),
+            _ => None,
+        });
+
+        let command = match workload {
+            Workload::Command(c) => Some(join_command(c)),
+            Workload::Pid(p) => Some(format!(
+                "--pid {}",
+                p.iter().map(u32::to_string).collect::<Vec<_>>().join(",")
+            )),
+            Workload::Thread(t) => Some(format!(
+                "--tid {}",
+                t.iter()
+                    .map(|(_, tid)| tid.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )),
+            Workload::User(user) => Some(format!("--uid {user}")),
+            Workload::ReadPerf(path) => Some(format!("--perfdata {}", path.display())),
+            Workload::Folded(path) => Some(format!("render {}", path.display())),
+            Workload::Import(format, path) => Some(format!(
+                "render --from {} {}",
+                format.name(),
+                path.display()
+            )),
+        };
+
+        RunMetadata {
+            bin,
+            command,
+            git_commit: git_commit(),
+            hostname: hostname(),
+            date: utc_date(SystemTime::now()),
+            frequency,
+            profile,
+        }
+    }
+
+    /// Expands the `{bin}`, `{profile}`, `{git}`, `{date}`, `{hostname}`, `{freq}` and
+    /// `{env:VAR}` placeholders in `template`. Unknown placeholders are kept as is.
+    pub fn expand(&self, template: &str) -> String {
+        let mut expanded = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            expanded.push_str(&rest[..start]);
+
+            let placeholder = &rest[start + 1..end];
+            let value = match placeholder {
+                "bin" => self.bin.clone(),
+                "profile" => self.profile.clone(),
+                "git" => self.git_commit.clone(),
+                "date" => self.date.split(' ').next().map(str::to_string),
+                "hostname" => self.hostname.clone(),
+                "freq" => Some(self.frequency.to_string()),
+                _ => placeholder
+                    .strip_prefix("env:")
+                    .map(|var| std::env::var(var).unwrap_or_default()),
+            };
+
+            match value {
+                Some(value) => expanded.push_str(&value),
+                None if matches!(placeholder, "bin" | "profile" | "git" | "hostname") => {
+                    expanded.push_str("unknown")
+                }
+                None => expanded.push_str(&rest[start..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+
+        expanded.push_str(rest);
+        expanded
+    }
+
+    /// A short, single line summary suitable for the subtitle.
+    pub fn summary(&self) -> String {
+        match &self.command {
+            Some(command) => format!("{} ({})", command, self.date),
+            None => self.date.clone(),
+        }
+    }
+
+    /// All collected information as a JSON object, with `null` for what is unknown.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "bin": self.bin,
+            "command": self.command,
+            "git_commit": self.git_commit,
+            "hostname": self.hostname,
+            "date": self.date,
+            "frequency": self.frequency,
+            "build_profile": self.profile,
+        })
+    }
+
+    /// All collected information, one `key: value` pair per line.
+    pub fn notes(&self) -> String {
+        let mut notes = Vec::new();
+        if let Some(command) = &self.command {
+            notes.push(format!("command: {command}"));
+        }
+        if let Some(commit) = &self.git_commit {
+            notes.push(format!("git commit: {commit}"));
+        }
+        if let Some(hostname) = &self.hostname {
+            notes.push(format!("hostname: {hostname}"));
+        }
+        notes.push(format!("date: {}", self.date));
+        notes.push(format!("frequency: {} Hz", self.frequency));
+        if let Some(profile) = &self.profile {
+            notes.push(format!("build profile: {profile}"));
+        }
+        notes.join("\n")
+    }
+}
+
+fn join_command(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            } else {
+                arg.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub(crate) fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let commit = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!commit.is_empty()).then_some(commit)
+}
+
+fn hostname() -> Option<String> {
+    #[cfg(target_os = "linux")]
+    if let Ok(hostname) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        return Some(hostname.trim().to_string());
+    }
+
+    if let Ok(hostname) = std::env::var("HOSTNAME").or_else(|_| std::env::var("COMPUTERNAME")) {
+        return Some(hostname);
+    }
+
+    let output = Command::new("hostname").output().ok()?;
+    let hostname = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!hostname.is_empty()).then_some(hostname)
+}
+
+/// Formats a point in time as `YYYY-MM-DD HH:MM:SS UTC`.
+pub(crate) fn utc_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
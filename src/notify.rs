@@ -0,0 +1,26 @@
+//! Desktop notifications for `--notify`.
+
+use crate::{FlamegraphError, Profile};
+
+/// Tells whether profiling succeeded, and where the flamegraph was written, with a desktop
+/// notification. Failing to show it is only a warning.
+pub(crate) fn finished(result: &Result<Profile, FlamegraphError>) {
+    let (summary, body) = match result {
+        Ok(profile) => (
+            "Flamegraph written",
+            match profile.outputs.first() {
+                Some(path) => format!("{} ({} samples)", path.display(), profile.total_samples),
+                None => format!("{} samples", profile.total_samples),
+            },
+        ),
+        Err(err) => ("Profiling failed", err.to_string()),
+    };
+    let shown = notify_rust::Notification::new()
+        .appname("flamegraph")
+        .summary(summary)
+        .body(&body)
+        .show();
+    if let Err(err) = shown {
+        log::warn!("unable to show a desktop notification: {}", err);
+    }
+}
@@ -0,0 +1,331 @@
+#[cfg(feature = "cli")]
+use std::cell::{Cell, RefCell};
+use std::{
+    io::{self, BufRead, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "cli")]
+use indicatif::{HumanBytes, HumanCount, ProgressBar, ProgressStyle};
+
+#[cfg(target_os = "linux")]
+use crate::kallsyms;
+
+/// Steps of generating a flamegraph, in the order they happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Phase {
+    /// Building the workload. Not reported by this crate, which starts from a built program, but
+    /// available to frontends that build before profiling.
+    Building,
+    /// Running the workload under the recorder.
+    Recording,
+    /// Resolving the recorded addresses to symbols (`perf script`, reading the DTrace output),
+    /// and folding the stacks as they are resolved.
+    Symbolizing,
+    /// Running the post-process command on the folded stacks.
+    Collapsing,
+    /// Writing the flamegraph and any additional outputs.
+    Rendering,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Building => "building",
+            Phase::Recording => "recording",
+            Phase::Symbolizing => "symbolizing",
+            Phase::Collapsing => "collapsing",
+            Phase::Rendering => "rendering",
+        }
+    }
+}
+
+/// How far a phase got, passed to [`ProfilerObserver::progress`].
+#[derive(Debug, Clone, Copy, Default)]
+#[non_exhaustive]
+pub struct Progress {
+    /// Stacks read so far. perf reports one stack per sample, DTrace one per distinct stack.
+    pub stacks: u64,
+    /// Size of the recorded data, e.g. perf.data, if known.
+    pub data_size: Option<u64>,
+}
+
+/// Receives progress updates, e.g. to display them in an IDE instead of on the terminal.
+pub trait ProfilerObserver {
+    /// Called when `phase` starts.
+    fn phase_started(&self, _phase: Phase) {}
+
+    /// Called repeatedly while `phase` runs. Currently only reported while symbolizing.
+    fn progress(&self, _phase: Phase, _progress: Progress) {}
+
+    /// Called when `phase` completed successfully, after taking `elapsed`.
+    fn phase_finished(&self, _phase: Phase, _elapsed: Duration) {}
+
+    /// Called once the stacks are folded, with the number of samples in them.
+    fn samples_collected(&self, _samples: u64) {}
+
+    /// Called for every written file, starting with the flamegraph.
+    fn output_written(&self, _path: &Path) {}
+}
+
+/// How progress and log messages are printed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum MessageFormat {
+    /// Text meant to be read on a terminal
+    #[default]
+    Human,
+    /// One JSON object per line on stdout, see [`JsonEvents`]
+    Json,
+}
+
+/// Prints every update as a JSON object on a line of its own to stdout, for scripts and
+/// editors driving the binaries with `--message-format json`. Each object has a `reason`:
+///
+/// - `phase-started` and `phase-finished`, with the `phase` and, when finished, the
+///   `elapsed_secs`
+/// - `progress`, with the `phase` and the `stacks` read so far
+/// - `samples-collected`, with the number of `samples`
+/// - `output-written`, with the `path` of the file
+/// - `message`, with the `level` and `message` of a log message, see
+///   [`logger::set_message_format`](crate::logger::set_message_format)
+#[derive(Debug, Default)]
+pub struct JsonEvents;
+
+impl JsonEvents {
+    /// Prints an event with the given `reason` and the fields of `event`, which must be an
+    /// object.
+    pub(crate) fn emit(reason: &str, event: serde_json::Value) {
+        let mut object = serde_json::Map::new();
+        object.insert("reason".to_string(), reason.into());
+        if let serde_json::Value::Object(fields) = event {
+            object.extend(fields);
+        }
+        let mut stdout = io::stdout().lock();
+        // Like println!, but a closed stdout must not abort profiling.
+        let _ = writeln!(stdout, "{}", serde_json::Value::Object(object));
+        let _ = stdout.flush();
+    }
+}
+
+impl ProfilerObserver for JsonEvents {
+    fn phase_started(&self, phase: Phase) {
+        Self::emit(
+            "phase-started",
+            serde_json::json!({ "phase": phase.name() }),
+        );
+    }
+
+    fn progress(&self, phase: Phase, progress: Progress) {
+        Self::emit(
+            "progress",
+            serde_json::json!({
+                "phase": phase.name(),
+                "stacks": progress.stacks,
+                "data_size": progress.data_size,
+            }),
+        );
+    }
+
+    fn phase_finished(&self, phase: Phase, elapsed: Duration) {
+        Self::emit(
+            "phase-finished",
+            serde_json::json!({ "phase": phase.name(), "elapsed_secs": elapsed.as_secs_f64() }),
+        );
+    }
+
+    fn samples_collected(&self, samples: u64) {
+        Self::emit(
+            "samples-collected",
+            serde_json::json!({ "samples": samples }),
+        );
+    }
+
+    fn output_written(&self, path: &Path) {
+        Self::emit("output-written", serde_json::json!({ "path": path }));
+    }
+}
+
+/// Ignores all progress updates.
+pub(crate) struct Silent;
+
+impl ProfilerObserver for Silent {}
+
+/// Shows a spinner with the number of stacks read while symbolizing, which can take a long
+/// time, and how long each phase after recording took. A spinner that is not finished because
+/// its phase failed is removed from the terminal.
+#[cfg(feature = "cli")]
+#[derive(Default)]
+pub(crate) struct Spinner {
+    spinner: RefCell<Option<ProgressBar>>,
+    stacks: Cell<u64>,
+}
+
+#[cfg(feature = "cli")]
+impl ProfilerObserver for Spinner {
+    fn phase_started(&self, phase: Phase) {
+        if phase != Phase::Symbolizing {
+            return;
+        }
+        let spinner = ProgressBar::new_spinner().with_prefix("Symbolizing");
+        spinner.set_style(
+            ProgressStyle::with_template("{prefix} [{elapsed}]: {spinner:.green} {msg}").unwrap(),
+        );
+        spinner.enable_steady_tick(Duration::from_millis(500));
+        *self.spinner.borrow_mut() = Some(spinner);
+    }
+
+    fn progress(&self, _phase: Phase, progress: Progress) {
+        self.stacks.set(progress.stacks);
+        if let Some(spinner) = &*self.spinner.borrow() {
+            let mut message = format!("{} stacks", HumanCount(progress.stacks));
+            let seconds = spinner.elapsed().as_secs_f64();
+            if seconds >= 1.0 {
+                let rate = (progress.stacks as f64 / seconds) as u64;
+                message.push_str(&format!(" ({}/s)", HumanCount(rate)));
+            }
+            if let Some(size) = progress.data_size {
+                message.push_str(&format!(" from {} of data", HumanBytes(size)));
+            }
+            spinner.set_message(message);
+        }
+    }
+
+    fn phase_finished(&self, phase: Phase, elapsed: Duration) {
+        if let Some(spinner) = self.spinner.borrow_mut().take() {
+            spinner.finish_and_clear();
+        }
+        let seconds = elapsed.as_secs_f64();
+        match phase {
+            Phase::Symbolizing => log::info!(
+                "symbolized {} stacks in {:.2}s",
+                HumanCount(self.stacks.get()),
+                seconds
+            ),
+            Phase::Collapsing => log::debug!("post-processing took {seconds:.2}s"),
+            Phase::Rendering => log::debug!("rendering took {seconds:.2}s"),
+            _ => (),
+        }
+    }
+}
+
+/// Passes the output of [`Backend::script`](crate::Backend::script) through, reporting the
+/// stacks read to an observer and counting the kernel frames perf could not symbolize.
+pub(crate) struct ScriptMonitor<'a, R> {
+    inner: R,
+    observer: &'a dyn ProfilerObserver,
+    reported: Instant,
+    stats: ScriptStats,
+}
+
+struct ScriptStats {
+    progress: Progress,
+    /// Whether the next byte starts a line, and whether that line continues a stack.
+    line_start: bool,
+    in_stack: bool,
+    /// End of the current line, as long as an unresolved kernel frame at most.
+    #[cfg(target_os = "linux")]
+    line_end: Vec<u8>,
+    #[cfg(target_os = "linux")]
+    unresolved: usize,
+}
+
+impl<'a, R: BufRead> ScriptMonitor<'a, R> {
+    pub fn new(inner: R, observer: &'a dyn ProfilerObserver, data_size: Option<u64>) -> Self {
+        ScriptMonitor {
+            inner,
+            observer,
+            reported: Instant::now(),
+            stats: ScriptStats {
+                progress: Progress {
+                    stacks: 0,
+                    data_size,
+                },
+                line_start: true,
+                in_stack: false,
+                #[cfg(target_os = "linux")]
+                line_end: Vec::new(),
+                #[cfg(target_os = "linux")]
+                unresolved: 0,
+            },
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn unresolved(&self) -> usize {
+        self.stats.unresolved
+    }
+
+    /// Reports the stacks read so far to the observer.
+    pub fn report(&mut self) {
+        self.reported = Instant::now();
+        self.observer
+            .progress(Phase::Symbolizing, self.stats.progress);
+    }
+}
+
+impl ScriptStats {
+    /// Stacks are separated by empty lines.
+    fn scan(&mut self, mut data: &[u8]) {
+        while let Some(&first) = data.first() {
+            if self.line_start {
+                if first == b'\n' {
+                    self.in_stack = false;
+                } else if !self.in_stack {
+                    self.in_stack = true;
+                    self.progress.stacks += 1;
+                }
+            }
+            let newline = data.iter().position(|&b| b == b'\n');
+            let line = &data[..newline.unwrap_or(data.len())];
+            #[cfg(target_os = "linux")]
+            {
+                self.line_end.extend_from_slice(line);
+                if newline.is_some() {
+                    if self.line_end.ends_with(kallsyms::UNRESOLVED) {
+                        self.unresolved += 1;
+                    }
+                    self.line_end.clear();
+                } else {
+                    let excess = self
+                        .line_end
+                        .len()
+                        .saturating_sub(kallsyms::UNRESOLVED.len());
+                    self.line_end.drain(..excess);
+                }
+            }
+            self.line_start = newline.is_some();
+            data = &data[(line.len() + 1).min(data.len())..];
+        }
+    }
+}
+
+impl<R: BufRead> Read for ScriptMonitor<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for ScriptMonitor<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // The consumed bytes are still buffered, so this does not read.
+        if let Ok(data) = self.inner.fill_buf() {
+            self.stats.scan(&data[..amt.min(data.len())]);
+        }
+        self.inner.consume(amt);
+
+        if self.reported.elapsed() >= Duration::from_millis(100) {
+            self.report();
+        }
+    }
+}
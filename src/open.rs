@@ -0,0 +1,73 @@
+//! Opening the written files with `--open` and `--open-with`.
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Context};
+
+/// Opens `path` with the default program of the desktop, or with `program` if given.
+///
+/// `program` is split like a shell command line. The absolute path of the file is passed as
+/// the last argument, unless an argument contains the placeholder `{path}` or `{url}`, which is
+/// replaced by the path or its `file://` URL, e.g. for programs that only take URLs.
+pub(crate) fn open(path: &Path, program: Option<&str>) -> anyhow::Result<()> {
+    let Some(program) = program else {
+        return opener::open(path).context(format!("failed to open '{}'", path.display()));
+    };
+
+    let path = absolute(path);
+    let url = file_url(&path);
+    let mut args =
+        shlex::split(program).ok_or_else(|| anyhow!("invalid --open-with command: {program}"))?;
+    if args.is_empty() {
+        return Err(anyhow!("--open-with needs a program"));
+    }
+    let placeholders = args
+        .iter()
+        .any(|arg| arg.contains("{path}") || arg.contains("{url}"));
+    if placeholders {
+        for arg in &mut args {
+            *arg = arg
+                .replace("{path}", &path.to_string_lossy())
+                .replace("{url}", &url);
+        }
+    } else {
+        args.push(path.to_string_lossy().into_owned());
+    }
+
+    log::debug!("opening {:?} with {:?}", path, args);
+    // Graphical programs keep running, so they are not waited for.
+    Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::null())
+        .spawn()
+        .with_context(|| format!("failed to open '{}' with {}", path.display(), args[0]))?;
+    Ok(())
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+    env::current_dir()
+        .map(|dir| dir.join(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// The `file://` URL of the absolute `path`, escaping the characters that would end or
+/// change the meaning of a URL path.
+fn file_url(path: &Path) -> String {
+    let mut url = String::from("file://");
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '%' | ' ' | '#' | '?' | '"' | '<' | '>' => url.push_str(&format!("%{:02X}", c as u32)),
+            #[cfg(windows)]
+            '\\' => url.push('/'),
+            c => url.push(c),
+        }
+    }
+    url
+}
@@ -0,0 +1,139 @@
+//! Smaller SVGs for `--optimize-svg`, which browsers can open even for large profiles.
+
+use std::collections::HashMap;
+
+use crate::folded;
+
+/// Name of the frame replacing the frames too narrow to be seen.
+const OTHER: &str = "[other]";
+
+/// Replaces every frame whose stacks add up to fewer than `min_count` samples, along with the
+/// frames it calls, by an `[other]` frame, so the narrow callees of each frame end up in a
+/// single one. The order of the stacks is kept, for flame charts.
+pub(crate) fn merge_small_frames(data: &[u8], min_count: u64) -> Vec<u8> {
+    let mut prefix_counts: HashMap<&str, u64> = HashMap::new();
+    for (stack, count) in folded::lines(data) {
+        for end in frame_ends(stack) {
+            *prefix_counts.entry(&stack[..end]).or_default() += count;
+        }
+    }
+
+    let mut merged: Vec<(String, u64)> = Vec::new();
+    for (stack, count) in folded::lines(data) {
+        let mut start = 0;
+        let mut merged_stack = None;
+        for end in frame_ends(stack) {
+            if prefix_counts[&stack[..end]] < min_count {
+                merged_stack = Some(format!("{}{OTHER}", &stack[..start]));
+                break;
+            }
+            start = end + 1;
+        }
+        let stack = merged_stack.unwrap_or_else(|| stack.to_string());
+        match merged.last_mut() {
+            Some((last, last_count)) if *last == stack => *last_count += count,
+            _ => merged.push((stack, count)),
+        }
+    }
+
+    let mut out = Vec::new();
+    for (stack, count) in merged {
+        out.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+    }
+    out
+}
+
+/// Ends of the frames of `stack`, i.e. the lengths of its prefixes, from the root.
+fn frame_ends(stack: &str) -> impl Iterator<Item = usize> + '_ {
+    stack
+        .match_indices(';')
+        .map(|(i, _)| i)
+        .chain(std::iter::once(stack.len()))
+}
+
+/// Shortens the attributes of the frames of an SVG written by inferno: coordinates are rounded
+/// to two decimals, which is still well below a pixel, and colors are written in hex. The
+/// script of the SVG reads the exact position of a frame from its `fg:` attributes, which are
+/// kept, so zooming is not affected.
+pub(crate) fn compact_svg(svg: &str) -> String {
+    let mut out = String::with_capacity(svg.len());
+    // inferno writes each frame as `<g><title>...</title><rect .../><text ...>...</text></g>`.
+    for piece in svg.split_inclusive("</g>") {
+        let rect = piece.rfind("<g><title>").and_then(|frame| {
+            piece[frame..]
+                .find("</title><rect ")
+                .map(|rect| frame + rect)
+        });
+        match rect {
+            Some(rect) => {
+                let (title, frame) = piece.split_at(rect);
+                out.push_str(title);
+                compact_frame(frame, &mut out);
+            }
+            None => out.push_str(piece),
+        }
+    }
+    out
+}
+
+/// Compacts the attributes of the `<rect>` and `<text>` tags of a frame, leaving the name in
+/// the text as it is.
+fn compact_frame(mut frame: &str, out: &mut String) {
+    while let Some(tag_start) = frame.find('<') {
+        out.push_str(&frame[..tag_start]);
+        let tag_end = frame[tag_start..]
+            .find('>')
+            .map_or(frame.len(), |end| tag_start + end + 1);
+        compact_attributes(&frame[tag_start..tag_end], out);
+        frame = &frame[tag_end..];
+    }
+    out.push_str(frame);
+}
+
+fn compact_attributes(mut tag: &str, out: &mut String) {
+    while let Some(value_start) = tag.find("=\"") {
+        let name_start = tag[..value_start].rfind(' ').map_or(0, |space| space + 1);
+        let name = &tag[name_start..value_start];
+        let rest = &tag[value_start + 2..];
+        let Some(value_len) = rest.find('"') else {
+            break;
+        };
+        let value = &rest[..value_len];
+        out.push_str(&tag[..value_start + 2]);
+        match name {
+            "x" | "y" | "width" => out.push_str(&round_number(value)),
+            "fill" => out.push_str(&hex_color(value).unwrap_or_else(|| value.to_string())),
+            _ => out.push_str(value),
+        }
+        out.push('"');
+        tag = &rest[value_len + 1..];
+    }
+    out.push_str(tag);
+}
+
+/// `value`, a number optionally followed by `%`, with at most two decimals.
+fn round_number(value: &str) -> String {
+    let (number, unit) = match value.strip_suffix('%') {
+        Some(number) => (number, "%"),
+        None => (value, ""),
+    };
+    let Ok(number) = number.parse::<f64>() else {
+        return value.to_string();
+    };
+    let rounded = format!("{number:.2}");
+    let rounded = rounded.trim_end_matches('0').trim_end_matches('.');
+    match rounded {
+        "-0" => format!("0{unit}"),
+        rounded => format!("{rounded}{unit}"),
+    }
+}
+
+/// `rgb(r,g,b)` as `#rrggbb`.
+fn hex_color(value: &str) -> Option<String> {
+    let channels = value.strip_prefix("rgb(")?.strip_suffix(')')?;
+    let mut hex = String::from("#");
+    for channel in channels.split(',') {
+        hex.push_str(&format!("{:02x}", channel.trim().parse::<u8>().ok()?));
+    }
+    (hex.len() == 7).then_some(hex)
+}
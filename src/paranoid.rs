@@ -0,0 +1,64 @@
+//! Detection of a `kernel.perf_event_paranoid` setting that keeps perf from
+//! recording, so users learn about it before their program is built and run.
+
+use std::os::unix::fs::MetadataExt;
+
+use anyhow::anyhow;
+
+use crate::sysctl;
+
+pub(crate) const PARANOID: &str = "kernel.perf_event_paranoid";
+
+/// Whether flamegraph itself runs as root, in which case the setting does not apply.
+pub(crate) fn is_root() -> bool {
+    std::fs::metadata("/proc/self").is_ok_and(|proc| proc.uid() == 0)
+}
+
+/// Highest `perf_event_paranoid` level at which the `perf record` arguments `args` work
+/// without root privileges. Kernel events such as tracepoints need a level of at most 1, user
+/// space sampling works up to level 2.
+pub(crate) fn required_level(args: &str) -> i32 {
+    let mut args = args.split_whitespace();
+    while let Some(arg) = args.next() {
+        let events = match arg {
+            "-e" | "--event" => args.next().unwrap_or_default(),
+            arg => match arg.strip_prefix("--event=") {
+                Some(events) => events,
+                None => continue,
+            },
+        };
+        if events.split(',').any(is_kernel_event) {
+            return 1;
+        }
+    }
+    2
+}
+
+/// Tracepoints are named `subsystem:event`, unlike the `event:modifiers` syntax, e.g.
+/// `cycles:u`.
+fn is_kernel_event(event: &str) -> bool {
+    match event.split_once(':') {
+        Some((_, modifiers)) => !modifiers.chars().all(|c| "ukhHpPGSDIW".contains(c)),
+        None => false,
+    }
+}
+
+/// Returns the current level if it is higher than `required` and the recorder does not run as
+/// root.
+pub(crate) fn too_high(required: i32, as_root: bool) -> Option<i32> {
+    if as_root || is_root() {
+        return None;
+    }
+    sysctl::read(PARANOID).filter(|&level| level > required)
+}
+
+/// Error explaining how to get past a `perf_event_paranoid` level that is too high.
+pub(crate) fn error(level: i32, required: i32) -> anyhow::Error {
+    anyhow!(
+        "kernel.perf_event_paranoid is {level}, which keeps perf from recording without root \
+         privileges (at most {required} is required). Either\n\
+         \x20 - lower it until the next reboot: sudo sysctl kernel.perf_event_paranoid={required}\n\
+         \x20 - run perf with root privileges: pass --root\n\
+         \x20 - let flamegraph lower it while recording: pass --fix-paranoid"
+    )
+}
@@ -0,0 +1,201 @@
+//! Energy-weighted flamegraphs for `--power`.
+//!
+//! RAPL energy counters cannot be sampled with call stacks, so the energy consumed by the CPU
+//! packages is read from the powercap interface while perf samples stacks as usual. The energy
+//! of each interval between two readings is then split among the samples taken during it.
+
+use std::{
+    fs,
+    io::BufRead,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+
+use crate::backend::perf::{clock_time, sample_time, Headers};
+
+const POWERCAP: &str = "/sys/class/powercap";
+
+/// Time between two readings of the energy counters.
+const INTERVAL: Duration = Duration::from_millis(10);
+
+/// Energy consumed by all packages since the meter started, in microjoules, at a time in
+/// nanoseconds since the Unix epoch.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Reading {
+    time: u64,
+    energy: u64,
+}
+
+/// The energy counter of a CPU package, which wraps around at `range`.
+struct Zone {
+    path: PathBuf,
+    range: u64,
+    last: u64,
+}
+
+impl Zone {
+    fn read(&self) -> anyhow::Result<u64> {
+        let energy = fs::read_to_string(&self.path)
+            .with_context(|| format!("unable to read {}", self.path.display()))?;
+        energy
+            .trim()
+            .parse()
+            .with_context(|| format!("unexpected content of {}", self.path.display()))
+    }
+
+    /// Energy consumed since the last reading.
+    fn consumed(&mut self) -> u64 {
+        let Ok(energy) = self.read() else { return 0 };
+        let consumed = if energy >= self.last {
+            energy - self.last
+        } else {
+            self.range - self.last + energy
+        };
+        self.last = energy;
+        consumed
+    }
+}
+
+/// The package zones of the powercap interface, e.g. `intel-rapl:0`, which AMD processors
+/// provide as well. Their subzones and the platform (`psys`) zone overlap with them.
+fn package_zones() -> anyhow::Result<Vec<Zone>> {
+    let mut zones = Vec::new();
+    let entries = fs::read_dir(POWERCAP).with_context(|| {
+        format!("unable to list {POWERCAP}; --power needs RAPL energy counters")
+    })?;
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        let name = fs::read_to_string(dir.join("name")).unwrap_or_default();
+        if !name.starts_with("package") {
+            continue;
+        }
+        let mut zone = Zone {
+            path: dir.join("energy_uj"),
+            range: fs::read_to_string(dir.join("max_energy_range_uj"))
+                .ok()
+                .and_then(|range| range.trim().parse().ok())
+                .unwrap_or(u64::MAX),
+            last: 0,
+        };
+        // The meter reads the counters in this process, so --root cannot help.
+        zone.last = zone.read().context(
+            "reading energy counters needs root privileges since Linux 5.10; run flamegraph \
+             itself as root for --power",
+        )?;
+        zones.push(zone);
+    }
+    if zones.is_empty() {
+        bail!("no CPU package energy counters found in {POWERCAP}");
+    }
+    Ok(zones)
+}
+
+/// Fails unless the energy counters can be read by this process.
+pub(crate) fn check() -> anyhow::Result<()> {
+    package_zones().map(drop)
+}
+
+/// Reads the energy counters in the background until it is finished.
+pub(crate) struct Meter {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Vec<Reading>>,
+}
+
+impl Meter {
+    pub fn start() -> anyhow::Result<Self> {
+        let mut zones = package_zones()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                let mut energy = 0;
+                let mut readings = vec![Reading {
+                    time: clock_time(),
+                    energy,
+                }];
+                while !stop.load(Ordering::Relaxed) {
+                    thread::sleep(INTERVAL);
+                    energy += zones.iter_mut().map(Zone::consumed).sum::<u64>();
+                    readings.push(Reading {
+                        time: clock_time(),
+                        energy,
+                    });
+                }
+                readings
+            }
+        });
+        Ok(Meter { stop, thread })
+    }
+
+    pub fn finish(self) -> Vec<Reading> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+/// Rewrites the periods of the samples in `script` to the energy in microjoules consumed while
+/// they were taken, dropping samples taken outside of the `readings`.
+pub(crate) fn weigh(script: impl BufRead, readings: &[Reading]) -> anyhow::Result<Vec<u8>> {
+    let lines = script
+        .lines()
+        .collect::<Result<Vec<_>, _>>()
+        .context("unable to read the perf script output")?;
+
+    // The interval of a sample is the index of the reading ending it.
+    let interval = |time: u64| {
+        let index = readings.partition_point(|reading| reading.time <= time);
+        (index > 0 && index < readings.len()).then_some(index)
+    };
+    let mut samples = vec![0u64; readings.len()];
+    let mut headers = Headers::default();
+    for line in lines.iter().filter(|line| headers.is_header(line)) {
+        if let Some(index) = sample_time(line).and_then(|(_, time)| interval(time)) {
+            samples[index] += 1;
+        }
+    }
+
+    // The energy of an interval is split evenly, the remainder going to its first samples.
+    let mut given = vec![0u64; readings.len()];
+    let mut output = Vec::new();
+    let mut keep = true;
+    let mut total = 0;
+    let mut headers = Headers::default();
+    for line in &lines {
+        let mut line = line.clone();
+        if headers.is_header(&line) {
+            let weighed = sample_time(&line).and_then(|(end, time)| {
+                let index = interval(time)?;
+                let energy = readings[index].energy - readings[index - 1].energy;
+                let weight =
+                    energy / samples[index] + u64::from(given[index] < energy % samples[index]);
+                given[index] += 1;
+                // Replace the period following the timestamp, if there is one.
+                let rest = line[end..].trim_start();
+                let rest = match rest.split_once(' ') {
+                    Some((period, event)) if period.bytes().all(|b| b.is_ascii_digit()) => event,
+                    _ => rest,
+                };
+                total += weight;
+                Some(format!("{} {weight} {rest}", &line[..end]))
+            });
+            keep = weighed.is_some();
+            line = weighed.unwrap_or(line);
+        }
+        if keep {
+            output.extend_from_slice(line.as_bytes());
+            output.push(b'\n');
+        }
+    }
+    log::info!(
+        "{:.3} J consumed by the CPU packages while the samples were taken",
+        total as f64 / 1e6
+    );
+    Ok(output)
+}
@@ -0,0 +1,75 @@
+use std::str::FromStr;
+
+use inferno::flamegraph::color::Palette;
+
+/// Bundles of recorder settings for common kinds of analysis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Preset {
+    /// On-CPU time, sampled at the configured frequency
+    Cpu,
+    /// Stacks that were switched off the CPU, weighted by context switches
+    Offcpu,
+    /// Stacks that grew memory, weighted by page faults (Linux) or malloc calls
+    Alloc,
+    /// Stacks that caused last level cache misses (Linux only)
+    Cache,
+    /// High frequency sampling for short-lived programs
+    Startup,
+}
+
+impl Preset {
+    /// Sampling frequency used by the startup preset.
+    const STARTUP_FREQUENCY: u32 = 9973;
+
+    /// The `perf record` command implementing this preset.
+    pub fn perf_command(self, freq: u32) -> String {
+        let call_graph = "--call-graph dwarf,16384 -g";
+        match self {
+            Preset::Cpu => format!("record -F {freq} {call_graph}"),
+            Preset::Offcpu => format!("record -e sched:sched_switch -c 1 {call_graph}"),
+            Preset::Alloc => format!("record -e page-faults -c 1 {call_graph}"),
+            Preset::Cache => format!("record -e cache-misses -c 10000 {call_graph}"),
+            Preset::Startup => format!("record -F {} {call_graph}", Self::STARTUP_FREQUENCY),
+        }
+    }
+
+    /// The DTrace script implementing this preset.
+    pub fn dtrace_command(self, freq: u32) -> anyhow::Result<String> {
+        let aggregate = "{ @[ustack(100)] = count(); }";
+        Ok(match self {
+            Preset::Cpu => format!("profile-{freq} /pid == $target/ {aggregate}"),
+            Preset::Offcpu => format!("sched:::off-cpu /pid == $target/ {aggregate}"),
+            Preset::Alloc => format!("pid$target::malloc:entry {aggregate}"),
+            Preset::Cache => {
+                return Err(anyhow::anyhow!(
+                    "the cache preset is only supported by the perf backend"
+                ))
+            }
+            Preset::Startup => format!(
+                "profile-{} /pid == $target/ {aggregate}",
+                Self::STARTUP_FREQUENCY
+            ),
+        })
+    }
+
+    /// What a sample of this preset counts.
+    pub fn count_name(self) -> &'static str {
+        match self {
+            Preset::Cpu | Preset::Startup => "samples",
+            Preset::Offcpu => "context switches",
+            Preset::Alloc if cfg!(target_os = "linux") => "page faults",
+            Preset::Alloc => "allocations",
+            Preset::Cache => "cache misses",
+        }
+    }
+
+    pub fn palette(self) -> Option<Palette> {
+        let name = match self {
+            Preset::Offcpu => "io",
+            Preset::Alloc | Preset::Cache => "mem",
+            Preset::Cpu | Preset::Startup => return None,
+        };
+        Palette::from_str(name).ok()
+    }
+}
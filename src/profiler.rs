@@ -0,0 +1,441 @@
+//! Sampling the current process without an external recorder.
+//!
+//! A `SIGPROF` timer interrupts whichever thread is using the CPU, and the signal handler
+//! unwinds the interrupted stack into a fixed set of slots. A collector thread empties the
+//! slots while profiling, so the handler never allocates. Addresses are only resolved to
+//! symbols when the profile is written.
+//!
+//! Unwinding with DWARF info takes locks and allocates, which is not safe in a signal handler,
+//! so the handler follows the chain of frame pointers instead. Stacks are only complete if the
+//! program and its libraries keep frame pointers, e.g. built with `-C force-frame-pointers=yes`;
+//! otherwise they are cut off at the first function without one. Frames are read with
+//! `process_vm_readv` on Linux, so a broken chain cannot crash the program. Only x86_64 and
+//! aarch64 on Linux and macOS are unwound; elsewhere no samples are taken.
+
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    mem,
+    path::PathBuf,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::{
+    export::{Exporter, Svg},
+    folded, FlamegraphError, FlamegraphOptions, Profile,
+};
+
+/// Deepest stack recorded; deeper stacks are cut off at the root.
+const MAX_DEPTH: usize = 128;
+/// Samples that can be waiting for the collector. Samples taken while all slots are full are
+/// dropped.
+const SLOTS: usize = 1024;
+
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const FULL: u8 = 2;
+
+struct Slot {
+    state: AtomicU8,
+    depth: AtomicUsize,
+    frames: [AtomicUsize; MAX_DEPTH],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_FRAME: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    state: AtomicU8::new(EMPTY),
+    depth: AtomicUsize::new(0),
+    frames: [EMPTY_FRAME; MAX_DEPTH],
+};
+
+static SAMPLES: [Slot; SLOTS] = [EMPTY_SLOT; SLOTS];
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+static LOST: AtomicU64 = AtomicU64::new(0);
+/// Whether a profiler is running; there can only be one per process.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Settings of [`ProfilerGuard::start`].
+#[derive(Debug, Clone)]
+pub struct ProfilerOptions {
+    /// Where the flamegraph is written.
+    pub output: PathBuf,
+    /// Sampling frequency in Hz.
+    pub frequency: u32,
+    pub flamegraph_options: FlamegraphOptions,
+}
+
+impl Default for ProfilerOptions {
+    fn default() -> Self {
+        ProfilerOptions {
+            output: "flamegraph.svg".into(),
+            frequency: 997,
+            flamegraph_options: FlamegraphOptions::default(),
+        }
+    }
+}
+
+/// Samples the current process until it is finished or dropped, and then writes the
+/// flamegraph.
+///
+/// Only one profiler can run at a time, and it replaces any `SIGPROF` handler and
+/// `ITIMER_PROF` timer of the process while it runs.
+pub struct ProfilerGuard {
+    options: ProfilerOptions,
+    previous_action: libc::sigaction,
+    collector: Option<JoinHandle<HashMap<Vec<usize>, u64>>>,
+}
+
+impl ProfilerGuard {
+    pub fn start(options: ProfilerOptions) -> Result<Self, FlamegraphError> {
+        if options.frequency == 0 {
+            return Err(anyhow::anyhow!("the sampling frequency must be positive").into());
+        }
+        if ACTIVE.swap(true, Ordering::SeqCst) {
+            return Err(anyhow::anyhow!("a profiler is already running in this process").into());
+        }
+        for slot in &SAMPLES {
+            slot.state.store(EMPTY, Ordering::Release);
+        }
+        LOST.store(0, Ordering::Relaxed);
+
+        let collector = thread::Builder::new()
+            .name("flamegraph-profiler".to_string())
+            .spawn(collect)
+            .context("unable to start the sample collector")
+            .map_err(|err| {
+                ACTIVE.store(false, Ordering::SeqCst);
+                FlamegraphError::RecordFailed(err)
+            })?;
+
+        let mut previous_action: libc::sigaction = unsafe { mem::zeroed() };
+        let installed = unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_sigprof as SigAction as usize;
+            action.sa_flags = libc::SA_RESTART | libc::SA_SIGINFO;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGPROF, &action, &mut previous_action)
+        };
+        if installed != 0 {
+            let err = io::Error::last_os_error();
+            return Err(abandon(
+                collector,
+                anyhow::Error::new(err).context("unable to install the SIGPROF handler"),
+            ));
+        }
+
+        let period = (1_000_000 / options.frequency).max(1);
+        let interval = libc::timeval {
+            tv_sec: (period / 1_000_000) as libc::time_t,
+            tv_usec: (period % 1_000_000) as libc::suseconds_t,
+        };
+        if let Err(err) = set_timer(interval) {
+            unsafe {
+                libc::sigaction(libc::SIGPROF, &previous_action, ptr::null_mut());
+            }
+            return Err(abandon(
+                collector,
+                anyhow::Error::new(err).context("unable to start the ITIMER_PROF timer"),
+            ));
+        }
+        log::debug!("sampling the current process at {} Hz", options.frequency);
+
+        Ok(ProfilerGuard {
+            options,
+            previous_action,
+            collector: Some(collector),
+        })
+    }
+
+    /// Stops sampling and writes the flamegraph. Dropping the guard does the same, but can only
+    /// log errors.
+    pub fn finish(mut self) -> Result<Profile, FlamegraphError> {
+        self.write()
+    }
+
+    fn write(&mut self) -> Result<Profile, FlamegraphError> {
+        let stacks = self.stop();
+        let lost = LOST.load(Ordering::Relaxed);
+        if lost > 0 {
+            log::warn!("{lost} samples were dropped because the collector fell behind");
+        }
+
+        let collapsed = collapse(&stacks);
+        if collapsed.is_empty() {
+            log::warn!("no samples were taken; did the profiled code run long enough?");
+        }
+
+        let output = &self.options.output;
+        log::info!("writing flamegraph to {:?}", output);
+        let file = File::create(output)
+            .with_context(|| format!("unable to create {}", output.display()))?;
+        let mut writer = BufWriter::new(file);
+        Svg::default()
+            .export(&collapsed, &self.options.flamegraph_options, &mut writer)
+            .and_then(|()| Ok(writer.flush()?))
+            .map_err(FlamegraphError::RenderFailed)?;
+
+        Ok(Profile {
+            total_samples: folded::total_samples(&collapsed),
+            collapsed,
+            outputs: vec![output.clone()],
+            exit_status: None,
+        })
+    }
+
+    /// Disables the timer and hands back the recorded stacks; empty if already stopped.
+    fn stop(&mut self) -> HashMap<Vec<usize>, u64> {
+        let collector = match self.collector.take() {
+            Some(collector) => collector,
+            None => return HashMap::new(),
+        };
+
+        if let Err(err) = set_timer(libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        }) {
+            log::warn!("unable to stop the ITIMER_PROF timer: {err}");
+        }
+        ACTIVE.store(false, Ordering::SeqCst);
+        unsafe {
+            // Ignoring the signal discards any still pending, which must not reach the previous
+            // action, e.g. terminate the process.
+            let mut ignore: libc::sigaction = mem::zeroed();
+            ignore.sa_sigaction = libc::SIG_IGN;
+            libc::sigemptyset(&mut ignore.sa_mask);
+            libc::sigaction(libc::SIGPROF, &ignore, ptr::null_mut());
+            libc::sigaction(libc::SIGPROF, &self.previous_action, ptr::null_mut());
+        }
+
+        collector.join().unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for ProfilerGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProfilerGuard")
+            .field("options", &self.options)
+            .field("running", &self.collector.is_some())
+            .finish()
+    }
+}
+
+impl Drop for ProfilerGuard {
+    fn drop(&mut self) {
+        if self.collector.is_some() {
+            if let Err(err) = self.write() {
+                log::error!("{:?}", anyhow::Error::from(err));
+            }
+        }
+    }
+}
+
+/// Runs `f` while sampling the current process with the default [`ProfilerOptions`], which
+/// writes `flamegraph.svg`. Failures to profile are logged, `f` runs either way.
+pub fn profile<T>(f: impl FnOnce() -> T) -> T {
+    let guard = ProfilerGuard::start(ProfilerOptions::default());
+    if let Err(err) = &guard {
+        log::error!("unable to profile: {err}");
+    }
+    let result = f();
+    drop(guard);
+    result
+}
+
+/// Stops the collector of a profiler that failed to start, returning `err` to report.
+fn abandon(collector: JoinHandle<HashMap<Vec<usize>, u64>>, err: anyhow::Error) -> FlamegraphError {
+    ACTIVE.store(false, Ordering::SeqCst);
+    let _ = collector.join();
+    FlamegraphError::RecordFailed(err)
+}
+
+fn set_timer(interval: libc::timeval) -> io::Result<()> {
+    let timer = libc::itimerval {
+        it_interval: interval,
+        it_value: interval,
+    };
+    if unsafe { libc::setitimer(libc::ITIMER_PROF, &timer, ptr::null_mut()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+type SigAction = extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut c_void);
+
+extern "C" fn on_sigprof(_: libc::c_int, _: *mut libc::siginfo_t, context: *mut c_void) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let slot = &SAMPLES[NEXT_SLOT.fetch_add(1, Ordering::Relaxed) % SLOTS];
+    if slot
+        .state
+        .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        LOST.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let mut depth = 0;
+    if let Some((pc, mut fp)) = unsafe { registers(context) } {
+        // Symbols are resolved for the instruction before the address, as return addresses
+        // point after the call. Only the interrupted instruction itself is not a return address.
+        slot.frames[0].store(pc + 1, Ordering::Relaxed);
+        depth = 1;
+        // Each frame starts with the frame pointer of its caller, followed by the return address.
+        while depth < MAX_DEPTH {
+            let Some([caller_fp, return_address]) = read_frame(fp) else {
+                break;
+            };
+            if return_address == 0 {
+                break;
+            }
+            slot.frames[depth].store(return_address, Ordering::Relaxed);
+            depth += 1;
+            // The stack grows down, so callers' frames are at higher addresses.
+            if caller_fp <= fp {
+                break;
+            }
+            fp = caller_fp;
+        }
+    }
+
+    slot.depth.store(depth, Ordering::Relaxed);
+    let state = if depth > 0 { FULL } else { EMPTY };
+    slot.state.store(state, Ordering::Release);
+}
+
+/// The interrupted instruction and frame pointer, read from the `ucontext_t` passed to the
+/// signal handler.
+#[allow(unused_variables)]
+unsafe fn registers(context: *mut c_void) -> Option<(usize, usize)> {
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    {
+        let gregs = &(*(context as *const libc::ucontext_t)).uc_mcontext.gregs;
+        return Some((
+            gregs[libc::REG_RIP as usize] as usize,
+            gregs[libc::REG_RBP as usize] as usize,
+        ));
+    }
+    #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+    {
+        let mcontext = &(*(context as *const libc::ucontext_t)).uc_mcontext;
+        return Some((mcontext.pc as usize, mcontext.regs[29] as usize));
+    }
+    #[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+    {
+        let state = &(*(*(context as *const libc::ucontext_t)).uc_mcontext).__ss;
+        return Some((state.__rip as usize, state.__rbp as usize));
+    }
+    #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+    {
+        let state = &(*(*(context as *const libc::ucontext_t)).uc_mcontext).__ss;
+        return Some((state.__pc as usize, state.__fp as usize));
+    }
+    #[allow(unreachable_code)]
+    None
+}
+
+/// The frame pointer of the caller and the return address saved at the frame pointer `fp`, or
+/// `None` if they cannot be read.
+fn read_frame(fp: usize) -> Option<[usize; 2]> {
+    if fp == 0 || fp % mem::align_of::<usize>() != 0 {
+        return None;
+    }
+    let mut frame = [0usize; 2];
+    #[cfg(target_os = "linux")]
+    {
+        // Unlike a plain read, the system call fails instead of faulting on unmapped memory.
+        let local = libc::iovec {
+            iov_base: frame.as_mut_ptr().cast(),
+            iov_len: mem::size_of_val(&frame),
+        };
+        let remote = libc::iovec {
+            iov_base: fp as *mut c_void,
+            iov_len: mem::size_of_val(&frame),
+        };
+        let read = unsafe { libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0) };
+        if read != mem::size_of_val(&frame) as isize {
+            return None;
+        }
+    }
+    // Frame pointers are required by the ABI on macOS, so the chain can be followed directly.
+    #[cfg(not(target_os = "linux"))]
+    {
+        frame = unsafe { ptr::read(fp as *const [usize; 2]) };
+    }
+    Some(frame)
+}
+
+/// Empties the sample slots into a count per stack until sampling stops.
+fn collect() -> HashMap<Vec<usize>, u64> {
+    let mut stacks = HashMap::new();
+    loop {
+        let active = ACTIVE.load(Ordering::SeqCst);
+        for slot in &SAMPLES {
+            if slot.state.load(Ordering::Acquire) != FULL {
+                continue;
+            }
+            let depth = slot.depth.load(Ordering::Relaxed);
+            let stack: Vec<_> = slot.frames[..depth]
+                .iter()
+                .map(|frame| frame.load(Ordering::Relaxed))
+                .collect();
+            slot.state.store(EMPTY, Ordering::Release);
+            *stacks.entry(stack).or_insert(0) += 1;
+        }
+        if !active {
+            return stacks;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Resolves the recorded addresses and folds the stacks, starting at the root.
+fn collapse(stacks: &HashMap<Vec<usize>, u64>) -> Vec<u8> {
+    let mut symbols = HashMap::new();
+    for &ip in stacks.keys().flatten() {
+        symbols.entry(ip).or_insert_with(|| resolve(ip));
+    }
+
+    let mut lines: HashMap<String, u64> = HashMap::new();
+    for (stack, count) in stacks {
+        // Inlined functions are resolved innermost first.
+        let frames: Vec<_> = stack
+            .iter()
+            .rev()
+            .flat_map(|ip| symbols[ip].iter().rev().map(String::as_str))
+            .collect();
+        *lines.entry(frames.join(";")).or_insert(0) += count;
+    }
+
+    let mut lines: Vec<_> = lines.into_iter().collect();
+    lines.sort();
+    let mut collapsed = Vec::new();
+    for (stack, count) in lines {
+        collapsed.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+    }
+    collapsed
+}
+
+fn resolve(ip: usize) -> Vec<String> {
+    let mut names = Vec::new();
+    backtrace::resolve(ip as *mut c_void, |symbol| {
+        if let Some(name) = symbol.name() {
+            names.push(format!("{name:#}").replace(';', ":"));
+        }
+    });
+    if names.is_empty() {
+        names.push(format!("{ip:#x}"));
+    }
+    names
+}
@@ -0,0 +1,123 @@
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::folded;
+
+/// Functions below this share of the baseline's samples (in percent) are not
+/// tracked, since their relative change is dominated by sampling noise.
+pub(crate) const MIN_TRACKED_SHARE: f64 = 0.5;
+
+struct Change {
+    function: String,
+    baseline: f64,
+    current: f64,
+}
+
+impl Change {
+    /// Relative change of the sample share, in percent.
+    fn relative(&self) -> f64 {
+        (self.current - self.baseline) / self.baseline * 100.0
+    }
+}
+
+/// Parses a percentage such as `10%` or `2.5`.
+pub(crate) fn parse_threshold(s: &str) -> Result<f64, String> {
+    let value: f64 = s
+        .trim()
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("invalid percentage '{s}'"))?;
+    if !value.is_finite() {
+        return Err(format!("percentage must be a finite number, got '{s}'"));
+    }
+    if value < 0.0 {
+        return Err(format!("percentage must not be negative, got '{s}'"));
+    }
+    Ok(value)
+}
+
+/// Compares the collapsed stacks of this run against a stored baseline and fails
+/// if the inclusive sample share of any tracked function grew by more than
+/// `threshold` percent.
+pub(crate) fn check(baseline_path: &Path, collapsed: &[u8], threshold: f64) -> anyhow::Result<()> {
+    let baseline = std::fs::read(baseline_path).with_context(|| {
+        format!(
+            "unable to read baseline stacks from '{}'",
+            baseline_path.display()
+        )
+    })?;
+    anyhow::ensure!(
+        folded::total_samples(&baseline) > 0,
+        "baseline '{}' does not contain any samples",
+        baseline_path.display()
+    );
+    anyhow::ensure!(
+        folded::total_samples(collapsed) > 0,
+        "no samples were collected in this run"
+    );
+
+    let regressions = compare(&baseline, collapsed, threshold);
+    anyhow::ensure!(
+        regressions == 0,
+        "{} function(s) regressed by more than {}% compared to '{}'",
+        regressions,
+        threshold,
+        baseline_path.display()
+    );
+
+    Ok(())
+}
+
+/// Prints how the inclusive sample share of each tracked function changed from `baseline` to
+/// `collapsed`, and returns the number of functions that grew by more than `threshold` percent.
+/// Both must contain samples.
+pub(crate) fn compare(baseline: &[u8], collapsed: &[u8], threshold: f64) -> usize {
+    let baseline_total = folded::total_samples(baseline);
+    let current_total = folded::total_samples(collapsed);
+    let current = folded::inclusive_counts(collapsed);
+    let mut changes: Vec<_> = folded::inclusive_counts(baseline)
+        .into_iter()
+        .map(|(function, count)| {
+            let baseline = count as f64 * 100.0 / baseline_total as f64;
+            let current =
+                current.get(&function).copied().unwrap_or(0) as f64 * 100.0 / current_total as f64;
+            Change {
+                function,
+                baseline,
+                current,
+            }
+        })
+        .filter(|change| change.baseline >= MIN_TRACKED_SHARE)
+        .collect();
+    changes.sort_by(|a, b| b.relative().total_cmp(&a.relative()));
+
+    let regressions = changes
+        .iter()
+        .filter(|change| change.relative() > threshold)
+        .count();
+
+    // Logged as one message so the table stays together in `--message-format json`.
+    let mut table = vec![format!(
+        "{:>10} {:>10} {:>10}  function",
+        "baseline", "current", "change"
+    )];
+    table.extend(changes.iter().map(|change| {
+        let marker = if change.relative() > threshold {
+            "!"
+        } else {
+            " "
+        };
+        format!(
+            "{:>9.2}% {:>9.2}% {:>+9.1}%{} {}",
+            change.baseline,
+            change.current,
+            change.relative(),
+            marker,
+            change.function
+        )
+    }));
+    log::info!("{}", table.join("\n"));
+
+    regressions
+}
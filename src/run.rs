@@ -0,0 +1,1118 @@
+//! The steps of generating a flamegraph, shared by the blocking and the asynchronous entry
+//! points.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Context};
+use inferno::flamegraph::color::Palette;
+#[cfg(unix)]
+use signal_hook::{consts::SIGINT, SigId};
+
+#[cfg(target_os = "macos")]
+use crate::entitlements;
+#[cfg(target_os = "linux")]
+use crate::{backend::perf, capabilities, frame_pointers, kallsyms, paranoid, power, sysctl};
+use crate::{
+    cache, chown_to_user, crates, escalate::Root, export, fold_std, folded, gallery, idle, libtest,
+    metadata, observer::ScriptMonitor, regression, sandwich, sibling_path, source_links, stripped,
+    variance, Backend, ExportFormat, Exporter, FlamegraphError, InputFormat, MessageFormat,
+    Options, Phase, Profile, ProfilerObserver, RecordSettings, Recording, Symbolizer, Workload,
+};
+
+/// A run after its options have been resolved.
+pub(crate) struct Run {
+    opts: Options,
+    metadata: metadata::RunMetadata,
+    /// The profiled binary, needed to resolve source locations after the workload has been
+    /// consumed.
+    binary: Option<PathBuf>,
+    /// Stacks that are rendered as they are instead of being symbolized from a recording.
+    folded: Option<PathBuf>,
+    /// The format `folded` is converted from, if it is the profile of another tool.
+    import: Option<InputFormat>,
+    /// Whether the profiled binary keeps frame pointers.
+    frame_pointers: bool,
+    backend: Arc<dyn Backend + Send + Sync>,
+    root_flags: Option<String>,
+    /// The words of the `--escalation-cmd` template.
+    escalation_cmd: Option<Vec<String>>,
+    /// The copy of perf given the capabilities of `--capabilities`.
+    #[cfg(target_os = "linux")]
+    perf: Option<PathBuf>,
+    /// Energy readings of the last recording with `--power`, taken when it is symbolized.
+    #[cfg(target_os = "linux")]
+    energy: Mutex<Option<Vec<power::Reading>>>,
+    /// Tests that ran during the last recording with `--per-test`.
+    tests: Mutex<Vec<libtest::TestRun>>,
+}
+
+/// Keeps the system prepared for the recorder while it runs.
+pub(crate) struct Recorder {
+    #[cfg(target_os = "linux")]
+    lowered_paranoid: Option<sysctl::Override>,
+    #[cfg(unix)]
+    handler: SigId,
+    #[cfg(target_os = "linux")]
+    meter: Option<power::Meter>,
+    #[cfg(target_os = "linux")]
+    uprobe: Option<perf::Uprobe>,
+    started: Instant,
+}
+
+impl Run {
+    pub fn new(workload: &Workload, mut opts: Options) -> Result<Self, FlamegraphError> {
+        let root_flags = opts.root.clone().flatten();
+        let escalation_cmd = opts
+            .escalation_cmd
+            .as_deref()
+            .map(crate::escalate::parse_template)
+            .transpose()?;
+
+        // The capable copy of perf is used for all perf invocations of this run, in place of a
+        // perf binary given in $PERF.
+        #[cfg(target_os = "linux")]
+        let capable_perf = if opts.capabilities && !opts.dry_run {
+            let escalation = Root {
+                tool: opts.escalate.unwrap_or_default(),
+                flags: root_flags.as_deref(),
+                custom: escalation_cmd.as_deref(),
+            };
+            Some(capabilities::perf(escalation)?)
+        } else {
+            None
+        };
+
+        if let Some(preset) = opts.preset {
+            let flamegraph_options = &mut opts.flamegraph_options;
+            if flamegraph_options.count_name.is_none() {
+                flamegraph_options.count_name = Some(preset.count_name().to_string());
+            }
+            if flamegraph_options.palette.is_none() {
+                flamegraph_options.palette = preset.palette();
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        let perf = opts.uses_perf();
+        #[cfg(not(target_os = "linux"))]
+        let perf = false;
+        if opts.sched_latency {
+            if !perf {
+                return Err(anyhow!("--sched-latency needs the perf backend").into());
+            }
+            if opts.flamegraph_options.count_name.is_none() {
+                opts.flamegraph_options.count_name = Some("µs waiting".to_string());
+            }
+        }
+
+        if opts.split_pids && !perf {
+            return Err(anyhow!("--split-pids needs the perf backend").into());
+        }
+
+        if opts.max_samples.is_some() && !perf {
+            return Err(anyhow!("--max-samples needs the perf backend").into());
+        }
+        if opts.max_samples == Some(0) {
+            return Err(anyhow!("--max-samples must be positive").into());
+        }
+
+        if opts.trim_before.is_some() && !perf {
+            return Err(anyhow!("--trim-before needs the perf backend").into());
+        }
+
+        if opts.cache_build_ids && !perf {
+            return Err(anyhow!("--cache-build-ids needs the perf backend").into());
+        }
+
+        if opts.per_test {
+            if !perf {
+                return Err(anyhow!("--per-test needs the perf backend").into());
+            }
+            if !matches!(workload, Workload::Command(_)) {
+                return Err(anyhow!("--per-test needs to run a test binary").into());
+            }
+        }
+
+        if opts.c2c {
+            if !perf {
+                return Err(anyhow!("--c2c needs the perf backend").into());
+            }
+            let flamegraph_options = &mut opts.flamegraph_options;
+            if flamegraph_options.count_name.is_none() {
+                flamegraph_options.count_name = Some("contended loads".to_string());
+            }
+            if flamegraph_options.palette.is_none() {
+                flamegraph_options.palette = Palette::from_str("mem").ok();
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if opts.power {
+            if !opts.uses_perf() {
+                return Err(anyhow!("--power needs the perf backend").into());
+            }
+            if matches!(
+                workload,
+                Workload::ReadPerf(_) | Workload::Folded(_) | Workload::Import(..)
+            ) {
+                return Err(anyhow!("--power needs to record the workload").into());
+            }
+            if !opts.dry_run {
+                power::check()?;
+            }
+            let flamegraph_options = &mut opts.flamegraph_options;
+            if flamegraph_options.count_name.is_none() {
+                flamegraph_options.count_name = Some("µJ".to_string());
+            }
+        }
+
+        if opts.count_calls.is_some() && opts.flamegraph_options.count_name.is_none() {
+            opts.flamegraph_options.count_name = Some("calls".to_string());
+        }
+        if !opts.usdt.is_empty() && opts.flamegraph_options.count_name.is_none() {
+            opts.flamegraph_options.count_name = Some("probe hits".to_string());
+        }
+
+        if opts.time_units {
+            let factor = 1000.0 / f64::from(opts.frequency());
+            let flamegraph_options = &mut opts.flamegraph_options;
+            flamegraph_options.factor = Some(factor);
+            if flamegraph_options.count_name.is_none() {
+                flamegraph_options.count_name = Some("ms".to_string());
+            }
+        }
+
+        if opts.reproducible {
+            opts.flamegraph_options.deterministic = true;
+            opts.no_metadata = true;
+        }
+
+        let metadata = metadata::RunMetadata::collect(
+            workload,
+            opts.frequency(),
+            opts.build_profile.clone(),
+            opts.target_name.clone(),
+        );
+        if opts.output.to_string_lossy().contains('{') {
+            opts.output = PathBuf::from(metadata.expand(&opts.output.to_string_lossy()));
+        }
+        if let Some(title) = &mut opts.flamegraph_options.title {
+            *title = metadata.expand(title);
+        }
+        if let Some(subtitle) = &mut opts.flamegraph_options.subtitle {
+            *subtitle = metadata.expand(subtitle);
+        }
+
+        if !opts.no_metadata {
+            let notes = &mut opts.flamegraph_options.notes;
+            *notes = Some(match notes.take() {
+                Some(notes) => format!("{}\n{}", notes, metadata.notes()),
+                None => metadata.notes(),
+            });
+            if opts.flamegraph_options.subtitle.is_none() {
+                opts.flamegraph_options.subtitle = Some(metadata.summary());
+            }
+        }
+
+        if opts.script.is_some() && opts.backend()?.name() != "bpftrace" {
+            return Err(anyhow!("--script needs --backend bpftrace").into());
+        }
+
+        let binary = match workload {
+            Workload::Command(c) => c.first().map(|program| find_program(program)),
+            #[cfg(target_os = "linux")]
+            Workload::Pid(p) => p
+                .first()
+                .and_then(|pid| std::fs::read_link(format!("/proc/{pid}/exe")).ok()),
+            #[cfg(target_os = "linux")]
+            Workload::Thread(t) => t
+                .first()
+                .and_then(|(pid, _)| std::fs::read_link(format!("/proc/{pid}/exe")).ok()),
+            #[cfg(target_os = "macos")]
+            Workload::Pid(p) => p.first().and_then(|pid| entitlements::executable(*pid)),
+            _ => None,
+        };
+
+        let (folded, import) = match workload {
+            Workload::Folded(path) => (Some(path.clone()), None),
+            Workload::Import(format, path) => (Some(path.clone()), Some(*format)),
+            _ => (None, None),
+        };
+        if let Some(count_name) = import.and_then(|format| format.count_name(opts.heap_weight)) {
+            let flamegraph_options = &mut opts.flamegraph_options;
+            if flamegraph_options.count_name.is_none() {
+                flamegraph_options.count_name = Some(count_name.to_string());
+            }
+        }
+        if folded.is_some() && !opts.annotate.is_empty() {
+            return Err(anyhow!("--annotate needs a recording, not folded stacks").into());
+        }
+        if folded.is_some() && opts.c2c {
+            return Err(anyhow!("--c2c needs a recording, not folded stacks").into());
+        }
+
+        if let (Some(binary), None) = (&binary, &folded) {
+            stripped::check(binary, opts.build_profile.as_deref());
+        }
+
+        // Only the default perf command picks the call graph mode.
+        #[cfg(target_os = "linux")]
+        let frame_pointers = match &binary {
+            Some(binary) if opts.uses_perf() && opts.record_command()?.is_none() => {
+                let keeps = frame_pointers::keeps_frame_pointers(binary) == Some(true);
+                if keeps {
+                    log::debug!("{:?} keeps frame pointers, recording with them", binary);
+                }
+                keeps
+            }
+            _ => false,
+        };
+        #[cfg(not(target_os = "linux"))]
+        let frame_pointers = false;
+
+        Ok(Run {
+            backend: opts.backend()?,
+            opts,
+            metadata,
+            binary,
+            folded,
+            import,
+            frame_pointers,
+            root_flags,
+            escalation_cmd,
+            #[cfg(target_os = "linux")]
+            perf: capable_perf,
+            #[cfg(target_os = "linux")]
+            energy: Mutex::new(None),
+            tests: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Whether the samples are weighed by energy with `--power`.
+    fn weighs_energy(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        return self.opts.power;
+        #[cfg(not(target_os = "linux"))]
+        false
+    }
+
+    pub fn backend(&self) -> &(dyn Backend + Send + Sync) {
+        &*self.backend
+    }
+
+    fn root(&self) -> Option<Root<'_>> {
+        self.opts.runs_as_root().then_some(Root {
+            tool: self.opts.escalate.unwrap_or_default(),
+            flags: self.root_flags.as_deref(),
+            custom: self.escalation_cmd.as_deref(),
+        })
+    }
+
+    pub fn settings(&self) -> Result<RecordSettings<'_>, FlamegraphError> {
+        let opts = &self.opts;
+        Ok(RecordSettings {
+            frequency: opts.frequency(),
+            custom_cmd: opts.record_command()?,
+            perf_args: opts.perf_args.clone(),
+            frame_pointers: self.frame_pointers,
+            buffer_size: opts.buffer_size.clone(),
+            proc_map_timeout: opts.proc_map_timeout,
+            dtrace_args: opts.dtrace_args.clone(),
+            script: opts.script.clone(),
+            ignore_status: opts.ignore_status,
+            dry_run: opts.dry_run,
+            quiet: opts.quiet,
+            script_no_inline: opts.script_no_inline,
+            max_stack: opts.script_max_stack,
+            internal_script: opts.internal_script,
+            symbolize: opts.symbolize,
+            count_samples: opts.time_units,
+            include_pid: opts.split_pids,
+            c2c: opts.c2c,
+            sched_latency: opts.sched_latency,
+            #[cfg(target_os = "linux")]
+            skip_after: opts.flamegraph_options.skip_after.clone(),
+            #[cfg(not(target_os = "linux"))]
+            skip_after: Vec::new(),
+            collapse_threads: opts.collapse_threads,
+            duration: opts.duration,
+            max_samples: opts.max_samples,
+            trim_before: opts.trim_before.clone(),
+            root: self.root(),
+            #[cfg(target_os = "linux")]
+            perf: self.perf.as_deref(),
+            #[cfg(not(target_os = "linux"))]
+            perf: None,
+            stop: opts.stop.as_ref(),
+            tests: opts.per_test.then_some(&self.tests),
+            json: opts.message_format == MessageFormat::Json,
+            #[cfg(feature = "async")]
+            deferred: None,
+        })
+    }
+
+    /// Prepares the system for running the recorder with `settings`.
+    pub fn start_recording(
+        &self,
+        settings: &RecordSettings,
+        observer: &dyn ProfilerObserver,
+    ) -> Result<Recorder, FlamegraphError> {
+        #[cfg(target_os = "linux")]
+        let lowered_paranoid = if self.opts.fix_paranoid
+            && !self.opts.capabilities
+            && !self.opts.dry_run
+            && self.opts.uses_perf()
+        {
+            let required = paranoid::required_level(&format!(
+                "{} {}",
+                settings.custom_cmd.as_deref().unwrap_or_default(),
+                settings.perf_args.as_deref().unwrap_or_default()
+            ));
+            paranoid::too_high(required, settings.runs_as_root())
+                .map(|level| {
+                    sysctl::Override::new(
+                        paranoid::PARANOID,
+                        level,
+                        required,
+                        Root {
+                            tool: self.opts.escalate.unwrap_or_default(),
+                            flags: None,
+                            custom: self.escalation_cmd.as_deref(),
+                        },
+                    )
+                })
+                .transpose()?
+        } else {
+            None
+        };
+        #[cfg(not(target_os = "linux"))]
+        let _ = settings;
+
+        // Handle SIGINT with an empty handler. This has the
+        // implicit effect of allowing the signal to reach the
+        // process under observation while we continue to
+        // generate our flamegraph.  (ctrl+c will send the
+        // SIGINT signal to all processes in the foreground
+        // process group).
+        #[cfg(unix)]
+        let handler = unsafe { signal_hook::low_level::register(SIGINT, || {}) }
+            .context("cannot register signal handler")?;
+
+        #[cfg(target_os = "linux")]
+        if !self.opts.usdt.is_empty() && !self.opts.dry_run && self.opts.uses_perf() {
+            match &self.binary {
+                Some(binary) => perf::cache_probes(binary, settings)?,
+                None => log::warn!("the probes of an unknown binary cannot be added to perf"),
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.opts.cache_build_ids && !self.opts.dry_run {
+            match &self.binary {
+                Some(binary) => perf::cache_build_ids(binary, settings)?,
+                None => log::warn!("the build id of an unknown binary cannot be cached"),
+            }
+        }
+
+        #[cfg(target_os = "macos")]
+        if !self.opts.dry_run && self.backend.name() == "dtrace" {
+            if let Some(binary) = &self.binary {
+                entitlements::check(binary, self.opts.fix_entitlements, self.root())?;
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        let uprobe = match &self.opts.count_calls {
+            Some(symbol) if !self.opts.dry_run && self.opts.uses_perf() => {
+                let binary = self
+                    .opts
+                    .count_calls_in
+                    .as_ref()
+                    .or(self.binary.as_ref())
+                    .ok_or_else(|| {
+                        anyhow!("the binary defining {symbol} is unknown; pass --count-calls-in")
+                    })?;
+                Some(perf::Uprobe::add(binary, symbol, settings)?)
+            }
+            _ => None,
+        };
+
+        #[cfg(target_os = "linux")]
+        let meter = if self.opts.power && !self.opts.dry_run {
+            Some(power::Meter::start()?)
+        } else {
+            None
+        };
+
+        observer.phase_started(Phase::Recording);
+        Ok(Recorder {
+            #[cfg(target_os = "linux")]
+            lowered_paranoid,
+            #[cfg(unix)]
+            handler,
+            #[cfg(target_os = "linux")]
+            meter,
+            #[cfg(target_os = "linux")]
+            uprobe,
+            started: Instant::now(),
+        })
+    }
+
+    /// Restores the system after the recorder finished with `recording`.
+    pub fn finish_recording(
+        &self,
+        recorder: Recorder,
+        recording: Result<Recording, FlamegraphError>,
+        observer: &dyn ProfilerObserver,
+    ) -> Result<Recording, FlamegraphError> {
+        if recording.is_ok() {
+            observer.phase_finished(Phase::Recording, recorder.started.elapsed());
+        }
+
+        // DTrace only says it failed to attach, not what keeps it from doing so.
+        #[cfg(target_os = "macos")]
+        let recording = match recording {
+            Err(FlamegraphError::RecordFailed(err)) if self.backend.name() == "dtrace" => {
+                Err(FlamegraphError::RecordFailed(match entitlements::diagnose(
+                    self.binary.as_deref(),
+                ) {
+                    Some(reason) => err.context(reason),
+                    None => err,
+                }))
+            }
+            recording => recording,
+        };
+
+        #[cfg(target_os = "linux")]
+        {
+            drop(recorder.lowered_paranoid);
+            drop(recorder.uprobe);
+            if let Some(meter) = recorder.meter {
+                *self.energy.lock().unwrap() = Some(meter.finish());
+            }
+        }
+
+        if let Ok(Recording {
+            data: Some(data), ..
+        }) = &recording
+        {
+            chown_to_user(&[data], self.root());
+        }
+
+        #[cfg(unix)]
+        signal_hook::low_level::unregister(recorder.handler);
+
+        recording
+    }
+
+    /// Converts `recording` into folded stacks.
+    fn symbolize(
+        &self,
+        recording: &Recording,
+        settings: &RecordSettings,
+        observer: &dyn ProfilerObserver,
+    ) -> Result<Vec<u8>, FlamegraphError> {
+        let backend = self.backend();
+        #[cfg(target_os = "linux")]
+        let opts = &self.opts;
+        #[cfg(target_os = "linux")]
+        let uses_perf = opts.uses_perf();
+
+        #[cfg(target_os = "linux")]
+        let kptr_override = match self.root() {
+            Some(root) if uses_perf && opts.fix_kptr_restrict => kallsyms::ensure_resolvable(root)?,
+            _ => None,
+        };
+
+        // The script output is collapsed while it is produced, without holding all of it.
+        observer.phase_started(Phase::Symbolizing);
+        let started = Instant::now();
+        let data_size = recording
+            .data
+            .as_ref()
+            .and_then(|data| data.metadata().ok())
+            .map(|metadata| metadata.len());
+        let script = backend.script(recording, settings)?;
+        #[cfg(target_os = "linux")]
+        let script = match self.energy.lock().unwrap().take() {
+            Some(readings) => Box::new(Cursor::new(power::weigh(script, &readings)?)),
+            None => script,
+        };
+        let script = {
+            let tests = self.tests.lock().unwrap();
+            if tests.is_empty() {
+                script
+            } else {
+                Box::new(Cursor::new(libtest::attribute(script, &tests)?))
+            }
+        };
+        let mut script = ScriptMonitor::new(script, observer, data_size);
+        let collapsed = backend
+            .collapse(&mut script, settings)
+            .map_err(FlamegraphError::CollapseFailed)?;
+        script.report();
+        observer.phase_finished(Phase::Symbolizing, started.elapsed());
+
+        #[cfg(target_os = "linux")]
+        {
+            drop(kptr_override);
+            if uses_perf {
+                kallsyms::warn_unresolved(
+                    script.unresolved(),
+                    settings.runs_as_root() || opts.capabilities,
+                );
+            }
+            let dwarf_call_graphs = uses_perf
+                && recording.exit_status.is_some()
+                && settings.custom_cmd.is_none()
+                && !settings.frame_pointers;
+            if dwarf_call_graphs && frame_pointers::truncated_share(&collapsed) > 0.1 {
+                log::warn!(
+                    "many stacks were cut off by DWARF unwinding; build with `-C \
+                     force-frame-pointers=yes` so perf can unwind with frame pointers"
+                );
+            }
+        }
+
+        Ok(collapsed)
+    }
+
+    /// Symbolizes and collapses `recording`, or takes its stacks from the cache.
+    fn collapsed(
+        &self,
+        recording: &Recording,
+        settings: &RecordSettings,
+        observer: &dyn ProfilerObserver,
+    ) -> Result<Vec<u8>, FlamegraphError> {
+        let cache_key = match &recording.data {
+            // The energy readings and the tests of a recording are not part of the cached stacks.
+            Some(data) if !self.opts.no_cache && !self.weighs_energy() && !self.opts.per_test => {
+                cache::key(data, self.backend.name(), settings)
+                    .map_err(|err| log::debug!("not caching the stacks of {:?}: {}", data, err))
+                    .ok()
+            }
+            _ => None,
+        };
+        if let Some(collapsed) = cache_key.as_deref().and_then(cache::load) {
+            log::info!("reusing the stacks of an earlier run with the same recording");
+            return Ok(collapsed);
+        }
+
+        let collapsed = self.symbolize(recording, settings, observer)?;
+        if let Some(key) = &cache_key {
+            cache::store(key, &collapsed);
+        }
+        Ok(collapsed)
+    }
+
+    /// Collapses `recording`, unless folded stacks are rendered, and writes all outputs.
+    pub fn finish(
+        &self,
+        recording: Recording,
+        settings: &RecordSettings,
+        observer: &dyn ProfilerObserver,
+    ) -> Result<Profile, FlamegraphError> {
+        if self.opts.dry_run {
+            return Ok(self.print_plan(recording, settings));
+        }
+
+        let collapsed = match &self.folded {
+            Some(path) => match self.import {
+                Some(format) => format.fold(path, self.opts.heap_weight)?,
+                None => std::fs::read(path)
+                    .with_context(|| format!("unable to read {}", path.display()))?,
+            },
+            None => self.collapsed(&recording, settings, observer)?,
+        };
+        let collapsed = match (&self.folded, settings.max_stack) {
+            (Some(_), Some(max)) => folded::innermost_frames(&collapsed, max as usize),
+            _ => collapsed,
+        };
+        self.render(recording, collapsed, None, settings, observer)
+    }
+
+    /// Whether the workload is recorded several times, see [`Run::finish_runs`].
+    pub fn repeats(&self) -> bool {
+        self.opts.runs > 1 && !self.opts.dry_run
+    }
+
+    /// Records `command` once per `--runs`, collapses every recording and writes the outputs
+    /// of all runs together, outlining the functions whose share of the samples varies between
+    /// the runs by more than `--unstable-threshold`.
+    pub fn finish_runs(
+        &self,
+        command: &[String],
+        settings: &RecordSettings,
+        observer: &dyn ProfilerObserver,
+    ) -> Result<Profile, FlamegraphError> {
+        let runs = self.opts.runs;
+        let mut stacks = Vec::with_capacity(runs);
+        let mut recording = None;
+        for run in 1..=runs {
+            log::info!("recording run {} of {}", run, runs);
+            let recorder = self.start_recording(settings, observer)?;
+            let recorded = self
+                .backend()
+                .record(Workload::Command(command.to_vec()), settings);
+            let recorded = self.finish_recording(recorder, recorded, observer)?;
+            stacks.push(self.collapsed(&recorded, settings, observer)?);
+            recording = Some(recorded);
+        }
+        let recording = recording.expect("at least two runs");
+
+        let unstable = variance::unstable_functions(&stacks, self.opts.unstable_threshold);
+        variance::print_unstable(&unstable, runs);
+        let frame_attrs = variance::frame_attrs(&unstable, runs);
+        self.render(
+            recording,
+            variance::merge(&stacks),
+            Some(frame_attrs),
+            settings,
+            observer,
+        )
+    }
+
+    /// Post-processes `collapsed` and writes all outputs. `frame_attrs` are added to the frames
+    /// of the flamegraph, in the format of inferno's `--nameattr` files.
+    fn render(
+        &self,
+        recording: Recording,
+        mut collapsed: Vec<u8>,
+        frame_attrs: Option<String>,
+        settings: &RecordSettings,
+        observer: &dyn ProfilerObserver,
+    ) -> Result<Profile, FlamegraphError> {
+        let opts = &self.opts;
+        let backend = self.backend();
+
+        observer.phase_started(Phase::Collapsing);
+        let started = Instant::now();
+
+        if opts.drop_idle {
+            collapsed = idle::drop_idle(&collapsed, &opts.idle_function);
+        }
+        if opts.strip_harness {
+            collapsed = libtest::strip_harness(&collapsed);
+        }
+        if opts.fold_std {
+            collapsed = fold_std::fold_std(&collapsed);
+        }
+        if let Some(command) = &opts.post_process {
+            collapsed = post_process(command, &collapsed)?;
+        }
+        let processes = if opts.split_pids {
+            let (merged, processes) = folded::split_pids(&collapsed);
+            collapsed = merged;
+            processes
+        } else {
+            Vec::new()
+        };
+        let tests = libtest::split(&collapsed, &self.tests.lock().unwrap());
+
+        observer.phase_finished(Phase::Collapsing, started.elapsed());
+        let total_samples = folded::total_samples(&collapsed);
+        observer.samples_collected(total_samples);
+
+        observer.phase_started(Phase::Rendering);
+        let started = Instant::now();
+
+        let mut svg = export::Svg {
+            frame_attrs,
+            optimize: opts.optimize_svg,
+        };
+        if let Some(template) = &opts.source_link {
+            match &self.binary {
+                Some(binary) => {
+                    let links = source_links::frame_attrs(binary, template)
+                        .context("unable to resolve source links")?;
+                    // Attributes of the same function on several lines are combined.
+                    svg.frame_attrs = Some(svg.frame_attrs.unwrap_or_default() + &links);
+                }
+                None => log::warn!("cannot add source links: the profiled binary is unknown"),
+            }
+        }
+
+        let flamegraph_filename = &opts.output;
+        log::info!("writing flamegraph to {:?}", flamegraph_filename);
+        let flamegraph_file = File::create(flamegraph_filename)
+            .context("unable to create flamegraph.svg output file")?;
+
+        let mut flamegraph_writer = BufWriter::new(flamegraph_file);
+        svg.export(&collapsed, &opts.flamegraph_options, &mut flamegraph_writer)
+            .and_then(|()| Ok(flamegraph_writer.flush()?))
+            .map_err(FlamegraphError::RenderFailed)?;
+
+        let mut artifacts = vec![gallery::Artifact::new(flamegraph_filename, "Flamegraph")];
+
+        // Writes the flamegraph of a part of the stacks, e.g. of a process, to a file named after
+        // `name`.
+        let write_part = |collapsed: &[u8], part: &str, name: &str| {
+            let name: String = name
+                .chars()
+                .map(|c| {
+                    if c.is_alphanumeric() || c == '-' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            let path = sibling_path(flamegraph_filename, &format!("{name}.svg"));
+            log::info!("writing flamegraph of {} to {:?}", part, path);
+            let mut options = opts.flamegraph_options.clone();
+            options.title = Some(format!(
+                "{}: {}",
+                options.title.as_deref().unwrap_or("Flame Graph"),
+                part
+            ));
+            let file = File::create(&path)
+                .with_context(|| format!("unable to create {}", path.display()))?;
+            let mut writer = BufWriter::new(file);
+            svg.export(collapsed, &options, &mut writer)
+                .and_then(|()| Ok(writer.flush()?))
+                .map_err(FlamegraphError::RenderFailed)?;
+            Ok::<_, FlamegraphError>(path)
+        };
+        for process in &processes {
+            let part = format!("{} ({})", process.command, process.pid);
+            let name = format!("{}-{}", process.command.replace('-', "_"), process.pid);
+            let path = write_part(&process.collapsed, &part, &name)?;
+            artifacts.push(gallery::Artifact::new(path, format!("Process {part}")));
+        }
+        for (test, collapsed) in &tests {
+            let path = write_part(collapsed, test, &format!("test-{test}"))?;
+            artifacts.push(gallery::Artifact::new(path, format!("Test {test}")));
+        }
+
+        let exporters = opts
+            .export
+            .iter()
+            .map(|format| -> Arc<dyn Exporter + Send + Sync> {
+                match format {
+                    ExportFormat::Html => Arc::new(export::Html { svg: svg.clone() }),
+                    format => format.exporter(),
+                }
+            })
+            .chain(opts.exporters.iter().cloned());
+        for exporter in exporters {
+            let path = flamegraph_filename.with_extension(exporter.extension());
+            log::info!("writing {} to {:?}", exporter.name(), path);
+            let file = File::create(&path)
+                .with_context(|| format!("unable to create {}", path.display()))?;
+            let mut writer = BufWriter::new(file);
+            exporter
+                .export(&collapsed, &opts.flamegraph_options, &mut writer)
+                .and_then(|()| Ok(writer.flush()?))
+                .map_err(FlamegraphError::RenderFailed)?;
+            artifacts.push(gallery::Artifact::new(
+                path,
+                format!("{} export", exporter.name()),
+            ));
+        }
+
+        if let Some(symbol) = &opts.sandwich {
+            let panels = sandwich::write(
+                &collapsed,
+                symbol,
+                &opts.flamegraph_options,
+                flamegraph_filename,
+            )?;
+            if let Some(page) = panels.last() {
+                log::info!("writing sandwich view for {:?} to {:?}", symbol, page.path);
+            }
+            artifacts.extend(panels);
+        }
+
+        for symbol in &opts.annotate {
+            let report = backend.annotate(&recording, symbol, settings)?;
+            let name: String = symbol
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect();
+            let path = sibling_path(flamegraph_filename, &format!("annotate-{name}.txt"));
+            log::info!("writing annotation of {:?} to {:?}", symbol, path);
+            std::fs::write(&path, report)
+                .with_context(|| format!("unable to write {}", path.display()))?;
+            artifacts.push(gallery::Artifact::new(
+                path,
+                format!("Annotation of {symbol}"),
+            ));
+        }
+
+        if opts.c2c {
+            let report = backend.c2c_report(&recording, settings)?;
+            let path = sibling_path(flamegraph_filename, "c2c.txt");
+            log::info!("writing contended cache lines to {:?}", path);
+            std::fs::write(&path, report)
+                .with_context(|| format!("unable to write {}", path.display()))?;
+            artifacts.push(gallery::Artifact::new(path, "Contended cache lines"));
+        }
+
+        if opts.crate_report() {
+            crates::report(
+                &collapsed,
+                &opts.known_crates,
+                opts.crate_flamegraph.as_deref(),
+                &opts.flamegraph_options,
+            )?;
+            if let Some(path) = &opts.crate_flamegraph {
+                artifacts.push(gallery::Artifact::new(path, "Crate flamegraph"));
+            }
+        }
+
+        if artifacts.iter().filter(|a| a.is_svg()).count() > 1 {
+            let path = sibling_path(flamegraph_filename, "index.html");
+            log::info!("writing index of all outputs to {:?}", path);
+            let notes = match opts.no_metadata {
+                true => String::new(),
+                false => self.metadata.notes(),
+            };
+            gallery::write_index(
+                &path,
+                opts.flamegraph_options
+                    .title
+                    .as_deref()
+                    .unwrap_or("Flamegraphs"),
+                &notes,
+                &artifacts,
+            )?;
+            artifacts.push(gallery::Artifact::new(path, "Index of all outputs"));
+        }
+
+        if opts.manifest {
+            let path = sibling_path(flamegraph_filename, "manifest.json");
+            log::info!("writing manifest of all outputs to {:?}", path);
+            let mut metadata = self.metadata.to_json();
+            metadata["total_samples"] = total_samples.into();
+            metadata["exit_code"] = recording
+                .exit_status
+                .and_then(|status| status.code())
+                .into();
+            gallery::write_manifest(&path, metadata, &artifacts)?;
+            artifacts.push(gallery::Artifact::new(path, "Manifest of all outputs"));
+        }
+
+        observer.phase_finished(Phase::Rendering, started.elapsed());
+
+        let outputs: Vec<_> = artifacts
+            .into_iter()
+            .map(|artifact| artifact.path)
+            .collect();
+        let written: Vec<_> = outputs.iter().map(PathBuf::as_path).collect();
+        chown_to_user(&written, None);
+        for path in &written {
+            observer.output_written(path);
+        }
+        if opts.quiet && opts.message_format == MessageFormat::Human {
+            for path in &written {
+                println!("{}", path.display());
+            }
+        }
+
+        #[cfg(feature = "cli")]
+        {
+            let index = sibling_path(flamegraph_filename, "index.html");
+            match outputs.contains(&index) {
+                true => opts.open_output(&index)?,
+                false => opts.open_output(flamegraph_filename)?,
+            }
+        }
+
+        if let Some(baseline) = &opts.check_against {
+            regression::check(baseline, &collapsed, opts.regression_threshold)?;
+        }
+
+        Ok(Profile {
+            total_samples,
+            collapsed,
+            outputs,
+            exit_status: recording.exit_status,
+        })
+    }
+
+    /// Prints how `recording` would be collapsed and which files would be written, for
+    /// `--dry-run`.
+    fn print_plan(&self, recording: Recording, settings: &RecordSettings) -> Profile {
+        let opts = &self.opts;
+        match &self.folded {
+            Some(path) => match self.import {
+                Some(format) => log::info!("convert the {} profile {:?}", format.name(), path),
+                None => log::info!("read folded stacks from {:?}", path),
+            },
+            None => {
+                let data = match &recording.data {
+                    Some(data) => format!("{:?}", data),
+                    None => "the recording".to_string(),
+                };
+                let script = if settings.symbolize == Symbolizer::Internal {
+                    "in-process with the debug info"
+                } else if settings.internal_script {
+                    "in-process"
+                } else {
+                    "with the recorder"
+                };
+                log::info!(
+                    "symbolize {} {} ({}), collapse with {}{}{}{}",
+                    data,
+                    script,
+                    self.backend.name(),
+                    settings
+                        .collapse_threads
+                        .map_or("one thread per CPU".to_string(), |threads| format!(
+                            "{threads} threads"
+                        )),
+                    if settings.script_no_inline {
+                        ", without inlined frames"
+                    } else {
+                        ""
+                    },
+                    settings
+                        .max_stack
+                        .map_or(String::new(), |n| format!(", {n} frames deep at most")),
+                    if settings.skip_after.is_empty() {
+                        String::new()
+                    } else {
+                        format!(", cut off after {}", settings.skip_after.join(", "))
+                    },
+                );
+            }
+        }
+        if opts.drop_idle {
+            log::info!("drop idle stacks");
+        }
+        if opts.strip_harness {
+            log::info!("strip the frames of the test harness");
+        }
+        if opts.fold_std {
+            log::info!("fold the frames of the standard library");
+        }
+        if let Some(command) = &opts.post_process {
+            log::info!("post-process the stacks with {:?}", command);
+        }
+
+        let output = &opts.output;
+        let mut outputs = vec![output.clone()];
+        outputs.extend(
+            opts.export
+                .iter()
+                .map(|format| output.with_extension(format.exporter().extension()))
+                .chain(
+                    opts.exporters
+                        .iter()
+                        .map(|exporter| output.with_extension(exporter.extension())),
+                ),
+        );
+        if opts.sandwich.is_some() {
+            outputs.extend(
+                ["callers.svg", "callees.svg", "sandwich.html"]
+                    .iter()
+                    .map(|suffix| sibling_path(output, suffix)),
+            );
+        }
+        outputs.extend(opts.annotate.iter().map(|symbol| {
+            let name: String = symbol
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect();
+            sibling_path(output, &format!("annotate-{name}.txt"))
+        }));
+        if opts.c2c {
+            outputs.push(sibling_path(output, "c2c.txt"));
+        }
+        if opts.crate_report() {
+            outputs.extend(opts.crate_flamegraph.clone());
+        }
+        let svgs = outputs
+            .iter()
+            .filter(|path| path.extension().is_some_and(|ext| ext == "svg"))
+            .count();
+        if svgs > 1 {
+            outputs.push(sibling_path(output, "index.html"));
+        }
+        if opts.manifest {
+            outputs.push(sibling_path(output, "manifest.json"));
+        }
+        for path in &outputs {
+            log::info!("write {:?}", path);
+        }
+
+        Profile {
+            collapsed: Vec::new(),
+            total_samples: 0,
+            outputs,
+            exit_status: recording.exit_status,
+        }
+    }
+}
+
+/// Finds `program` in `$PATH` like a shell would, if it is not a path itself.
+fn find_program(program: &str) -> PathBuf {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.to_path_buf();
+    }
+    std::env::var_os("PATH")
+        .and_then(|dirs| {
+            std::env::split_paths(&dirs)
+                .map(|dir| dir.join(program))
+                .find(|candidate| candidate.is_file())
+        })
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Runs the folded stacks through the post-process `command`.
+fn post_process(command: &str, collapsed: &[u8]) -> Result<Vec<u8>, FlamegraphError> {
+    let command_vec =
+        shlex::split(command).ok_or_else(|| anyhow!("unable to parse post-process command"))?;
+
+    let mut child = Command::new(
+        command_vec
+            .first()
+            .ok_or_else(|| anyhow!("unable to parse post-process command"))?,
+    )
+    .args(command_vec.get(1..).unwrap_or(&[]))
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .with_context(|| format!("unable to execute {:?}", command_vec))?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("unable to capture post-process stdin"))?;
+
+    let mut stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("unable to capture post-process stdout"))?;
+
+    let thread_handle = std::thread::spawn(move || -> anyhow::Result<_> {
+        let mut collapsed_processed = Vec::new();
+        stdout.read_to_end(&mut collapsed_processed).context(
+            "unable to read the processed stacks from the stdout of the post-process process",
+        )?;
+        Ok(collapsed_processed)
+    });
+
+    stdin
+        .write_all(collapsed)
+        .context("unable to write the raw stacks to the stdin of the post-process process")?;
+    drop(stdin);
+
+    let status = child
+        .wait()
+        .context("unable to wait for the post-process command")?;
+    if !status.success() {
+        return Err(anyhow!("post-process exited with a non zero exit code").into());
+    }
+
+    Ok(thread_handle.join().unwrap()?)
+}
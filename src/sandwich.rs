@@ -0,0 +1,122 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use inferno::flamegraph::{from_reader, Direction};
+
+use crate::{folded, gallery::Artifact, sibling_path, FlamegraphOptions};
+
+/// Splits every stack containing `symbol` at the outermost matching frame into
+/// the stack of its callers (reversed, so `symbol` is the root) and the stack
+/// of its callees (starting at `symbol`).
+fn split_stacks(collapsed: &[u8], symbol: &str) -> (Vec<u8>, Vec<u8>) {
+    let mut callers = Vec::new();
+    let mut callees = Vec::new();
+
+    for (stack, count) in folded::lines(collapsed) {
+        let frames: Vec<_> = stack.split(';').collect();
+        let position = match frames.iter().position(|frame| frame.contains(symbol)) {
+            Some(position) => position,
+            None => continue,
+        };
+
+        let caller_stack: Vec<_> = frames[..=position].iter().rev().copied().collect();
+        writeln!(callers, "{} {}", caller_stack.join(";"), count).unwrap();
+        writeln!(callees, "{} {}", frames[position..].join(";"), count).unwrap();
+    }
+
+    (callers, callees)
+}
+
+fn render(
+    stacks: &[u8],
+    options: &FlamegraphOptions,
+    title: String,
+    direction: Direction,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let mut inferno_opts = options.clone().into_inferno();
+    inferno_opts.title = title;
+    inferno_opts.direction = direction;
+    inferno_opts.reverse_stack_order = false;
+
+    let writer = BufWriter::new(
+        File::create(path).with_context(|| format!("unable to create {}", path.display()))?,
+    );
+    from_reader(&mut inferno_opts, BufReader::new(stacks), writer)
+        .with_context(|| format!("unable to generate {}", path.display()))
+}
+
+/// Writes a two-panel view for `symbol` next to `output`: the merged callers
+/// grow upwards from the symbol and the merged callees grow downwards from it.
+///
+/// Returns the written panels and the page combining them.
+pub(crate) fn write(
+    collapsed: &[u8],
+    symbol: &str,
+    options: &FlamegraphOptions,
+    output: &Path,
+) -> anyhow::Result<Vec<Artifact>> {
+    let (callers, callees) = split_stacks(collapsed, symbol);
+    anyhow::ensure!(
+        !callers.is_empty(),
+        "no sampled stack contains a frame matching '{symbol}'"
+    );
+
+    let callers_path = sibling_path(output, "callers.svg");
+    let callees_path = sibling_path(output, "callees.svg");
+    let page_path = sibling_path(output, "sandwich.html");
+
+    render(
+        &callers,
+        options,
+        format!("Callers of {symbol}"),
+        Direction::Straight,
+        &callers_path,
+    )?;
+    render(
+        &callees,
+        options,
+        format!("Callees of {symbol}"),
+        Direction::Inverted,
+        &callees_path,
+    )?;
+
+    let file_name = |path: &Path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Sandwich: {title}</title></head>
+<body style="margin: 0">
+<object type="image/svg+xml" data="{callers}" style="display: block; width: 100%"></object>
+<object type="image/svg+xml" data="{callees}" style="display: block; width: 100%"></object>
+</body>
+</html>
+"#,
+        title = html_escape(symbol),
+        callers = html_escape(&file_name(&callers_path)),
+        callees = html_escape(&file_name(&callees_path)),
+    );
+    std::fs::write(&page_path, page)
+        .with_context(|| format!("unable to create {}", page_path.display()))?;
+
+    Ok(vec![
+        Artifact::new(callers_path, format!("Callers of {symbol}")),
+        Artifact::new(callees_path, format!("Callees of {symbol}")),
+        Artifact::new(page_path, format!("Sandwich view of {symbol}")),
+    ])
+}
+
+pub(crate) fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,121 @@
+//! Run-queue delay flamegraphs for `--sched-latency`.
+//!
+//! A task waits in the run queue from being woken up, or from being preempted, until it is
+//! switched in again. That time is attributed to the stack the task was switched out with,
+//! which is where it resumes.
+
+use std::{collections::HashMap, fmt::Write, io::BufRead};
+
+use anyhow::Context;
+
+use crate::backend::perf::{sample_time, Headers};
+
+/// The perf events recorded for `--sched-latency`. Tasks are switched in and woken up by other
+/// tasks, so the whole system is recorded.
+pub(crate) const EVENTS: &str =
+    "-a -e sched:sched_switch -e sched:sched_wakeup -e sched:sched_wakeup_new";
+
+/// The stack a task was last switched out with.
+struct SwitchedOut {
+    /// The sample header up to its timestamp, with the command and thread of the task.
+    header: String,
+    stack: Vec<String>,
+}
+
+/// A sample being read: its header and its stack.
+struct Event {
+    header: String,
+    stack: Vec<String>,
+}
+
+/// Value of the tracepoint field `name` in a sample header, e.g. `pid` of `pid=1234`.
+fn field<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.split_whitespace().find_map(|word| {
+        word.strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('='))
+    })
+}
+
+#[derive(Default)]
+struct State {
+    switched_out: HashMap<u32, SwitchedOut>,
+    /// Since when each runnable task waits to be switched in, in nanoseconds.
+    waiting: HashMap<u32, u64>,
+    output: String,
+    total: u64,
+}
+
+impl State {
+    fn finish(&mut self, event: Event) {
+        let Some((end, time)) = sample_time(&event.header) else {
+            return;
+        };
+        let pid = |name| field(&event.header, name).and_then(|pid| pid.parse::<u32>().ok());
+        if event.header.contains(" sched:sched_switch:") {
+            if let Some(next) = pid("next_pid").filter(|&pid| pid != 0) {
+                let since = self.waiting.remove(&next);
+                if let (Some(since), Some(out)) = (since, self.switched_out.get(&next)) {
+                    let delay = time.saturating_sub(since) / 1000;
+                    if delay > 0 {
+                        self.total += delay;
+                        writeln!(self.output, "{} {delay} sched:sched_switch: ", out.header)
+                            .unwrap();
+                        for frame in &out.stack {
+                            writeln!(self.output, "{frame}").unwrap();
+                        }
+                        self.output.push('\n');
+                    }
+                }
+            }
+            if let Some(prev) = pid("prev_pid").filter(|&pid| pid != 0) {
+                // A preempted task is still runnable and waits right away.
+                if field(&event.header, "prev_state").is_some_and(|state| state.starts_with('R')) {
+                    self.waiting.insert(prev, time);
+                }
+                self.switched_out.insert(
+                    prev,
+                    SwitchedOut {
+                        header: event.header[..end].to_string(),
+                        stack: event.stack,
+                    },
+                );
+            }
+        } else if event.header.contains(" sched:sched_wakeup") {
+            if let Some(woken) = pid("pid") {
+                self.waiting.entry(woken).or_insert(time);
+            }
+        }
+    }
+}
+
+/// Turns `perf script` output of the [`EVENTS`] into samples weighted by the microseconds the
+/// tasks waited in the run queue, with the stacks they waited in.
+pub(crate) fn run_queue_delays(script: impl BufRead) -> anyhow::Result<Vec<u8>> {
+    let mut state = State::default();
+    let mut event: Option<Event> = None;
+    let mut headers = Headers::default();
+    for line in script.lines() {
+        let line = line.context("unable to read the perf script output")?;
+        if headers.is_header(&line) {
+            if let Some(event) = event.take() {
+                state.finish(event);
+            }
+            event = Some(Event {
+                header: line,
+                stack: Vec::new(),
+            });
+        } else if !line.trim().is_empty() {
+            if let Some(event) = &mut event {
+                event.stack.push(line);
+            }
+        }
+    }
+    if let Some(event) = event.take() {
+        state.finish(event);
+    }
+    log::info!(
+        "tasks waited {:.3} ms in run queues in total",
+        state.total as f64 / 1000.0
+    );
+    Ok(state.output.into_bytes())
+}
@@ -0,0 +1,209 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, Context};
+use gimli::{AttributeValue, EndianSlice, RunTimeEndian};
+use object::{Object, ObjectSection};
+
+/// Location of a function's definition, relative to the repository root.
+struct SourceLocation {
+    path: PathBuf,
+    line: u64,
+}
+
+fn git(args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .context("unable to run git")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "'git {}' failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Turns a template into a URL with the placeholders `{path}` and `{line}` for the location
+/// of a function. The template is either a full URL with these placeholders, or of the form
+/// `host/org/repo@{rev}` for one of the code hosting sites in [`file_url`]. `{rev}` is replaced
+/// with the checked out commit in both.
+fn url_template(template: &str) -> anyhow::Result<String> {
+    let template = if template.contains("{path}") {
+        template.to_string()
+    } else {
+        let (repository, rev) = template.rsplit_once('@').unwrap_or((template, "{rev}"));
+        file_url(repository.trim_end_matches('/'), rev)?
+    };
+    let template = if template.contains("{rev}") {
+        template.replace("{rev}", &git(&["rev-parse", "HEAD"])?)
+    } else {
+        template
+    };
+
+    if template.contains("://") {
+        Ok(template)
+    } else {
+        Ok(format!("https://{template}"))
+    }
+}
+
+/// The URL of the file `{path}` at `rev` of `repository`, scrolled to `{line}`. Supports
+/// GitHub, GitLab, Bitbucket, Codeberg and other Gitea or Forgejo hosts, and SourceHut.
+fn file_url(repository: &str, rev: &str) -> anyhow::Result<String> {
+    let host = repository
+        .split_once("://")
+        .map_or(repository, |(_, rest)| rest)
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let path = match host {
+        "github.com" => format!("blob/{rev}/{{path}}#L{{line}}"),
+        "bitbucket.org" => format!("src/{rev}/{{path}}#lines-{{line}}"),
+        "git.sr.ht" => format!("tree/{rev}/item/{{path}}#L{{line}}"),
+        host if host == "gitlab.com" || host.starts_with("gitlab.") => {
+            format!("-/blob/{rev}/{{path}}#L{{line}}")
+        }
+        host if host == "codeberg.org" || host.starts_with("gitea.") => {
+            format!("src/commit/{rev}/{{path}}#L{{line}}")
+        }
+        host => {
+            return Err(anyhow!(
+                "unknown code hosting site '{host}', pass a URL template with {{path}} and \
+                 {{line}} instead, e.g. 'https://{repository}/blob/{{rev}}/{{path}}#L{{line}}'"
+            ))
+        }
+    };
+    Ok(format!("{repository}/{path}"))
+}
+
+/// Reads the declaration site of every function with debug info in `binary`,
+/// keyed by the demangled name (without hash) as it appears in the stacks.
+fn function_locations(
+    binary: &Path,
+    root: &Path,
+) -> anyhow::Result<HashMap<String, SourceLocation>> {
+    let data =
+        std::fs::read(binary).with_context(|| format!("unable to read '{}'", binary.display()))?;
+    let object = object::File::parse(&*data)
+        .with_context(|| format!("unable to parse '{}'", binary.display()))?;
+    let endian = if object.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+
+    let sections = gimli::DwarfSections::load(|id| -> Result<_, gimli::Error> {
+        Ok(object
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or(Cow::Borrowed(&[])))
+    })?;
+    let dwarf = sections.borrow(|section| EndianSlice::new(section, endian));
+
+    let mut locations = HashMap::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let line_program = match &unit.line_program {
+            Some(line_program) => line_program.header(),
+            None => continue,
+        };
+        let comp_dir = unit
+            .comp_dir
+            .map(|dir| PathBuf::from(dir.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_subprogram {
+                continue;
+            }
+
+            let linkage_name = match entry.attr_value(gimli::DW_AT_linkage_name)? {
+                Some(name) => dwarf.attr_string(&unit, name)?,
+                None => continue,
+            };
+            let file = match entry.attr_value(gimli::DW_AT_decl_file)? {
+                Some(AttributeValue::FileIndex(index)) => index,
+                _ => continue,
+            };
+            let line = match entry.attr_value(gimli::DW_AT_decl_line)? {
+                Some(AttributeValue::Udata(line)) => line,
+                _ => continue,
+            };
+
+            let file = match line_program.file(file) {
+                Some(file) => file,
+                None => continue,
+            };
+            let mut path = comp_dir.clone();
+            if let Some(directory) = file.directory(line_program) {
+                path.push(
+                    dwarf
+                        .attr_string(&unit, directory)?
+                        .to_string_lossy()
+                        .as_ref(),
+                );
+            }
+            path.push(
+                dwarf
+                    .attr_string(&unit, file.path_name())?
+                    .to_string_lossy()
+                    .as_ref(),
+            );
+
+            // Only functions from the repository can be linked to.
+            let path = match path.strip_prefix(root) {
+                Ok(path) => path.to_path_buf(),
+                Err(_) => continue,
+            };
+
+            let name = format!(
+                "{:#}",
+                rustc_demangle::demangle(&linkage_name.to_string_lossy())
+            );
+            locations.insert(name, SourceLocation { path, line });
+        }
+    }
+
+    Ok(locations)
+}
+
+/// Builds frame attributes that link every function defined in the current git
+/// repository to its source on the code hosting site described by `template`, in the
+/// format of inferno's `--nameattr` files.
+pub(crate) fn frame_attrs(binary: &Path, template: &str) -> anyhow::Result<String> {
+    let root = PathBuf::from(git(&["rev-parse", "--show-toplevel"])?);
+    let url = url_template(template)?;
+    let locations = function_locations(binary, &root)?;
+    if locations.is_empty() {
+        return Err(anyhow!(
+            "no debug info for functions in '{}' found in '{}'",
+            root.display(),
+            binary.display()
+        ));
+    }
+
+    let mut attrs = String::new();
+    for (function, location) in locations {
+        let path = location
+            .path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let href = url
+            .replace("{path}", &path)
+            .replace("{line}", &location.line.to_string());
+        writeln!(attrs, "{}\thref={}\ttarget=_blank", function, href).unwrap();
+    }
+
+    Ok(attrs)
+}
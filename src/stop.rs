@@ -0,0 +1,30 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Ends a recording early from another thread.
+///
+/// Once [`stop`](StopHandle::stop) is called, the recorder is interrupted the way Ctrl-C would
+/// interrupt it, and the flamegraph is generated from the samples recorded so far. Stopping
+/// before the recording started cancels the run with [`FlamegraphError::Cancelled`].
+///
+/// [`FlamegraphError::Cancelled`]: crate::FlamegraphError::Cancelled
+#[derive(Debug, Clone, Default)]
+pub struct StopHandle {
+    stopped: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
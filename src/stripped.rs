@@ -0,0 +1,44 @@
+//! Detection of stripped binaries, whose flamegraphs only show addresses or `[unknown]`
+//! frames, before spending time on recording them.
+
+use std::path::Path;
+
+use object::Object;
+
+/// Warns if `binary` has no symbol table or no debug info, with the Cargo.toml change that
+/// keeps them when it was built with the cargo `profile`.
+pub(crate) fn check(binary: &Path, profile: Option<&str>) {
+    let data = match std::fs::read(binary) {
+        Ok(data) => data,
+        Err(_) => return,
+    };
+    let file = match object::File::parse(&*data) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+
+    let fix = |settings: &str| match profile {
+        Some(profile) => format!(
+            "; add the following lines to Cargo.toml to keep them:\n\n\
+             [profile.{profile}]\n{settings}\n"
+        ),
+        None => String::new(),
+    };
+
+    if file.symbols().next().is_none() {
+        log::warn!(
+            "{} is stripped, so most frames will only show addresses{}",
+            binary.display(),
+            fix("strip = false")
+        );
+    } else if file.format() == object::BinaryFormat::Elf
+        && file.section_by_name(".debug_info").is_none()
+    {
+        // Mach-O binaries keep their debug info in a dSYM bundle or the object files instead.
+        log::info!(
+            "{} has no debug info, so inlined functions are not shown{}",
+            binary.display(),
+            fix("debug = true\nstrip = \"none\"")
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! Reading and temporarily changing integer kernel parameters.
+
+use anyhow::Context;
+
+use crate::escalate::{Escalate, Root};
+
+/// Current value of the kernel parameter `name` (e.g. `kernel.kptr_restrict`), if it can be
+/// read.
+pub(crate) fn read(name: &str) -> Option<i32> {
+    let path = format!("/proc/sys/{}", name.replace('.', "/"));
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+}
+
+fn write(name: &str, value: i32, root: Root) -> anyhow::Result<()> {
+    let root = Root {
+        flags: None,
+        ..root
+    };
+    let mut command = root.command("sysctl");
+    command.args(["-w", &format!("{name}={value}")]);
+    crate::print_command(&command);
+    let output = command
+        .output()
+        .with_context(|| format!("unable to run sysctl to change {name}"))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "unable to set {} to {}: {}",
+        name,
+        value,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+/// Sets a kernel parameter until dropped, at which point the previous value is restored.
+pub(crate) struct Override {
+    name: &'static str,
+    previous: i32,
+    tool: Escalate,
+    custom: Option<Vec<String>>,
+}
+
+impl Override {
+    pub fn new(name: &'static str, previous: i32, value: i32, root: Root) -> anyhow::Result<Self> {
+        log::info!("temporarily changing {name} from {previous} to {value}");
+        write(name, value, root)?;
+        Ok(Override {
+            name,
+            previous,
+            tool: root.tool,
+            custom: root.custom.map(<[String]>::to_vec),
+        })
+    }
+}
+
+impl Drop for Override {
+    fn drop(&mut self) {
+        let root = Root {
+            tool: self.tool,
+            flags: None,
+            custom: self.custom.as_deref(),
+        };
+        if let Err(err) = write(self.name, self.previous, root) {
+            log::error!("{err:#}");
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Profiling the processes of a systemd unit, for `--systemd-unit`.
+
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::paranoid;
+
+/// Where the unified (v2) and the legacy (v1) cgroup hierarchy of systemd are mounted.
+const CGROUP_ROOTS: &[&str] = &["/sys/fs/cgroup", "/sys/fs/cgroup/systemd"];
+
+/// A property of `unit` as shown by `systemctl show`.
+fn property(unit: &str, name: &str) -> anyhow::Result<String> {
+    let output = Command::new("systemctl")
+        .args(["show", "--value", "--property", name, unit])
+        .output()
+        .context("unable to run systemctl; is systemd running?")?;
+    if !output.status.success() {
+        bail!(
+            "unable to look up {unit}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Adds the processes of the control group `dir` and of its child groups to `pids`.
+fn cgroup_pids(dir: &Path, pids: &mut Vec<u32>) {
+    if let Ok(procs) = fs::read_to_string(dir.join("cgroup.procs")) {
+        pids.extend(
+            procs
+                .lines()
+                .filter_map(|pid| pid.trim().parse::<u32>().ok()),
+        );
+    }
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            cgroup_pids(&entry.path(), pids);
+        }
+    }
+}
+
+/// The processes of `unit`, e.g. `nginx.service`: all processes in its control group, or its
+/// main process if the control group cannot be read.
+pub fn pids(unit: &str) -> anyhow::Result<Vec<u32>> {
+    let cgroup = property(unit, "ControlGroup")?;
+    let mut pids = Vec::new();
+    if !cgroup.is_empty() {
+        let relative = cgroup.trim_start_matches('/');
+        for root in CGROUP_ROOTS {
+            let dir = PathBuf::from(root).join(relative);
+            if dir.join("cgroup.procs").exists() {
+                cgroup_pids(&dir, &mut pids);
+                break;
+            }
+        }
+    }
+    if pids.is_empty() {
+        let main = property(unit, "MainPID")?;
+        pids.extend(main.parse::<u32>().ok().filter(|&pid| pid != 0));
+    }
+    pids.sort_unstable();
+    pids.dedup();
+    if pids.is_empty() {
+        return Err(anyhow!("{unit} has no running processes"));
+    }
+    log::info!("profiling {} processes of {unit}", pids.len());
+    Ok(pids)
+}
+
+/// Whether some of `pids` run as another user than flamegraph, so profiling them needs root
+/// privileges.
+pub fn needs_root(pids: &[u32]) -> bool {
+    if paranoid::is_root() {
+        return false;
+    }
+    let Ok(own) = fs::metadata("/proc/self").map(|proc| proc.uid()) else {
+        return false;
+    };
+    pids.iter()
+        .any(|pid| fs::metadata(format!("/proc/{pid}")).is_ok_and(|proc| proc.uid() != own))
+}
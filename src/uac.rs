@@ -0,0 +1,113 @@
+//! Administrator rights on Windows, which ETW and DTrace need to record.
+//!
+//! There is no `sudo` to run the recorder with, so `--root` relaunches the whole invocation
+//! elevated through a UAC prompt instead, in a console window of its own that stays open if it
+//! fails.
+
+use std::{
+    env,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Once,
+    },
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::Options;
+
+/// Whether this process runs with administrator rights, i.e. at a high integrity level.
+pub fn is_elevated() -> bool {
+    static CHECKED: Once = Once::new();
+    static ELEVATED: AtomicBool = AtomicBool::new(false);
+    CHECKED.call_once(|| {
+        let elevated = Command::new("whoami")
+            .arg("/groups")
+            .output()
+            .is_ok_and(|output| {
+                let groups = String::from_utf8_lossy(&output.stdout);
+                // The High and System Mandatory Levels.
+                groups.contains("S-1-16-12288") || groups.contains("S-1-16-16384")
+            });
+        ELEVATED.store(elevated, Ordering::Relaxed);
+    });
+    ELEVATED.load(Ordering::Relaxed)
+}
+
+/// The error recording without administrator rights ends in, with how to get them.
+pub(crate) fn error() -> anyhow::Error {
+    anyhow!(
+        "recording on Windows needs administrator rights; pass --root to relaunch flamegraph \
+         elevated through a UAC prompt, or run it from a terminal started with \"Run as \
+         administrator\""
+    )
+}
+
+/// Quotes `s` for PowerShell, in which single-quoted strings are taken literally.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Encodes a PowerShell script for `-EncodedCommand`, which spares quoting it for the command
+/// line: Base64 of its UTF-16LE bytes.
+fn encode(script: &str) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let bytes: Vec<u8> = script.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// With `--root` and without administrator rights, runs this invocation again elevated and
+/// returns its exit code once it is done, for the caller to exit with. The process is started
+/// again with its own command line, so this is meant for the binaries rather than for embedders.
+/// Returns `None` without relaunching otherwise.
+pub fn relaunch_elevated(opts: &Options) -> anyhow::Result<Option<i32>> {
+    if opts.root.is_none() || is_elevated() {
+        return Ok(None);
+    }
+    let exe = env::current_exe().context("unable to find the flamegraph binary")?;
+    let dir = env::current_dir().context("unable to read the current directory")?;
+    let mut args = vec![quote(&exe.to_string_lossy())];
+    args.extend(
+        env::args_os()
+            .skip(1)
+            .map(|arg| quote(&arg.to_string_lossy())),
+    );
+    let elevated = format!(
+        "Set-Location -LiteralPath {}; & {}; if ($LASTEXITCODE) {{ Read-Host 'flamegraph \
+         failed; press Enter to close this window' }}; exit $LASTEXITCODE",
+        quote(&dir.to_string_lossy()),
+        args.join(" ")
+    );
+    let launcher = format!(
+        "$p = Start-Process -FilePath powershell -Verb RunAs -Wait -PassThru -ArgumentList \
+         '-NoProfile', '-EncodedCommand', '{}'; exit $p.ExitCode",
+        encode(&elevated)
+    );
+
+    log::info!("relaunching elevated, as recording needs administrator rights");
+    let mut command = Command::new("powershell");
+    command.args(["-NoProfile", "-EncodedCommand", &encode(&launcher)]);
+    crate::print_command(&command);
+    let status = command
+        .status()
+        .context("unable to run powershell to relaunch flamegraph elevated")?;
+    if !status.success() {
+        log::error!("the elevated flamegraph failed or the UAC prompt was declined");
+    }
+    Ok(Some(status.code().unwrap_or(1)))
+}
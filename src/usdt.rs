@@ -0,0 +1,33 @@
+use std::{fmt, str::FromStr};
+
+/// A user-level statically defined tracepoint, parsed from `PROVIDER:PROBE`, whose hits are
+/// recorded with `--usdt`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsdtProbe {
+    pub provider: String,
+    pub name: String,
+}
+
+impl FromStr for UsdtProbe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((provider, name))
+                if !provider.is_empty() && !name.is_empty() && !name.contains(':') =>
+            {
+                Ok(UsdtProbe {
+                    provider: provider.to_string(),
+                    name: name.to_string(),
+                })
+            }
+            _ => Err(format!("expected PROVIDER:PROBE, got '{s}'")),
+        }
+    }
+}
+
+impl fmt::Display for UsdtProbe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.provider, self.name)
+    }
+}
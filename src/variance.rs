@@ -0,0 +1,119 @@
+//! Comparing the stacks of several runs of the same workload, for `--runs`.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+};
+
+use crate::{folded, regression::MIN_TRACKED_SHARE};
+
+/// A function whose share of the samples varied between the runs.
+pub(crate) struct Unstable {
+    pub function: String,
+    /// Mean and standard deviation of the share, in percent.
+    pub mean: f64,
+    pub deviation: f64,
+}
+
+impl Unstable {
+    /// Standard deviation relative to the mean, in percent.
+    fn variation(&self) -> f64 {
+        self.deviation * 100.0 / self.mean
+    }
+}
+
+/// The stacks of all `runs` added up.
+pub(crate) fn merge(runs: &[Vec<u8>]) -> Vec<u8> {
+    let mut counts: BTreeMap<&str, u64> = BTreeMap::new();
+    for (stack, count) in runs.iter().flat_map(|run| folded::lines(run)) {
+        *counts.entry(stack).or_default() += count;
+    }
+    let mut merged = Vec::new();
+    for (stack, count) in counts {
+        merged.extend_from_slice(format!("{stack} {count}\n").as_bytes());
+    }
+    merged
+}
+
+/// The functions whose inclusive share of the samples has a standard deviation of more than
+/// `threshold` percent of its mean across `runs`, most unstable first. Functions with a small
+/// share are left out, as their share is dominated by sampling noise.
+pub(crate) fn unstable_functions(runs: &[Vec<u8>], threshold: f64) -> Vec<Unstable> {
+    let shares: Vec<_> = runs
+        .iter()
+        .map(|run| {
+            let total = folded::total_samples(run).max(1) as f64;
+            folded::inclusive_counts(run)
+                .into_iter()
+                .map(|(function, count)| (function, count as f64 * 100.0 / total))
+                .collect::<BTreeMap<_, _>>()
+        })
+        .collect();
+    let functions: HashSet<&String> = shares.iter().flat_map(|run| run.keys()).collect();
+
+    let n = runs.len() as f64;
+    let mut unstable: Vec<_> = functions
+        .into_iter()
+        .map(|function| {
+            let values: Vec<f64> = shares
+                .iter()
+                .map(|run| run.get(function).copied().unwrap_or(0.0))
+                .collect();
+            let mean = values.iter().sum::<f64>() / n;
+            let variance =
+                values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0).max(1.0);
+            Unstable {
+                function: function.clone(),
+                mean,
+                deviation: variance.sqrt(),
+            }
+        })
+        .filter(|f| f.mean >= MIN_TRACKED_SHARE && f.variation() > threshold)
+        .collect();
+    unstable.sort_by(|a, b| {
+        b.variation()
+            .total_cmp(&a.variation())
+            .then_with(|| a.function.cmp(&b.function))
+    });
+    unstable
+}
+
+/// Prints the `unstable` functions, if any, with their share of the samples.
+pub(crate) fn print_unstable(unstable: &[Unstable], runs: usize) {
+    if unstable.is_empty() {
+        log::info!(
+            "the share of every function was stable across {} runs",
+            runs
+        );
+        return;
+    }
+    log::warn!(
+        "the share of {} function(s) varied between the {} runs; they are outlined in the \
+         flamegraph",
+        unstable.len(),
+        runs
+    );
+    let mut table = vec![format!("{:>10} {:>10}  function", "share", "deviation")];
+    table.extend(
+        unstable
+            .iter()
+            .map(|f| format!("{:>9.2}% {:>9.2}%  {}", f.mean, f.deviation, f.function)),
+    );
+    log::info!("{}", table.join("\n"));
+}
+
+/// Frame attributes outlining the `unstable` functions and noting the variation in their
+/// titles, in the format of inferno's `--nameattr` files.
+pub(crate) fn frame_attrs(unstable: &[Unstable], runs: usize) -> String {
+    let mut attrs = String::new();
+    for f in unstable {
+        writeln!(
+            attrs,
+            "{0}\ttitle={0} (unstable: {1:.2}% ± {2:.2}% of the samples over {3} runs)\t\
+             g_extra=style=\"stroke:rgb(0,0,0);stroke-width:1;stroke-dasharray:3,2\"",
+            f.function, f.mean, f.deviation, runs
+        )
+        .unwrap();
+    }
+    attrs
+}